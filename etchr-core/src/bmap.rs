@@ -0,0 +1,588 @@
+//! Reads and writes block maps (`.bmap` files), which list the parts of an
+//! image that hold data.
+//!
+//! Build systems such as Yocto ship a block map next to sparse images like
+//! `.wic` files. Only the mapped blocks have to be written; the rest of the
+//! image is free space whose contents do not matter. Versions 1.4 and 2.0 of
+//! the format, as written by `bmaptool`, are understood, with sha256 checksums.
+//! Block maps for images that are read from a device are written as version
+//! 2.0, with every block that is not all zeros mapped.
+//! A block map looks like this (comments and the range checksums shortened):
+//!
+//! ```xml
+//! <?xml version="1.0" ?>
+//! <bmap version="2.0">
+//!     <ImageSize> 821752 </ImageSize>
+//!     <BlockSize> 4096 </BlockSize>
+//!     <BlocksCount> 201 </BlocksCount>
+//!     <MappedBlocksCount> 117 </MappedBlocksCount>
+//!     <ChecksumType> sha256 </ChecksumType>
+//!     <BmapFileChecksum> d9cf7d... </BmapFileChecksum>
+//!     <BlockMap>
+//!         <Range chksum="9eaf19..."> 0-1 </Range>
+//!         <Range chksum="e8a26f..."> 3-117 </Range>
+//!     </BlockMap>
+//! </bmap>
+//! ```
+use anyhow::{Result, anyhow};
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// A run of consecutive blocks that hold data.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct MappedRange {
+    /// The offset of the range in the image, in bytes.
+    pub(crate) start: u64,
+    /// The end of the range, cut off at the end of the image.
+    pub(crate) end: u64,
+    /// The SHA-256 of the image data in the range, if the block map has one.
+    pub(crate) sha256: Option<[u8; 32]>,
+}
+
+/// The parsed contents of a block map.
+#[derive(Clone, Debug)]
+pub(crate) struct BlockMap {
+    /// The size of the (uncompressed) image, in bytes.
+    pub(crate) image_size: u64,
+    /// The size of the blocks the ranges are counted in, in bytes.
+    pub(crate) block_size: u64,
+    /// The mapped ranges, in order and without overlaps.
+    pub(crate) ranges: Vec<MappedRange>,
+}
+
+impl BlockMap {
+    /// Reads and checks the block map at `path`.
+    pub(crate) fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Could not read the block map {}: {}", path.display(), e))?;
+        parse(&text).map_err(|e| anyhow!("Invalid block map {}: {}", path.display(), e))
+    }
+
+    /// The total number of mapped bytes.
+    pub(crate) fn mapped_len(&self) -> u64 {
+        self.ranges.iter().map(|r| r.end - r.start).sum()
+    }
+
+    /// Returns the part of `start..end` (image offsets) that runs from the
+    /// first to the last mapped byte in it, or `None` if nothing in it is mapped.
+    pub(crate) fn mapped_span(&self, start: u64, end: u64) -> Option<(u64, u64)> {
+        let first = self.ranges.partition_point(|r| r.end <= start);
+        let last = self.ranges.partition_point(|r| r.start < end);
+        if first >= last {
+            return None;
+        }
+        Some((
+            self.ranges[first].start.max(start),
+            self.ranges[last - 1].end.min(end),
+        ))
+    }
+
+    /// Renders the block map as a version 2.0 `.bmap` file, checksum included.
+    pub(crate) fn to_xml(&self) -> String {
+        let blocks_count = self.image_size.div_ceil(self.block_size);
+        let mapped_blocks_count: u64 = self
+            .ranges
+            .iter()
+            .map(|r| r.end.div_ceil(self.block_size) - r.start / self.block_size)
+            .sum();
+        // Writing to a String cannot fail, so the results are ignored.
+        let mut xml = String::new();
+        let _ = writeln!(xml, "<?xml version=\"1.0\" ?>");
+        let _ = writeln!(
+            xml,
+            "<!-- The blocks of an image read by etchr that are not all zeros. -->"
+        );
+        let _ = writeln!(xml, "<bmap version=\"2.0\">");
+        let _ = writeln!(xml, "    <ImageSize> {} </ImageSize>", self.image_size);
+        let _ = writeln!(xml, "    <BlockSize> {} </BlockSize>", self.block_size);
+        let _ = writeln!(xml, "    <BlocksCount> {} </BlocksCount>", blocks_count);
+        let _ = writeln!(
+            xml,
+            "    <MappedBlocksCount> {} </MappedBlocksCount>",
+            mapped_blocks_count
+        );
+        let _ = writeln!(xml, "    <ChecksumType> sha256 </ChecksumType>");
+        let zeros = "0".repeat(64);
+        let _ = writeln!(xml, "    <BmapFileChecksum> {} </BmapFileChecksum>", zeros);
+        let _ = writeln!(xml, "    <BlockMap>");
+        for range in &self.ranges {
+            let first = range.start / self.block_size;
+            let last = range.end.div_ceil(self.block_size) - 1;
+            let _ = write!(xml, "        <Range");
+            if let Some(sha256) = range.sha256 {
+                let _ = write!(xml, " chksum=\"{}\"", hex::encode(sha256));
+            }
+            if first == last {
+                let _ = writeln!(xml, "> {} </Range>", first);
+            } else {
+                let _ = writeln!(xml, "> {}-{} </Range>", first, last);
+            }
+        }
+        xml.push_str("    </BlockMap>\n</bmap>\n");
+
+        // The file checksum is taken with its own value replaced by zeros.
+        let checksum = hex::encode(Sha256::digest(xml.as_bytes()));
+        xml.replacen(&zeros, &checksum, 1)
+    }
+
+    /// Names the blocks of a range as in the block map, for messages.
+    pub(crate) fn blocks(&self, range: &MappedRange) -> String {
+        let first = range.start / self.block_size;
+        let last = range.end.div_ceil(self.block_size) - 1;
+        if first == last {
+            format!("block {}", first)
+        } else {
+            format!("blocks {}-{}", first, last)
+        }
+    }
+}
+
+/// Checks the image against the checksums of the mapped ranges as it streams
+/// past, and remembers what each range should read back as.
+pub(crate) struct RangeChecker<'m> {
+    map: &'m BlockMap,
+    /// The range the data is currently in, or the next one.
+    next: usize,
+    hasher: Sha256,
+    /// Whether the data started partway into the current range, as it does
+    /// when a write is resumed, so it cannot be checked.
+    partial: bool,
+    /// The SHA-256 of each range that has been passed.
+    hashes: Vec<Option<[u8; 32]>>,
+}
+
+impl<'m> RangeChecker<'m> {
+    /// Creates a checker for image data starting at offset `start`.
+    pub(crate) fn new(map: &'m BlockMap, start: u64) -> Self {
+        let next = map.ranges.partition_point(|r| r.end <= start);
+        let mut hashes = vec![None; map.ranges.len()];
+        // Ranges that were written before a resume can only be checked
+        // against the block map.
+        for (hash, range) in hashes.iter_mut().zip(&map.ranges).take(next) {
+            *hash = range.sha256;
+        }
+        Self {
+            map,
+            next,
+            hasher: Sha256::new(),
+            partial: map.ranges.get(next).is_some_and(|r| r.start < start),
+            hashes,
+        }
+    }
+
+    /// Feeds the image data at offset `pos`, failing if it completes a range
+    /// whose checksum does not match.
+    pub(crate) fn update(&mut self, pos: u64, data: &[u8]) -> Result<()> {
+        let end = pos + data.len() as u64;
+        while let Some(range) = self.map.ranges.get(self.next) {
+            if range.start >= end {
+                break;
+            }
+            let (from, to) = (range.start.max(pos), range.end.min(end));
+            self.hasher
+                .update(&data[(from - pos) as usize..(to - pos) as usize]);
+            if range.end > end {
+                break;
+            }
+
+            let actual: [u8; 32] = self.hasher.finalize_reset().into();
+            if self.partial {
+                self.hashes[self.next] = range.sha256;
+                self.partial = false;
+            } else {
+                if let Some(expected) = range.sha256
+                    && expected != actual
+                {
+                    return Err(anyhow!(
+                        "The image does not match its block map in {}: expected sha256={}, got sha256={}",
+                        self.map.blocks(range),
+                        hex::encode(expected),
+                        hex::encode(actual)
+                    ));
+                }
+                self.hashes[self.next] = Some(actual);
+            }
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns the SHA-256 of each range, once the whole image of `len` bytes
+    /// has been fed.
+    pub(crate) fn finish(self, len: u64) -> Result<Vec<Option<[u8; 32]>>> {
+        if len != self.map.image_size {
+            return Err(anyhow!(
+                "The block map is for an image of {} bytes, but the image is {} bytes",
+                self.map.image_size,
+                len
+            ));
+        }
+        if self.next < self.map.ranges.len() {
+            return Err(anyhow!(
+                "The image ended before {} of its block map",
+                self.map.blocks(&self.map.ranges[self.next])
+            ));
+        }
+        Ok(self.hashes)
+    }
+}
+
+/// Works out the block map of an image as its data streams past, mapping
+/// every block that is not all zeros.
+pub(crate) struct MapBuilder {
+    block_size: usize,
+    /// The start of a block that has only partly been fed.
+    partial: Vec<u8>,
+    /// The number of bytes fed so far, not counting `partial`.
+    len: u64,
+    ranges: Vec<MappedRange>,
+    /// The hash of the last range, while it may still grow.
+    open: Option<Sha256>,
+}
+
+impl MapBuilder {
+    pub(crate) fn new(block_size: usize) -> Self {
+        Self {
+            block_size,
+            partial: Vec::new(),
+            len: 0,
+            ranges: Vec::new(),
+            open: None,
+        }
+    }
+
+    /// Feeds the next part of the image.
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = (self.block_size - self.partial.len()).min(data.len());
+            let (head, rest) = data.split_at(take);
+            data = rest;
+            if self.partial.is_empty() && take == self.block_size {
+                self.add_block(head);
+            } else {
+                self.partial.extend_from_slice(head);
+                if self.partial.len() == self.block_size {
+                    let block = std::mem::take(&mut self.partial);
+                    self.add_block(&block);
+                    self.partial = block;
+                    self.partial.clear();
+                }
+            }
+        }
+    }
+
+    fn add_block(&mut self, block: &[u8]) {
+        let start = self.len;
+        self.len += block.len() as u64;
+        if block.iter().all(|&b| b == 0) {
+            self.close_range();
+            return;
+        }
+        match (&mut self.open, self.ranges.last_mut()) {
+            (Some(hasher), Some(range)) => {
+                hasher.update(block);
+                range.end = self.len;
+            }
+            _ => {
+                self.open = Some(Sha256::new_with_prefix(block));
+                self.ranges.push(MappedRange {
+                    start,
+                    end: self.len,
+                    sha256: None,
+                });
+            }
+        }
+    }
+
+    fn close_range(&mut self) {
+        if let Some(hasher) = self.open.take()
+            && let Some(range) = self.ranges.last_mut()
+        {
+            range.sha256 = Some(hasher.finalize().into());
+        }
+    }
+
+    /// Returns the block map, once the whole image has been fed.
+    pub(crate) fn finish(mut self) -> BlockMap {
+        if !self.partial.is_empty() {
+            let block = std::mem::take(&mut self.partial);
+            self.add_block(&block);
+        }
+        self.close_range();
+        BlockMap {
+            image_size: self.len,
+            block_size: self.block_size as u64,
+            ranges: self.ranges,
+        }
+    }
+}
+
+/// Removes `<!-- ... -->` comments, which may contain anything.
+fn strip_comments(xml: &str) -> String {
+    let mut out = String::with_capacity(xml.len());
+    let mut rest = xml;
+    while let Some(start) = rest.find("<!--") {
+        out.push_str(&rest[..start]);
+        rest = match rest[start..].find("-->") {
+            Some(end) => &rest[start + end + 3..],
+            None => "",
+        };
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Returns the trimmed text of the first `<name>` element in `xml`.
+fn element<'x>(xml: &'x str, name: &str) -> Result<&'x str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = xml
+        .find(&open)
+        .ok_or_else(|| anyhow!("missing <{}>", name))?
+        + open.len();
+    let len = xml[start..]
+        .find(&close)
+        .ok_or_else(|| anyhow!("unterminated <{}>", name))?;
+    Ok(xml[start..start + len].trim())
+}
+
+/// Parses the number in the `<name>` element of `xml`.
+fn number(xml: &str, name: &str) -> Result<u64> {
+    let text = element(xml, name)?;
+    text.parse()
+        .map_err(|_| anyhow!("<{}> is not a number: {:?}", name, text))
+}
+
+/// Returns the value of the attribute `name` in the attributes of a tag.
+fn attribute<'x>(attributes: &'x str, name: &str) -> Option<&'x str> {
+    let key = format!("{}=", name);
+    let mut rest = attributes;
+    loop {
+        let at = rest.find(&key)?;
+        // Make sure this is not the tail of a longer attribute name.
+        let whole = at == 0 || rest[..at].ends_with(char::is_whitespace);
+        rest = &rest[at + key.len()..];
+        let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'')?;
+        let end = rest[1..].find(quote)?;
+        if whole {
+            return Some(&rest[1..1 + end]);
+        }
+        rest = &rest[1 + end..];
+    }
+}
+
+fn parse_sha256(text: &str) -> Result<[u8; 32]> {
+    let mut sha256 = [0; 32];
+    hex::decode_to_slice(text.trim(), &mut sha256)
+        .map_err(|_| anyhow!("{:?} is not a sha256 checksum", text))?;
+    Ok(sha256)
+}
+
+fn parse(text: &str) -> Result<BlockMap> {
+    let xml = strip_comments(text);
+
+    let tag_start = xml.find("<bmap").ok_or_else(|| anyhow!("missing <bmap>"))?;
+    let tag_end = xml[tag_start..]
+        .find('>')
+        .ok_or_else(|| anyhow!("unterminated <bmap>"))?;
+    let version = attribute(&xml[tag_start + 5..tag_start + tag_end], "version")
+        .ok_or_else(|| anyhow!("the <bmap> element has no version"))?;
+    let (major, minor) = version
+        .split_once('.')
+        .and_then(|(major, minor)| Some((major.parse::<u32>().ok()?, minor.parse::<u32>().ok()?)))
+        .ok_or_else(|| anyhow!("invalid version {:?}", version))?;
+    // 1.4 added the checksum type; 2.0 made sha256 the default.
+    if !(major == 1 && minor >= 4 || major == 2) {
+        return Err(anyhow!(
+            "version {} is not supported, only versions 1.4 to 2.x are",
+            version
+        ));
+    }
+
+    let checksum_type = element(&xml, "ChecksumType")?;
+    if checksum_type != "sha256" {
+        return Err(anyhow!(
+            "{} checksums are not supported, only sha256 ones are",
+            checksum_type
+        ));
+    }
+
+    // The file checksum is taken with its own value replaced by zeros.
+    let file_checksum = element(&xml, "BmapFileChecksum")?;
+    let expected = parse_sha256(file_checksum)?;
+    let zeroed = text.replacen(file_checksum, &"0".repeat(file_checksum.len()), 1);
+    let actual: [u8; 32] = Sha256::digest(zeroed.as_bytes()).into();
+    if actual != expected {
+        return Err(anyhow!("the file is corrupt (its checksum does not match)"));
+    }
+
+    let image_size = number(&xml, "ImageSize")?;
+    let block_size = number(&xml, "BlockSize")?;
+    let blocks_count = number(&xml, "BlocksCount")?;
+    let mapped_blocks_count = number(&xml, "MappedBlocksCount")?;
+    if block_size == 0 || blocks_count != image_size.div_ceil(block_size) {
+        return Err(anyhow!(
+            "{} blocks of {} bytes do not make up an image of {} bytes",
+            blocks_count,
+            block_size,
+            image_size
+        ));
+    }
+
+    let mut ranges: Vec<MappedRange> = Vec::new();
+    let mut mapped_blocks = 0;
+    let mut rest = element(&xml, "BlockMap")?;
+    while let Some(start) = rest.find("<Range") {
+        rest = &rest[start + "<Range".len()..];
+        let (attributes, after) = rest
+            .split_once('>')
+            .ok_or_else(|| anyhow!("unterminated <Range>"))?;
+        let (blocks, after) = after
+            .split_once("</Range>")
+            .ok_or_else(|| anyhow!("unterminated <Range>"))?;
+        rest = after;
+
+        let blocks = blocks.trim();
+        let (first, last) = blocks.split_once('-').unwrap_or((blocks, blocks));
+        let (Ok(first), Ok(last)) = (first.trim().parse::<u64>(), last.trim().parse::<u64>())
+        else {
+            return Err(anyhow!("invalid range {:?}", blocks));
+        };
+        if first > last || last >= blocks_count {
+            return Err(anyhow!("range {} is outside the image", blocks));
+        }
+        if ranges.last().is_some_and(|r| first * block_size < r.end) {
+            return Err(anyhow!("range {} overlaps or is out of order", blocks));
+        }
+        mapped_blocks += last - first + 1;
+        ranges.push(MappedRange {
+            start: first * block_size,
+            end: ((last + 1) * block_size).min(image_size),
+            sha256: attribute(attributes, "chksum")
+                .map(parse_sha256)
+                .transpose()?,
+        });
+    }
+    if mapped_blocks != mapped_blocks_count {
+        return Err(anyhow!(
+            "the ranges hold {} blocks, but {} are counted",
+            mapped_blocks,
+            mapped_blocks_count
+        ));
+    }
+
+    Ok(BlockMap {
+        image_size,
+        block_size,
+        ranges,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    pub(crate) const BLOCK: usize = 4096;
+
+    /// The image the fixtures map: 63.5 blocks, where the mapped blocks
+    /// 0-1, 10, 20-23 and 63 are filled with their number plus one, and the
+    /// rest are zeros.
+    pub(crate) fn image() -> Vec<u8> {
+        let mut image = vec![0u8; 63 * BLOCK + 2048];
+        for block in [0, 1, 10, 20, 21, 22, 23, 63] {
+            let end = ((block + 1) * BLOCK).min(image.len());
+            image[block * BLOCK..end].fill(block as u8 + 1);
+        }
+        image
+    }
+
+    /// The block map of [`image`] in `version`, as `bmaptool create` writes
+    /// it.
+    pub(crate) fn fixture(version: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/data")
+            .join(format!("image-{}.bmap", version))
+    }
+
+    fn spans(map: &BlockMap) -> Vec<(u64, u64, Option<[u8; 32]>)> {
+        map.ranges
+            .iter()
+            .map(|r| (r.start, r.end, r.sha256))
+            .collect()
+    }
+
+    #[test]
+    fn reads_bmaptool_versions() {
+        let image = image();
+        for version in ["1.4", "2.0"] {
+            let map = BlockMap::read(&fixture(version)).unwrap();
+            assert_eq!(map.image_size, image.len() as u64);
+            assert_eq!(map.block_size, BLOCK as u64);
+            let ends: Vec<_> = map.ranges.iter().map(|r| (r.start, r.end)).collect();
+            let block = BLOCK as u64;
+            assert_eq!(
+                ends,
+                [
+                    (0, 2 * block),
+                    (10 * block, 11 * block),
+                    (20 * block, 24 * block),
+                    (63 * block, image.len() as u64),
+                ]
+            );
+            for range in &map.ranges {
+                let data = &image[range.start as usize..range.end as usize];
+                assert_eq!(range.sha256, Some(Sha256::digest(data).into()));
+            }
+            assert_eq!(map.mapped_len(), 7 * block + 2048);
+        }
+    }
+
+    #[test]
+    fn refuses_changed_files_and_other_versions() {
+        let text = std::fs::read_to_string(fixture("2.0")).unwrap();
+        parse(&text).unwrap();
+        let changed = text.replacen("> 10 <", "> 11 <", 1);
+        let e = parse(&changed).unwrap_err();
+        assert!(e.to_string().contains("checksum does not match"), "{}", e);
+        let older = text.replacen("version=\"2.0\"", "version=\"1.3\"", 1);
+        let e = parse(&older).unwrap_err();
+        assert!(e.to_string().contains("not supported"), "{}", e);
+    }
+
+    #[test]
+    fn built_maps_match_bmaptool() {
+        let image = image();
+        let mut builder = MapBuilder::new(BLOCK);
+        // Fed in pieces that do not line up with the blocks.
+        for piece in image.chunks(3000) {
+            builder.update(piece);
+        }
+        let built = builder.finish();
+        let expected = BlockMap::read(&fixture("2.0")).unwrap();
+        assert_eq!(spans(&built), spans(&expected));
+        assert_eq!(
+            parse(&built.to_xml()).map(|map| spans(&map)).unwrap(),
+            spans(&expected)
+        );
+    }
+
+    #[test]
+    fn checker_compares_ranges_with_the_map() {
+        let map = BlockMap::read(&fixture("1.4")).unwrap();
+        let mut image = image();
+        let mut checker = RangeChecker::new(&map, 0);
+        for (i, piece) in image.chunks(5000).enumerate() {
+            checker.update((i * 5000) as u64, piece).unwrap();
+        }
+        let hashes = checker.finish(image.len() as u64).unwrap();
+        let expected: Vec<_> = map.ranges.iter().map(|r| r.sha256).collect();
+        assert_eq!(hashes, expected);
+
+        image[21 * BLOCK] = 0;
+        let mut checker = RangeChecker::new(&map, 0);
+        let e = checker.update(0, &image).unwrap_err();
+        assert!(e.to_string().contains("blocks 20-23"), "{}", e);
+    }
+}
@@ -0,0 +1,91 @@
+//! Heap buffers aligned for direct I/O.
+//!
+//! `O_DIRECT` transfers must start at an address that is a multiple of the
+//! device's logical sector size. A `Vec<u8>` only guarantees byte alignment,
+//! so the buffer is allocated with an explicit [`Layout`] instead.
+use std::alloc::{self, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+/// A zero-initialized, fixed-size byte buffer whose start is aligned to a
+/// power of two.
+pub(crate) struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+// The buffer owns its allocation exclusively, like a `Box<[u8]>`.
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocates `len` bytes aligned to `align`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is not a power of two, or if `len` rounded up to
+    /// `align` overflows `isize`.
+    pub(crate) fn new(len: usize, align: usize) -> Self {
+        // Zero-sized allocations are not allowed, but an empty slice at a
+        // dangling address is.
+        let layout = Layout::from_size_align(len.max(1), align)
+            .expect("invalid alignment for an aligned buffer");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, len, layout }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // The allocation is at least `len` bytes and was zeroed when made.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_aligned_and_zeroed() {
+        for align in [512, 4096] {
+            // Several at once, so that they are not all the same allocation.
+            let mut buffers: Vec<_> = [0, 1, 511, 4096, 1 << 20]
+                .into_iter()
+                .map(|len| AlignedBuffer::new(len, align))
+                .collect();
+            for buffer in &mut buffers {
+                assert_eq!(buffer.as_ptr() as usize % align, 0, "{}", align);
+                assert!(buffer.iter().all(|&b| b == 0));
+                buffer.fill(0xFF);
+                assert!(buffer.iter().all(|&b| b == 0xFF));
+            }
+            assert_eq!(
+                buffers.iter().map(|b| b.len()).collect::<Vec<_>>(),
+                [0, 1, 511, 4096, 1 << 20]
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid alignment")]
+    fn alignment_must_be_a_power_of_two() {
+        AlignedBuffer::new(4096, 1000);
+    }
+}
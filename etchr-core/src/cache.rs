@@ -0,0 +1,271 @@
+//! A cache of decompressed images that is kept between runs.
+//!
+//! Decompressing an xz image often takes longer than writing it to a fast
+//! card, so when the same image is flashed over and over, the decompressed
+//! data can be kept in a [`Cache`] instead of a temporary file. Entries are
+//! named by the SHA-256 of the compressed file, so a new download of the same
+//! name is never confused with an old one. Each entry records the size and
+//! SHA-256 of the decompressed data, and is checked against them before it is
+//! used; an entry that does not match (left behind by a crash, or modified
+//! since) is deleted and the image is decompressed again. Once the cache grows
+//! beyond its maximum size, the entries used least recently are evicted.
+use crate::source::ImageFile;
+use crate::write::hash_file;
+use anyhow::Result;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::time::SystemTime;
+use tempfile::NamedTempFile;
+
+/// The maximum size of a cache unless set otherwise.
+pub const DEFAULT_MAX_SIZE: u64 = 32 << 30;
+
+/// The prefix of files that are still being written.
+const PARTIAL_PREFIX: &str = ".partial-";
+
+/// A directory of decompressed images, limited to a maximum size.
+#[derive(Clone, Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl Cache {
+    /// Creates a cache in `dir`, which is created when the first image is
+    /// stored in it, with a maximum size of [`DEFAULT_MAX_SIZE`].
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+
+    /// Sets the maximum size of the cache in bytes. An image larger than this
+    /// is decompressed as if there were no cache.
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// The directory holding the cache.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn image_path(&self, key: &[u8; 32]) -> PathBuf {
+        self.dir.join(format!("{}.img", hex::encode(key)))
+    }
+
+    fn meta_path(&self, key: &[u8; 32]) -> PathBuf {
+        self.dir.join(format!("{}.sha256", hex::encode(key)))
+    }
+
+    /// Returns the path of the decompressed image for the compressed file
+    /// with SHA-256 `key`, if it is cached and still intact. Checking it reads
+    /// the whole entry, reporting the number of bytes read.
+    pub(crate) fn lookup(
+        &self,
+        key: &[u8; 32],
+        running: &AtomicBool,
+        on_progress: &mut dyn FnMut(u64),
+    ) -> Result<Option<PathBuf>> {
+        let image = self.image_path(key);
+        let meta = self.meta_path(key);
+        let recorded = match fs::read_to_string(&meta) {
+            Ok(contents) => parse_meta(&contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                remove_if_exists(&image)?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let len = match fs::metadata(&image) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                remove_if_exists(&meta)?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let intact = match recorded {
+            Some((sha256, size)) if size == len => {
+                hash_file(&ImageFile::single(&image), running, on_progress)? == sha256
+            }
+            _ => false,
+        };
+        if !intact {
+            remove_if_exists(&image)?;
+            remove_if_exists(&meta)?;
+            return Ok(None);
+        }
+
+        // The modification time of an entry records when it was last used.
+        File::options()
+            .write(true)
+            .open(&image)?
+            .set_modified(SystemTime::now())?;
+        Ok(Some(image))
+    }
+
+    /// Creates a file in the cache directory to decompress an image into.
+    /// It is deleted when dropped, unless it is stored with [`Cache::insert`].
+    pub(crate) fn partial_file(&self) -> io::Result<NamedTempFile> {
+        fs::create_dir_all(&self.dir)?;
+        tempfile::Builder::new()
+            .prefix(PARTIAL_PREFIX)
+            .tempfile_in(&self.dir)
+    }
+
+    /// Whether an image of `len` bytes can be stored at all.
+    pub(crate) fn admits(&self, len: u64) -> bool {
+        len <= self.max_size
+    }
+
+    /// Stores `file`, holding `len` bytes of decompressed data with SHA-256
+    /// `sha256`, as the entry for the compressed file with SHA-256 `key`,
+    /// evicting older entries to make room. Returns the path of the entry.
+    pub(crate) fn insert(
+        &self,
+        key: &[u8; 32],
+        file: NamedTempFile,
+        len: u64,
+        sha256: [u8; 32],
+    ) -> io::Result<PathBuf> {
+        self.evict(self.max_size.saturating_sub(len))?;
+
+        // The image goes in place before its size and checksum, so an entry
+        // that is interrupted halfway fails the check in `lookup`.
+        file.as_file().sync_all()?;
+        let image = self.image_path(key);
+        file.persist(&image).map_err(|e| e.error)?;
+
+        let mut meta = self.partial_file()?;
+        writeln!(meta, "{} {}", hex::encode(sha256), len)?;
+        meta.as_file().sync_all()?;
+        meta.persist(self.meta_path(key)).map_err(|e| e.error)?;
+        Ok(image)
+    }
+
+    /// Deletes the entries used least recently until the rest take up at
+    /// most `budget` bytes.
+    fn evict(&self, budget: u64) -> io::Result<()> {
+        let mut entries = entries(&self.dir)?;
+        let mut total: u64 = entries.iter().map(|e| e.len).sum();
+        entries.sort_by_key(|e| e.used);
+        for entry in entries {
+            if total <= budget {
+                break;
+            }
+            remove_if_exists(&entry.path)?;
+            remove_if_exists(&entry.path.with_extension("sha256"))?;
+            total -= entry.len;
+        }
+        Ok(())
+    }
+}
+
+/// The size of a cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of cached images.
+    pub entries: usize,
+    /// The total size of the cached images in bytes.
+    pub bytes: u64,
+}
+
+/// The directory the cache is kept in by default: `etchr` in the user's cache
+/// directory, such as `~/.cache/etchr`. Returns `None` if the user's cache
+/// directory cannot be determined.
+pub fn default_dir() -> Option<PathBuf> {
+    #[cfg(windows)]
+    let base = std::env::var_os("LOCALAPPDATA").map(PathBuf::from);
+    #[cfg(not(windows))]
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")));
+    base.map(|dir| dir.join("etchr"))
+}
+
+/// Counts the images cached in `dir`. A missing directory is an empty cache.
+pub fn stats(dir: &Path) -> io::Result<CacheStats> {
+    let entries = entries(dir)?;
+    Ok(CacheStats {
+        entries: entries.len(),
+        bytes: entries.iter().map(|e| e.len).sum(),
+    })
+}
+
+/// Deletes every image cached in `dir`, along with anything left behind by
+/// an interrupted run, and returns what was deleted.
+pub fn clear(dir: &Path) -> io::Result<CacheStats> {
+    let cleared = stats(dir)?;
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(cleared),
+        Err(e) => return Err(e),
+    };
+    for file in read_dir {
+        let path = file?.path();
+        let ours = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|name| {
+                name.starts_with(PARTIAL_PREFIX)
+                    || name.ends_with(".img")
+                    || name.ends_with(".sha256")
+            });
+        if ours {
+            remove_if_exists(&path)?;
+        }
+    }
+    Ok(cleared)
+}
+
+/// A cached image.
+struct Entry {
+    path: PathBuf,
+    len: u64,
+    used: SystemTime,
+}
+
+/// Lists the images cached in `dir`.
+fn entries(dir: &Path) -> io::Result<Vec<Entry>> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    for file in read_dir {
+        let file = file?;
+        let path = file.path();
+        if path.extension().is_some_and(|ext| ext == "img") {
+            let metadata = file.metadata()?;
+            entries.push(Entry {
+                path,
+                len: metadata.len(),
+                used: metadata.modified()?,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Parses the `<sha256 hex> <size>` line recorded for an entry.
+fn parse_meta(contents: &str) -> Option<([u8; 32], u64)> {
+    let (sha256_hex, size) = contents.trim().split_once(' ')?;
+    let mut sha256 = [0u8; 32];
+    hex::decode_to_slice(sha256_hex, &mut sha256).ok()?;
+    Some((sha256, size.parse().ok()?))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
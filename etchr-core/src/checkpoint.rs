@@ -0,0 +1,87 @@
+//! Checkpoint files that allow an interrupted write to be resumed.
+//!
+//! While writing, [`WriteOptions::checkpoint`](crate::write::WriteOptions::checkpoint)
+//! periodically records how far the write got in a small text file. A
+//! checkpoint is only recorded after the device has been synced, so every byte
+//! before `offset` is known to be on the media. Passing the file to
+//! [`WriteOptions::resume`](crate::write::WriteOptions::resume) skips the part
+//! of the image that was already written.
+use crate::error::Error;
+use anyhow::Result;
+use std::fs;
+use std::path::Path;
+
+const HEADER: &str = "etchr-checkpoint 1";
+
+/// The progress of a write at the time the checkpoint was recorded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// The number of image bytes written and synced to the device.
+    pub offset: u64,
+    /// The size of the target device in bytes.
+    pub device_size: u64,
+    /// The SHA-256 of the first `offset` bytes of the (decompressed) image.
+    pub image_sha256: [u8; 32],
+}
+
+impl Checkpoint {
+    /// Reads a checkpoint from `path`.
+    ///
+    /// Returns [`Error::InvalidCheckpoint`] if the file is not a checkpoint.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Self::parse(&contents).ok_or_else(|| {
+            Error::InvalidCheckpoint {
+                reason: format!("{} is not a valid checkpoint file", path.display()),
+            }
+            .into()
+        })
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let mut lines = contents.lines();
+        if lines.next()? != HEADER {
+            return None;
+        }
+
+        let (mut offset, mut device_size, mut image_sha256) = (None, None, None);
+        for line in lines {
+            match line.split_once('=')? {
+                ("offset", value) => offset = value.parse().ok(),
+                ("device_size", value) => device_size = value.parse().ok(),
+                ("image_sha256", value) => {
+                    let mut digest = [0u8; 32];
+                    hex::decode_to_slice(value, &mut digest).ok()?;
+                    image_sha256 = Some(digest);
+                }
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            offset: offset?,
+            device_size: device_size?,
+            image_sha256: image_sha256?,
+        })
+    }
+
+    /// Writes the checkpoint to `path`.
+    ///
+    /// The file is replaced atomically, so a crash while saving leaves the
+    /// previous checkpoint intact.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = format!(
+            "{}\noffset={}\ndevice_size={}\nimage_sha256={}\n",
+            HEADER,
+            self.offset,
+            self.device_size,
+            hex::encode(self.image_sha256)
+        );
+
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+//! Published checksums of images, as found in the checksum files that
+//! distributions ship next to them.
+//!
+//! Both formats of `sha256sum` are understood: the coreutils one
+//! (`<hash>  <name>`, or `<hash> *<name>` for a file hashed in binary mode)
+//! and the BSD one written with `--tag` and by `shasum` on macOS
+//! (`SHA256 (<name>) = <hash>`). A checksum file may list many files, as
+//! `SHA256SUMS` files do, so the entry for an image is found by its file
+//! name. Lines that are neither, such as comments or the armour of a signed
+//! file, are skipped.
+//!
+//! ```rust,no_run
+//! use etchr_core::checksum;
+//! use std::path::Path;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! if let Some(sidecar) = checksum::find(Path::new("debian-12.img.xz"))? {
+//!     println!("{} lists the image's SHA-256", sidecar.path.display());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// The name of the checksum file that lists every image in a directory.
+const SUMS_FILE: &str = "SHA256SUMS";
+
+/// A published SHA-256 of an image, found by [`find`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sidecar {
+    /// The checksum file it was found in.
+    pub path: PathBuf,
+    /// The SHA-256 of the image file, as downloaded.
+    pub sha256: [u8; 32],
+}
+
+/// Looks for the published SHA-256 of the image file at `image_path` in the
+/// checksum files next to it: `<image>.sha256`, `<image>.sha256sum` and
+/// `SHA256SUMS` in the same directory, in that order. Returns `None` if none
+/// of them exists or lists the image.
+///
+/// A `<image>.sha256` or `<image>.sha256sum` file with a single entry is taken
+/// to be for the image whatever name it gives, since it is named after it.
+pub fn find(image_path: &Path) -> io::Result<Option<Sidecar>> {
+    let Some(name) = image_path.file_name() else {
+        return Ok(None);
+    };
+    let dir = image_path.parent().unwrap_or(Path::new(""));
+    let mut candidates: Vec<(PathBuf, bool)> = [".sha256", ".sha256sum"]
+        .iter()
+        .map(|extension| {
+            let mut sidecar = name.to_owned();
+            sidecar.push(extension);
+            (dir.join(sidecar), true)
+        })
+        .collect();
+    candidates.push((dir.join(SUMS_FILE), false));
+
+    for (path, only_entry) in candidates {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(sha256) = lookup(&contents, &name.to_string_lossy(), only_entry) {
+            return Ok(Some(Sidecar { path, sha256 }));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the SHA-256 of the image file at `image_path` from the checksum file
+/// at `path`. A checksum file with a single entry is taken to be for the
+/// image whatever name it gives. Returns `None` if it does not list the
+/// image.
+pub fn read(path: &Path, image_path: &Path) -> io::Result<Option<[u8; 32]>> {
+    let contents = std::fs::read_to_string(path)?;
+    let name = image_path
+        .file_name()
+        .unwrap_or(image_path.as_os_str())
+        .to_string_lossy();
+    Ok(lookup(&contents, &name, true))
+}
+
+/// Finds the SHA-256 of the file called `name` in the `contents` of a
+/// checksum file, or, with `only_entry`, the one it lists if it only lists
+/// one. Entries are matched on the file name alone, as they may give a path.
+fn lookup(contents: &str, name: &str, only_entry: bool) -> Option<[u8; 32]> {
+    let entries: Vec<(String, [u8; 32])> = contents.lines().filter_map(parse_line).collect();
+    let matching = entries.iter().find(|(entry, _)| {
+        Path::new(entry)
+            .file_name()
+            .is_some_and(|entry| entry.to_string_lossy() == name)
+    });
+    match (matching, entries.as_slice()) {
+        (Some((_, sha256)), _) => Some(*sha256),
+        (None, [(_, sha256)]) if only_entry => Some(*sha256),
+        _ => None,
+    }
+}
+
+/// Parses a line of a checksum file into the name and the SHA-256 it gives.
+/// A line with only a hash gives an empty name.
+fn parse_line(line: &str) -> Option<(String, [u8; 32])> {
+    let line = line.trim();
+    if let Some(entry) = line.strip_prefix("SHA256 (") {
+        let (name, hash) = entry.rsplit_once(") = ")?;
+        return Some((name.to_string(), parse_hash(hash)?));
+    }
+
+    // coreutils escapes a name with a backslash or a newline in it, and marks
+    // the line with a leading backslash.
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (hash, name) = match line.split_once(' ') {
+        // The separator is a space, then another for text mode or `*` for
+        // binary mode.
+        Some((hash, rest)) => (hash, rest.strip_prefix([' ', '*']).unwrap_or(rest)),
+        None => (line, ""),
+    };
+    let name = if escaped {
+        unescape(name)
+    } else {
+        name.to_string()
+    };
+    Some((name, parse_hash(hash)?))
+}
+
+/// Undoes the escaping of a name by coreutils.
+fn unescape(name: &str) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+fn parse_hash(hash: &str) -> Option<[u8; 32]> {
+    let mut sha256 = [0u8; 32];
+    hex::decode_to_slice(hash.trim(), &mut sha256).ok()?;
+    Some(sha256)
+}
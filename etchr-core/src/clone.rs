@@ -0,0 +1,462 @@
+//! Copying one device straight to another.
+//!
+//! Cloning a card by reading it to an image and then writing the image takes
+//! twice as long as the copy itself, and needs room for the image. A
+//! [`CloneOptions`] streams each chunk read from the source straight to the
+//! target instead, with the same sector alignment as reads and writes.
+use crate::buffer::AlignedBuffer;
+use crate::device::{DirectIo, SectorSizes};
+use crate::error::{self, Error, PartialTransfer};
+use crate::os_options::DeviceOpenOptions;
+use crate::platform;
+use crate::progress::{Stage, StageTiming};
+use crate::warning::{Warning, WarningKind};
+use crate::write::{io_error, read_full};
+use anyhow::{Result, anyhow};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// Use a 1 MiB buffer for I/O operations.
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A summary of a completed clone, returned by [`CloneOptions::run`].
+#[derive(Clone, Debug)]
+pub struct CloneReport {
+    /// The number of bytes copied, which is the size of the source device.
+    pub bytes_copied: u64,
+    /// The SHA-256 of the data copied from the source device.
+    pub sha256: [u8; 32],
+    /// Time spent copying and syncing the target device.
+    pub duration: Duration,
+    /// Whether both devices were read again and matched (see
+    /// [`CloneOptions::verify`]).
+    pub verified: bool,
+    /// Time spent verifying, or zero if the clone was not verified.
+    pub verify_duration: Duration,
+    /// The sector sizes of the source device.
+    pub source_sector_sizes: SectorSizes,
+    /// The sector sizes of the target device.
+    pub target_sector_sizes: SectorSizes,
+    /// Whether both devices were accessed with `O_DIRECT`. A device that
+    /// rejects it is accessed through the page cache instead.
+    pub direct_io: bool,
+}
+
+impl CloneReport {
+    /// The SHA-256 as a lowercase hex string.
+    pub fn sha256_hex(&self) -> String {
+        hex::encode(self.sha256)
+    }
+
+    /// The bytes copied and the time spent copying and syncing.
+    pub fn copy_timing(&self) -> StageTiming {
+        StageTiming {
+            bytes: self.bytes_copied,
+            duration: self.duration,
+        }
+    }
+
+    /// The bytes compared and the time it took, if the clone was verified.
+    pub fn verify_timing(&self) -> Option<StageTiming> {
+        self.verified.then_some(StageTiming {
+            bytes: self.bytes_copied,
+            duration: self.verify_duration,
+        })
+    }
+
+    /// The average copy speed in bytes per second, over the copy and sync.
+    pub fn average_bps(&self) -> f64 {
+        self.copy_timing().bytes_per_sec()
+    }
+}
+
+/// The options for copying the whole of one device to another.
+///
+/// The target must be at least as large as the source. Anything on the target
+/// past the size of the source is left as it was.
+///
+/// ```rust,no_run
+/// use etchr_core::clone::CloneOptions;
+///
+/// # fn main() -> anyhow::Result<()> {
+/// CloneOptions::new("/dev/sdb", "/dev/sdc")
+///     .verify(true)
+///     .on_progress(|bytes| println!("{} bytes copied", bytes))
+///     .run()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct CloneOptions<'a> {
+    source_path: PathBuf,
+    target_path: PathBuf,
+    buffer_size: usize,
+    direct_io: DirectIo,
+    exclusive: bool,
+    allow_system_disk: bool,
+    verify: bool,
+    running: Arc<AtomicBool>,
+    on_copy_start: Box<dyn FnMut(u64) + 'a>,
+    on_progress: Box<dyn FnMut(u64) + 'a>,
+    on_sync_start: Box<dyn FnMut() + 'a>,
+    on_sync_done: Box<dyn FnMut() + 'a>,
+    on_verify_start: Box<dyn FnMut(u64) + 'a>,
+    on_verify_progress: Box<dyn FnMut(u64) + 'a>,
+    on_warning: Box<dyn FnMut(Warning) + 'a>,
+}
+
+impl<'a> CloneOptions<'a> {
+    /// Creates the options for copying the block device at `source_path` to
+    /// the block device at `target_path`.
+    pub fn new(source_path: impl Into<PathBuf>, target_path: impl Into<PathBuf>) -> Self {
+        Self {
+            source_path: source_path.into(),
+            target_path: target_path.into(),
+            buffer_size: BUFFER_SIZE,
+            direct_io: DirectIo::default(),
+            exclusive: true,
+            allow_system_disk: false,
+            verify: false,
+            running: Arc::new(AtomicBool::new(true)),
+            on_copy_start: Box::new(|_| {}),
+            on_progress: Box::new(|_| {}),
+            on_sync_start: Box::new(|| {}),
+            on_sync_done: Box::new(|| {}),
+            on_verify_start: Box::new(|_| {}),
+            on_verify_progress: Box::new(|_| {}),
+            on_warning: Box::new(|_| {}),
+        }
+    }
+
+    /// The size of each chunk copied. Must be a non-zero multiple of the
+    /// logical sector sizes of both devices. Defaults to 1 MiB.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Whether to bypass the page cache on both devices. Defaults to
+    /// [`DirectIo::Preferred`].
+    pub fn direct_io(mut self, direct_io: DirectIo) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// Whether to refuse the clone while the target or one of its partitions
+    /// is mounted, or otherwise claimed, as [`Error::DeviceInUse`]. Defaults
+    /// to `true`.
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// Whether the target may be the disk holding the running system, which
+    /// is otherwise refused as [`Error::SystemDisk`]. Defaults to `false`.
+    pub fn allow_system_disk(mut self, allow_system_disk: bool) -> Self {
+        self.allow_system_disk = allow_system_disk;
+        self
+    }
+
+    /// Whether to read both devices again once the copy has been synced, and
+    /// fail with [`Error::VerifyMismatch`] if they differ. Defaults to `false`.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// A flag that cancels the clone when cleared. Defaults to a flag that
+    /// is never cleared.
+    pub fn running(mut self, running: Arc<AtomicBool>) -> Self {
+        self.running = running;
+        self
+    }
+
+    /// Called with the number of bytes to copy, once both devices are open.
+    pub fn on_copy_start(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_copy_start = Box::new(f);
+        self
+    }
+
+    /// Called with the number of bytes copied so far. The final total is
+    /// only reported once the target has been synced.
+    pub fn on_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_progress = Box::new(f);
+        self
+    }
+
+    /// Called before the target is synced.
+    pub fn on_sync_start(mut self, f: impl FnMut() + 'a) -> Self {
+        self.on_sync_start = Box::new(f);
+        self
+    }
+
+    /// Called once the target has been synced.
+    pub fn on_sync_done(mut self, f: impl FnMut() + 'a) -> Self {
+        self.on_sync_done = Box::new(f);
+        self
+    }
+
+    /// Called with the number of bytes to verify, if the clone is verified.
+    pub fn on_verify_start(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_verify_start = Box::new(f);
+        self
+    }
+
+    /// Called with the number of bytes verified so far.
+    pub fn on_verify_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_verify_progress = Box::new(f);
+        self
+    }
+
+    /// Called with each non-fatal problem, such as a device that does not
+    /// support direct I/O.
+    pub fn on_warning(mut self, f: impl FnMut(Warning) + 'a) -> Self {
+        self.on_warning = Box::new(f);
+        self
+    }
+
+    /// Copies the source device to the target device.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - Either path is not a block device ([`Error::NotABlockDevice`]), or
+    ///   both are the same device.
+    /// - The target is the system disk ([`Error::SystemDisk`]).
+    /// - The target is in use and `exclusive` was requested
+    ///   ([`Error::DeviceInUse`]).
+    /// - The source is larger than the target ([`Error::ImageTooLarge`]).
+    /// - The buffer size is not a non-zero multiple of the logical sector
+    ///   sizes of both devices.
+    /// - An I/O error occurs. Errors reading the source are reported as an
+    ///   [`Error::Io`] in the [`Stage::Read`] stage, errors writing and
+    ///   syncing the target in the [`Stage::Write`] and [`Stage::Sync`]
+    ///   stages, and errors reading either device to verify it in the
+    ///   [`Stage::Verify`] stage, or as [`Error::DeviceRemoved`] if a device
+    ///   was unplugged.
+    /// - The devices differ when they are verified ([`Error::VerifyMismatch`]).
+    /// - The operation is cancelled by the user ([`Error::Cancelled`]). What
+    ///   was copied so far is synced to the target first.
+    ///
+    /// Errors that occur once copying has started carry a [`PartialTransfer`]
+    /// context with the number of bytes copied and synced so far.
+    pub fn run(&mut self) -> Result<CloneReport> {
+        let mut transfer = None;
+        self.clone_devices(&mut transfer).map_err(|e| {
+            let bytes_done = transfer.map_or(0, |t| t.bytes_done);
+            let e = error::detect_removal(e, bytes_done);
+            match transfer {
+                Some(transfer) => e.context(transfer),
+                None => e,
+            }
+        })
+    }
+
+    /// Does the work of [`CloneOptions::run`], keeping `transfer` up to date
+    /// once the target is being written.
+    fn clone_devices(&mut self, transfer: &mut Option<PartialTransfer>) -> Result<CloneReport> {
+        // Resolve symlinks such as /dev/disk/by-id/..., so that the checks
+        // below and any error refer to the real device nodes.
+        let source_path = canonical(&self.source_path);
+        let target_path = canonical(&self.target_path);
+        for path in [&source_path, &target_path] {
+            if !platform::is_block_device(path) {
+                return Err(Error::NotABlockDevice { path: path.clone() }.into());
+            }
+        }
+        if source_path == target_path {
+            return Err(anyhow!("The source and target are the same device"));
+        }
+        if !self.allow_system_disk
+            && let Some(system_disk) = platform::system_disk()
+            && platform::is_on_disk(&target_path, &system_disk)
+        {
+            return Err(Error::SystemDisk {
+                path: target_path,
+                system_disk,
+            }
+            .into());
+        }
+
+        let (mut source, source_fallback) = DeviceOpenOptions::read_only()
+            .direct_io(self.direct_io)
+            .open(&source_path)?;
+        let exclusive = self.exclusive;
+        let (mut target, target_fallback) = DeviceOpenOptions::read_write()
+            .direct_io(self.direct_io)
+            .exclusive(exclusive)
+            .open(&target_path)
+            .map_err(|e| match e.raw_os_error() {
+                Some(libc::EBUSY) if exclusive => Error::DeviceInUse {
+                    path: target_path.clone(),
+                }
+                .into(),
+                _ => anyhow::Error::from(e),
+            })?;
+        let mut direct_io = self.direct_io != DirectIo::Off;
+        for (device, fallback) in [("source", source_fallback), ("target", target_fallback)] {
+            if let Some(e) = fallback {
+                (self.on_warning)(Warning::new(
+                    WarningKind::DirectIoFallback,
+                    format!(
+                        "The {} device does not support direct I/O ({}), using the page cache instead.",
+                        device, e
+                    ),
+                ));
+                direct_io = false;
+            }
+        }
+
+        let source_len = platform::get_device_size(&source)?;
+        let target_len = platform::get_device_size(&target)?;
+        if source_len == 0 {
+            return Err(anyhow!("Source device size is reported as zero"));
+        }
+        if source_len > target_len {
+            return Err(Error::ImageTooLarge {
+                image: source_len,
+                device: target_len,
+            }
+            .into());
+        }
+
+        // O_DIRECT requires transfers aligned to the logical sector size of
+        // each device. Sector sizes are powers of two, so the larger one is a
+        // multiple of both.
+        let source_sector_sizes = platform::get_sector_sizes(&source).unwrap_or_default();
+        let target_sector_sizes = platform::get_sector_sizes(&target).unwrap_or_default();
+        let block_size = source_sector_sizes.logical.max(target_sector_sizes.logical) as usize;
+        if self.buffer_size == 0 || !self.buffer_size.is_multiple_of(block_size) {
+            return Err(anyhow!(
+                "Buffer size must be a non-zero multiple of the {} byte sector size",
+                block_size
+            ));
+        }
+        // A source of 512 byte sectors may not end on a sector of the target.
+        if direct_io && !source_len.is_multiple_of(block_size as u64) {
+            platform::set_direct_io(&source, false)?;
+            platform::set_direct_io(&target, false)?;
+            direct_io = false;
+        }
+
+        (self.on_copy_start)(source_len);
+        let started = Instant::now();
+        let mut buffer = AlignedBuffer::new(self.buffer_size, block_size);
+        let mut hasher = Sha256::new();
+        let mut copied: u64 = 0;
+        let transfer = transfer.insert(PartialTransfer::default());
+        while copied < source_len {
+            if !self.running.load(Ordering::SeqCst) {
+                target.sync_data().map_err(io_error(Stage::Sync, None))?;
+                transfer.bytes_synced = copied;
+                return Err(Error::Cancelled {
+                    bytes_synced: copied,
+                }
+                .into());
+            }
+            let n = (source_len - copied).min(self.buffer_size as u64) as usize;
+            let filled = read_full(&mut source, &mut buffer[..n])
+                .map_err(io_error(Stage::Read, Some(copied)))?;
+            if filled < n {
+                return Err(anyhow!(
+                    "The source device ended {} bytes before the size it reported",
+                    source_len - copied - filled as u64
+                ));
+            }
+            hasher.update(&buffer[..n]);
+            target
+                .write_all(&buffer[..n])
+                .map_err(io_error(Stage::Write, Some(copied)))?;
+            copied += n as u64;
+            transfer.bytes_done = copied;
+            if copied < source_len {
+                (self.on_progress)(copied);
+            }
+        }
+
+        // Make sure the data has left the target's cache before reporting
+        // success.
+        (self.on_sync_start)();
+        target.sync_data().map_err(io_error(Stage::Sync, None))?;
+        transfer.bytes_synced = copied;
+        (self.on_sync_done)();
+        (self.on_progress)(copied);
+        let duration = started.elapsed();
+
+        let verify_started = Instant::now();
+        if self.verify {
+            (self.on_verify_start)(source_len);
+            source.seek(SeekFrom::Start(0))?;
+            target.seek(SeekFrom::Start(0))?;
+            verify_devices(
+                &mut source,
+                &mut target,
+                &mut buffer,
+                block_size,
+                source_len,
+                &self.running,
+                &mut *self.on_verify_progress,
+            )?;
+        }
+        let verify_duration = verify_started.elapsed();
+
+        Ok(CloneReport {
+            bytes_copied: copied,
+            sha256: hasher.finalize().into(),
+            duration,
+            verified: self.verify,
+            verify_duration: if self.verify {
+                verify_duration
+            } else {
+                Duration::ZERO
+            },
+            source_sector_sizes,
+            target_sector_sizes,
+            direct_io,
+        })
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Reads the first `len` bytes of both devices and fails with
+/// [`Error::VerifyMismatch`] at the first byte where they differ.
+fn verify_devices(
+    source: &mut File,
+    target: &mut File,
+    buffer: &mut [u8],
+    block_size: usize,
+    len: u64,
+    running: &AtomicBool,
+    on_verify_progress: &mut dyn FnMut(u64),
+) -> Result<()> {
+    let mut target_buf = AlignedBuffer::new(buffer.len(), block_size);
+    let mut verified = 0;
+    while verified < len {
+        if !running.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled { bytes_synced: len }.into());
+        }
+        let n = (len - verified).min(buffer.len() as u64) as usize;
+        source
+            .read_exact(&mut buffer[..n])
+            .map_err(io_error(Stage::Verify, Some(verified)))?;
+        target
+            .read_exact(&mut target_buf[..n])
+            .map_err(io_error(Stage::Verify, Some(verified)))?;
+        if let Some(i) = (0..n).find(|&i| buffer[i] != target_buf[i]) {
+            return Err(Error::VerifyMismatch {
+                offset: verified + i as u64,
+            }
+            .into());
+        }
+        verified += n as u64;
+        on_verify_progress(verified);
+    }
+    Ok(())
+}
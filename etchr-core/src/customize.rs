@@ -0,0 +1,252 @@
+//! Adding files to a FAT partition of a freshly written image.
+//!
+//! Images for boards like the Raspberry Pi are configured by dropping files
+//! (`ssh`, `userconf.txt`, ...) onto their FAT boot partition. Rather than
+//! mounting the partition, its location is read from the image's MBR or GPT
+//! and the filesystem is edited in place through the device handle that was
+//! used for writing.
+use crate::partition_table;
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// A file to add to a partition once the image has been written.
+#[derive(Clone, Debug)]
+pub(crate) struct PartitionFile {
+    /// The 1-based number of the partition, as in `/dev/sdb1`.
+    pub(crate) partition: u32,
+    /// The `/`-separated path of the file, relative to the filesystem root.
+    pub(crate) path: String,
+    pub(crate) contents: Vec<u8>,
+}
+
+/// A window onto the part of the device that holds one partition.
+struct PartitionSlice<'f> {
+    file: &'f mut File,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl PartitionSlice<'_> {
+    /// The number of bytes that can be transferred at the current position.
+    fn available(&self, wanted: usize) -> usize {
+        self.len.saturating_sub(self.pos).min(wanted as u64) as usize
+    }
+}
+
+impl Read for PartitionSlice<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.available(buf.len());
+        let n = self.file.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for PartitionSlice<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.available(buf.len());
+        if n == 0 && !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "write past the end of the partition",
+            ));
+        }
+        let n = self.file.write(&buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for PartitionSlice<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))?;
+        self.file.seek(SeekFrom::Start(self.start + pos))?;
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+/// Looks up partition `number` (1-based) in the partition table of the image
+/// written at `base`, returning its offset from `base` and its length in bytes.
+///
+/// Only the primary partitions of an MBR are supported, and every entry of a
+/// GPT, whose checksums must match.
+fn find_partition(
+    file: &mut File,
+    base: u64,
+    sector: u64,
+    len: u64,
+    number: u32,
+) -> Result<(u64, u64)> {
+    let partitions = partition_table::partitions(file, base, sector, len)?
+        .ok_or_else(|| anyhow!("the image has no partition table"))?;
+    let partition = partitions
+        .into_iter()
+        .find(|p| p.number == number)
+        .ok_or_else(|| anyhow!("partition {} does not exist", number))?;
+    if partition.extended {
+        return Err(anyhow!(
+            "partition {} is an extended partition, which holds no filesystem",
+            number
+        ));
+    }
+    Ok((
+        partition.range.start,
+        partition.range.end - partition.range.start,
+    ))
+}
+
+/// Writes `files` into the FAT filesystems of the image written at `base`.
+///
+/// Parent directories are created as needed and existing files are replaced.
+/// `sector` is the logical sector size the partition table is expressed in,
+/// and `len` the space on the device from `base`. The device must not be open
+/// with `O_DIRECT`.
+pub(crate) fn add_files(
+    file: &mut File,
+    base: u64,
+    sector: u64,
+    len: u64,
+    files: &[PartitionFile],
+) -> Result<()> {
+    // Each filesystem is opened once, in the order its first file was given.
+    let mut by_partition: Vec<(u32, Vec<&PartitionFile>)> = Vec::new();
+    let mut index = HashMap::new();
+    for f in files {
+        let i = *index.entry(f.partition).or_insert_with(|| {
+            by_partition.push((f.partition, Vec::new()));
+            by_partition.len() - 1
+        });
+        by_partition[i].1.push(f);
+    }
+
+    for (number, files) in by_partition {
+        let (start, len) = find_partition(file, base, sector, len, number)?;
+        let slice = PartitionSlice {
+            file: &mut *file,
+            start: base + start,
+            len,
+            pos: 0,
+        };
+        let fs = fatfs::FileSystem::new(slice, fatfs::FsOptions::new())
+            .map_err(|e| anyhow!("partition {} is not a FAT filesystem: {}", number, e))?;
+
+        let root = fs.root_dir();
+        for f in files {
+            let path = f.path.trim_matches('/');
+            let failed = |e: io::Error| anyhow!("{} on partition {}: {}", path, number, e);
+            if let Some((parent, _)) = path.rsplit_once('/') {
+                let mut dir = String::new();
+                for component in parent.split('/').filter(|c| !c.is_empty()) {
+                    if !dir.is_empty() {
+                        dir.push('/');
+                    }
+                    dir.push_str(component);
+                    root.create_dir(&dir).map_err(failed)?;
+                }
+            }
+            let mut out = root.create_file(path).map_err(failed)?;
+            out.truncate().map_err(failed)?;
+            out.write_all(&f.contents).map_err(failed)?;
+            out.flush().map_err(failed)?;
+        }
+        drop(root);
+        fs.unmount()?;
+    }
+
+    file.sync_data()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition_table::tests::gpt_image;
+    use crate::usedblocks::tests::mbr_device;
+
+    const MIB: u64 = 1 << 20;
+
+    fn file(partition: u32, path: &str, contents: &[u8]) -> PartitionFile {
+        PartitionFile {
+            partition,
+            path: path.to_string(),
+            contents: contents.to_vec(),
+        }
+    }
+
+    /// Reads `path` from the FAT filesystem of `len` bytes at `start`.
+    fn read_file(device: &mut File, start: u64, len: u64, path: &str) -> Vec<u8> {
+        let slice = PartitionSlice {
+            file: device,
+            start,
+            len,
+            pos: 0,
+        };
+        let fs = fatfs::FileSystem::new(slice, fatfs::FsOptions::new()).unwrap();
+        let mut contents = Vec::new();
+        fs.root_dir()
+            .open_file(path)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    #[test]
+    fn files_are_added_to_a_partition_of_an_image_past_an_offset() {
+        // An image with a 4 MiB FAT partition at 1 MiB, written 1 MiB into
+        // the device.
+        let base = MIB;
+        let mut image = mbr_device(6 * MIB, &[(0x0C, 2048, 8192)]);
+        let mut device = tempfile::tempfile().unwrap();
+        device.set_len(base + 6 * MIB).unwrap();
+        device.seek(SeekFrom::Start(base)).unwrap();
+        image.rewind().unwrap();
+        io::copy(&mut image, &mut device).unwrap();
+        let slice = PartitionSlice {
+            file: &mut device,
+            start: base + MIB,
+            len: 4 * MIB,
+            pos: 0,
+        };
+        fatfs::format_volume(slice, fatfs::FormatVolumeOptions::new()).unwrap();
+
+        let files = [
+            file(1, "ssh", b""),
+            file(1, "/boot/firmware/config.txt", b"dtoverlay=dwc2\n"),
+        ];
+        add_files(&mut device, base, 512, 6 * MIB, &files).unwrap();
+        let config = read_file(&mut device, base + MIB, 4 * MIB, "boot/firmware/config.txt");
+        assert_eq!(config, b"dtoverlay=dwc2\n");
+        assert!(read_file(&mut device, base + MIB, 4 * MIB, "ssh").is_empty());
+    }
+
+    #[test]
+    fn a_missing_partition_is_refused() {
+        let mut device = mbr_device(6 * MIB, &[(0x0C, 2048, 8192)]);
+        let e = add_files(&mut device, 0, 512, 6 * MIB, &[file(2, "ssh", b"")]).unwrap_err();
+        assert_eq!(e.to_string(), "partition 2 does not exist");
+    }
+
+    #[test]
+    fn a_corrupt_gpt_is_not_trusted() {
+        let mut device = gpt_image(0, 512, 2048, MIB, &[(34, 2000)]);
+        // Flips a bit of the partition array, which the header checksums.
+        device.seek(SeekFrom::Start(2 * 512 + 32)).unwrap();
+        device.write_all(&[35]).unwrap();
+        let e = add_files(&mut device, 0, 512, MIB, &[file(1, "ssh", b"")]).unwrap_err();
+        assert!(e.to_string().contains("checksum"), "{}", e);
+    }
+}
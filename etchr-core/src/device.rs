@@ -14,6 +14,8 @@ pub struct Device {
     pub name: String,
     /// The total size of the device in gigabytes (GB).
     pub size_gb: f64,
+    /// The total size of the device in bytes.
+    pub size_bytes: u64,
     /// The primary mount point of the device, if any.
     pub mount_point: String,
 }
@@ -34,4 +36,48 @@ impl fmt::Display for Device {
             mount_info
         )
     }
-}
\ No newline at end of file
+}
+/// The sector sizes of a block device.
+///
+/// O_DIRECT transfers must be aligned to, and a multiple of, the logical
+/// sector size. Most removable media use 512-byte sectors, but 4Kn enclosures
+/// and some NVMe namespaces use 4096-byte logical sectors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SectorSizes {
+    /// The smallest unit the device can address, in bytes.
+    pub logical: u32,
+    /// The unit the device writes internally, in bytes.
+    pub physical: u32,
+}
+
+impl Default for SectorSizes {
+    fn default() -> Self {
+        Self {
+            logical: 512,
+            physical: 512,
+        }
+    }
+}
+
+impl fmt::Display for SectorSizes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} B logical / {} B physical sectors",
+            self.logical, self.physical
+        )
+    }
+}
+
+/// Whether a device is opened with `O_DIRECT`, bypassing the page cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DirectIo {
+    /// Fail if the device cannot be opened with `O_DIRECT`.
+    Required,
+    /// Use `O_DIRECT`, but fall back to buffered I/O if the device or its
+    /// driver rejects it (some USB bridges and FUSE-backed loop files do).
+    #[default]
+    Preferred,
+    /// Always use buffered I/O.
+    Off,
+}
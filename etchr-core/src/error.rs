@@ -0,0 +1,571 @@
+//! Typed errors for conditions a front-end may want to handle specially.
+//!
+//! The imaging functions return `anyhow::Result`, but failures that deserve a
+//! tailored message (rather than a raw I/O error) are raised as an [`Error`].
+//! Callers can recover them with `anyhow::Error::downcast_ref::<Error>()`.
+use crate::hash::Digest;
+use crate::image::Format;
+use crate::progress::Stage;
+use crate::vhd::DiskType;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// An error condition detected by `etchr-core` itself.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The image does not fit on the target device.
+    ///
+    /// `image` is the size of the image in bytes (a lower bound when it was
+    /// estimated from compression metadata) and `device` is the space
+    /// available on the target in bytes.
+    ImageTooLarge { image: u64, device: u64 },
+    /// The target ran out of space while writing an image whose size was not
+    /// known up front.
+    ///
+    /// `capacity` is the space available on the target in bytes, if known, and
+    /// `bytes_written` is how much of the image was written before it ran out.
+    DeviceFull {
+        capacity: Option<u64>,
+        bytes_written: u64,
+    },
+    /// There is not enough free space in `dir` to decompress the image to a
+    /// temporary file.
+    ///
+    /// `needed` is the estimated size of the decompressed image and
+    /// `available` the free space in `dir`, both in bytes.
+    InsufficientTempSpace {
+        dir: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+    /// There is not enough free space for the image file of a read.
+    ///
+    /// `mount_point` is where the filesystem that would hold the image is
+    /// mounted. `needed` is the size the image may reach, and `available` the
+    /// free space on the filesystem, both in bytes.
+    InsufficientSpace {
+        mount_point: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+    /// The target is not a block device: it is a directory, a character device
+    /// such as `/dev/null`, a path that does not exist, or a regular file while
+    /// file targets are not allowed.
+    ///
+    /// `path` is the target with any symlinks resolved.
+    NotABlockDevice { path: PathBuf },
+    /// The target is the disk holding the running system, or one of its
+    /// partitions. Writing to it is refused unless explicitly allowed.
+    SystemDisk { path: PathBuf, system_disk: PathBuf },
+    /// The target is a partition rather than a whole disk, which is refused
+    /// unless explicitly allowed: a disk image written into a partition does
+    /// not boot. `disk` is the disk the partition belongs to.
+    PartitionTarget { path: PathBuf, disk: PathBuf },
+    /// The device is not the size it had when it was selected, so it is most
+    /// likely not the same medium (a card swapped in a multi-slot reader).
+    ///
+    /// `expected` and `actual` are the sizes in bytes.
+    DeviceChanged {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    /// The device could not be opened exclusively because it is mounted or
+    /// claimed by another subsystem (device-mapper, mdraid, ...).
+    DeviceInUse { path: PathBuf },
+    /// Some partitions of the device could not be unmounted.
+    ///
+    /// `mount_points` lists the mount points that are still busy.
+    UnmountFailed { mount_points: Vec<PathBuf> },
+    /// A resume checkpoint is unreadable or does not match the image and device.
+    InvalidCheckpoint { reason: String },
+    /// The operation was cancelled through its `running` flag.
+    ///
+    /// `bytes_synced` is how much of the image is known to have been written
+    /// and flushed to the device before returning. For a read, it is how much
+    /// of the device was kept in the image file, which is nothing unless
+    /// [`ReadOptions::keep_partial`] was set.
+    ///
+    /// [`ReadOptions::keep_partial`]: crate::read::ReadOptions::keep_partial
+    Cancelled { bytes_synced: u64 },
+    /// The image was written (and verified), but adding files to one of its
+    /// partitions afterwards failed.
+    CustomizeFailed { reason: String },
+    /// The image file does not match the checksum it was expected to have,
+    /// most likely because the download is corrupt or incomplete.
+    ChecksumMismatch {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    /// The image is a zip archive that does not hold exactly one disk image
+    /// (an `.img` or `.iso` file), so which file to write is unclear.
+    ///
+    /// `images` is how many it holds, and `entries` lists the names of all the
+    /// files in it, for the user to extract the right one by hand.
+    ArchiveContents { images: usize, entries: Vec<String> },
+    /// The image is compressed with zstd using a larger window than the
+    /// decoder accepts, 2^`window_log_max` bytes, as `zstd --long` does. See
+    /// [`WriteOptions::zstd_window_log_max`].
+    ///
+    /// [`WriteOptions::zstd_window_log_max`]: crate::write::WriteOptions::zstd_window_log_max
+    ZstdWindowTooLarge { window_log_max: u32 },
+    /// The compressed image is damaged: the decoder for its `format` failed
+    /// on it, as `detail` explains. A file that was not downloaded in full
+    /// is the usual cause.
+    CorruptImage { format: Format, detail: Corruption },
+    /// The image is a VHD that does not simply hold the whole disk, such as a
+    /// dynamic or differencing one, so it cannot be written as it is. Only
+    /// fixed VHDs can be.
+    UnsupportedVhd { disk_type: DiskType },
+    /// The image is split into numbered parts, but some are missing from the
+    /// sequence. `parts` are the parts that were found, in order, and
+    /// `missing` the names of the ones that were not.
+    SplitImageIncomplete {
+        parts: Vec<PathBuf>,
+        missing: Vec<String>,
+    },
+    /// The image at `url` could not be downloaded: the server refused it, or
+    /// the connection failed and could not be picked up again.
+    Download { url: String, reason: String },
+    /// A downloaded image does not match the checksum it was expected to
+    /// have. Unlike [`Error::ChecksumMismatch`], this is only found out once
+    /// the download is complete, so the device has been written with it.
+    DownloadChecksumMismatch {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    /// A chunk read back right after it was written does not match the image,
+    /// or a device compared byte for byte with an image or another device
+    /// differs from it.
+    ///
+    /// `offset` is the byte offset on the device of the first byte that
+    /// differs. The device is most likely failing or counterfeit.
+    VerifyMismatch { offset: u64 },
+    /// A device verified by hashing it does not hash to the same digest as
+    /// the image, so it does not hold it.
+    ///
+    /// `image` and `device` are the two digests, in the algorithm the
+    /// verification used. The device is most likely failing or counterfeit.
+    HashMismatch { image: Digest, device: Digest },
+    /// A range of the image that its block map lists does not read back from
+    /// the device as it was written, when only the mapped ranges are
+    /// verified.
+    ///
+    /// `range` is where the range lies on the device, in bytes, and `image`
+    /// and `device` are the SHA-256 digests of the range in the image and on
+    /// the device. The device is most likely failing or counterfeit.
+    MappedRangeMismatch {
+        range: Range<u64>,
+        image: Digest,
+        device: Digest,
+    },
+    /// A chunk of the image checked by a partial verification
+    /// ([`VerifyMode::Sampled`] or [`VerifyMode::NonZero`]) does not read back
+    /// from the device with the CRC-32C it had when it was written.
+    ///
+    /// `range` is where the chunk lies on the device, in bytes. Only the
+    /// chunk's checksum is known, not which of its bytes differ. The device is
+    /// most likely failing or counterfeit.
+    ///
+    /// [`VerifyMode::Sampled`]: crate::write::VerifyMode::Sampled
+    /// [`VerifyMode::NonZero`]: crate::write::VerifyMode::NonZero
+    ChunkMismatch { range: Range<u64> },
+    /// The device stopped responding: no chunk write completed within the
+    /// stall timeout.
+    ///
+    /// `offset` is the byte offset on the device of the write that hung, and
+    /// `elapsed` how long ago the last one completed. The write may still be
+    /// stuck in the kernel, so the device should be unplugged.
+    Stalled { offset: u64, elapsed: Duration },
+    /// The device disappeared, most likely because it was unplugged, during
+    /// the given stage of a write or read.
+    ///
+    /// `offset` is the byte offset on the device of the chunk that failed, as
+    /// for [`Error::Io`], and `bytes_done` is how much of the image had reached
+    /// the device (or, for a read, the image file) by then.
+    DeviceRemoved {
+        stage: Stage,
+        offset: Option<u64>,
+        bytes_done: u64,
+    },
+    /// An I/O error interrupted one of the stages of a write or read.
+    ///
+    /// `offset` is the byte offset on the device of the chunk that failed, for
+    /// the discard, write and verify stages. Errors reading the image itself
+    /// are reported as [`Stage::Decompress`], even if it is not compressed.
+    Io {
+        stage: Stage,
+        offset: Option<u64>,
+        source: io::Error,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ImageTooLarge { image, device } => write!(
+                f,
+                "Image is larger than the target device ({} bytes > {} bytes)",
+                image, device
+            ),
+            Error::DeviceFull {
+                capacity,
+                bytes_written,
+            } => {
+                write!(
+                    f,
+                    "Image is larger than the target device: it ran out of space after {} bytes",
+                    bytes_written
+                )?;
+                if let Some(capacity) = capacity {
+                    write!(f, " ({} bytes available)", capacity)?;
+                }
+                Ok(())
+            }
+            Error::InsufficientTempSpace {
+                dir,
+                needed,
+                available,
+            } => write!(
+                f,
+                "Not enough free space in {} to decompress the image: about {} bytes are needed but only {} are available ({} bytes short)",
+                dir.display(),
+                needed,
+                available,
+                needed - available
+            ),
+            Error::InsufficientSpace {
+                mount_point,
+                needed,
+                available,
+            } => write!(
+                f,
+                "Not enough free space on the filesystem mounted at {} for the image: up to {} bytes are needed but only {} are available ({} bytes short)",
+                mount_point.display(),
+                needed,
+                available,
+                needed - available
+            ),
+            Error::NotABlockDevice { path } => {
+                write!(f, "{} is not a block device", path.display())
+            }
+            Error::SystemDisk { path, system_disk } => {
+                if path == system_disk {
+                    write!(f, "{}", path.display())?;
+                } else {
+                    write!(
+                        f,
+                        "{} is a partition of {}, which",
+                        path.display(),
+                        system_disk.display()
+                    )?;
+                }
+                write!(f, " holds the running system; refusing to write to it")
+            }
+            Error::PartitionTarget { path, disk } => write!(
+                f,
+                "{} is a partition of {}, not a whole disk; refusing to write to it",
+                path.display(),
+                disk.display()
+            ),
+            Error::DeviceChanged {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} changed since it was selected: it is now {} bytes instead of {} bytes",
+                path.display(),
+                actual,
+                expected
+            ),
+            Error::DeviceInUse { path } => write!(
+                f,
+                "{} is in use (mounted or claimed by another subsystem); unmount it first",
+                path.display()
+            ),
+            Error::UnmountFailed { mount_points } => {
+                write!(f, "Could not unmount")?;
+                for (i, mount_point) in mount_points.iter().enumerate() {
+                    let sep = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{}", sep, mount_point.display())?;
+                }
+                write!(f, "; the device is still busy")
+            }
+            Error::InvalidCheckpoint { reason } => {
+                write!(f, "Cannot resume from checkpoint: {}", reason)
+            }
+            Error::Cancelled { bytes_synced } => write!(
+                f,
+                "Operation cancelled by user ({} bytes written and synced)",
+                bytes_synced
+            ),
+            Error::CustomizeFailed { reason } => write!(
+                f,
+                "The image was written, but customizing it failed: {}",
+                reason
+            ),
+            Error::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Source checksum mismatch: expected sha256={}, got sha256={}",
+                hex::encode(expected),
+                hex::encode(actual)
+            ),
+            Error::ArchiveContents { images, entries } => {
+                if *images == 0 {
+                    write!(f, "The zip archive holds no .img or .iso file")?;
+                } else {
+                    write!(
+                        f,
+                        "The zip archive holds {} .img or .iso files, not one",
+                        images
+                    )?;
+                }
+                write!(f, " (its files are: {})", entries.join(", "))
+            }
+            Error::ZstdWindowTooLarge { window_log_max } => write!(
+                f,
+                "The zstd image needs a window larger than the 2^{} bytes allowed (it was probably compressed with --long)",
+                window_log_max
+            ),
+            Error::CorruptImage { format, detail } => {
+                write!(f, "The {} image is corrupt: {}", format, detail)
+            }
+            Error::UnsupportedVhd { disk_type } => {
+                let subtype = match disk_type {
+                    DiskType::Fixed => "a fixed".to_string(),
+                    DiskType::Dynamic => "a dynamic".to_string(),
+                    DiskType::Differencing => "a differencing".to_string(),
+                    DiskType::Other(other) => format!("a type {}", other),
+                };
+                write!(
+                    f,
+                    "The image is {} VHD, an unsupported VHD subtype (only fixed VHDs can be written)",
+                    subtype
+                )
+            }
+            Error::SplitImageIncomplete { parts, missing } => {
+                let parts: Vec<_> = parts
+                    .iter()
+                    .map(|part| part.display().to_string())
+                    .collect();
+                write!(
+                    f,
+                    "The split image is missing {} (found {})",
+                    missing.join(", "),
+                    parts.join(", ")
+                )
+            }
+            Error::Download { url, reason } => {
+                write!(f, "Could not download {}: {}", url, reason)
+            }
+            Error::DownloadChecksumMismatch { expected, actual } => write!(
+                f,
+                "Download checksum mismatch: expected sha256={}, got sha256={}",
+                hex::encode(expected),
+                hex::encode(actual)
+            ),
+            Error::VerifyMismatch { offset } => write!(
+                f,
+                "Verification failed: the device returned different data at offset {}",
+                offset
+            ),
+            Error::HashMismatch { image, device } => write!(
+                f,
+                "Verification failed: hash mismatch (the image has {}, the device {})",
+                image, device
+            ),
+            Error::MappedRangeMismatch {
+                range,
+                image,
+                device,
+            } => write!(
+                f,
+                "Verification failed: hash mismatch in device bytes {}-{} (the image has {}, the device {})",
+                range.start,
+                range.end - 1,
+                image,
+                device
+            ),
+            Error::ChunkMismatch { range } => write!(
+                f,
+                "Verification failed: device bytes {}-{} do not match the image",
+                range.start,
+                range.end - 1
+            ),
+            Error::Stalled { offset, elapsed } => write!(
+                f,
+                "The device stopped responding: no write completed for {:.1}s (at device offset {})",
+                elapsed.as_secs_f64(),
+                offset
+            ),
+            Error::DeviceRemoved {
+                stage,
+                offset,
+                bytes_done,
+            } => {
+                write!(f, "The device was disconnected in the {} stage", stage)?;
+                if let Some(offset) = offset {
+                    write!(f, " at device offset {}", offset)?;
+                }
+                write!(f, " ({} bytes were transferred)", bytes_done)
+            }
+            Error::Io {
+                stage,
+                offset,
+                source,
+            } => {
+                write!(f, "I/O error in the {} stage", stage)?;
+                if let Some(offset) = offset {
+                    write!(f, " at device offset {}", offset)?;
+                }
+                write!(f, ": {}", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Turns an [`Error::Io`] caused by the device going away into an
+/// [`Error::DeviceRemoved`], noting that `bytes_done` bytes were transferred.
+/// Any other error is returned as it is.
+pub(crate) fn detect_removal(e: anyhow::Error, bytes_done: u64) -> anyhow::Error {
+    match e.downcast_ref::<Error>() {
+        Some(&Error::Io {
+            stage,
+            offset,
+            ref source,
+        }) if is_removal(source) => Error::DeviceRemoved {
+            stage,
+            offset,
+            bytes_done,
+        }
+        .into(),
+        _ => e,
+    }
+}
+
+/// Whether `e` is what a read or write fails with once the device is gone.
+pub(crate) fn is_removal(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    let codes = [libc::ENODEV, libc::ENXIO];
+    #[cfg(windows)]
+    let codes = [
+        windows_sys::Win32::Foundation::ERROR_DEVICE_NOT_CONNECTED as i32,
+        windows_sys::Win32::Foundation::ERROR_NO_SUCH_DEVICE as i32,
+    ];
+    e.raw_os_error().is_some_and(|code| codes.contains(&code))
+}
+
+/// How far a write or read got before it failed.
+///
+/// Once data has started moving, [`WriteOptions::run`], [`read::run`] and
+/// [`CloneOptions::run`] attach this as context to any error they return, so a
+/// front-end can say how much was done without tracking the progress callbacks
+/// itself. Recover it with `anyhow::Error::downcast_ref::<PartialTransfer>()`;
+/// the underlying error (an [`Error`], for example) can still be downcast to
+/// as before.
+///
+/// [`WriteOptions::run`]: crate::write::WriteOptions::run
+/// [`read::run`]: crate::read::run
+/// [`CloneOptions::run`]: crate::clone::CloneOptions::run
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PartialTransfer {
+    /// The number of image bytes that reached the device (or image file).
+    pub bytes_done: u64,
+    /// How many of those are known to have been flushed out of the cache.
+    pub bytes_synced: u64,
+}
+
+impl fmt::Display for PartialTransfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed after {} bytes were transferred ({} bytes synced)",
+            self.bytes_done, self.bytes_synced
+        )
+    }
+}
+
+/// What is wrong with a corrupt image, as told by its decoder. See
+/// [`Error::CorruptImage`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Corruption {
+    /// The compressed data stops in the middle of a stream.
+    Truncated,
+    /// The data decompresses, but does not match the checksum stored with it
+    /// (the CRC-32 of gzip and zip, the integrity checks of zstd and lz4).
+    ChecksumMismatch,
+    /// The file starts like the format but its header is not valid, so it is
+    /// most likely something else, such as an error page saved in its place.
+    WrongFormat,
+    /// The compressed data is invalid in some other way. xz reports failed
+    /// integrity checks this way too. `reason` is the decoder's message.
+    Invalid { reason: String },
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::Truncated => {
+                f.write_str("the file ends in the middle of the compressed data")
+            }
+            Corruption::ChecksumMismatch => {
+                f.write_str("the decompressed data does not match its stored checksum")
+            }
+            Corruption::WrongFormat => f.write_str("its header is not valid"),
+            Corruption::Invalid { reason } => {
+                write!(f, "the compressed data is invalid ({})", reason)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn io_error(code: i32) -> anyhow::Error {
+        Error::Io {
+            stage: Stage::Write,
+            offset: Some(4096),
+            source: io::Error::from_raw_os_error(code),
+        }
+        .into()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn removal_errors_become_device_removed() {
+        for code in [libc::ENODEV, libc::ENXIO] {
+            let e = detect_removal(io_error(code), 1024);
+            assert!(
+                matches!(
+                    e.downcast_ref::<Error>(),
+                    Some(Error::DeviceRemoved {
+                        stage: Stage::Write,
+                        offset: Some(4096),
+                        bytes_done: 1024,
+                    })
+                ),
+                "{}",
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn other_errors_are_left_alone() {
+        let e = detect_removal(io_error(libc::EIO), 1024);
+        assert!(matches!(e.downcast_ref::<Error>(), Some(Error::Io { .. })));
+        // An errno only counts inside an I/O error of a stage.
+        let e = detect_removal(io::Error::from_raw_os_error(libc::ENODEV).into(), 1024);
+        assert!(e.downcast_ref::<Error>().is_none());
+    }
+}
@@ -0,0 +1,77 @@
+//! Failures that tests inject into device I/O, for the fallbacks that only
+//! real hardware triggers: a device that rejects `O_DIRECT`, or a card reader
+//! that fails a write now and then.
+//!
+//! A failure is armed for a file and identified by its device and inode, so
+//! it also hits handles that are opened or cloned from it later. Outside of
+//! tests, nothing is ever armed and [`check`] always succeeds.
+
+/// A failure that can be injected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Fault {
+    /// Turning on direct I/O fails with `EINVAL`, as on a filesystem or
+    /// driver without support for it.
+    DirectIo,
+    /// A write fails with `EIO`, as a transient error of a card reader.
+    Write,
+}
+
+/// Fails with the error of `fault` if it is armed for `file`, using up one of
+/// the times it was armed for.
+#[cfg(not(all(test, unix)))]
+pub(crate) fn check(_file: &std::fs::File, _fault: Fault) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+pub(crate) use armed::{arm, check};
+
+#[cfg(all(test, unix))]
+mod armed {
+    use super::Fault;
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::Mutex;
+
+    /// A fault armed for a file, and how many more times it fires.
+    struct Armed {
+        file: (u64, u64),
+        fault: Fault,
+        times: u32,
+    }
+
+    /// The faults armed by tests. Tests run in parallel, each on its own files.
+    static ARMED: Mutex<Vec<Armed>> = Mutex::new(Vec::new());
+
+    fn id(file: &File) -> Option<(u64, u64)> {
+        let metadata = file.metadata().ok()?;
+        Some((metadata.dev(), metadata.ino()))
+    }
+
+    /// Makes the next `times` checks of `fault` on `file` fail.
+    pub(crate) fn arm(file: &File, fault: Fault, times: u32) {
+        let file = id(file).expect("the file can be looked at");
+        ARMED.lock().unwrap().push(Armed { file, fault, times });
+    }
+
+    /// Fails with the error of `fault` if it is armed for `file`, using up
+    /// one of the times it was armed for.
+    pub(crate) fn check(file: &File, fault: Fault) -> io::Result<()> {
+        let Some(file) = id(file) else {
+            return Ok(());
+        };
+        let mut armed = ARMED.lock().unwrap();
+        let Some(entry) = armed
+            .iter_mut()
+            .find(|armed| armed.file == file && armed.fault == fault && armed.times > 0)
+        else {
+            return Ok(());
+        };
+        entry.times -= 1;
+        Err(io::Error::from_raw_os_error(match fault {
+            Fault::DirectIo => libc::EINVAL,
+            Fault::Write => libc::EIO,
+        }))
+    }
+}
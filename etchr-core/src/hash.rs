@@ -0,0 +1,181 @@
+//! The hash algorithms a device can be verified with.
+//!
+//! SHA-256 is the default, and the one used wherever a hash is stored, such as
+//! checksum files, block maps and write checkpoints. Verification can use a
+//! faster one instead: hashing with SHA-256 runs at a few hundred MB/s, which
+//! is slower than a fast device can be read, while BLAKE3 is several times
+//! faster on modern CPUs. CRC32C and xxHash64 are faster still, but only catch
+//! accidental corruption.
+use sha2::{Sha256, Sha512};
+use std::fmt;
+
+/// A hash algorithm, set with
+/// [`VerifyOptions::hash`](crate::verify::VerifyOptions::hash) or
+/// [`WriteOptions::verify_hash`](crate::write::WriteOptions::verify_hash).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    /// SHA-256.
+    #[default]
+    Sha256,
+    /// SHA-512.
+    Sha512,
+    /// BLAKE3, with a 256-bit digest.
+    Blake3,
+    /// CRC-32C (Castagnoli), which modern CPUs compute in hardware.
+    Crc32c,
+    /// xxHash64, with a seed of 0.
+    XxHash64,
+}
+
+impl HashAlgorithm {
+    /// Every algorithm, in the order of the variants.
+    pub const ALL: [HashAlgorithm; 5] = [
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Sha512,
+        HashAlgorithm::Blake3,
+        HashAlgorithm::Crc32c,
+        HashAlgorithm::XxHash64,
+    ];
+
+    /// The short name of the algorithm, such as `blake3`.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Crc32c => "crc32c",
+            HashAlgorithm::XxHash64 => "xxh64",
+        }
+    }
+
+    /// The algorithm with the short name `name`, as returned by
+    /// [`HashAlgorithm::name`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name() == name)
+    }
+
+    /// Starts hashing with this algorithm.
+    pub(crate) fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            HashAlgorithm::Sha256 => Box::new(Sha256::default()),
+            HashAlgorithm::Sha512 => Box::new(Sha512::default()),
+            HashAlgorithm::Blake3 => Box::new(blake3::Hasher::new()),
+            HashAlgorithm::Crc32c => Box::new(Crc32c(0)),
+            HashAlgorithm::XxHash64 => Box::new(xxhash_rust::xxh64::Xxh64::new(0)),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The digest of some data in one of the [`HashAlgorithm`]s.
+///
+/// It displays as the name of the algorithm and the digest in hex, as in
+/// `blake3=af13…`. The CRC-32C and xxHash64 values are stored big-endian, so
+/// that the hex matches what other tools print for them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Digest {
+    algorithm: HashAlgorithm,
+    bytes: Vec<u8>,
+}
+
+impl Digest {
+    /// A digest in `algorithm`, as returned by [`Hasher::finalize`].
+    pub(crate) fn new(algorithm: HashAlgorithm, bytes: Vec<u8>) -> Self {
+        Self { algorithm, bytes }
+    }
+
+    /// A SHA-256 digest.
+    pub(crate) fn sha256(sha256: [u8; 32]) -> Self {
+        Self::new(HashAlgorithm::Sha256, sha256.to_vec())
+    }
+
+    /// The algorithm of the digest.
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// The digest itself.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The digest as a lowercase hex string.
+    pub fn to_hex(&self) -> String {
+        hex::encode(&self.bytes)
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.algorithm, self.to_hex())
+    }
+}
+
+/// A hash in progress, so that the same loop can hash with any algorithm.
+pub(crate) trait Hasher: Send {
+    /// Adds `data` to the hash.
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the digest of everything added so far.
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+impl Hasher for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        sha2::Digest::finalize(*self).to_vec()
+    }
+}
+
+impl Hasher for Sha512 {
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        sha2::Digest::finalize(*self).to_vec()
+    }
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        blake3::Hasher::finalize(&self).as_bytes().to_vec()
+    }
+}
+
+/// The running CRC-32C of the data so far.
+struct Crc32c(u32);
+
+impl Hasher for Crc32c {
+    fn update(&mut self, data: &[u8]) {
+        self.0 = crc32c::crc32c_append(self.0, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.to_be_bytes().to_vec()
+    }
+}
+
+impl Hasher for xxhash_rust::xxh64::Xxh64 {
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh64::Xxh64::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.digest().to_be_bytes().to_vec()
+    }
+}
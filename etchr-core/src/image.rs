@@ -0,0 +1,169 @@
+//! Recognises what kind of data an image holds.
+//!
+//! A disk image starts with a partition table or, for optical media, an ISO
+//! 9660 volume descriptor. Anything else may still be written on purpose (a
+//! raw filesystem or a firmware blob), but is more often the archive an image
+//! was downloaded in, so front-ends can use [`looks_like_disk_image`] to ask
+//! before writing it.
+//!
+//! Compressed images and zip archives are recognised by [`detect_format`], and
+//! are unpacked on the fly wherever an image is read.
+use crate::source::{ImageFile, whole_name};
+use crate::vhd;
+use crate::write::{DEFAULT_ZSTD_WINDOW_LOG_MAX, decompressed_size_hint, open_image, read_full};
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// How much of the start of an image [`looks_like_disk_image`] needs to see
+/// to recognise every kind, i.e. up to the first ISO 9660 volume descriptor.
+pub const PROBE_LEN: usize = 64 * 1024;
+
+/// What the start of an image looks like.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ImageKind {
+    /// A GUID partition table, with 512 or 4096 byte sectors.
+    Gpt,
+    /// An MBR partition table, or a boot sector with the MBR signature.
+    Mbr,
+    /// An ISO 9660 filesystem, such as a (hybrid) installer ISO.
+    Iso9660,
+    /// None of the above, so probably not a disk image.
+    Unknown,
+}
+
+impl ImageKind {
+    /// Whether this is one of the layouts of a disk image.
+    pub fn is_disk_image(self) -> bool {
+        self != ImageKind::Unknown
+    }
+}
+
+/// Works out what kind of image `head`, the start of the image data, belongs
+/// to. It should be at least [`PROBE_LEN`] bytes long, unless the image is
+/// shorter than that.
+pub fn looks_like_disk_image(head: &[u8]) -> ImageKind {
+    let has = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+
+    // Hybrid ISOs also carry a partition table, but are ISOs first.
+    if has(0x8001, b"CD001") {
+        ImageKind::Iso9660
+    } else if has(512, b"EFI PART") || has(4096, b"EFI PART") {
+        ImageKind::Gpt
+    } else if has(510, &[0x55, 0xAA]) {
+        ImageKind::Mbr
+    } else {
+        ImageKind::Unknown
+    }
+}
+
+/// Reads the start of the image in `file`, decompressing it if needed, and
+/// works out what kind of image it is.
+pub fn probe(file: &ImageFile) -> io::Result<ImageKind> {
+    let mut source = open_image(file, DEFAULT_ZSTD_WINDOW_LOG_MAX)?;
+    let mut head = vec![0; PROBE_LEN];
+    let n = read_full(&mut source.reader, &mut head)?;
+    Ok(looks_like_disk_image(&head[..n]))
+}
+
+/// How an image file is packed, as told by [`detect_format`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Format {
+    /// gzip, usually `.gz`.
+    Gzip,
+    /// xz, usually `.xz`.
+    Xz,
+    /// Zstandard, usually `.zst`.
+    Zstd,
+    /// bzip2, usually `.bz2`.
+    Bzip2,
+    /// LZ4 frames, usually `.lz4`.
+    Lz4,
+    /// A zip archive holding the image, usually `.zip`.
+    Zip,
+}
+
+impl Format {
+    /// Recognises the magic number at the start of `head`.
+    pub(crate) fn from_magic(head: &[u8]) -> Option<Format> {
+        let magics: [(&[u8], Format); 7] = [
+            (&[0x1F, 0x8B, 0x08], Format::Gzip),
+            (&[0xFD, b'7', b'z', b'X', b'Z', 0x00], Format::Xz),
+            (&[0x28, 0xB5, 0x2F, 0xFD], Format::Zstd),
+            (b"BZh", Format::Bzip2),
+            (&[0x04, 0x22, 0x4D, 0x18], Format::Lz4),
+            (b"PK\x03\x04", Format::Zip),
+            // An archive with nothing in it.
+            (b"PK\x05\x06", Format::Zip),
+        ];
+        magics
+            .into_iter()
+            .find(|(magic, _)| head.starts_with(magic))
+            .map(|(_, format)| format)
+    }
+
+    /// Recognises the extension of `path`, or of the image it is a part of.
+    pub(crate) fn from_extension(path: &Path) -> Option<Format> {
+        let ext = whole_name(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+
+        match ext.as_str() {
+            "gz" | "gzip" => Some(Format::Gzip),
+            "xz" => Some(Format::Xz),
+            "zst" | "zstd" => Some(Format::Zstd),
+            "bz2" | "bzip2" => Some(Format::Bzip2),
+            "lz4" => Some(Format::Lz4),
+            "zip" => Some(Format::Zip),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Gzip => "gzip",
+            Format::Xz => "xz",
+            Format::Zstd => "zstd",
+            Format::Bzip2 => "bzip2",
+            Format::Lz4 => "lz4",
+            Format::Zip => "zip",
+        })
+    }
+}
+
+/// Works out how the image in `file` is packed, from the magic number it
+/// starts with or, if it has none that is known, from its extension. Returns
+/// `None` for a raw image.
+///
+/// The contents win over the extension, so that a renamed download is still
+/// unpacked.
+pub fn detect_format(file: &ImageFile) -> io::Result<Option<Format>> {
+    let mut head = [0u8; 6];
+    let n = read_full(&mut file.open()?, &mut head)?;
+    Ok(Format::from_magic(&head[..n]).or_else(|| Format::from_extension(file.path())))
+}
+
+/// Reads the size of the image in `file` once it is decompressed
+/// from the metadata of its format, without decompressing it. For an image
+/// that is not compressed, this is the size of the file, or of the disk in a
+/// fixed VHD.
+///
+/// Returns `None` if the format does not record the size (bzip2, and lz4 or
+/// zstd unless the compressor was told it), or the file cannot be read. The
+/// size is only a lower bound: gzip records it modulo 4 GiB, and xz, zstd and
+/// lz4 record it for the first stream or frame only.
+pub fn decompressed_size(file: &ImageFile) -> Option<u64> {
+    match detect_format(file).ok()? {
+        Some(format) => decompressed_size_hint(file, format),
+        None => match vhd::disk_size(file).ok()? {
+            Some(disk_size) => Some(disk_size),
+            None => file.open().ok().map(|reader| reader.len()),
+        },
+    }
+}
@@ -6,24 +6,39 @@
 //! I/O, and verification.
 //!
 //! The library is structured into several key modules:
+//! - [`cache`]: Keeps decompressed images between runs.
+//! - [`clone`]: Copies one device straight to another.
+//! - [`checkpoint`]: Records the progress of a write so it can be resumed.
+//! - [`checksum`]: Finds the published SHA-256 of an image in the checksum files
+//!   next to it.
 //! - [`device`]: Contains the cross-platform `Device` struct.
+//! - [`error`]: Typed errors that front-ends may want to report specially.
+//! - [`hash`]: The hash algorithms a device can be verified with.
+//! - [`image`]: Recognises whether an image looks like a disk image.
+//! - [`metadata`]: Integrity information kept in the extended attributes of
+//!   an image file.
 //! - [`platform`]: Provides platform-specific logic, primarily for discovering
 //!   removable block devices.
+//! - [`progress`]: Structured progress reports with throughput and ETA.
 //! - [`mod@read`]: Contains the logic for reading data from a device to an image file.
+//! - [`source`]: Where an image to write comes from, a file (possibly split into
+//!   parts) or a URL.
+//! - [`verify`]: Checks a device against an image without writing it.
+//! - [`vhd`]: Reads the footer of VHD images, which are written without it.
+//! - [`warning`]: Non-fatal problems reported while an operation carries on.
 //! - [`mod@write`]: Contains the logic for writing an image file to a device.
 //!
-//! The primary entry points for imaging operations are the [`read::run`] and
-//! [`write::run`] functions. These functions are designed to be asynchronous in
-
+//! The primary entry points for imaging operations are [`read::ReadOptions`] and
+//! [`write::WriteOptions`]. These are designed to be asynchronous in
 //! nature and report their progress via callbacks, allowing the calling application
 //! to display progress in any way it chooses.
 //!
 //! ## Example: Writing an Image with Progress Reporting
 //!
 //! ```rust,no_run
-//! use etchr_core::{device::Device, platform, write};
+//! use etchr_core::{platform, write};
 //! use std::path::Path;
-//! use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+//! use std::sync::{Arc, atomic::AtomicBool};
 //! use anyhow::Result;
 //!
 //! fn main() -> Result<()> {
@@ -42,28 +57,74 @@
 //!
 //!     println!("Starting write...");
 //!
-//!     write::run(
-//!         image_path,
-//!         &device_to_write.path,
-//!         true, // Enable verification
-//!         running.clone(),
-//!         || {}, // on_decompress_start
-//!         |_| {}, // on_decompress_progress
-//!         |_| {}, // on_write_start
-//!         on_write_progress,
-//!         |_| {}, // on_verify_start
-//!         |_| {}, // on_verify_progress
-//!     )?;
+//!     write::WriteOptions::new(image_path, &device_to_write.path)
+//!         .verify(true)
+//!         .auto_unmount(true)
+//!         .running(running.clone())
+//!         .on_write_progress(on_write_progress)
+//!         .run()?;
 //!
 //!     println!("Write complete!");
 //!
 //!     Ok(())
 //! }
 //! ```
+//!
+//! ## Example: Reading a Device to an Image
+//!
+//! A completed read returns a [`read::ReadReport`] with everything a front-end
+//! needs for its summary.
+//!
+//! ```rust,no_run
+//! use etchr_core::read::{Compression, ReadOptions};
+//! use anyhow::Result;
+//!
+//! fn main() -> Result<()> {
+//!     let report = ReadOptions::new("/dev/sdb", "backup.img.zst")
+//!         .compression(Compression::Zstd(3))
+//!         .verify(true)
+//!         .run()?;
+//!
+//!     println!(
+//!         "Read {} bytes in {:?} ({:.0} bytes/s), sha256={}",
+//!         report.bytes_read,
+//!         report.duration,
+//!         report.average_bps(),
+//!         report.sha256_hex()
+//!     );
+//!     if let Some(compressed) = report.compressed_bytes {
+//!         println!("Compressed to {} bytes", compressed);
+//!     }
+//!     if !report.unreadable.is_empty() {
+//!         println!("{} bytes could not be read", report.bytes_unreadable());
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
 
+mod bmap;
+mod buffer;
+pub mod cache;
+pub mod checkpoint;
+pub mod checksum;
+pub mod clone;
+mod customize;
 pub mod device;
+pub mod error;
+mod fault;
+pub mod hash;
+pub mod image;
+pub mod metadata;
 mod os_options;
+mod partition_table;
 pub mod platform;
+pub mod progress;
 pub mod read;
+pub mod source;
+mod throttle;
+mod usedblocks;
+pub mod verify;
+pub mod vhd;
+pub mod warning;
 pub mod write;
-
@@ -0,0 +1,168 @@
+//! Integrity information kept in the extended attributes of an image file.
+//!
+//! Once a device has been read, [`ReadOptions`](crate::read::ReadOptions)
+//! stores the SHA-256 of the image data, the device it came from and when it
+//! was read in `user.etchr.*` attributes of the image file. The file then
+//! carries them wherever it is copied along with its attributes (for example
+//! with `cp --preserve=xattr` or `rsync -X`). On filesystems without extended
+//! attributes, and on Windows, nothing is stored and nothing is found.
+//!
+//! The attributes are plain text, so they can also be read with `getfattr`:
+//!
+//! - `user.etchr.sha256`: the SHA-256 of the image data in hex. For a
+//!   compressed image, this is the hash of the data before compression.
+//! - `user.etchr.source`: the path of the device that was read.
+//! - `user.etchr.date`: when the device was read, in seconds since the Unix
+//!   epoch.
+use crate::platform;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SHA256: &CStr = c"user.etchr.sha256";
+const SOURCE: &CStr = c"user.etchr.source";
+const DATE: &CStr = c"user.etchr.date";
+
+/// The integrity information of an image file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageMetadata {
+    /// The SHA-256 of the image data. For a compressed image, this is the
+    /// hash of the data before compression.
+    pub sha256: [u8; 32],
+    /// The device the image was read from, if known.
+    pub source: Option<PathBuf>,
+    /// When the image was read, if known. Stored to the second.
+    pub date: Option<SystemTime>,
+}
+
+impl ImageMetadata {
+    /// Returns the SHA-256 of the image data as a lowercase hex string.
+    pub fn sha256_hex(&self) -> String {
+        hex::encode(self.sha256)
+    }
+}
+
+/// Stores `metadata` in the extended attributes of the file at `path`,
+/// replacing any that are there. Returns `false`, without storing anything,
+/// if the filesystem does not support extended attributes.
+pub fn set(path: &Path, metadata: &ImageMetadata) -> io::Result<bool> {
+    set_file(&File::open(path)?, metadata)
+}
+
+/// Reads the metadata stored in the extended attributes of the file at
+/// `path`. Returns `None` if there is none, or the filesystem does not
+/// support extended attributes.
+///
+/// Fails with [`ErrorKind::InvalidData`] if the stored SHA-256 is not a
+/// valid hash. A source or date that cannot be understood is left out.
+pub fn get(path: &Path) -> io::Result<Option<ImageMetadata>> {
+    let file = File::open(path)?;
+    let Some(sha256) = platform::get_xattr(&file, SHA256)? else {
+        return Ok(None);
+    };
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(&sha256, &mut hash).map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("the stored SHA-256 is not valid ({})", e),
+        )
+    })?;
+    let source = platform::get_xattr(&file, SOURCE)?
+        .and_then(|source| String::from_utf8(source).ok())
+        .map(PathBuf::from);
+    let date = platform::get_xattr(&file, DATE)?
+        .and_then(|date| String::from_utf8(date).ok()?.parse().ok())
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+    Ok(Some(ImageMetadata {
+        sha256: hash,
+        source,
+        date,
+    }))
+}
+
+/// Removes any metadata from the extended attributes of the file at `path`.
+pub fn clear(path: &Path) -> io::Result<()> {
+    clear_file(&File::open(path)?)
+}
+
+/// [`set`] for a file that is already open.
+fn set_file(file: &File, metadata: &ImageMetadata) -> io::Result<bool> {
+    clear_file(file)?;
+    if let Some(source) = &metadata.source
+        && !platform::set_xattr(file, SOURCE, source.to_string_lossy().as_bytes())?
+    {
+        return Ok(false);
+    }
+    if let Some(date) = metadata.date {
+        let secs = date.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        if !platform::set_xattr(file, DATE, secs.to_string().as_bytes())? {
+            return Ok(false);
+        }
+    }
+    // The hash goes last, so that it is never found without the rest.
+    platform::set_xattr(file, SHA256, metadata.sha256_hex().as_bytes())
+}
+
+/// [`clear`] for a file that is already open.
+pub(crate) fn clear_file(file: &File) -> io::Result<()> {
+    // Whatever is left without the hash is ignored, so it goes first.
+    for name in [SHA256, SOURCE, DATE] {
+        platform::remove_xattr(file, name)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    /// A file on tmpfs, which has user extended attributes since Linux 6.6.
+    fn tmpfs_file() -> NamedTempFile {
+        NamedTempFile::new_in("/dev/shm").unwrap()
+    }
+
+    fn sample() -> ImageMetadata {
+        ImageMetadata {
+            sha256: [0x3C; 32],
+            source: Some(PathBuf::from("/dev/sdz")),
+            date: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+        }
+    }
+
+    #[test]
+    fn round_trip_on_tmpfs() {
+        let file = tmpfs_file();
+        assert_eq!(get(file.path()).unwrap(), None);
+        if !set(file.path(), &sample()).unwrap() {
+            // An older kernel, whose tmpfs has no user attributes.
+            assert_eq!(get(file.path()).unwrap(), None);
+            return;
+        }
+        assert_eq!(get(file.path()).unwrap(), Some(sample()));
+
+        // Setting again replaces what was there, fields left out included.
+        let bare = ImageMetadata {
+            sha256: [0xC3; 32],
+            source: None,
+            date: None,
+        };
+        assert!(set(file.path(), &bare).unwrap());
+        assert_eq!(get(file.path()).unwrap(), Some(bare));
+
+        clear(file.path()).unwrap();
+        assert_eq!(get(file.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn a_damaged_hash_is_refused() {
+        let file = tmpfs_file();
+        if !platform::set_xattr(file.as_file(), SHA256, b"not a hash").unwrap() {
+            return;
+        }
+        let e = get(file.path()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,20 +1,141 @@
-#![allow(unused_imports)]
-#![allow(dead_code)]
+//! Opening devices with the right flags for each platform.
+//!
+//! The read and write paths describe how they want a device opened (direct
+//! I/O, exclusive access, synchronous writes, read-only) with
+//! [`DeviceOpenOptions`], which maps each toggle to the open flags of the
+//! platform in one place.
+use crate::device::DirectIo;
+use std::fs::{File, OpenOptions};
+use std::io;
 #[cfg(unix)]
-pub(crate) use std::os::unix::fs::OpenOptionsExt;
-
+use std::os::unix::fs::OpenOptionsExt;
 #[cfg(windows)]
-pub(crate) trait OpenOptionsExt {
-    fn custom_flags(&mut self, flags: u32) -> &mut Self;
+use std::os::windows::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// How to open a device, or a regular file standing in for one.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct DeviceOpenOptions {
+    write: bool,
+    create: bool,
+    direct_io: DirectIo,
+    exclusive: bool,
+    sync_writes: bool,
 }
 
-#[cfg(windows)]
-impl OpenOptionsExt for std::fs::OpenOptions {
-    fn custom_flags(&mut self, _flags: u32) -> &mut Self {
-        // On Windows, FILE_FLAG_NO_BUFFERING is handled via `CreateFileW`
-        // which is not directly exposed in `std::fs::OpenOptions`.
-        // This would require using `winapi` or `windows-sys` directly
-        // to open the file handle. For now, this is a placeholder.
+impl DeviceOpenOptions {
+    /// Options for reading through the page cache.
+    pub(crate) fn read_only() -> Self {
+        Self {
+            write: false,
+            create: false,
+            direct_io: DirectIo::Off,
+            exclusive: false,
+            sync_writes: false,
+        }
+    }
+
+    /// Options for reading and writing through the page cache.
+    pub(crate) fn read_write() -> Self {
+        Self {
+            write: true,
+            ..Self::read_only()
+        }
+    }
+
+    /// Whether to create a regular file if nothing exists at the path. Only
+    /// applies to [`DeviceOpenOptions::read_write`].
+    pub(crate) fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Whether to bypass the page cache (`O_DIRECT`, or
+    /// `FILE_FLAG_NO_BUFFERING` on Windows).
+    pub(crate) fn direct_io(mut self, direct_io: DirectIo) -> Self {
+        self.direct_io = direct_io;
         self
     }
+
+    /// Whether the open should fail while the device or one of its partitions
+    /// is mounted (`O_EXCL` on a block device). On Windows the volumes are
+    /// locked instead (see `platform::lock_volumes`), so this has no effect.
+    pub(crate) fn exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// Whether each write should only return once it has reached the device
+    /// (`O_SYNC`, or `FILE_FLAG_WRITE_THROUGH` on Windows).
+    pub(crate) fn sync_writes(mut self, sync_writes: bool) -> Self {
+        self.sync_writes = sync_writes;
+        self
+    }
+
+    /// Opens `path`.
+    ///
+    /// Returns the file, along with the error the direct open failed with if
+    /// it fell back to the page cache under [`DirectIo::Preferred`].
+    pub(crate) fn open(&self, path: &Path) -> io::Result<(File, Option<io::Error>)> {
+        if self.direct_io == DirectIo::Off {
+            return Ok((self.options(false).open(path)?, None));
+        }
+        match self.options(true).open(path) {
+            Ok(file) => Ok((file, None)),
+            Err(e) if self.direct_io == DirectIo::Preferred && rejects_direct_io(&e) => {
+                let file = self.options(false).open(path)?;
+                Ok((file, Some(e)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The standard library options for an open with or without direct I/O.
+    fn options(&self, direct: bool) -> OpenOptions {
+        let mut options = OpenOptions::new();
+        options
+            .read(true)
+            .write(self.write)
+            .create(self.write && self.create);
+
+        #[cfg(unix)]
+        {
+            let mut flags = 0;
+            if direct {
+                flags |= libc::O_DIRECT;
+            }
+            if self.exclusive {
+                flags |= libc::O_EXCL;
+            }
+            if self.sync_writes {
+                flags |= libc::O_SYNC;
+            }
+            options.custom_flags(flags);
+        }
+        #[cfg(windows)]
+        {
+            use windows_sys::Win32::Storage::FileSystem::{
+                FILE_FLAG_NO_BUFFERING, FILE_FLAG_WRITE_THROUGH,
+            };
+            let mut flags = 0;
+            if direct {
+                flags |= FILE_FLAG_NO_BUFFERING;
+            }
+            if self.sync_writes {
+                flags |= FILE_FLAG_WRITE_THROUGH;
+            }
+            options.custom_flags(flags);
+        }
+        options
+    }
+}
+
+/// Whether an open failed because the device or its driver does not support
+/// direct I/O, rather than for any other reason.
+fn rejects_direct_io(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    let codes = [libc::EINVAL, libc::EOPNOTSUPP];
+    #[cfg(windows)]
+    let codes = [windows_sys::Win32::Foundation::ERROR_INVALID_PARAMETER as i32];
+    e.raw_os_error().is_some_and(|code| codes.contains(&code))
 }
@@ -0,0 +1,767 @@
+//! Editing the partition table of a freshly written image.
+//!
+//! An image's partition table describes a disk exactly as large as the image.
+//! On a larger device, the backup GPT that an image carries at its own end
+//! sits somewhere in the middle, and the space after the last partition is
+//! unused. Both are fixed up in place, through the device handle that was
+//! used for writing, once the image has been written and verified.
+//!
+//! When reading a device, the partition table also tells how much of it is in
+//! use, so the free space after the last partition need not be read, and
+//! where the partitions are, so their filesystems can be looked at.
+use crate::write::GrownPartition;
+use anyhow::{Result, anyhow};
+use flate2::Crc;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+/// The MBR partition type of a protective MBR, announcing a GPT.
+const GPT_PROTECTIVE: u8 = 0xEE;
+
+/// The MBR partition types of an extended partition, which holds logical
+/// partitions rather than a filesystem.
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+/// The signature at the start of a GPT header.
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// The size of the part of a GPT header that its checksum covers, in the
+/// revision every tool writes.
+const HEADER_SIZE: usize = 92;
+
+/// Reads `buf.len()` bytes of the device at `offset`.
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)?;
+    Ok(())
+}
+
+/// Writes `buf` to the device at `offset`.
+fn write_at(file: &mut File, offset: u64, buf: &[u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)?;
+    Ok(())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+fn set_u32(buf: &mut [u8], at: usize, value: u32) {
+    buf[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u64(buf: &mut [u8], at: usize, value: u64) {
+    buf[at..at + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Recomputes the checksum of a GPT header in place.
+fn seal_header(header: &mut [u8]) {
+    set_u32(header, 16, 0);
+    let crc = crc32(&header[..HEADER_SIZE]);
+    set_u32(header, 16, crc);
+}
+
+/// Reads the MBR of the image written at `base`, or `None` if it has no
+/// partition table at all.
+fn read_mbr(file: &mut File, base: u64) -> Result<Option<[u8; 512]>> {
+    let mut mbr = [0u8; 512];
+    read_at(file, base, &mut mbr)?;
+    Ok((mbr[510..512] == [0x55, 0xAA]).then_some(mbr))
+}
+
+/// The offset of the 16-byte entry `i` of the MBR partition table.
+fn mbr_entry(i: usize) -> usize {
+    446 + i * 16
+}
+
+/// A primary GPT, with its header sector and partition array.
+struct Gpt {
+    header: Vec<u8>,
+    /// The partition array, padded to whole sectors.
+    entries: Vec<u8>,
+    entry_count: usize,
+    entry_size: usize,
+    /// The last sector used by any partition, as read.
+    last_used_lba: u64,
+}
+
+/// Where the backup GPT goes on a device, in sectors from the image start.
+struct BackupLayout {
+    /// The sector of the backup header, the last one on the device.
+    header_lba: u64,
+    /// The first sector of the backup partition array.
+    entries_lba: u64,
+    /// The last sector partitions may use.
+    last_usable_lba: u64,
+}
+
+impl Gpt {
+    /// Reads and checks the primary GPT of the image written at `base`.
+    fn read(file: &mut File, base: u64, sector: u64) -> Result<Self> {
+        let mut header = vec![0u8; sector as usize];
+        read_at(file, base + sector, &mut header)?;
+        if &header[0..8] != SIGNATURE {
+            return Err(anyhow!("the GPT header is missing"));
+        }
+        if u32_at(&header, 12) as usize != HEADER_SIZE {
+            return Err(anyhow!(
+                "unsupported GPT header size {}",
+                u32_at(&header, 12)
+            ));
+        }
+        let stored_crc = u32_at(&header, 16);
+        seal_header(&mut header);
+        if u32_at(&header, 16) != stored_crc {
+            return Err(anyhow!("the GPT header checksum does not match"));
+        }
+
+        let entries_lba = u64_at(&header, 72);
+        let entry_count = u32_at(&header, 80) as usize;
+        let entry_size = u32_at(&header, 84) as usize;
+        if entry_size < 128 || entry_count == 0 || entry_count > 1024 {
+            return Err(anyhow!("the GPT partition array is malformed"));
+        }
+        let entries_offset = entries_lba
+            .checked_mul(sector)
+            .and_then(|offset| offset.checked_add(base))
+            .ok_or_else(|| anyhow!("the GPT partition array is malformed"))?;
+        let mut entries = vec![0u8; (entry_count * entry_size).next_multiple_of(sector as usize)];
+        read_at(file, entries_offset, &mut entries)?;
+        if crc32(&entries[..entry_count * entry_size]) != u32_at(&header, 88) {
+            return Err(anyhow!("the GPT partition array checksum does not match"));
+        }
+
+        let mut gpt = Self {
+            header,
+            entries,
+            entry_count,
+            entry_size,
+            last_used_lba: 0,
+        };
+        gpt.last_used_lba = gpt
+            .partitions()
+            .map(|(_, e)| u64_at(e, 40))
+            .max()
+            .unwrap_or(0);
+        Ok(gpt)
+    }
+
+    /// The partitions in use, with their 1-based numbers.
+    fn partitions(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        // An unused entry has an all-zero partition type GUID.
+        (1..)
+            .zip(
+                self.entries
+                    .chunks_exact(self.entry_size)
+                    .take(self.entry_count),
+            )
+            .filter(|(_, e)| e[0..16].iter().any(|&b| b != 0))
+    }
+
+    /// Works out where the backup GPT goes on a device whose image area is
+    /// `sectors` sectors long.
+    fn layout(&self, sectors: u64, sector: u64) -> Result<BackupLayout> {
+        // The backup array sits right before the backup header, and the space
+        // partitions may use ends right before the backup array.
+        let too_small = || anyhow!("the partitions extend past the end of the device");
+        let header_lba = sectors.checked_sub(1).ok_or_else(too_small)?;
+        let entries_lba = header_lba
+            .checked_sub(self.entries.len() as u64 / sector)
+            .ok_or_else(too_small)?;
+        let last_usable_lba = entries_lba.checked_sub(1).ok_or_else(too_small)?;
+        if self.last_used_lba > last_usable_lba || u64_at(&self.header, 40) > last_usable_lba {
+            return Err(too_small());
+        }
+        Ok(BackupLayout {
+            header_lba,
+            entries_lba,
+            last_usable_lba,
+        })
+    }
+
+    /// Writes this GPT back to the image at `base`, with the backup at
+    /// `layout`, and grows the protective MBR partition to match.
+    fn write(
+        &mut self,
+        file: &mut File,
+        base: u64,
+        sector: u64,
+        mbr: &mut [u8; 512],
+        layout: &BackupLayout,
+    ) -> Result<()> {
+        let old_backup_lba = u64_at(&self.header, 32);
+
+        let entries_crc = crc32(&self.entries[..self.entry_count * self.entry_size]);
+        set_u32(&mut self.header, 88, entries_crc);
+        set_u64(&mut self.header, 32, layout.header_lba);
+        set_u64(&mut self.header, 48, layout.last_usable_lba);
+        seal_header(&mut self.header);
+
+        let mut backup = self.header.clone();
+        set_u64(&mut backup, 24, layout.header_lba);
+        set_u64(&mut backup, 32, 1);
+        set_u64(&mut backup, 72, layout.entries_lba);
+        seal_header(&mut backup);
+
+        // The backup is written before the primary GPT, so an interrupted
+        // fixup leaves at least one of them intact.
+        write_at(file, base + layout.entries_lba * sector, &self.entries)?;
+        write_at(file, base + layout.header_lba * sector, &backup)?;
+        file.sync_data()?;
+        let entries_lba = u64_at(&self.header, 72);
+        write_at(file, base + entries_lba * sector, &self.entries)?;
+        write_at(file, base + sector, &self.header)?;
+
+        // The old backup header would otherwise be found by tools that scan for
+        // one, now in the middle of the free space.
+        if old_backup_lba > self.last_used_lba && old_backup_lba < layout.entries_lba {
+            let mut old = vec![0u8; sector as usize];
+            read_at(file, base + old_backup_lba * sector, &mut old)?;
+            if &old[0..8] == SIGNATURE {
+                write_at(
+                    file,
+                    base + old_backup_lba * sector,
+                    &vec![0u8; sector as usize],
+                )?;
+            }
+        }
+
+        // The protective partition covers the whole disk, as far as 32 bits go.
+        let protective_len = u32::try_from(layout.header_lba).unwrap_or(u32::MAX);
+        if u32_at(mbr, mbr_entry(0) + 12) != protective_len {
+            set_u32(mbr, mbr_entry(0) + 12, protective_len);
+            write_at(file, base, mbr)?;
+        }
+
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Moves the backup GPT of the image written at `base` to the last sector
+/// before `end`, returning whether anything was changed.
+///
+/// `sector` is the logical sector size the GPT is expressed in. Images with
+/// only an MBR, or whose backup GPT is already in place, are left untouched.
+/// The primary header's `alternate_lba` and `last_usable_lba` are updated to
+/// match, and the size of the protective MBR partition is grown to cover the
+/// device. The device must not be open with `O_DIRECT`.
+///
+/// # Errors
+///
+/// Returns an error if the primary GPT is corrupt, or if the device is too
+/// small to hold the partitions and the backup behind them.
+pub(crate) fn relocate_backup(file: &mut File, base: u64, sector: u64, end: u64) -> Result<bool> {
+    let Some(mut mbr) = read_mbr(file, base)? else {
+        return Ok(false);
+    };
+    if mbr[mbr_entry(0) + 4] != GPT_PROTECTIVE {
+        return Ok(false);
+    }
+
+    let mut gpt = Gpt::read(file, base, sector)?;
+    let layout = gpt.layout((end - base) / sector, sector)?;
+    if u64_at(&gpt.header, 32) == layout.header_lba {
+        return Ok(false);
+    }
+    gpt.write(file, base, sector, &mut mbr, &layout)?;
+    Ok(true)
+}
+
+/// Grows the partition that ends last in the image written at `base` up to
+/// `end`, returning it, or `None` if the image has no partitions.
+///
+/// The partition is the one with the highest end sector, which is not always
+/// the last entry of the table. With a GPT, the backup GPT is also moved to
+/// the end of the device, and the partition grows up to it. With an MBR, the
+/// partition can only grow to 2 TiB with 512-byte sectors. Only the partition
+/// entry changes; the filesystem inside it keeps its size. The device must not
+/// be open with `O_DIRECT`.
+///
+/// # Errors
+///
+/// Returns an error if the partition table is corrupt, or if the partition
+/// that ends last is an MBR extended partition.
+pub(crate) fn grow_last_partition(
+    file: &mut File,
+    base: u64,
+    sector: u64,
+    end: u64,
+) -> Result<Option<GrownPartition>> {
+    let Some(mut mbr) = read_mbr(file, base)? else {
+        return Ok(None);
+    };
+    let sectors = (end - base) / sector;
+
+    if mbr[mbr_entry(0) + 4] == GPT_PROTECTIVE {
+        let mut gpt = Gpt::read(file, base, sector)?;
+        let layout = gpt.layout(sectors, sector)?;
+        let Some((number, index)) = gpt
+            .partitions()
+            .max_by_key(|(_, e)| u64_at(e, 40))
+            .map(|(number, _)| (number, number as usize - 1))
+        else {
+            return Ok(None);
+        };
+
+        let entry = &mut gpt.entries[index * gpt.entry_size..(index + 1) * gpt.entry_size];
+        let first = u64_at(entry, 32);
+        let last = u64_at(entry, 40);
+        set_u64(entry, 40, layout.last_usable_lba);
+        gpt.write(file, base, sector, &mut mbr, &layout)?;
+        return Ok(Some(GrownPartition {
+            number,
+            start: first * sector,
+            old_len: (last - first + 1) * sector,
+            new_len: (layout.last_usable_lba - first + 1) * sector,
+        }));
+    }
+
+    // An MBR partition ends at `first + count`, exclusive, as 32-bit sectors.
+    let Some(index) = (0..4)
+        .filter(|&i| mbr[mbr_entry(i) + 4] != 0)
+        .max_by_key(|&i| {
+            u32_at(&mbr, mbr_entry(i) + 8) as u64 + u32_at(&mbr, mbr_entry(i) + 12) as u64
+        })
+    else {
+        return Ok(None);
+    };
+    let entry = &mut mbr[mbr_entry(index)..mbr_entry(index + 1)];
+    if MBR_EXTENDED.contains(&entry[4]) {
+        return Err(anyhow!(
+            "partition {} is an extended partition, which cannot be grown",
+            index + 1
+        ));
+    }
+    let first = u32_at(entry, 8) as u64;
+    let count = u32_at(entry, 12) as u64;
+    let new_count = sectors
+        .min(u32::MAX as u64)
+        .saturating_sub(first)
+        .max(count);
+    if new_count != count {
+        set_u32(entry, 12, new_count as u32);
+        // The end is past what CHS addressing can describe, which is marked
+        // with its largest value.
+        entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        write_at(file, base, &mbr)?;
+        file.sync_data()?;
+    }
+    Ok(Some(GrownPartition {
+        number: index as u32 + 1,
+        start: first * sector,
+        old_len: count * sector,
+        new_len: new_count * sector,
+    }))
+}
+
+/// The number of bytes at the start of a device of `len` bytes that its
+/// partition table accounts for: up to the end of the partition that ends
+/// last, or of the backup GPT if that comes later. Returns `None` if the
+/// device has no partitions.
+///
+/// `sector` is the logical sector size the table is expressed in. The device
+/// must not be open with `O_DIRECT`.
+///
+/// # Errors
+///
+/// Returns an error if the GPT is corrupt, or if the table describes
+/// partitions that extend past the end of the device.
+pub(crate) fn used_len(file: &mut File, sector: u64, len: u64) -> Result<Option<u64>> {
+    let Some(mbr) = read_mbr(file, 0)? else {
+        return Ok(None);
+    };
+    let end_lba = if mbr[mbr_entry(0) + 4] == GPT_PROTECTIVE {
+        let gpt = Gpt::read(file, 0, sector)?;
+        if gpt.partitions().next().is_none() {
+            return Ok(None);
+        }
+        // The backup header is the last sector of the disk it was made for.
+        gpt.last_used_lba.max(u64_at(&gpt.header, 32)) + 1
+    } else {
+        // A filesystem without a partition table carries the same signature,
+        // but its boot code makes for boot flags other than these.
+        if (0..4).any(|i| ![0x00, 0x80].contains(&mbr[mbr_entry(i)])) {
+            return Ok(None);
+        }
+        let Some(end) = (0..4)
+            .filter(|&i| mbr[mbr_entry(i) + 4] != 0)
+            .map(|i| u32_at(&mbr, mbr_entry(i) + 8) as u64 + u32_at(&mbr, mbr_entry(i) + 12) as u64)
+            .max()
+        else {
+            return Ok(None);
+        };
+        end
+    };
+    end_lba
+        .checked_mul(sector)
+        .filter(|&end| end <= len)
+        .map(Some)
+        .ok_or_else(|| anyhow!("the partitions extend past the end of the device"))
+}
+
+/// A partition listed in a partition table.
+#[derive(Clone, Debug)]
+pub(crate) struct Partition {
+    /// The 1-based number of the partition.
+    pub(crate) number: u32,
+    /// Where the partition lies on the device, in bytes.
+    pub(crate) range: Range<u64>,
+    /// Whether this is an MBR extended partition, which holds logical
+    /// partitions rather than a filesystem.
+    pub(crate) extended: bool,
+}
+
+/// Lists the partitions of the image written at `base`, in the order of the
+/// table, with their ranges counted from `base`. Returns `None` if the image
+/// has no partition table.
+///
+/// `sector` is the logical sector size the table is expressed in, and `len`
+/// the space on the device from `base`. The device must not be open with
+/// `O_DIRECT`.
+///
+/// # Errors
+///
+/// Returns an error if the GPT is corrupt, or if a partition extends past the
+/// end of the device.
+pub(crate) fn partitions(
+    file: &mut File,
+    base: u64,
+    sector: u64,
+    len: u64,
+) -> Result<Option<Vec<Partition>>> {
+    let Some(mbr) = read_mbr(file, base)? else {
+        return Ok(None);
+    };
+    let mut partitions = Vec::new();
+    if mbr[mbr_entry(0) + 4] == GPT_PROTECTIVE {
+        let gpt = Gpt::read(file, base, sector)?;
+        for (number, entry) in gpt.partitions() {
+            // The last sector of a GPT partition is inclusive.
+            let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+            if last < first {
+                return Err(anyhow!("partition {} ends before it starts", number));
+            }
+            let range = first
+                .checked_mul(sector)
+                .zip(last.checked_add(1).and_then(|end| end.checked_mul(sector)))
+                .ok_or_else(|| {
+                    anyhow!("partition {} extends past the end of the device", number)
+                })?;
+            partitions.push(Partition {
+                number,
+                range: range.0..range.1,
+                extended: false,
+            });
+        }
+    } else {
+        // As in `used_len`, other boot flags mean a filesystem without a
+        // partition table.
+        if (0..4).any(|i| ![0x00, 0x80].contains(&mbr[mbr_entry(i)])) {
+            return Ok(None);
+        }
+        for i in (0..4).filter(|&i| mbr[mbr_entry(i) + 4] != 0) {
+            let first = u32_at(&mbr, mbr_entry(i) + 8) as u64;
+            let count = u32_at(&mbr, mbr_entry(i) + 12) as u64;
+            partitions.push(Partition {
+                number: i as u32 + 1,
+                range: first * sector..(first + count) * sector,
+                extended: MBR_EXTENDED.contains(&mbr[mbr_entry(i) + 4]),
+            });
+        }
+        if partitions.is_empty() {
+            return Ok(None);
+        }
+    }
+    if let Some(partition) = partitions.iter().find(|p| p.range.end > len) {
+        return Err(anyhow!(
+            "partition {} extends past the end of the device",
+            partition.number
+        ));
+    }
+    Ok(Some(partitions))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The number of entries in the GPTs built by [`gpt_image`], 128 bytes
+    /// each.
+    const ENTRIES: usize = 128;
+
+    /// A file of `len` bytes holding, at `base`, an image of `sectors`
+    /// sectors of `sector` bytes whose GPT lists `partitions`, as their first
+    /// and last sectors.
+    pub(crate) fn gpt_image(
+        base: u64,
+        sector: u64,
+        sectors: u64,
+        len: u64,
+        partitions: &[(u64, u64)],
+    ) -> File {
+        let entries_sectors = (ENTRIES * 128) as u64 / sector;
+        let mut entries = vec![0u8; ENTRIES * 128];
+        for (i, &(first, last)) in partitions.iter().enumerate() {
+            let entry = &mut entries[i * 128..(i + 1) * 128];
+            entry[..16].copy_from_slice(&[0xAF; 16]);
+            entry[16..32].copy_from_slice(&[i as u8 + 1; 16]);
+            set_u64(entry, 32, first);
+            set_u64(entry, 40, last);
+        }
+
+        let mut header = vec![0u8; sector as usize];
+        header[..8].copy_from_slice(SIGNATURE);
+        set_u32(&mut header, 8, 0x0001_0000);
+        set_u32(&mut header, 12, HEADER_SIZE as u32);
+        set_u64(&mut header, 24, 1);
+        set_u64(&mut header, 32, sectors - 1);
+        set_u64(&mut header, 40, 2 + entries_sectors);
+        set_u64(&mut header, 48, sectors - 2 - entries_sectors);
+        set_u64(&mut header, 72, 2);
+        set_u32(&mut header, 80, ENTRIES as u32);
+        set_u32(&mut header, 84, 128);
+        set_u32(&mut header, 88, crc32(&entries));
+        seal_header(&mut header);
+        let mut backup = header.clone();
+        set_u64(&mut backup, 24, sectors - 1);
+        set_u64(&mut backup, 32, 1);
+        set_u64(&mut backup, 72, sectors - 1 - entries_sectors);
+        seal_header(&mut backup);
+
+        let mut mbr = [0u8; 512];
+        mbr[mbr_entry(0) + 4] = GPT_PROTECTIVE;
+        set_u32(&mut mbr, mbr_entry(0) + 8, 1);
+        set_u32(&mut mbr, mbr_entry(0) + 12, (sectors - 1) as u32);
+        mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(len).unwrap();
+        write_at(&mut file, base, &mbr).unwrap();
+        write_at(&mut file, base + sector, &header).unwrap();
+        write_at(&mut file, base + 2 * sector, &entries).unwrap();
+        let backup_entries = base + (sectors - 1 - entries_sectors) * sector;
+        write_at(&mut file, backup_entries, &entries).unwrap();
+        write_at(&mut file, base + (sectors - 1) * sector, &backup).unwrap();
+        file
+    }
+
+    /// Checks that the image at `base` has a valid GPT whose backup is at the
+    /// end of a disk of `sectors` sectors, and returns it.
+    fn check_gpt(file: &mut File, base: u64, sector: u64, sectors: u64) -> Gpt {
+        // Reading the primary GPT checks the CRCs of its header and array.
+        let gpt = Gpt::read(file, base, sector).unwrap();
+        let entries_sectors = gpt.entries.len() as u64 / sector;
+        let last_usable_lba = sectors - 2 - entries_sectors;
+        assert_eq!(u64_at(&gpt.header, 32), sectors - 1);
+        assert_eq!(u64_at(&gpt.header, 48), last_usable_lba);
+
+        let mut backup = vec![0u8; sector as usize];
+        read_at(file, base + (sectors - 1) * sector, &mut backup).unwrap();
+        assert_eq!(&backup[..8], SIGNATURE);
+        let stored_crc = u32_at(&backup, 16);
+        seal_header(&mut backup);
+        assert_eq!(u32_at(&backup, 16), stored_crc);
+        assert_eq!(u64_at(&backup, 24), sectors - 1);
+        assert_eq!(u64_at(&backup, 32), 1);
+        assert_eq!(u64_at(&backup, 48), last_usable_lba);
+        assert_eq!(u64_at(&backup, 72), sectors - 1 - entries_sectors);
+        let mut entries = vec![0u8; gpt.entries.len()];
+        read_at(file, base + u64_at(&backup, 72) * sector, &mut entries).unwrap();
+        assert_eq!(entries, gpt.entries);
+        assert_eq!(crc32(&entries), u32_at(&backup, 88));
+
+        // The protective partition covers everything after the MBR.
+        let mbr = read_mbr(file, base).unwrap().unwrap();
+        assert_eq!(u32_at(&mbr, mbr_entry(0) + 12) as u64, sectors - 1);
+        gpt
+    }
+
+    /// Whether the sector at `lba` of the image at `base` is all zeros.
+    fn is_zeroed(file: &mut File, base: u64, sector: u64, lba: u64) -> bool {
+        let mut buf = vec![0u8; sector as usize];
+        read_at(file, base + lba * sector, &mut buf).unwrap();
+        buf.iter().all(|&b| b == 0)
+    }
+
+    #[test]
+    fn backup_gpt_is_moved_to_the_end() {
+        // A 4 MiB image on a 16 MiB device.
+        let mut file = gpt_image(0, 512, 8192, 16 << 20, &[(2048, 6143)]);
+        assert!(relocate_backup(&mut file, 0, 512, 16 << 20).unwrap());
+        let gpt = check_gpt(&mut file, 0, 512, 32768);
+        assert_eq!(gpt.last_used_lba, 6143);
+        assert!(is_zeroed(&mut file, 0, 512, 8191));
+
+        // Once it is there, nothing changes.
+        assert!(!relocate_backup(&mut file, 0, 512, 16 << 20).unwrap());
+    }
+
+    #[test]
+    fn backup_gpt_is_moved_with_4k_sectors_past_an_offset() {
+        let base = 1 << 20;
+        let mut file = gpt_image(base, 4096, 1024, base + (16 << 20), &[(256, 767)]);
+        assert!(relocate_backup(&mut file, base, 4096, base + (16 << 20)).unwrap());
+        check_gpt(&mut file, base, 4096, 4096);
+        assert!(is_zeroed(&mut file, base, 4096, 1023));
+    }
+
+    #[test]
+    fn backup_gpt_needs_room_after_the_partitions() {
+        let mut file = gpt_image(0, 512, 8192, 8192 * 512, &[(2048, 8150)]);
+        assert!(relocate_backup(&mut file, 0, 512, 8000 * 512).is_err());
+    }
+
+    #[test]
+    fn mbr_images_are_left_alone() {
+        let mut file = mbr_image(1 << 20, &[(0x83, 2048, 1024)]);
+        assert!(!relocate_backup(&mut file, 0, 512, 1 << 20).unwrap());
+    }
+
+    /// A file of `len` bytes whose MBR lists `partitions`, as their type,
+    /// first sector and sector count.
+    fn mbr_image(len: u64, partitions: &[(u8, u32, u32)]) -> File {
+        let mut mbr = [0u8; 512];
+        for (i, &(kind, first, count)) in partitions.iter().enumerate() {
+            mbr[mbr_entry(i) + 4] = kind;
+            set_u32(&mut mbr, mbr_entry(i) + 8, first);
+            set_u32(&mut mbr, mbr_entry(i) + 12, count);
+        }
+        mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(len).unwrap();
+        write_at(&mut file, 0, &mbr).unwrap();
+        file
+    }
+
+    #[test]
+    fn mbr_partition_is_grown() {
+        let mut file = mbr_image(16 << 20, &[(0x0C, 2048, 2048), (0x83, 4096, 4096)]);
+        let grown = grow_last_partition(&mut file, 0, 512, 16 << 20).unwrap();
+        assert_eq!(
+            grown,
+            Some(GrownPartition {
+                number: 2,
+                start: 4096 * 512,
+                old_len: 4096 * 512,
+                new_len: (32768 - 4096) * 512,
+            })
+        );
+        let mbr = read_mbr(&mut file, 0).unwrap().unwrap();
+        assert_eq!(u32_at(&mbr, mbr_entry(1) + 12), 32768 - 4096);
+        assert_eq!(mbr[mbr_entry(1) + 5..mbr_entry(1) + 8], [0xFE, 0xFF, 0xFF]);
+        // The other partition is untouched.
+        assert_eq!(u32_at(&mbr, mbr_entry(0) + 12), 2048);
+    }
+
+    #[test]
+    fn mbr_partition_that_ends_last_is_grown_wherever_it_is_listed() {
+        let mut file = mbr_image(16 << 20, &[(0x83, 6144, 2048), (0x0C, 2048, 2048)]);
+        let grown = grow_last_partition(&mut file, 0, 512, 16 << 20).unwrap();
+        assert_eq!(grown.map(|p| p.number), Some(1));
+        let mbr = read_mbr(&mut file, 0).unwrap().unwrap();
+        assert_eq!(u32_at(&mbr, mbr_entry(0) + 12), 32768 - 6144);
+        assert_eq!(u32_at(&mbr, mbr_entry(1) + 12), 2048);
+    }
+
+    #[test]
+    fn extended_partition_is_not_grown() {
+        let mut file = mbr_image(16 << 20, &[(0x83, 2048, 2048), (0x05, 4096, 4096)]);
+        let before = read_mbr(&mut file, 0).unwrap();
+        assert!(grow_last_partition(&mut file, 0, 512, 16 << 20).is_err());
+        assert_eq!(read_mbr(&mut file, 0).unwrap(), before);
+    }
+
+    #[test]
+    fn gpt_partition_is_grown_up_to_the_moved_backup() {
+        // The first entry is the partition that ends last.
+        let partitions = [(4096, 6143), (2048, 4095)];
+        let mut file = gpt_image(0, 512, 8192, 16 << 20, &partitions);
+        let grown = grow_last_partition(&mut file, 0, 512, 16 << 20).unwrap();
+        let last_usable_lba = 32768 - 2 - 32;
+        assert_eq!(
+            grown,
+            Some(GrownPartition {
+                number: 1,
+                start: 4096 * 512,
+                old_len: 2048 * 512,
+                new_len: (last_usable_lba - 4096 + 1) * 512,
+            })
+        );
+        let gpt = check_gpt(&mut file, 0, 512, 32768);
+        let ends: Vec<_> = gpt.partitions().map(|(_, e)| u64_at(e, 40)).collect();
+        assert_eq!(ends, [last_usable_lba, 4095]);
+        assert!(is_zeroed(&mut file, 0, 512, 8191));
+    }
+
+    #[test]
+    fn images_without_partitions_are_left_alone() {
+        let mut file = mbr_image(1 << 20, &[]);
+        assert_eq!(
+            grow_last_partition(&mut file, 0, 512, 1 << 20).unwrap(),
+            None
+        );
+        let mut file = gpt_image(0, 512, 2048, 1 << 20, &[]);
+        assert_eq!(
+            grow_last_partition(&mut file, 0, 512, 1 << 20).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn partitions_are_listed_from_the_image_start() {
+        let base = 1 << 20;
+        let mut file = gpt_image(
+            base,
+            512,
+            2048,
+            base + (1 << 20),
+            &[(34, 1023), (1024, 2000)],
+        );
+        let partitions = partitions(&mut file, base, 512, 1 << 20).unwrap().unwrap();
+        let ranges: Vec<_> = partitions.iter().map(|p| p.range.clone()).collect();
+        assert_eq!(ranges, [34 * 512..1024 * 512, 1024 * 512..2001 * 512]);
+        assert_eq!(partitions[1].number, 2);
+    }
+
+    #[test]
+    fn partitions_of_a_corrupt_gpt_are_refused() {
+        let mut file = gpt_image(0, 512, 2048, 1 << 20, &[(34, 2000)]);
+        let mut header = [0u8; 512];
+        read_at(&mut file, 512, &mut header).unwrap();
+        header[32] ^= 1;
+        write_at(&mut file, 512, &header).unwrap();
+        let e = partitions(&mut file, 0, 512, 1 << 20).unwrap_err();
+        assert!(e.to_string().contains("checksum"), "{}", e);
+    }
+
+    #[test]
+    fn partitions_far_past_the_end_are_refused() {
+        // A partition array that cannot be addressed.
+        let mut file = gpt_image(0, 512, 2048, 1 << 20, &[(34, 2000)]);
+        let mut header = [0u8; 512];
+        read_at(&mut file, 512, &mut header).unwrap();
+        set_u64(&mut header, 72, u64::MAX / 2);
+        seal_header(&mut header);
+        write_at(&mut file, 512, &header).unwrap();
+        let e = partitions(&mut file, 0, 512, 1 << 20).unwrap_err();
+        assert!(e.to_string().contains("malformed"), "{}", e);
+
+        // A partition whose end cannot be addressed.
+        let mut file = gpt_image(0, 512, 2048, 1 << 20, &[(34, u64::MAX / 2)]);
+        let e = partitions(&mut file, 0, 512, 1 << 20).unwrap_err();
+        assert!(e.to_string().contains("past the end"), "{}", e);
+    }
+}
@@ -1,10 +1,61 @@
-use crate::device::Device;
-use anyhow::{anyhow, Result};
-use std::fs;
+use crate::device::{Device, SectorSizes};
+use crate::error::Error;
+use crate::fault::{self, Fault};
+use anyhow::{Result, anyhow};
+use nix::fcntl::{FallocateFlags, fallocate};
+use nix::mount::{MntFlags, umount2};
+use nix::sys::statvfs::statvfs;
+use nix::{
+    ioctl_none_bad, ioctl_read, ioctl_read_bad, ioctl_readwrite_bad, ioctl_write_ptr_bad,
+    request_code_none,
+};
+use std::ffi::CStr;
+use std::fs::{self, File};
 use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::ptr;
 use sysinfo;
 
+ioctl_read!(blkgetsize64, 0x12, 114, u64);
+ioctl_read_bad!(blksszget, request_code_none!(0x12, 104), libc::c_int);
+ioctl_read_bad!(blkpbszget, request_code_none!(0x12, 123), libc::c_uint);
+ioctl_write_ptr_bad!(blkdiscard, request_code_none!(0x12, 119), [u64; 2]);
+ioctl_write_ptr_bad!(blksecdiscard, request_code_none!(0x12, 125), [u64; 2]);
+ioctl_write_ptr_bad!(blkzeroout, request_code_none!(0x12, 127), [u64; 2]);
+ioctl_none_bad!(blkrrpart, request_code_none!(0x12, 95));
+ioctl_none_bad!(blkflsbuf, request_code_none!(0x12, 97));
+ioctl_none_bad!(cdromeject, 0x5309);
+ioctl_readwrite_bad!(sg_io, 0x2285, SgIoHdr);
+
+/// The `sg_io_hdr` structure used by the `SG_IO` ioctl.
+#[repr(C)]
+struct SgIoHdr {
+    interface_id: libc::c_int,
+    dxfer_direction: libc::c_int,
+    cmd_len: libc::c_uchar,
+    mx_sb_len: libc::c_uchar,
+    iovec_count: libc::c_ushort,
+    dxfer_len: libc::c_uint,
+    dxferp: *mut libc::c_void,
+    cmdp: *mut libc::c_uchar,
+    sbp: *mut libc::c_uchar,
+    timeout: libc::c_uint,
+    flags: libc::c_uint,
+    pack_id: libc::c_int,
+    usr_ptr: *mut libc::c_void,
+    status: libc::c_uchar,
+    masked_status: libc::c_uchar,
+    msg_status: libc::c_uchar,
+    sb_len_wr: libc::c_uchar,
+    host_status: libc::c_ushort,
+    driver_status: libc::c_ushort,
+    resid: libc::c_int,
+    duration: libc::c_uint,
+    info: libc::c_uint,
+}
+
 /// Helper to read a specific file from the /sys/block filesystem.
 fn read_sys_file(device_name: &str, file: &str) -> io::Result<String> {
     let path = PathBuf::from("/sys/block").join(device_name).join(file);
@@ -13,22 +64,66 @@ fn read_sys_file(device_name: &str, file: &str) -> io::Result<String> {
 
 /// Helper to find the parent device of a partition (e.g., /dev/sda1 -> /dev/sda).
 /// This is used to find the system drive's parent for exclusion.
+///
+/// The kernel's view in `/sys/class/block` is used where the partition has an
+/// entry there, and the device name is taken apart otherwise.
 fn get_parent_device_path(path: &Path) -> PathBuf {
+    if let Some(name) = path.file_name() {
+        let sys_path = Path::new("/sys/class/block").join(name);
+        if sys_path.join("partition").exists()
+            && let Ok(parent) = fs::canonicalize(sys_path.join(".."))
+            && let Some(parent_name) = parent.file_name()
+        {
+            return PathBuf::from("/dev/").join(parent_name);
+        }
+    }
+
     let path_str = path.to_string_lossy();
 
     if path_str.starts_with("/dev/sd") {
         if let Some(index) = path_str.rfind(|c: char| c.is_alphabetic()) {
             return PathBuf::from(&path_str[..=index]);
         }
-    } else if path_str.starts_with("/dev/mmcblk") || path_str.starts_with("/dev/nvme") {
-        if let Some(index) = path_str.find('p') {
-            return PathBuf::from(&path_str[..index]);
-        }
+    } else if (path_str.starts_with("/dev/mmcblk") || path_str.starts_with("/dev/nvme"))
+        && let Some(index) = path_str.find('p')
+    {
+        return PathBuf::from(&path_str[..index]);
     }
 
     path.to_path_buf()
 }
 
+/// Finds the whole disk that holds the root filesystem of the running system
+/// (e.g., `/dev/nvme0n1` when `/` is mounted from `/dev/nvme0n1p2`).
+///
+/// Returns `None` if no block device is mounted at `/`, as in a container or
+/// when booted from the network.
+pub fn system_disk() -> Option<PathBuf> {
+    let disks = sysinfo::Disks::new_with_refreshed_list();
+    disks
+        .iter()
+        .find(|disk| disk.mount_point() == Path::new("/"))
+        .map(|disk| get_parent_device_path(&PathBuf::from("/dev/").join(disk.name())))
+}
+
+/// Returns the whole disk that the partition at `path` belongs to (e.g.,
+/// `/dev/sdb` for `/dev/sdb1`), or `None` if `path` is not a partition.
+pub fn parent_disk(path: &Path) -> Option<PathBuf> {
+    let path = fs::canonicalize(path).ok()?;
+    let sys_path = Path::new("/sys/class/block").join(path.file_name()?);
+    if !sys_path.join("partition").exists() {
+        return None;
+    }
+    let parent = fs::canonicalize(sys_path.join("..")).ok()?;
+    Some(PathBuf::from("/dev/").join(parent.file_name()?))
+}
+
+/// Returns `true` if `path` is the whole disk `disk` or one of its partitions.
+pub fn is_on_disk(path: &Path, disk: &Path) -> bool {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    path == disk || get_parent_device_path(&path) == disk
+}
+
 /// Scans for all removable block devices on a Linux system.
 ///
 /// This function discovers devices by iterating through the `/sys/block` directory.
@@ -48,17 +143,9 @@ fn get_parent_device_path(path: &Path) -> PathBuf {
 /// A `Result<Vec<Device>>` which is a list of discovered [`Device`]s on success,
 /// or an error if the system drive cannot be determined or `/sys/block` cannot be read.
 pub fn get_removable_devices() -> Result<Vec<Device>> {
-    let disks = sysinfo::Disks::new_with_refreshed_list();
-    let mut system_disk_parent = None;
-    for disk in disks.iter() {
-        if disk.mount_point() == Path::new("/") {
-            let path = PathBuf::from("/dev/").join(disk.name());
-            system_disk_parent = Some(get_parent_device_path(&path));
-            break;
-        }
-    }
     let system_disk_parent =
-        system_disk_parent.ok_or_else(|| anyhow!("Could not determine system drive."))?;
+        system_disk().ok_or_else(|| anyhow!("Could not determine system drive."))?;
+    let disks = sysinfo::Disks::new_with_refreshed_list();
 
     let mut devices = Vec::new();
     let block_dir = fs::read_dir("/sys/block")?;
@@ -90,7 +177,8 @@ pub fn get_removable_devices() -> Result<Vec<Device>> {
             continue;
         }
 
-        let size_gb = (size_sectors * 512) as f64 / (1024.0 * 1024.0 * 1024.0);
+        let size_bytes = size_sectors * 512;
+        let size_gb = size_bytes as f64 / (1024.0 * 1024.0 * 1024.0);
 
         // Try to find a mount point by checking the `sysinfo` list.
         let mut mount_point = "".to_string();
@@ -108,9 +196,364 @@ pub fn get_removable_devices() -> Result<Vec<Device>> {
             path: device_path,
             name: device_name,
             size_gb,
+            size_bytes,
             mount_point,
         });
     }
 
     Ok(devices)
 }
+
+/// Queries the size in bytes of an open block device.
+///
+/// This uses the `BLKGETSIZE64` ioctl, so it fails for anything that is not a
+/// block device.
+pub fn get_device_size(file: &File) -> io::Result<u64> {
+    let mut size_bytes: u64 = 0;
+    unsafe {
+        blkgetsize64(file.as_raw_fd(), &mut size_bytes)?;
+    }
+    Ok(size_bytes)
+}
+
+/// Queries the logical and physical sector sizes of an open block device.
+///
+/// This uses the `BLKSSZGET` and `BLKPBSZGET` ioctls, so it fails for anything
+/// that is not a block device.
+pub fn get_sector_sizes(file: &File) -> io::Result<SectorSizes> {
+    let mut logical: libc::c_int = 0;
+    let mut physical: libc::c_uint = 0;
+    unsafe {
+        blksszget(file.as_raw_fd(), &mut logical)?;
+        blkpbszget(file.as_raw_fd(), &mut physical)?;
+    }
+    Ok(SectorSizes {
+        logical: logical as u32,
+        physical,
+    })
+}
+
+/// Discards (TRIMs) `len` bytes of an open block device, starting at `start`.
+///
+/// This uses the `BLKDISCARD` ioctl. Devices that do not support discard fail
+/// with `EOPNOTSUPP`.
+pub fn discard(file: &File, start: u64, len: u64) -> io::Result<()> {
+    let range = [start, len];
+    unsafe {
+        blkdiscard(file.as_raw_fd(), &range)?;
+    }
+    Ok(())
+}
+
+/// Securely discards `len` bytes of an open block device, starting at `start`.
+///
+/// This uses the `BLKSECDISCARD` ioctl, which also erases any copies of the
+/// data the device keeps internally (e.g. blocks remapped by wear levelling).
+/// Few devices other than eMMC support it; the rest fail with `EOPNOTSUPP`.
+pub fn secure_discard(file: &File, start: u64, len: u64) -> io::Result<()> {
+    let range = [start, len];
+    unsafe {
+        blksecdiscard(file.as_raw_fd(), &range)?;
+    }
+    Ok(())
+}
+
+/// Zeroes `len` bytes of an open block device, starting at `start`.
+///
+/// This uses the `BLKZEROOUT` ioctl, which offloads the work to the device
+/// (e.g. with WRITE ZEROES) where it can and writes zeros otherwise. Both
+/// `start` and `len` must be multiples of the logical sector size.
+pub fn zero_out(file: &File, start: u64, len: u64) -> io::Result<()> {
+    let range = [start, len];
+    unsafe {
+        blkzeroout(file.as_raw_fd(), &range)?;
+    }
+    Ok(())
+}
+
+/// Asks the kernel to re-read the partition table of an open block device.
+///
+/// This uses the `BLKRRPART` ioctl. It fails with `EBUSY` while a partition is
+/// in use, and with `EINVAL` for devices that cannot be partitioned.
+pub fn reread_partitions(file: &File) -> io::Result<()> {
+    unsafe {
+        blkrrpart(file.as_raw_fd())?;
+    }
+    Ok(())
+}
+
+/// Sends a SCSI command that transfers no data, returning an error if the
+/// device rejects it.
+fn scsi_command(file: &File, mut cdb: [u8; 6]) -> io::Result<()> {
+    let mut sense = [0u8; 32];
+    let mut hdr = SgIoHdr {
+        interface_id: b'S' as libc::c_int,
+        dxfer_direction: -1, // SG_DXFER_NONE
+        cmd_len: cdb.len() as libc::c_uchar,
+        mx_sb_len: sense.len() as libc::c_uchar,
+        iovec_count: 0,
+        dxfer_len: 0,
+        dxferp: std::ptr::null_mut(),
+        cmdp: cdb.as_mut_ptr(),
+        sbp: sense.as_mut_ptr(),
+        timeout: 10_000,
+        flags: 0,
+        pack_id: 0,
+        usr_ptr: std::ptr::null_mut(),
+        status: 0,
+        masked_status: 0,
+        msg_status: 0,
+        sb_len_wr: 0,
+        host_status: 0,
+        driver_status: 0,
+        resid: 0,
+        duration: 0,
+        info: 0,
+    };
+    unsafe {
+        sg_io(file.as_raw_fd(), &mut hdr)?;
+    }
+    if hdr.status != 0 || hdr.host_status != 0 || hdr.driver_status != 0 {
+        return Err(io::Error::other("the device rejected the SCSI command"));
+    }
+    Ok(())
+}
+
+/// Flushes a device and ejects its medium, so it can be safely removed.
+///
+/// Anything still cached for the device is written out and the kernel's
+/// buffers are dropped. The medium is then ejected with the SCSI `START STOP
+/// UNIT` command, which covers USB mass storage and most card readers, falling
+/// back to the `CDROMEJECT` ioctl. Desktop environments then show the device
+/// as safe to remove.
+pub fn eject(device_path: &Path) -> Result<()> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(device_path)?;
+    file.sync_all()?;
+    unsafe {
+        blkflsbuf(file.as_raw_fd())?;
+    }
+
+    // ALLOW MEDIUM REMOVAL, then START STOP UNIT with LoEj set and Start clear.
+    let ejected = scsi_command(&file, [0x1E, 0, 0, 0, 0, 0])
+        .and_then(|()| scsi_command(&file, [0x1B, 0, 0, 0, 0x02, 0]));
+    if ejected.is_ok() {
+        return Ok(());
+    }
+
+    unsafe { cdromeject(file.as_raw_fd()) }
+        .map(|_| ())
+        .map_err(|e| anyhow!("Could not eject {}: {}", device_path.display(), e))
+}
+
+/// Turns `O_DIRECT` on or off for an open file.
+///
+/// O_DIRECT transfers must cover whole sectors, so a trailing partial sector
+/// has to be written with it turned off.
+pub fn set_direct_io(file: &File, enabled: bool) -> io::Result<()> {
+    if enabled {
+        fault::check(file, Fault::DirectIo)?;
+    }
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if enabled {
+        flags | libc::O_DIRECT
+    } else {
+        flags & !libc::O_DIRECT
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Drops the cached pages of the `len` bytes of `file` at `offset` from the
+/// page cache, so that the next buffered read of them comes from the device.
+///
+/// This uses `posix_fadvise(POSIX_FADV_DONTNEED)`, which only drops pages that
+/// have been written back, so the file should be synced first.
+pub fn drop_page_cache(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let advice = libc::POSIX_FADV_DONTNEED;
+    match unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            advice,
+        )
+    } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// Allocates `len` bytes of disk space for a regular file up front, growing
+/// it to that size, so the filesystem can lay it out in a few large extents
+/// rather than extend it piece by piece.
+///
+/// This uses `fallocate`. Filesystems that cannot allocate space ahead of time
+/// fail with `EOPNOTSUPP`.
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    fallocate(file, FallocateFlags::empty(), 0, len as libc::off_t)?;
+    Ok(())
+}
+
+/// Returns the number of bytes available to unprivileged users on the
+/// filesystem that holds `path`.
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    let stats = statvfs(path)?;
+    Ok(stats.blocks_available() as u64 * stats.fragment_size() as u64)
+}
+
+/// Returns the mount point of the filesystem that holds `path`, which must
+/// exist: the highest directory above it on the same filesystem.
+pub fn mount_point(path: &Path) -> io::Result<PathBuf> {
+    let path = fs::canonicalize(path)?;
+    let dev = fs::metadata(&path)?.dev();
+    let mut mount_point = path.as_path();
+    while let Some(parent) = mount_point.parent() {
+        if fs::metadata(parent)?.dev() != dev {
+            break;
+        }
+        mount_point = parent;
+    }
+    Ok(mount_point.to_path_buf())
+}
+
+/// Sets the extended attribute `name` of `file` to `value`. Returns `false`,
+/// without setting it, if the filesystem does not support extended
+/// attributes.
+pub fn set_xattr(file: &File, name: &CStr, value: &[u8]) -> io::Result<bool> {
+    let fd = file.as_raw_fd();
+    let ret = unsafe { libc::fsetxattr(fd, name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    match io::Error::last_os_error() {
+        e if e.raw_os_error() == Some(libc::ENOTSUP) => Ok(false),
+        e => Err(e),
+    }
+}
+
+/// Returns the value of the extended attribute `name` of `file`, or `None` if
+/// it is not set or the filesystem does not support extended attributes.
+pub fn get_xattr(file: &File, name: &CStr) -> io::Result<Option<Vec<u8>>> {
+    let fd = file.as_raw_fd();
+    loop {
+        // Ask for the size first, then read it into a buffer that size.
+        let len = unsafe { libc::fgetxattr(fd, name.as_ptr(), ptr::null_mut(), 0) };
+        if len >= 0 {
+            let mut value = vec![0u8; len as usize];
+            let len = unsafe {
+                libc::fgetxattr(fd, name.as_ptr(), value.as_mut_ptr().cast(), value.len())
+            };
+            if len >= 0 {
+                value.truncate(len as usize);
+                return Ok(Some(value));
+            }
+        }
+        match io::Error::last_os_error() {
+            // The value grew in between, so try again.
+            e if e.raw_os_error() == Some(libc::ERANGE) => continue,
+            e if [Some(libc::ENODATA), Some(libc::ENOTSUP)].contains(&e.raw_os_error()) => {
+                return Ok(None);
+            }
+            e => return Err(e),
+        }
+    }
+}
+
+/// Removes the extended attribute `name` of `file`, if it is set.
+pub fn remove_xattr(file: &File, name: &CStr) -> io::Result<()> {
+    if unsafe { libc::fremovexattr(file.as_raw_fd(), name.as_ptr()) } == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        e if [Some(libc::ENODATA), Some(libc::ENOTSUP)].contains(&e.raw_os_error()) => Ok(()),
+        e => Err(e),
+    }
+}
+
+/// Returns `true` if `path` is a block device rather than a regular file.
+pub fn is_block_device(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|m| m.file_type().is_block_device())
+        .unwrap_or(false)
+}
+
+/// Decodes the octal escapes (e.g. `\040` for a space) used in `/proc/self/mounts`.
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && i + 3 < bytes.len()
+            && let Some(byte) = std::str::from_utf8(&bytes[i + 1..i + 4])
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        {
+            out.push(byte);
+            i += 4;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Unmounts every mounted filesystem that lives on the given device.
+///
+/// Mounts are discovered through `/proc/self/mounts`. An entry belongs to the
+/// device if its source is the device itself or one of its partitions (e.g.
+/// `/dev/sdb1` for `/dev/sdb`). Nested mount points are unmounted first.
+///
+/// # Returns
+///
+/// The mount points that were unmounted, or [`Error::UnmountFailed`] listing
+/// the mount points that are still busy if any of them could not be unmounted.
+pub fn unmount_device(device_path: &Path) -> Result<Vec<PathBuf>> {
+    let device = fs::canonicalize(device_path)?;
+    let mounts = fs::read_to_string("/proc/self/mounts")?;
+
+    let mut targets = Vec::new();
+    for line in mounts.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(source), Some(target)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if !source.starts_with("/dev/") {
+            continue;
+        }
+
+        let source = PathBuf::from(unescape_mount_field(source));
+        let source = fs::canonicalize(&source).unwrap_or(source);
+        if source == device || get_parent_device_path(&source) == device {
+            targets.push(PathBuf::from(unescape_mount_field(target)));
+        }
+    }
+
+    // Unmount the most deeply nested mount points first.
+    targets.sort_by_key(|t| std::cmp::Reverse(t.components().count()));
+    targets.dedup();
+
+    let mut unmounted = Vec::new();
+    let mut busy = Vec::new();
+    for target in targets {
+        match umount2(&target, MntFlags::empty()) {
+            Ok(()) => unmounted.push(target),
+            Err(_) => busy.push(target),
+        }
+    }
+
+    if !busy.is_empty() {
+        return Err(Error::UnmountFailed { mount_points: busy }.into());
+    }
+    Ok(unmounted)
+}
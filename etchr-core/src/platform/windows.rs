@@ -1,5 +1,25 @@
-use crate::device::Device;
+use crate::device::{Device, SectorSizes};
 use anyhow::Result;
+use std::ffi::{CStr, c_void};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::Storage::FileSystem::{
+    FILE_FLAG_NO_BUFFERING, FILE_FLAG_WRITE_THROUGH, FILE_SHARE_READ, FILE_SHARE_WRITE,
+};
+use windows_sys::Win32::System::IO::DeviceIoControl;
+use windows_sys::Win32::System::Ioctl::{
+    DISK_GEOMETRY, FSCTL_DISMOUNT_VOLUME, FSCTL_LOCK_VOLUME, FSCTL_UNLOCK_VOLUME,
+    GET_LENGTH_INFORMATION, IOCTL_DISK_GET_DRIVE_GEOMETRY, IOCTL_DISK_GET_LENGTH_INFO,
+    IOCTL_STORAGE_GET_DEVICE_NUMBER, STORAGE_DEVICE_NUMBER,
+};
+
+/// The prefix of the path of a whole disk, as in `\\.\PhysicalDrive1`.
+const PHYSICAL_DRIVE_PREFIX: &str = r"\\.\PhysicalDrive";
 
 /// Scans for all removable block devices on a Windows system.
 ///
@@ -17,3 +37,195 @@ pub fn get_removable_devices() -> Result<Vec<Device>> {
     // and their properties (e.g., removable, size).
     unimplemented!("Windows support is not yet implemented.");
 }
+
+/// Sends a `DeviceIoControl` request that takes no input, filling `out` with
+/// the result if the request returns any.
+fn ioctl<T>(file: &File, code: u32, out: Option<&mut T>) -> io::Result<()> {
+    let (out_ptr, out_len) = match out {
+        Some(out) => (out as *mut T as *mut c_void, size_of::<T>() as u32),
+        None => (ptr::null_mut(), 0),
+    };
+    let mut returned = 0u32;
+    let ok = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle() as HANDLE,
+            code,
+            ptr::null(),
+            0,
+            out_ptr,
+            out_len,
+            &mut returned,
+            ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the disk number of a path like `\\.\PhysicalDrive1`.
+fn disk_number(device_path: &Path) -> Option<u32> {
+    let path = device_path.to_str()?;
+    let prefix = path.get(..PHYSICAL_DRIVE_PREFIX.len())?;
+    if !prefix.eq_ignore_ascii_case(PHYSICAL_DRIVE_PREFIX) {
+        return None;
+    }
+    path[PHYSICAL_DRIVE_PREFIX.len()..].parse().ok()
+}
+
+/// Returns `true` if `path` names a whole disk (`\\.\PhysicalDriveN`) rather
+/// than a regular file.
+pub fn is_block_device(path: &Path) -> bool {
+    disk_number(path).is_some()
+}
+
+/// Returns the whole disk that the partition at `path` belongs to. Only whole
+/// disks can be written on Windows, so this is always `None`.
+pub fn parent_disk(_path: &Path) -> Option<PathBuf> {
+    None
+}
+
+/// Queries the size in bytes of an open disk.
+///
+/// This uses `IOCTL_DISK_GET_LENGTH_INFO`, so it fails for anything that is
+/// not a disk or volume.
+pub fn get_device_size(file: &File) -> io::Result<u64> {
+    let mut info: GET_LENGTH_INFORMATION = unsafe { std::mem::zeroed() };
+    ioctl(file, IOCTL_DISK_GET_LENGTH_INFO, Some(&mut info))?;
+    Ok(info.Length as u64)
+}
+
+/// Queries the sector size of an open disk.
+///
+/// This uses `IOCTL_DISK_GET_DRIVE_GEOMETRY`, which only reports the logical
+/// sector size, so the physical size is assumed to be the same.
+pub fn get_sector_sizes(file: &File) -> io::Result<SectorSizes> {
+    let mut geometry: DISK_GEOMETRY = unsafe { std::mem::zeroed() };
+    ioctl(file, IOCTL_DISK_GET_DRIVE_GEOMETRY, Some(&mut geometry))?;
+    Ok(SectorSizes {
+        logical: geometry.BytesPerSector,
+        physical: geometry.BytesPerSector,
+    })
+}
+
+/// Allocates `len` bytes of disk space for a regular file up front, growing
+/// it to that size, so the filesystem can lay it out in a few large extents.
+///
+/// Setting the end of the file makes NTFS allocate its clusters.
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    file.set_len(len)
+}
+
+/// Sets the extended attribute `name` of `file` to `value`. Returns `false`,
+/// without setting it, if the filesystem does not support extended
+/// attributes, which is always the case on Windows.
+pub fn set_xattr(_file: &File, _name: &CStr, _value: &[u8]) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Returns the value of the extended attribute `name` of `file`, or `None` if
+/// it is not set or the filesystem does not support extended attributes,
+/// which is always the case on Windows.
+pub fn get_xattr(_file: &File, _name: &CStr) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+/// Removes the extended attribute `name` of `file`, if it is set.
+pub fn remove_xattr(_file: &File, _name: &CStr) -> io::Result<()> {
+    Ok(())
+}
+
+/// Drops the cached pages of the `len` bytes of `file` at `offset` from the
+/// page cache. Devices are always opened unbuffered on Windows, so there is
+/// nothing to drop.
+pub fn drop_page_cache(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Opens a whole disk for unbuffered writing.
+///
+/// Writes bypass the cache (`FILE_FLAG_NO_BUFFERING`), so, as with `O_DIRECT`
+/// on Linux, every transfer must be sector-aligned in offset, length and
+/// buffer address. `FILE_FLAG_WRITE_THROUGH` makes each write reach the disk
+/// before it completes. The volumes on the disk should be locked with
+/// [`lock_volumes`] first, or Windows refuses writes to the sectors they hold.
+pub fn open_physical_drive(device_path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE)
+        .custom_flags(FILE_FLAG_NO_BUFFERING | FILE_FLAG_WRITE_THROUGH)
+        .open(device_path)
+}
+
+/// The volumes of a disk, locked and dismounted for as long as this is alive.
+///
+/// Dropping it unlocks the volumes, including when the write failed, so that
+/// Windows can mount whatever is on the disk again.
+#[derive(Debug)]
+pub struct VolumeLock {
+    volumes: Vec<File>,
+}
+
+impl Drop for VolumeLock {
+    fn drop(&mut self) {
+        for volume in &self.volumes {
+            let _ = ioctl::<()>(volume, FSCTL_UNLOCK_VOLUME, None);
+        }
+    }
+}
+
+/// Locks and dismounts every volume with a drive letter on the disk at
+/// `device_path` (`\\.\PhysicalDriveN`).
+///
+/// Each volume is locked with `FSCTL_LOCK_VOLUME`, which fails while any file
+/// on it is open, then dismounted with `FSCTL_DISMOUNT_VOLUME`. The volumes
+/// stay locked until the returned guard is dropped.
+///
+/// # Errors
+///
+/// Returns an error if `device_path` does not name a physical drive or if a
+/// volume could not be locked or dismounted. Volumes locked up to that point
+/// are unlocked again.
+pub fn lock_volumes(device_path: &Path) -> io::Result<VolumeLock> {
+    let disk = disk_number(device_path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a physical drive", device_path.display()),
+        )
+    })?;
+
+    let mut lock = VolumeLock {
+        volumes: Vec::new(),
+    };
+    for letter in b'A'..=b'Z' {
+        let path = format!(r"\\.\{}:", letter as char);
+        let Ok(volume) = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE)
+            .open(&path)
+        else {
+            continue;
+        };
+        // Volumes that span several disks cannot report a single device
+        // number, and are never on a removable drive anyway.
+        let mut number: STORAGE_DEVICE_NUMBER = unsafe { std::mem::zeroed() };
+        if ioctl(&volume, IOCTL_STORAGE_GET_DEVICE_NUMBER, Some(&mut number)).is_err()
+            || number.DeviceNumber != disk
+        {
+            continue;
+        }
+
+        let locked = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path, e));
+        ioctl::<()>(&volume, FSCTL_LOCK_VOLUME, None).map_err(locked)?;
+        // Keep the handle before dismounting, so the lock is released even if
+        // the dismount fails.
+        lock.volumes.push(volume);
+        let volume = lock.volumes.last().expect("volume was just pushed");
+        ioctl::<()>(volume, FSCTL_DISMOUNT_VOLUME, None).map_err(locked)?;
+    }
+    Ok(lock)
+}
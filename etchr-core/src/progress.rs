@@ -0,0 +1,238 @@
+//! Structured progress reports with throughput and ETA.
+//!
+//! The plain `FnMut(u64)` callbacks only pass a byte count. [`Progress`] also
+//! carries the stage, the total and a speed averaged over a sliding window, so
+//! that every front-end shows the same numbers without keeping its own clock.
+//!
+//! Each report also carries an [`overall`](Progress::overall) fraction of the
+//! whole operation. Decompression, writing and verification are weighted by
+//! the bytes each of them goes through: the compressed size of the image for
+//! decompression, and its decompressed size for writing and for verification.
+//! A stage that does not run has no weight, and the short stages around them
+//! (checksum, erase, discard, sync, wipe) have none either. With a streamed
+//! compressed image, decompression and writing advance together, so a 1 GB
+//! image compressed to 250 MB and verified weighs 250 MB + 1 GB + 1 GB.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The stage of an operation that a [`Progress`] report refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Stage {
+    /// Hashing the image file to check it against an expected checksum.
+    Checksum,
+    /// Erasing the whole device before writing.
+    Erase,
+    /// Discarding (TRIMming) the device before writing.
+    Discard,
+    /// Decompressing the image. Reported in compressed bytes consumed, out of
+    /// the size of the compressed file.
+    Decompress,
+    /// Writing the image to the device.
+    Write,
+    /// Flushing the data written to the device out of its cache, or syncing
+    /// the image file once a device has been read.
+    Sync,
+    /// Reading the device back to verify it.
+    Verify,
+    /// Zeroing the rest of the device after the image.
+    Wipe,
+    /// Reading a device to an image file.
+    Read,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Checksum => "checksum",
+            Stage::Erase => "erase",
+            Stage::Discard => "discard",
+            Stage::Decompress => "decompress",
+            Stage::Write => "write",
+            Stage::Sync => "sync",
+            Stage::Verify => "verify",
+            Stage::Wipe => "wipe",
+            Stage::Read => "read",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A snapshot of the progress of one stage.
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    /// The stage this report refers to.
+    pub stage: Stage,
+    /// The number of bytes processed so far.
+    pub bytes_done: u64,
+    /// The total number of bytes, if known.
+    pub total: Option<u64>,
+    /// The throughput averaged over the sliding window, in bytes per second.
+    pub bytes_per_sec: f64,
+    /// The estimated time left, if the total is known and data is moving.
+    pub eta: Option<Duration>,
+    /// How far along the whole operation is, from 0.0 to 1.0, weighted as
+    /// described in the [module docs](self). `None` while the total of a
+    /// weighted stage is unknown, as when writing a compressed image whose
+    /// decompressed size is not recorded.
+    pub overall: Option<f64>,
+}
+
+/// How far decompression has got, on both sides of the decompressor.
+///
+/// The compressed side is always known up front, as the size of the image
+/// file, so it gives a percentage even when the decompressed size is not
+/// recorded anywhere.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecompressProgress {
+    /// The number of compressed bytes read from the image file.
+    pub compressed_read: u64,
+    /// The number of decompressed bytes produced so far.
+    pub decompressed_written: u64,
+}
+
+/// How much data one stage of a finished operation went through, and how long
+/// it took. The reports of reads and writes hand these out for their stages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StageTiming {
+    /// The number of bytes the stage went through.
+    pub bytes: u64,
+    /// The time the stage took.
+    pub duration: Duration,
+}
+
+impl StageTiming {
+    /// The throughput averaged over the whole stage, in bytes per second, or
+    /// zero if it took no measurable time.
+    pub fn bytes_per_sec(&self) -> f64 {
+        let secs = self.duration.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Turns a stream of byte counts into [`Progress`] reports.
+pub(crate) struct ProgressTracker {
+    stage: Stage,
+    total: Option<u64>,
+    window: Duration,
+    /// Recent `(time, bytes_done)` samples, oldest first. The oldest one is
+    /// kept just outside the window so the speed covers the full window.
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(stage: Stage, total: Option<u64>, window: Duration) -> Self {
+        Self {
+            stage,
+            total,
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn update(&mut self, bytes_done: u64) -> Progress {
+        let now = Instant::now();
+        self.samples.push_back((now, bytes_done));
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= self.window {
+            self.samples.pop_front();
+        }
+
+        let (since, base) = self.samples[0];
+        let elapsed = now.duration_since(since).as_secs_f64();
+        let bytes_per_sec = if elapsed > 0.0 {
+            bytes_done.saturating_sub(base) as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = match self.total {
+            Some(total) if bytes_per_sec > 0.0 => Some(Duration::from_secs_f64(
+                total.saturating_sub(bytes_done) as f64 / bytes_per_sec,
+            )),
+            _ => None,
+        };
+
+        Progress {
+            stage: self.stage,
+            bytes_done,
+            total: self.total,
+            bytes_per_sec,
+            eta,
+            overall: None,
+        }
+    }
+}
+
+/// Combines the stages of an operation into one overall fraction.
+#[derive(Default)]
+struct Overall {
+    /// The weighted stages with their totals and bytes done so far.
+    stages: Vec<(Stage, Option<u64>, u64)>,
+}
+
+impl Overall {
+    fn plan(&mut self, stage: Stage, total: Option<u64>) {
+        match self.stages.iter_mut().find(|(s, _, _)| *s == stage) {
+            Some(entry) => entry.1 = total,
+            None => self.stages.push((stage, total, 0)),
+        }
+    }
+
+    fn update(&mut self, stage: Stage, bytes_done: u64) -> Option<f64> {
+        if let Some(entry) = self.stages.iter_mut().find(|(s, _, _)| *s == stage) {
+            entry.2 = bytes_done;
+        }
+        let (mut total, mut done) = (0u64, 0u64);
+        for &(_, stage_total, stage_done) in &self.stages {
+            let stage_total = stage_total?;
+            total += stage_total;
+            done += stage_done.min(stage_total);
+        }
+        (total > 0).then(|| done as f64 / total as f64)
+    }
+}
+
+/// The structured progress callback of an operation, along with what it
+/// needs to work out the overall fraction.
+pub(crate) struct Reporter<'s, F: ?Sized> {
+    callback: RefCell<&'s mut F>,
+    overall: RefCell<Overall>,
+}
+
+impl<'s, F: FnMut(&Progress) + ?Sized> Reporter<'s, F> {
+    pub(crate) fn new(callback: &'s mut F) -> Self {
+        Self {
+            callback: RefCell::new(callback),
+            overall: RefCell::default(),
+        }
+    }
+
+    /// Counts `stage` towards the overall fraction with `total` bytes, or
+    /// updates its total. Only stages that are planned carry weight.
+    pub(crate) fn plan(&self, stage: Stage, total: Option<u64>) {
+        self.overall.borrow_mut().plan(stage, total);
+    }
+}
+
+/// Wraps a plain byte-count callback so that every call also sends a
+/// [`Progress`] report for `stage` to the shared structured callback.
+pub(crate) fn tracked<'c, 's: 'c, F: FnMut(&Progress) + ?Sized + 'c>(
+    stage: Stage,
+    total: Option<u64>,
+    window: Duration,
+    plain: &'c mut dyn FnMut(u64),
+    structured: &'c Reporter<'s, F>,
+) -> impl FnMut(u64) + 'c {
+    let mut tracker = ProgressTracker::new(stage, total, window);
+    move |bytes_done| {
+        plain(bytes_done);
+        let mut progress = tracker.update(bytes_done);
+        progress.overall = structured.overall.borrow_mut().update(stage, bytes_done);
+        (structured.callback.borrow_mut())(&progress);
+    }
+}
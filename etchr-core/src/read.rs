@@ -1,98 +1,1816 @@
 //! Contains the logic for reading data from a device to an image file.
-use crate::os_options::OpenOptionsExt;
-use anyhow::{anyhow, Result};
-use nix::ioctl_read;
+use crate::bmap::MapBuilder;
+use crate::buffer::AlignedBuffer;
+use crate::device::{DirectIo, SectorSizes};
+use crate::error::{self, Error, PartialTransfer};
+use crate::metadata::{self, ImageMetadata};
+use crate::os_options::DeviceOpenOptions;
+use crate::partition_table;
+use crate::platform;
+use crate::progress::{Stage, StageTiming};
+use crate::source::ImageFile;
+use crate::throttle::RateLimiter;
+use crate::usedblocks;
+use crate::warning::{Warning, WarningKind};
+use crate::write::{Lz4Frames, hash_file, io_error, read_full};
+use anyhow::{Result, anyhow};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use lz4_flex::frame::FrameEncoder;
+use sha2::{Digest, Sha256};
 use std::fs::File;
-use std::io::{Read, Write};
-#[cfg(unix)]
-use std::os::unix::io::AsRawFd;
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use xz2::read::XzDecoder;
+use xz2::stream::{Check, MtStreamBuilder};
+use xz2::write::XzEncoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+use zstd::stream::write::Encoder as ZstdEncoder;
 
 // Use a 1 MiB buffer for I/O operations.
 const BUFFER_SIZE: usize = 1024 * 1024;
 
-ioctl_read!(blkgetsize64, 0x12, 114, u64);
+/// The size of the runs of zeros that a sparse image file leaves out, which
+/// matches the block size of most filesystems.
+const SPARSE_BLOCK: usize = 4096;
 
-/// Reads the entire contents of a block device to an image file.
-///
-/// This function performs a raw, block-by-block read from the specified device
-/// and writes the data to a new file. It determines the device size using a
-/// platform-specific `ioctl` call and reports progress via callbacks.
+/// A summary of a completed read, returned by [`ReadOptions::run`].
+#[derive(Clone, Debug)]
+pub struct ReadReport {
+    /// The number of bytes read from the device.
+    pub bytes_read: u64,
+    /// The offset on the device the read started at (see
+    /// [`ReadOptions::offset`]).
+    pub offset: u64,
+    /// The SHA-256 of the data read from the device. If the image file is
+    /// compressed, this is the hash of the data before compression.
+    pub sha256: [u8; 32],
+    /// Time spent reading the device and syncing the image file.
+    pub duration: Duration,
+    /// Whether the device was read again and matched the image file (see
+    /// [`ReadOptions::verify`]).
+    pub verified: bool,
+    /// Time spent verifying the image file, or zero if it was not verified.
+    pub verify_duration: Duration,
+    /// The sector sizes of the device. Reads were aligned to the logical size.
+    pub sector_sizes: SectorSizes,
+    /// Whether the device was read with `O_DIRECT`. A device that rejects it,
+    /// or a range that is not aligned to its sectors, is read through the page
+    /// cache instead.
+    pub direct_io: bool,
+    /// The size of the image file, if it was compressed (see
+    /// [`ReadOptions::compression`]).
+    pub compressed_bytes: Option<u64>,
+    /// The byte ranges of the device that could not be read and were filled
+    /// with zeros in the image, if [`ReadOptions::tolerate_errors`] was set.
+    pub unreadable: Vec<Range<u64>>,
+    /// The number of bytes at the end of the range that the device included
+    /// in its reported size but did not return. They are left out of the
+    /// image or filled with zeros in it, as [`ReadOptions::short_device`]
+    /// asks.
+    pub bytes_missing: u64,
+    /// The path of the checksum file, if one was written (see
+    /// [`ReadOptions::checksum_file`]).
+    pub checksum_file: Option<PathBuf>,
+    /// The path of the block map, if one was written (see
+    /// [`ReadOptions::bmap`]).
+    pub bmap_file: Option<PathBuf>,
+    /// The number of bytes in the range that were not read because no
+    /// filesystem uses them, and are zeros in the image, if
+    /// [`ReadOptions::used_blocks_only`] was set.
+    pub bytes_skipped: u64,
+}
+
+impl ReadReport {
+    /// The SHA-256 as a lowercase hex string.
+    pub fn sha256_hex(&self) -> String {
+        hex::encode(self.sha256)
+    }
+
+    /// The bytes read from the device and the time spent reading and syncing.
+    pub fn read_timing(&self) -> StageTiming {
+        StageTiming {
+            bytes: self.bytes_read,
+            duration: self.duration,
+        }
+    }
+
+    /// The bytes compared with the image file and the time it took, if it was
+    /// verified.
+    pub fn verify_timing(&self) -> Option<StageTiming> {
+        self.verified.then_some(StageTiming {
+            bytes: self.bytes_read,
+            duration: self.verify_duration,
+        })
+    }
+
+    /// The average read speed in bytes per second, over the read and sync.
+    pub fn average_bps(&self) -> f64 {
+        self.read_timing().bytes_per_sec()
+    }
+
+    /// The number of bytes that could not be read and were filled with zeros
+    /// (see [`ReadReport::unreadable`]).
+    pub fn bytes_unreadable(&self) -> u64 {
+        self.unreadable.iter().map(|r| r.end - r.start).sum()
+    }
+
+    /// Writes which parts of the device were read and which could not be, as
+    /// a GNU ddrescue mapfile. `ddrescue` can then be pointed at the device,
+    /// the image and the mapfile to retry only the unreadable sectors.
+    /// Positions are offsets on the device; anything outside the range that
+    /// was read is marked as not tried.
+    pub fn write_mapfile(&self, out: &mut dyn Write) -> io::Result<()> {
+        let end = self.offset + self.bytes_read;
+        writeln!(out, "# Mapfile. Created by etchr")?;
+        writeln!(out, "# current_pos  current_status  current_pass")?;
+        writeln!(out, "0x{:08X}     +               1", end)?;
+        writeln!(out, "#      pos        size  status")?;
+        if self.offset > 0 {
+            writeln!(out, "0x{:08X}  0x{:08X}  ?", 0, self.offset)?;
+        }
+        let mut pos = self.offset;
+        for range in &self.unreadable {
+            if range.start > pos {
+                writeln!(out, "0x{:08X}  0x{:08X}  +", pos, range.start - pos)?;
+            }
+            writeln!(
+                out,
+                "0x{:08X}  0x{:08X}  -",
+                range.start,
+                range.end - range.start
+            )?;
+            pos = range.end;
+        }
+        if end > pos {
+            writeln!(out, "0x{:08X}  0x{:08X}  +", pos, end - pos)?;
+        }
+        Ok(())
+    }
+}
+
+/// A compression format for the image file, along with its level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip, at a level from 0 (no compression) to 9 (smallest).
+    Gzip(u32),
+    /// xz, at a preset from 0 (fastest) to 9 (smallest).
+    Xz(u32),
+    /// Zstandard, at a level from 1 (fastest) to 22 (smallest).
+    Zstd(i32),
+    /// LZ4 frames, which have no level: much faster to compress than the
+    /// others, but larger.
+    Lz4,
+}
+
+/// zstd at level 3, which is fast enough to keep up with most devices.
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Zstd(3)
+    }
+}
+
+impl Compression {
+    /// The file extension images in this format usually have, such as `zst`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip(_) => "gz",
+            Compression::Xz(_) => "xz",
+            Compression::Zstd(_) => "zst",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    fn check_level(&self) -> Result<()> {
+        let (name, level, range) = match *self {
+            Compression::Gzip(level) => ("gzip", level as i64, 0..=9),
+            Compression::Xz(level) => ("xz", level as i64, 0..=9),
+            Compression::Zstd(level) => ("zstd", level as i64, 1..=22),
+            Compression::Lz4 => return Ok(()),
+        };
+        if !range.contains(&level) {
+            return Err(anyhow!(
+                "The {} level must be from {} to {}, not {}",
+                name,
+                range.start(),
+                range.end(),
+                level
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// What to do when a device ends before the size it reported, as some USB
+/// card readers do by a few sectors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShortDevice {
+    /// End the image where the device stopped returning data.
+    #[default]
+    Truncate,
+    /// Fill the rest of the image with zeros, so it has the reported size.
+    Pad,
+}
+
+/// The device to read from.
+enum DeviceInput {
+    /// The path of the device, opened by the read.
+    Path(PathBuf),
+    /// A handle to the device that the caller already opened.
+    File(File),
+}
+
+/// Where the image goes.
+enum ImageOutput<'a> {
+    /// An image file, created by the read.
+    Path(PathBuf),
+    /// An arbitrary sink for the image data, taken by the first `run`.
+    Writer(Option<Box<dyn Write + 'a>>),
+}
+
+/// Configures and runs the reading of a block device to an image file.
 ///
-/// # Arguments
+/// This performs a raw, block-by-block read from the device and writes the
+/// data to a new file, optionally compressing it on the way. Every setting
+/// has a sensible default, so only the options that differ need to be set
+/// before calling [`ReadOptions::run`]:
 ///
-/// * `device_path` - The path to the block device to read from.
-/// * `image_path` - The path where the output image file will be created.
-/// * `running` - An `Arc<AtomicBool>` used to gracefully cancel the operation.
-///   If the flag is set to `false`, the operation will be aborted.
-/// * `on_read_start` - A closure that is called once at the beginning of the
-///   operation, providing the total number of bytes that will be read.
-/// * `on_progress` - A closure that is called repeatedly as data is read. It
-///   receives the total number of bytes read so far.
+/// ```rust,no_run
+/// use etchr_core::read::{Compression, ReadOptions};
 ///
-/// # Errors
+/// # fn main() -> anyhow::Result<()> {
+/// ReadOptions::new("/dev/sdb", "backup.img.xz")
+///     .compression(Compression::Xz(6))
+///     .on_progress(|bytes| println!("{} bytes read", bytes))
+///     .run()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ReadOptions<'a> {
+    device: DeviceInput,
+    output: ImageOutput<'a>,
+    buffer_size: usize,
+    direct_io: DirectIo,
+    max_bytes_per_sec: Option<u64>,
+    compression: Option<Compression>,
+    compression_threads: Option<u32>,
+    stop_at_last_partition: bool,
+    used_blocks_only: bool,
+    tolerate_errors: bool,
+    offset: u64,
+    length: Option<u64>,
+    keep_partial: bool,
+    verify: bool,
+    sparse: bool,
+    preallocate: Option<bool>,
+    check_space: bool,
+    short_device: ShortDevice,
+    reported_len: Option<u64>,
+    checksum_file: bool,
+    bmap: bool,
+    bmap_block_size: usize,
+    xattrs: bool,
+    running: Arc<AtomicBool>,
+    on_read_start: Box<dyn FnMut(u64) + 'a>,
+    on_progress: Box<dyn FnMut(u64) + 'a>,
+    on_sync_start: Box<dyn FnMut() + 'a>,
+    on_sync_done: Box<dyn FnMut() + 'a>,
+    on_verify_start: Box<dyn FnMut(u64) + 'a>,
+    on_verify_progress: Box<dyn FnMut(u64) + 'a>,
+    on_warning: Box<dyn FnMut(Warning) + 'a>,
+}
+
+impl<'a> ReadOptions<'a> {
+    /// Creates the options for reading the block device at `device_path` to
+    /// a new file at `image_path`.
+    pub fn new(device_path: impl Into<PathBuf>, image_path: impl Into<PathBuf>) -> Self {
+        let output = ImageOutput::Path(image_path.into());
+        Self::with_device(DeviceInput::Path(device_path.into()), output)
+    }
+
+    /// Creates the options for reading through `device_file`, a handle to
+    /// the device that the caller already opened, to a new file at
+    /// `image_path`. This lets a small privileged helper open the device and
+    /// hand it to an unprivileged process that does the read.
+    ///
+    /// The caller is responsible for the open flags. `O_DIRECT` is still
+    /// switched on or off with `fcntl` as [`ReadOptions::direct_io`] asks, so
+    /// a handle opened without it is fine. If the device rejects it, it is
+    /// read through the page cache with a [`WarningKind::DirectIoFallback`]
+    /// warning, unless direct I/O is [`DirectIo::Required`].
+    pub fn from_device_file(device_file: File, image_path: impl Into<PathBuf>) -> Self {
+        let output = ImageOutput::Path(image_path.into());
+        Self::with_device(DeviceInput::File(device_file), output)
+    }
+
+    /// Creates the options for reading the block device at `device_path`
+    /// into `writer` (for example stdout) rather than a file.
+    ///
+    /// The data is streamed to the writer as it is read, and the writer is
+    /// only flushed at the end: nothing is created, synced or deleted, so
+    /// what happens to the data, including on cancellation, is up to the
+    /// caller. [`ReadOptions::verify`] needs an image file to compare with,
+    /// and [`ReadOptions::sparse`] has no effect. The writer is consumed by
+    /// the first call to [`ReadOptions::run`].
+    pub fn to_writer(device_path: impl Into<PathBuf>, writer: impl Write + 'a) -> Self {
+        let output = ImageOutput::Writer(Some(Box::new(writer)));
+        Self::with_device(DeviceInput::Path(device_path.into()), output)
+    }
+
+    fn with_device(device: DeviceInput, output: ImageOutput<'a>) -> Self {
+        Self {
+            device,
+            output,
+            buffer_size: BUFFER_SIZE,
+            direct_io: DirectIo::default(),
+            max_bytes_per_sec: None,
+            compression: None,
+            compression_threads: None,
+            stop_at_last_partition: false,
+            used_blocks_only: false,
+            tolerate_errors: false,
+            offset: 0,
+            length: None,
+            keep_partial: false,
+            verify: false,
+            sparse: false,
+            preallocate: None,
+            check_space: true,
+            short_device: ShortDevice::default(),
+            reported_len: None,
+            checksum_file: false,
+            bmap: false,
+            bmap_block_size: SPARSE_BLOCK,
+            xattrs: true,
+            running: Arc::new(AtomicBool::new(true)),
+            on_read_start: Box::new(|_| {}),
+            on_progress: Box::new(|_| {}),
+            on_sync_start: Box::new(|| {}),
+            on_sync_done: Box::new(|| {}),
+            on_verify_start: Box::new(|_| {}),
+            on_verify_progress: Box::new(|_| {}),
+            on_warning: Box::new(|_| {}),
+        }
+    }
+
+    /// The size of each chunk read from the device. Must be a non-zero
+    /// multiple of the device's logical sector size. Defaults to 1 MiB.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Whether to read the device with `O_DIRECT`, so that the data comes
+    /// from the device rather than the page cache. With the default,
+    /// [`DirectIo::Preferred`], a device that rejects `O_DIRECT` is read
+    /// through the page cache instead and a
+    /// [`WarningKind::DirectIoFallback`] warning is sent to `on_warning`.
+    pub fn direct_io(mut self, direct_io: DirectIo) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// Limits the average read rate, so that a backup does not saturate a
+    /// USB bus shared with other devices. Defaults to no limit.
+    pub fn max_bytes_per_sec(mut self, max_bytes_per_sec: u64) -> Self {
+        self.max_bytes_per_sec = Some(max_bytes_per_sec);
+        self
+    }
+
+    /// Compresses the image file as it is written. Progress is still reported
+    /// in bytes read from the device. Defaults to no compression.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// The number of threads to compress the image with. xz and zstd split the
+    /// data between them, so that compressing keeps up with the device, while
+    /// gzip and lz4 always use one. Each xz thread holds a few blocks of data,
+    /// about 100 MiB at level 6. Defaults to one per CPU.
+    pub fn compression_threads(mut self, threads: u32) -> Self {
+        self.compression_threads = Some(threads);
+        self
+    }
+
+    /// Whether to stop reading at the end of the partition that ends last
+    /// (or of the backup GPT), as described by the device's partition table,
+    /// rather than read the unused space after it. A device without a usable
+    /// partition table is read in full, with a
+    /// [`WarningKind::NoPartitionTable`] warning. Defaults to `false`.
+    pub fn stop_at_last_partition(mut self, stop_at_last_partition: bool) -> Self {
+        self.stop_at_last_partition = stop_at_last_partition;
+        self
+    }
+
+    /// Whether to only read the blocks that the filesystems on the device use,
+    /// and write zeros for the rest. The allocation metadata of ext2/3/4 and
+    /// FAT filesystems is understood; the partition table, the space between
+    /// partitions and partitions holding any other filesystem are read in
+    /// full, with a [`WarningKind::UnknownFilesystem`] warning. Combine with
+    /// [`ReadOptions::sparse`] and [`ReadOptions::bmap`] for a sparse image
+    /// with a block map of its data. Defaults to `false`.
+    pub fn used_blocks_only(mut self, used_blocks_only: bool) -> Self {
+        self.used_blocks_only = used_blocks_only;
+        self
+    }
+
+    /// Whether to carry on past sectors that cannot be read, as when rescuing
+    /// data from a failing card. A chunk that fails to read is read again in
+    /// smaller and smaller pieces, down to single sectors; the sectors that
+    /// still fail are filled with zeros in the image and listed in
+    /// [`ReadReport::unreadable`], and a [`WarningKind::UnreadableSectors`]
+    /// warning is sent once the read is done. The read only fails if nothing
+    /// at all could be read, or if the device is removed. Defaults to `false`.
+    pub fn tolerate_errors(mut self, tolerate_errors: bool) -> Self {
+        self.tolerate_errors = tolerate_errors;
+        self
+    }
+
+    /// The offset on the device to start reading at, in bytes. Defaults to
+    /// `0`.
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// The number of bytes to read from the offset. Cannot be combined with
+    /// [`ReadOptions::stop_at_last_partition`]. Defaults to reading up to the
+    /// end of the device.
+    ///
+    /// A range that does not start and end on a sector boundary is read
+    /// through the page cache, as `O_DIRECT` only reads whole sectors.
+    pub fn length(mut self, length: u64) -> Self {
+        self.length = Some(length);
+        self
+    }
+
+    /// Whether to keep the image file when the read is cancelled, rather than
+    /// delete it. The data read so far is flushed to it (and a compressed
+    /// image is finished, so it can be decompressed), and the
+    /// [`Error::Cancelled`] the read fails with records how much was kept.
+    /// Defaults to `false`.
+    pub fn keep_partial(mut self, keep_partial: bool) -> Self {
+        self.keep_partial = keep_partial;
+        self
+    }
+
+    /// Whether to read the device again once the image file has been synced,
+    /// and compare it with the image file (decompressing it if needed). The
+    /// device is read with `O_DIRECT` where possible, so that the data comes
+    /// from the device rather than the page cache. With
+    /// [`ReadOptions::used_blocks_only`], only the used blocks are compared,
+    /// as the image holds zeros for the rest. Cannot be combined with
+    /// [`ReadOptions::tolerate_errors`]. Defaults to `false`.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Whether to leave holes in the image file where the device holds only
+    /// zeros, rather than write them out. The file has the same size, but on
+    /// a filesystem that supports sparse files, it takes up only as much
+    /// space as the data in it. Has no effect on a compressed image, or when
+    /// reading to a writer. Defaults to `false`.
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Whether to allocate the disk space for the whole image file before
+    /// reading, so that the filesystem does not fragment it as it grows. A
+    /// filesystem that cannot allocate space ahead of time is written to as
+    /// usual. Has no effect on a compressed image, or when reading to a
+    /// writer. Cannot be combined with [`ReadOptions::sparse`]. Defaults to
+    /// `true` unless the image is sparse.
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.preallocate = Some(preallocate);
+        self
+    }
+
+    /// Whether to check that the filesystem that is to hold the image file has
+    /// room for it before reading, failing early with
+    /// [`Error::InsufficientSpace`] instead of when the disk fills up. A
+    /// compressed image is assumed not to shrink, and a sparse one to take up
+    /// as much space as the data on the device, which makes for a
+    /// conservative estimate. Has no effect when reading to a writer.
+    /// Defaults to `true`.
+    pub fn check_space(mut self, check_space: bool) -> Self {
+        self.check_space = check_space;
+        self
+    }
+
+    /// What to do if the device ends before the size it reported, rather than
+    /// fail the read. Either way, a [`WarningKind::ShortDevice`] warning is
+    /// sent and the missing bytes are counted in
+    /// [`ReadReport::bytes_missing`]. Defaults to [`ShortDevice::Truncate`].
+    pub fn short_device(mut self, short_device: ShortDevice) -> Self {
+        self.short_device = short_device;
+        self
+    }
+
+    /// The size of the device in bytes, in place of the size it reports. This
+    /// lets a regular file stand in for a device, which has no size to report.
+    /// If the device ends before it, the read carries on as
+    /// [`ReadOptions::short_device`] asks. Defaults to the reported size.
+    pub fn reported_len(mut self, reported_len: u64) -> Self {
+        self.reported_len = Some(reported_len);
+        self
+    }
+
+    /// Whether to save the SHA-256 of the image next to it once the read is
+    /// done, in `<image>.sha256`, in the format `sha256sum -c` reads. For a
+    /// compressed image, the file also holds the SHA-256 of the data read
+    /// from the device, under the name of the image without its compression
+    /// extension. Failing to save it only sends a
+    /// [`WarningKind::ChecksumFileFailed`] warning. Cannot be used when
+    /// reading to a writer. Defaults to `false`.
+    pub fn checksum_file(mut self, checksum_file: bool) -> Self {
+        self.checksum_file = checksum_file;
+        self
+    }
+
+    /// Whether to save a block map (`.bmap` file) of the image next to it once
+    /// the read is done, listing the blocks that are not all zeros. Writing
+    /// the image with its block map, with `bmaptool` or
+    /// [`WriteOptions::bmap`](crate::write::WriteOptions::bmap), then skips
+    /// the rest. It is named after the image without its compression
+    /// extension, so `backup.img.xz` gets `backup.img.bmap`. Failing to save
+    /// it only sends a [`WarningKind::BmapFileFailed`] warning. Cannot be used
+    /// when reading to a writer. Defaults to `false`.
+    pub fn bmap(mut self, bmap: bool) -> Self {
+        self.bmap = bmap;
+        self
+    }
+
+    /// The size of the blocks a block map is counted in. Must not be zero.
+    /// Defaults to 4 KiB.
+    pub fn bmap_block_size(mut self, block_size: usize) -> Self {
+        self.bmap_block_size = block_size;
+        self
+    }
+
+    /// Whether to store the SHA-256 of the image, the device it was read from
+    /// and the time of the read in extended attributes of the image file once
+    /// the read is done (see [`crate::metadata`]). Filesystems without
+    /// extended attributes are skipped silently, and failing to store them
+    /// otherwise only sends a [`WarningKind::XattrsFailed`] warning. Has no
+    /// effect when reading to a writer. Defaults to `true`.
+    pub fn xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// A flag that cancels the operation when set to `false`.
+    pub fn running(mut self, running: Arc<AtomicBool>) -> Self {
+        self.running = running;
+        self
+    }
+
+    /// Called when reading begins with the number of bytes to read.
+    pub fn on_read_start(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_read_start = Box::new(f);
+        self
+    }
+
+    /// Called with the number of bytes read. The final total is only
+    /// reported once the image file has been synced to disk.
+    pub fn on_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_progress = Box::new(f);
+        self
+    }
+
+    /// Called once the whole range has been read, when the image file starts
+    /// being synced (or the writer flushed), which can take a while for large
+    /// images on slow disks.
+    pub fn on_sync_start(mut self, f: impl FnMut() + 'a) -> Self {
+        self.on_sync_start = Box::new(f);
+        self
+    }
+
+    /// Called once the image file has been synced, or the writer flushed.
+    pub fn on_sync_done(mut self, f: impl FnMut() + 'a) -> Self {
+        self.on_sync_done = Box::new(f);
+        self
+    }
+
+    /// Called when verification begins with the number of bytes to verify.
+    pub fn on_verify_start(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_verify_start = Box::new(f);
+        self
+    }
+
+    /// Called with the number of bytes verified.
+    pub fn on_verify_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_verify_progress = Box::new(f);
+        self
+    }
+
+    /// Called with each [`Warning`] about a problem that does not stop the
+    /// read, such as a device that rejected `O_DIRECT` and is read through
+    /// the page cache.
+    pub fn on_warning(mut self, f: impl FnMut(Warning) + 'a) -> Self {
+        self.on_warning = Box::new(f);
+        self
+    }
+
+    /// Reads the device to the image file.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The compression level is out of range.
+    /// - The device cannot be opened or its size cannot be determined.
+    /// - The buffer size is not a non-zero multiple of the device's logical
+    ///   sector size.
+    /// - The range to read lies outside the device.
+    /// - The filesystem that is to hold the image file does not have room for
+    ///   it ([`Error::InsufficientSpace`]).
+    /// - The output file cannot be created.
+    /// - An I/O error occurs during reading or writing. Errors reading the device
+    ///   are reported as an [`Error::Io`] in the [`Stage::Read`] stage, or as
+    ///   [`Error::DeviceRemoved`] if the device was unplugged, and errors
+    ///   syncing the image file as an [`Error::Io`] in the [`Stage::Sync`]
+    ///   stage.
+    /// - The device does not match the image file when it is verified
+    ///   ([`Error::VerifyMismatch`]).
+    /// - The operation is cancelled by the user ([`Error::Cancelled`]). The
+    ///   image file is deleted unless [`ReadOptions::keep_partial`] is set.
+    ///
+    /// Errors that occur once reading has started carry a [`PartialTransfer`]
+    /// context with the number of bytes read and synced so far.
+    pub fn run(&mut self) -> Result<ReadReport> {
+        if let Some(compression) = &self.compression {
+            compression.check_level()?;
+        }
+        if self.compression_threads == Some(0) {
+            return Err(anyhow!("At least one compression thread is needed"));
+        }
+        let opened = match &self.device {
+            DeviceInput::Path(path) => DeviceOpenOptions::read_only()
+                .direct_io(self.direct_io)
+                .open(path)?,
+            DeviceInput::File(file) => {
+                // The caller opened the device, so only O_DIRECT is up to us.
+                let file = file.try_clone()?;
+                let mut fallback = None;
+                match platform::set_direct_io(&file, self.direct_io != DirectIo::Off) {
+                    Ok(()) => {}
+                    Err(e) if self.direct_io == DirectIo::Preferred => fallback = Some(e),
+                    Err(e) => return Err(e.into()),
+                }
+                (file, fallback)
+            }
+        };
+        let mut transfer = None;
+        self.read(opened, &mut transfer).map_err(|e| {
+            let bytes_done = transfer.map_or(0, |t| t.bytes_done);
+            let e = error::detect_removal(e, bytes_done);
+            match transfer {
+                Some(transfer) => e.context(transfer),
+                None => e,
+            }
+        })
+    }
+
+    /// Does the work of [`ReadOptions::run`] on the opened device, along with
+    /// the error enabling `O_DIRECT` failed with, if it did, keeping
+    /// `transfer` up to date once the device is being read.
+    fn read(
+        &mut self,
+        (mut device_file, fallback): (File, Option<io::Error>),
+        transfer: &mut Option<PartialTransfer>,
+    ) -> Result<ReadReport> {
+        let mut direct_io = self.direct_io != DirectIo::Off && fallback.is_none();
+        if let Some(e) = &fallback {
+            (self.on_warning)(Warning::new(
+                WarningKind::DirectIoFallback,
+                format!(
+                    "The device does not support direct I/O ({}), reading through the page cache instead.",
+                    e
+                ),
+            ));
+        }
+
+        // A writer is used up by the first run, which is checked before the
+        // device is read.
+        match self.output {
+            ImageOutput::Writer(None) => {
+                return Err(anyhow!("The image writer has already been used"));
+            }
+            ImageOutput::Writer(Some(_)) if self.verify => {
+                return Err(anyhow!("Only an image file can be verified, not a writer"));
+            }
+            ImageOutput::Writer(Some(_)) if self.checksum_file => {
+                return Err(anyhow!(
+                    "Only an image file can have a checksum file, not a writer"
+                ));
+            }
+            ImageOutput::Writer(Some(_)) if self.bmap => {
+                return Err(anyhow!(
+                    "Only an image file can have a block map, not a writer"
+                ));
+            }
+            _ => {}
+        }
+        if self.bmap && self.bmap_block_size == 0 {
+            return Err(anyhow!("The block size of a block map cannot be zero"));
+        }
+        if self.sparse && self.preallocate == Some(true) {
+            return Err(anyhow!("A sparse image cannot be preallocated"));
+        }
+        if self.verify && self.tolerate_errors {
+            return Err(anyhow!("A read that tolerates errors cannot be verified"));
+        }
+        if self.stop_at_last_partition && self.length.is_some() {
+            return Err(anyhow!(
+                "A length cannot be combined with stopping at the last partition"
+            ));
+        }
+
+        // Get the device size in bytes using a platform-specific ioctl.
+        let device_len = match self.reported_len {
+            Some(len) => len,
+            None => platform::get_device_size(&device_file)?,
+        };
+
+        if device_len == 0 {
+            return Err(anyhow!("Device size is reported as zero"));
+        }
+        let offset = self.offset;
+        if offset > device_len {
+            return Err(anyhow!("The offset is beyond the end of the device"));
+        }
+        let mut end = match self.length {
+            Some(length) => offset
+                .checked_add(length)
+                .filter(|&end| end <= device_len)
+                .ok_or_else(|| anyhow!("The range to read extends past the end of the device"))?,
+            None => device_len,
+        };
+
+        // O_DIRECT requires buffers to be aligned to the logical sector size.
+        let sector_sizes = platform::get_sector_sizes(&device_file).unwrap_or_default();
+        let block_size = sector_sizes.logical as usize;
+        if self.buffer_size == 0 || !self.buffer_size.is_multiple_of(block_size) {
+            return Err(anyhow!(
+                "Buffer size must be a non-zero multiple of the {} byte sector size",
+                block_size
+            ));
+        }
+
+        if self.stop_at_last_partition {
+            // The partition table is parsed with unaligned buffers.
+            platform::set_direct_io(&device_file, false)?;
+            let used = partition_table::used_len(&mut device_file, block_size as u64, device_len);
+            if direct_io {
+                platform::set_direct_io(&device_file, true)?;
+            }
+            match used {
+                Ok(Some(len)) => end = len,
+                Ok(None) => (self.on_warning)(Warning::new(
+                    WarningKind::NoPartitionTable,
+                    "The device has no partition table, so all of it is read.",
+                )),
+                Err(e) => (self.on_warning)(Warning::new(
+                    WarningKind::NoPartitionTable,
+                    format!(
+                        "The partition table of the device cannot be used ({}), so all of it is read.",
+                        e
+                    ),
+                )),
+            }
+        }
+        if end <= offset {
+            return Err(anyhow!("There is nothing to read in the range"));
+        }
+        // The filesystems are also parsed with unaligned buffers.
+        let used_ranges = if self.used_blocks_only {
+            platform::set_direct_io(&device_file, false)?;
+            let used = usedblocks::used_ranges(
+                &mut device_file,
+                block_size as u64,
+                device_len,
+                &mut *self.on_warning,
+            );
+            if direct_io {
+                platform::set_direct_io(&device_file, true)?;
+            }
+            Some(used)
+        } else {
+            None
+        };
+
+        // O_DIRECT can only read whole sectors.
+        let block = block_size as u64;
+        if direct_io && !(offset.is_multiple_of(block) && end.is_multiple_of(block)) {
+            platform::set_direct_io(&device_file, false)?;
+            direct_io = false;
+        }
+        device_file.seek(SeekFrom::Start(offset))?;
+
+        let size_bytes = end - offset;
+        let bytes_skipped = used_ranges.as_ref().map_or(0, |used| {
+            let read: u64 = used
+                .iter()
+                .map(|r| r.end.min(end).saturating_sub(r.start.max(offset)))
+                .sum();
+            size_bytes - read
+        });
+        if let ImageOutput::Path(path) = &self.output
+            && self.check_space
+        {
+            // Only the free blocks that are skipped are sure to take up no
+            // space, as holes or as next to nothing once compressed.
+            let needed = if self.sparse || self.compression.is_some() {
+                size_bytes - bytes_skipped
+            } else {
+                size_bytes
+            };
+            check_space(path, needed)?;
+        }
+        (self.on_read_start)(size_bytes);
+        let started = Instant::now();
+
+        let sink = match &mut self.output {
+            ImageOutput::Path(path) => {
+                let file = File::create(path)?;
+                // Those of an earlier image at the same path no longer apply.
+                metadata::clear_file(&file)?;
+                if self.compression.is_none() && self.preallocate.unwrap_or(!self.sparse) {
+                    match platform::preallocate(&file, size_bytes) {
+                        Ok(()) => {}
+                        Err(e) if is_unsupported(&e) => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Sink::File(file)
+            }
+            ImageOutput::Writer(writer) => Sink::Writer(
+                writer
+                    .take()
+                    .expect("the writer is checked before the device is read"),
+                0,
+            ),
+        };
+        let threads = self
+            .compression_threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get() as u32));
+        let mut image_file = ImageWriter::new(sink, self.compression, threads, self.sparse)?;
+        let mut buffer = AlignedBuffer::new(self.buffer_size, block_size);
+
+        let mut limiter = self.max_bytes_per_sec.map(RateLimiter::new);
+        let mut read_total: u64 = 0;
+        let mut hasher = Sha256::new();
+        let mut map_builder = self.bmap.then(|| MapBuilder::new(self.bmap_block_size));
+        let mut unreadable = Vec::new();
+        let mut first_error = None;
+        let mut bytes_missing = 0;
+        let transfer = transfer.insert(PartialTransfer::default());
+        while read_total < size_bytes {
+            if !self.running.load(Ordering::SeqCst) {
+                let bytes_synced = match &self.output {
+                    // What the writer was given is left to the caller.
+                    ImageOutput::Writer(_) => 0,
+                    ImageOutput::Path(_) if self.keep_partial => {
+                        image_file
+                            .finish()
+                            .and_then(Sink::sync)
+                            .map_err(io_error(Stage::Sync, None))?;
+                        transfer.bytes_synced = read_total;
+                        read_total
+                    }
+                    ImageOutput::Path(path) => {
+                        // The file is closed before it is deleted, which
+                        // Windows requires.
+                        drop(image_file);
+                        std::fs::remove_file(path)?;
+                        0
+                    }
+                };
+                return Err(Error::Cancelled { bytes_synced }.into());
+            }
+
+            let to_read = std::cmp::min(self.buffer_size as u64, size_bytes - read_total) as usize;
+
+            let position = offset + read_total;
+            let read_error = |source| Error::Io {
+                stage: Stage::Read,
+                offset: Some(position),
+                source,
+            };
+            // The device may return less than asked, and it may end before the
+            // size it reported.
+            let filled = match &used_ranges {
+                Some(used) => read_used(&mut device_file, &mut buffer[..to_read], position, used),
+                None => read_full(&mut device_file, &mut buffer[..to_read]),
+            };
+            let filled = match filled {
+                Ok(filled) => filled,
+                Err(e) if self.tolerate_errors && is_unreadable(&e) => {
+                    match salvage(
+                        &mut device_file,
+                        &mut buffer[..to_read],
+                        position,
+                        block_size,
+                        &self.running,
+                        &mut unreadable,
+                    ) {
+                        Ok(()) => {}
+                        // Cancelled, which the top of the loop takes care of.
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(read_error(e).into()),
+                    }
+                    device_file
+                        .seek(SeekFrom::Start(position + to_read as u64))
+                        .map_err(read_error)?;
+                    first_error.get_or_insert((position, e));
+                    to_read
+                }
+                Err(e) => return Err(read_error(e).into()),
+            };
+            let kept = match self.short_device {
+                ShortDevice::Truncate => filled,
+                ShortDevice::Pad => {
+                    buffer[filled..to_read].fill(0);
+                    to_read
+                }
+            };
+            bytes_missing += (to_read - filled) as u64;
+            hasher.update(&buffer[..kept]);
+            if let Some(map_builder) = &mut map_builder {
+                map_builder.update(&buffer[..kept]);
+            }
+            image_file.write_all(&buffer[..kept])?;
+
+            read_total += kept as u64;
+            transfer.bytes_done = read_total;
+            if kept < to_read {
+                bytes_missing = size_bytes - read_total;
+                break;
+            }
+            if let Some(limiter) = &mut limiter {
+                limiter.throttle(to_read as u64, &self.running);
+            }
+            // The final total is reported once the image has been synced below.
+            if read_total < size_bytes {
+                (self.on_progress)(read_total);
+            }
+        }
+
+        if let Some((position, source)) = first_error {
+            let lost: u64 = unreadable.iter().map(|r| r.end - r.start).sum();
+            if lost == read_total {
+                return Err(Error::Io {
+                    stage: Stage::Read,
+                    offset: Some(position),
+                    source,
+                }
+                .into());
+            }
+            (self.on_warning)(Warning::new(
+                WarningKind::UnreadableSectors,
+                format!(
+                    "{} bytes in {} places could not be read and were filled with zeros.",
+                    lost,
+                    unreadable.len()
+                ),
+            ));
+        }
+
+        if bytes_missing > 0 {
+            (self.on_warning)(Warning::new(
+                WarningKind::ShortDevice,
+                format!(
+                    "The device ended {} bytes before the size it reported, so the image was {}.",
+                    bytes_missing,
+                    match self.short_device {
+                        ShortDevice::Truncate => "cut short",
+                        ShortDevice::Pad => "padded with zeros",
+                    }
+                ),
+            ));
+        }
+
+        // Force the image to disk so a power loss right after we report success
+        // can't leave a truncated capture behind. A writer is only flushed, as
+        // what happens to the data next is up to the caller.
+        (self.on_sync_start)();
+        let image_len = image_file
+            .finish()
+            .and_then(Sink::sync)
+            .map_err(io_error(Stage::Sync, None))?;
+        transfer.bytes_synced = read_total;
+        (self.on_sync_done)();
+        (self.on_progress)(read_total);
+        let duration = started.elapsed();
+
+        let verify_started = Instant::now();
+        if let ImageOutput::Path(image_path) = &self.output
+            && self.verify
+        {
+            // Only what the device returned can be compared with it.
+            let device_bytes = size_bytes - bytes_missing;
+            (self.on_verify_start)(device_bytes);
+            device_file.seek(SeekFrom::Start(offset))?;
+            verify_image(
+                &mut device_file,
+                &mut buffer,
+                offset,
+                device_bytes,
+                used_ranges.as_deref(),
+                image_path,
+                self.compression,
+                &self.running,
+                &mut *self.on_verify_progress,
+            )?;
+        }
+        let verify_duration = verify_started.elapsed();
+
+        let sha256 = hasher.finalize().into();
+        let checksum_file = match &self.output {
+            ImageOutput::Path(image_path) if self.checksum_file => {
+                match write_checksum_file(image_path, &sha256, self.compression, &self.running) {
+                    Ok(path) => Some(path),
+                    Err(e) => {
+                        (self.on_warning)(Warning::new(
+                            WarningKind::ChecksumFileFailed,
+                            format!("The checksum file could not be saved ({}).", e),
+                        ));
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let bmap_file = match (&self.output, map_builder) {
+            (ImageOutput::Path(image_path), Some(map_builder)) => {
+                let path = bmap_path(image_path, self.compression);
+                match std::fs::write(&path, map_builder.finish().to_xml()) {
+                    Ok(()) => Some(path),
+                    Err(e) => {
+                        (self.on_warning)(Warning::new(
+                            WarningKind::BmapFileFailed,
+                            format!("The block map could not be saved ({}).", e),
+                        ));
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        if let ImageOutput::Path(image_path) = &self.output
+            && self.xattrs
+        {
+            let image_metadata = ImageMetadata {
+                sha256,
+                source: match &self.device {
+                    DeviceInput::Path(path) => Some(path.clone()),
+                    DeviceInput::File(_) => None,
+                },
+                date: Some(SystemTime::now()),
+            };
+            if let Err(e) = metadata::set(image_path, &image_metadata) {
+                (self.on_warning)(Warning::new(
+                    WarningKind::XattrsFailed,
+                    format!("The SHA-256 could not be stored with the image ({}).", e),
+                ));
+            }
+        }
+
+        Ok(ReadReport {
+            bytes_read: read_total,
+            offset,
+            sha256,
+            duration,
+            verified: self.verify,
+            verify_duration: if self.verify {
+                verify_duration
+            } else {
+                Duration::ZERO
+            },
+            sector_sizes,
+            direct_io,
+            compressed_bytes: self.compression.map(|_| image_len),
+            unreadable,
+            bytes_missing,
+            checksum_file,
+            bmap_file,
+            bytes_skipped,
+        })
+    }
+}
+
+/// The image file or writer that the image data ends up in.
+enum Sink<'a> {
+    File(File),
+    /// A writer, along with the number of bytes written to it so far.
+    Writer(Box<dyn Write + 'a>, u64),
+}
+
+impl Sink<'_> {
+    /// Syncs the file, or flushes the writer, and returns the number of bytes
+    /// in it.
+    fn sync(self) -> io::Result<u64> {
+        match self {
+            Sink::File(file) => {
+                file.sync_all()?;
+                Ok(file.metadata()?.len())
+            }
+            Sink::Writer(mut writer, written) => {
+                writer.flush()?;
+                Ok(written)
+            }
+        }
+    }
+}
+
+impl Write for Sink<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::File(file) => file.write(buf),
+            Sink::Writer(writer, written) => {
+                let n = writer.write(buf)?;
+                *written += n as u64;
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::File(file) => file.flush(),
+            Sink::Writer(writer, _) => writer.flush(),
+        }
+    }
+}
+
+/// The image sink, written through an encoder if it is compressed.
+enum ImageWriter<'a> {
+    Raw(Sink<'a>),
+    Sparse(File),
+    Gzip(GzEncoder<Sink<'a>>),
+    Xz(XzEncoder<Sink<'a>>),
+    Zstd(ZstdEncoder<'static, Sink<'a>>),
+    Lz4(FrameEncoder<Sink<'a>>),
+}
+
+impl<'a> ImageWriter<'a> {
+    fn new(
+        sink: Sink<'a>,
+        compression: Option<Compression>,
+        threads: u32,
+        sparse: bool,
+    ) -> io::Result<Self> {
+        Ok(match compression {
+            // Only a file can have holes.
+            None => match sink {
+                Sink::File(file) if sparse => ImageWriter::Sparse(file),
+                sink => ImageWriter::Raw(sink),
+            },
+            Some(Compression::Gzip(level)) => {
+                ImageWriter::Gzip(GzEncoder::new(sink, flate2::Compression::new(level)))
+            }
+            Some(Compression::Xz(level)) if threads > 1 => {
+                let stream = MtStreamBuilder::new()
+                    .preset(level)
+                    .threads(threads)
+                    .check(Check::Crc64)
+                    .encoder()?;
+                ImageWriter::Xz(XzEncoder::new_stream(sink, stream))
+            }
+            Some(Compression::Xz(level)) => ImageWriter::Xz(XzEncoder::new(sink, level)),
+            Some(Compression::Zstd(level)) => {
+                let mut encoder = ZstdEncoder::new(sink, level)?;
+                if threads > 1 {
+                    encoder.multithread(threads)?;
+                }
+                ImageWriter::Zstd(encoder)
+            }
+            Some(Compression::Lz4) => ImageWriter::Lz4(FrameEncoder::new(sink)),
+        })
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            ImageWriter::Raw(sink) => sink.write_all(buf),
+            ImageWriter::Sparse(file) => write_sparse(file, buf),
+            ImageWriter::Gzip(encoder) => encoder.write_all(buf),
+            ImageWriter::Xz(encoder) => encoder.write_all(buf),
+            ImageWriter::Zstd(encoder) => encoder.write_all(buf),
+            ImageWriter::Lz4(encoder) => encoder.write_all(buf),
+        }
+    }
+
+    /// Writes out whatever the encoder still holds, and returns the sink.
+    fn finish(self) -> io::Result<Sink<'a>> {
+        match self {
+            // A preallocated file, or one the device ended early for, may be
+            // longer than what was written to it.
+            ImageWriter::Raw(Sink::File(mut file)) => {
+                let len = file.stream_position()?;
+                file.set_len(len)?;
+                Ok(Sink::File(file))
+            }
+            ImageWriter::Raw(sink) => Ok(sink),
+            // A hole at the end is only part of the file once its length
+            // covers it.
+            ImageWriter::Sparse(mut file) => {
+                let len = file.stream_position()?;
+                file.set_len(len)?;
+                Ok(Sink::File(file))
+            }
+            ImageWriter::Gzip(encoder) => encoder.finish(),
+            ImageWriter::Xz(encoder) => encoder.finish(),
+            ImageWriter::Zstd(encoder) => encoder.finish(),
+            ImageWriter::Lz4(encoder) => encoder.finish().map_err(io::Error::from),
+        }
+    }
+}
+
+/// Reads the entire contents of a block device to an image file.
 ///
-/// This function will return an error if:
-/// - The device cannot be opened or its size cannot be determined.
-/// - The output file cannot be created.
-/// - An I/O error occurs during reading or writing.
-/// - The operation is cancelled by the user.
+/// This is the positional form of [`ReadOptions`], kept for compatibility with
+/// etchr-core 1.0. It uses the default for every option that it does not take.
+#[deprecated(note = "use `ReadOptions` instead")]
 pub fn run<F>(
     device_path: &Path,
     image_path: &Path,
     running: Arc<AtomicBool>,
     on_read_start: impl FnOnce(u64),
-    mut on_progress: F,
-) -> Result<()>
+    on_progress: F,
+) -> Result<ReadReport>
 where
     F: FnMut(u64),
 {
-    let mut device_file = std::fs::OpenOptions::new()
-        .read(true)
-        .custom_flags(libc::O_DIRECT)
-        .open(device_path)?;
+    run_positional(
+        ReadOptions::new(device_path, image_path),
+        None,
+        running,
+        on_read_start,
+        on_progress,
+        || {},
+        || {},
+        |_| {},
+    )
+}
 
-    // Get the device size in bytes using a platform-specific ioctl.
-    #[cfg(unix)]
-    let fd = device_file.as_raw_fd();
-    let mut size_bytes: u64 = 0;
+/// Reads the entire contents of a block device to an image file, keeping the
+/// average read rate at or below `max_bytes_per_sec` if it is set.
+///
+/// This is the positional form of [`ReadOptions`] with
+/// [`ReadOptions::max_bytes_per_sec`], kept for compatibility with etchr-core
+/// 1.0. It uses the default for every option that it does not take.
+#[deprecated(note = "use `ReadOptions` instead")]
+#[allow(clippy::too_many_arguments)]
+pub fn run_limited<F>(
+    device_path: &Path,
+    image_path: &Path,
+    max_bytes_per_sec: Option<u64>,
+    running: Arc<AtomicBool>,
+    on_read_start: impl FnOnce(u64),
+    on_progress: F,
+    on_sync_start: impl FnOnce(),
+    on_sync_done: impl FnOnce(),
+    on_warning: impl FnMut(Warning),
+) -> Result<ReadReport>
+where
+    F: FnMut(u64),
+{
+    run_positional(
+        ReadOptions::new(device_path, image_path),
+        max_bytes_per_sec,
+        running,
+        on_read_start,
+        on_progress,
+        on_sync_start,
+        on_sync_done,
+        on_warning,
+    )
+}
+
+/// Reads the entire contents of a block device to an image file through
+/// `device_file`, a handle to the device that the caller already opened.
+///
+/// This is the positional form of [`ReadOptions::from_device_file`], kept for
+/// compatibility with etchr-core 1.0. It uses the default for every option
+/// that it does not take.
+#[deprecated(note = "use `ReadOptions` instead")]
+#[allow(clippy::too_many_arguments)]
+pub fn run_with_device<F>(
+    device_file: File,
+    image_path: &Path,
+    max_bytes_per_sec: Option<u64>,
+    running: Arc<AtomicBool>,
+    on_read_start: impl FnOnce(u64),
+    on_progress: F,
+    on_sync_start: impl FnOnce(),
+    on_sync_done: impl FnOnce(),
+    on_warning: impl FnMut(Warning),
+) -> Result<ReadReport>
+where
+    F: FnMut(u64),
+{
+    run_positional(
+        ReadOptions::from_device_file(device_file, image_path),
+        max_bytes_per_sec,
+        running,
+        on_read_start,
+        on_progress,
+        on_sync_start,
+        on_sync_done,
+        on_warning,
+    )
+}
+
+/// Runs `options` with the arguments the positional functions take.
+#[allow(clippy::too_many_arguments)]
+fn run_positional(
+    options: ReadOptions,
+    max_bytes_per_sec: Option<u64>,
+    running: Arc<AtomicBool>,
+    on_read_start: impl FnOnce(u64),
+    on_progress: impl FnMut(u64),
+    on_sync_start: impl FnOnce(),
+    on_sync_done: impl FnOnce(),
+    on_warning: impl FnMut(Warning),
+) -> Result<ReadReport> {
+    let mut on_read_start = Some(on_read_start);
+    let mut on_sync_start = Some(on_sync_start);
+    let mut on_sync_done = Some(on_sync_done);
+
+    let mut options = options
+        .running(running)
+        .on_read_start(move |len| {
+            if let Some(f) = on_read_start.take() {
+                f(len);
+            }
+        })
+        .on_progress(on_progress)
+        .on_sync_start(move || {
+            if let Some(f) = on_sync_start.take() {
+                f();
+            }
+        })
+        .on_sync_done(move || {
+            if let Some(f) = on_sync_done.take() {
+                f();
+            }
+        })
+        .on_warning(on_warning);
+    if let Some(max_bytes_per_sec) = max_bytes_per_sec {
+        options = options.max_bytes_per_sec(max_bytes_per_sec);
+    }
+    options.run()
+}
+
+/// Saves the SHA-256 of the image at `image_path` next to it, in the format
+/// `sha256sum -c` reads, and returns the path of the checksum file.
+///
+/// `sha256` is the hash of the data read from the device. A compressed image
+/// is hashed as well, and the data goes by the name the image has once it is
+/// decompressed.
+fn write_checksum_file(
+    image_path: &Path,
+    sha256: &[u8; 32],
+    compression: Option<Compression>,
+    running: &AtomicBool,
+) -> Result<PathBuf> {
+    let name = image_path
+        .file_name()
+        .unwrap_or(image_path.as_os_str())
+        .to_string_lossy();
+    let contents = match compression {
+        None => format!("{}  {}\n", hex::encode(sha256), name),
+        Some(compression) => {
+            let file_sha256 = hash_file(&ImageFile::single(image_path), running, &mut |_| {})?;
+            let data_name = match name.strip_suffix(&format!(".{}", compression.extension())) {
+                Some(stem) => stem.to_string(),
+                None => format!("{}.raw", name),
+            };
+            format!(
+                "{}  {}\n{}  {}\n",
+                hex::encode(file_sha256),
+                name,
+                hex::encode(sha256),
+                data_name
+            )
+        }
+    };
+    let mut path = image_path.as_os_str().to_owned();
+    path.push(".sha256");
+    let path = PathBuf::from(path);
+    std::fs::write(&path, contents)?;
+    Ok(path)
+}
+
+/// Fails with [`Error::InsufficientSpace`] if the filesystem that is to hold
+/// the image file at `path` has less than `needed` bytes free. A file already
+/// at `path` is replaced, so the space it takes up counts as free.
+fn check_space(path: &Path, needed: u64) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let replaced = std::fs::metadata(path)
+        .ok()
+        .filter(|m| m.is_file())
+        .map_or(0, |m| m.len());
+    let available = platform::free_space(dir)?.saturating_add(replaced);
+    if needed > available {
+        return Err(Error::InsufficientSpace {
+            mount_point: platform::mount_point(dir)?,
+            needed,
+            available,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// The path of the block map for the image at `image_path`: the image without
+/// its compression extension, with `.bmap` added.
+fn bmap_path(image_path: &Path, compression: Option<Compression>) -> PathBuf {
+    let data_path = match compression {
+        Some(compression)
+            if image_path
+                .extension()
+                .is_some_and(|ext| ext == compression.extension()) =>
+        {
+            image_path.with_extension("")
+        }
+        _ => image_path.to_path_buf(),
+    };
+    let mut path = data_path.into_os_string();
+    path.push(".bmap");
+    PathBuf::from(path)
+}
+
+/// Writes `buf` to `file`, seeking past whole blocks of zeros instead of
+/// writing them, so they become holes.
+fn write_sparse(file: &mut File, buf: &[u8]) -> io::Result<()> {
+    let is_zero = |block: &[u8]| block.iter().all(|&b| b == 0);
+    let mut blocks = buf.chunks(SPARSE_BLOCK).peekable();
+    let mut start = 0;
+    while let Some(block) = blocks.next() {
+        // Neighbouring blocks of the same kind are handled in one go.
+        let zero = is_zero(block);
+        let mut end = start + block.len();
+        while let Some(next) = blocks.next_if(|next| is_zero(next) == zero) {
+            end += next.len();
+        }
+        if zero {
+            file.seek(SeekFrom::Current((end - start) as i64))?;
+        } else {
+            file.write_all(&buf[start..end])?;
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+/// Reads `len` bytes of the device from where `device_file` is, at `offset`,
+/// and compares them with the image file at `image_path`, which is
+/// decompressed with `compression`. If only the `used` ranges of the device
+/// were read, the rest is taken to be zeros. Fails with
+/// [`Error::VerifyMismatch`] at the first byte that differs.
+#[allow(clippy::too_many_arguments)]
+fn verify_image(
+    device_file: &mut File,
+    buffer: &mut [u8],
+    offset: u64,
+    len: u64,
+    used: Option<&[Range<u64>]>,
+    image_path: &Path,
+    compression: Option<Compression>,
+    running: &AtomicBool,
+    on_verify_progress: &mut dyn FnMut(u64),
+) -> Result<()> {
+    let file = File::open(image_path)?;
+    let mut image: Box<dyn Read> = match compression {
+        None => Box::new(BufReader::new(file)),
+        Some(Compression::Gzip(_)) => Box::new(GzDecoder::new(BufReader::new(file))),
+        Some(Compression::Xz(_)) => Box::new(XzDecoder::new(BufReader::new(file))),
+        Some(Compression::Zstd(_)) => Box::new(ZstdDecoder::new(file)?),
+        Some(Compression::Lz4) => Box::new(Lz4Frames::new(BufReader::new(file))),
+    };
+    let mut image_buf = vec![0u8; buffer.len()];
+    let mut verified = 0;
+    while verified < len {
+        if !running.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled { bytes_synced: len }.into());
+        }
+        let n = (len - verified).min(buffer.len() as u64) as usize;
+        let position = offset + verified;
+        let read = match used {
+            Some(used) => read_used(device_file, &mut buffer[..n], position, used),
+            None => read_full(device_file, &mut buffer[..n]),
+        };
+        let read = read.and_then(|filled| {
+            if filled < n {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(())
+        });
+        read.map_err(|source| Error::Io {
+            stage: Stage::Verify,
+            offset: Some(position),
+            source,
+        })?;
+        let filled = read_full(&mut image, &mut image_buf[..n])?;
+        if let Some(i) = (0..n).find(|&i| i >= filled || buffer[i] != image_buf[i]) {
+            return Err(Error::VerifyMismatch {
+                offset: position + i as u64,
+            }
+            .into());
+        }
+        verified += n as u64;
+        on_verify_progress(verified);
+    }
+    Ok(())
+}
+
+/// Fills `buf` with the data of the device at `position`, only reading the
+/// parts that lie in `used` and zeroing the rest. Returns how much was
+/// filled, which is less than asked only if the device ended early.
+fn read_used(
+    file: &mut File,
+    buf: &mut [u8],
+    position: u64,
+    used: &[Range<u64>],
+) -> io::Result<usize> {
+    let end = position + buf.len() as u64;
+    let mut pos = position;
+    let first = used.partition_point(|r| r.end <= position);
+    for range in used[first..].iter().take_while(|r| r.start < end) {
+        let (from, to) = (range.start.max(position), range.end.min(end));
+        buf[(pos - position) as usize..(from - position) as usize].fill(0);
+        file.seek(SeekFrom::Start(from))?;
+        let part = &mut buf[(from - position) as usize..(to - position) as usize];
+        let filled = read_full(file, part)?;
+        if filled < part.len() {
+            return Ok((from - position) as usize + filled);
+        }
+        pos = to;
+    }
+    buf[(pos - position) as usize..].fill(0);
+    file.seek(SeekFrom::Start(end))?;
+    Ok(buf.len())
+}
+
+/// Whether preallocating failed because the filesystem cannot allocate space
+/// ahead of time, rather than for any other reason.
+fn is_unsupported(e: &io::Error) -> bool {
     #[cfg(unix)]
-    unsafe {
-        blkgetsize64(fd, &mut size_bytes)?;
+    let codes = [libc::EOPNOTSUPP, libc::ENOSYS];
+    #[cfg(windows)]
+    let codes = [windows_sys::Win32::Foundation::ERROR_INVALID_FUNCTION as i32];
+    e.raw_os_error().is_some_and(|code| codes.contains(&code))
+}
+
+/// Whether a failed read may be down to bad sectors, rather than a device
+/// that is gone or shorter than it claimed to be.
+fn is_unreadable(e: &io::Error) -> bool {
+    !error::is_removal(e)
+        && !matches!(
+            e.kind(),
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::Interrupted
+        )
+}
+
+/// Reads `buf` from the device at `offset` after reading all of it at once
+/// failed, splitting it in halves until the sectors that cannot be read are
+/// found. Those are zeroed in `buf` and added to `unreadable`.
+///
+/// Fails with [`io::ErrorKind::Interrupted`] if the read is cancelled, and
+/// with any error that [`is_unreadable`] does not cover.
+fn salvage(
+    file: &mut File,
+    buf: &mut [u8],
+    offset: u64,
+    block_size: usize,
+    running: &AtomicBool,
+    unreadable: &mut Vec<Range<u64>>,
+) -> io::Result<()> {
+    if !running.load(Ordering::SeqCst) {
+        return Err(io::ErrorKind::Interrupted.into());
+    }
+    if buf.len() <= block_size {
+        buf.fill(0);
+        let end = offset + buf.len() as u64;
+        match unreadable.last_mut() {
+            Some(last) if last.end == offset => last.end = end,
+            _ => unreadable.push(offset..end),
+        }
+        return Ok(());
+    }
+    let mid = (buf.len() / 2 / block_size).max(1) * block_size;
+    let (first, second) = buf.split_at_mut(mid);
+    for (half, at) in [(first, offset), (second, offset + mid as u64)] {
+        file.seek(SeekFrom::Start(at))?;
+        match file.read_exact(half) {
+            Ok(()) => {}
+            Err(e) if is_unreadable(&e) => {
+                salvage(file, half, at, block_size, running, unreadable)?
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usedblocks::tests::{fixture, mbr_device};
+
+    const MIB: u64 = 1 << 20;
+
+    /// A device holding the ext4 fixture in a partition at 1 MiB. The free
+    /// blocks of the filesystem were filled with 0xAA when it was made, and
+    /// each block of its files starts with `ETCHR-FILE`.
+    fn device() -> File {
+        let mut device = mbr_device(34 * MIB, &[(0x83, 2048, 65536)]);
+        let mut ext4 = fixture("ext4.img.zst");
+        ext4.rewind().unwrap();
+        device.seek(SeekFrom::Start(MIB)).unwrap();
+        io::copy(&mut ext4, &mut device).unwrap();
+        device
+    }
+
+    /// Reads all of `device` as a read of the used blocks only does, returning
+    /// what was read along with the used ranges.
+    fn read_used_blocks(device: &mut File) -> (Vec<u8>, Vec<Range<u64>>) {
+        let len = device.metadata().unwrap().len();
+        let used = usedblocks::used_ranges(device, 512, len, &mut |_| {});
+        let mut copy = vec![0u8; len as usize];
+        for (i, chunk) in copy.chunks_mut(MIB as usize).enumerate() {
+            let filled = read_used(device, chunk, i as u64 * MIB, &used).unwrap();
+            assert_eq!(filled, chunk.len());
+        }
+        (copy, used)
+    }
+
+    #[test]
+    fn used_blocks_keep_all_file_data() {
+        let mut device = device();
+        let (copy, used) = read_used_blocks(&mut device);
+        let mut original = Vec::new();
+        device.rewind().unwrap();
+        device.read_to_end(&mut original).unwrap();
+
+        let mut file_blocks = 0;
+        for (block, data) in original.chunks(1024).enumerate() {
+            if data.starts_with(b"ETCHR-FILE ") {
+                assert_eq!(&copy[block * 1024..][..1024], data, "block {}", block);
+                file_blocks += 1;
+            }
+        }
+        assert_eq!(file_blocks, 12 + 40 + 3);
+        // Only the table, the gap after it, the blocks the filesystem uses and
+        // the space after the partition were read.
+        let read: u64 = used.iter().map(|r| r.end - r.start).sum();
+        assert_eq!(read, 2 * MIB + (2384 + 257 + 4096 + 257) * 1024);
     }
 
-    if size_bytes == 0 {
-        return Err(anyhow!("Device size is reported as zero"));
+    #[test]
+    fn verify_compares_only_used_blocks() {
+        let mut device = device();
+        let len = device.metadata().unwrap().len();
+        let (copy, used) = read_used_blocks(&mut device);
+        let mut image = tempfile::NamedTempFile::new().unwrap();
+        image.write_all(&copy).unwrap();
+
+        let running = AtomicBool::new(true);
+        let mut buffer = vec![0u8; MIB as usize];
+        let mut verify = |device: &mut File, used| {
+            device.rewind().unwrap();
+            verify_image(
+                device,
+                &mut buffer,
+                0,
+                len,
+                used,
+                image.path(),
+                None,
+                &running,
+                &mut |_| {},
+            )
+        };
+        verify(&mut device, Some(&used)).unwrap();
+        let e = verify(&mut device, None).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<Error>(),
+            Some(Error::VerifyMismatch { .. })
+        ));
     }
 
-    on_read_start(size_bytes);
+    #[test]
+    fn zeros_become_holes() {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::fs::MetadataExt;
 
-    let mut image_file = File::create(image_path)?;
+        // Data at the start and in the middle, with zeros partly filling the
+        // blocks around it (six blocks in all), and zeros up to the end.
+        let mut image = vec![0u8; 4 * MIB as usize];
+        image[..3 * SPARSE_BLOCK + 100].fill(0x5A);
+        image[2 * MIB as usize + 10..][..SPARSE_BLOCK].fill(0xA5);
+        let data = 6 * SPARSE_BLOCK as u64;
 
-    // O_DIRECT requires buffers to be memory-aligned.
-    let block_size = 512;
-    let mut buf = vec![0u8; BUFFER_SIZE + block_size];
-    let offset = buf.as_ptr().align_offset(block_size);
-    let buffer = &mut buf[offset..offset + BUFFER_SIZE];
+        // Other filesystems may allocate more than they were written, so
+        // only tmpfs and ext4 are checked.
+        for dir in [Path::new("/dev/shm"), &std::env::temp_dir()] {
+            let Ok(file) = tempfile::tempfile_in(dir) else {
+                continue;
+            };
+            let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+            assert_eq!(unsafe { libc::fstatfs(file.as_raw_fd(), &mut stat) }, 0);
+            if ![libc::TMPFS_MAGIC, libc::EXT4_SUPER_MAGIC].contains(&stat.f_type) {
+                continue;
+            }
+            let mut writer = ImageWriter::new(Sink::File(file), None, 1, true).unwrap();
+            for chunk in image.chunks(MIB as usize / 2) {
+                writer.write_all(chunk).unwrap();
+            }
+            let Sink::File(mut file) = writer.finish().unwrap() else {
+                unreachable!()
+            };
+            file.sync_all().unwrap();
 
-    let mut read_total: u64 = 0;
-    while read_total < size_bytes {
-        if !running.load(Ordering::SeqCst) {
-            std::fs::remove_file(image_path)?;
-            return Err(anyhow!("Operation cancelled by user"));
+            let metadata = file.metadata().unwrap();
+            assert_eq!(metadata.len(), image.len() as u64, "{}", dir.display());
+            assert_eq!(metadata.blocks() * 512, data, "{}", dir.display());
+            let mut read = Vec::new();
+            file.rewind().unwrap();
+            file.read_to_end(&mut read).unwrap();
+            assert!(read == image, "{}", dir.display());
         }
+    }
 
-        let to_read = std::cmp::min(BUFFER_SIZE as u64, size_bytes - read_total) as usize;
+    #[test]
+    fn a_device_that_ends_early_is_cut_short_or_padded() {
+        let contents: Vec<u8> = (0..MIB + 3000).map(|i| (i % 251) as u8).collect();
+        let mut device = tempfile::tempfile().unwrap();
+        device.write_all(&contents).unwrap();
+        let reported = 3 * MIB;
 
-        device_file.read_exact(&mut buffer[..to_read])?;
-        image_file.write_all(&buffer[..to_read])?;
+        for short_device in [ShortDevice::Truncate, ShortDevice::Pad] {
+            let dir = tempfile::tempdir().unwrap();
+            let image = dir.path().join("image.img");
+            let mut warnings = Vec::new();
+            let report = ReadOptions::from_device_file(device.try_clone().unwrap(), &image)
+                .reported_len(reported)
+                .direct_io(DirectIo::Off)
+                .short_device(short_device)
+                .verify(true)
+                .on_warning(|warning| warnings.push(warning.kind))
+                .run()
+                .unwrap();
 
-        read_total += to_read as u64;
-        on_progress(read_total);
+            let mut expected = contents.clone();
+            if short_device == ShortDevice::Pad {
+                expected.resize(reported as usize, 0);
+            }
+            assert_eq!(report.bytes_read, expected.len() as u64);
+            assert_eq!(report.bytes_missing, reported - contents.len() as u64);
+            assert!(std::fs::read(&image).unwrap() == expected);
+            assert_eq!(warnings, [WarningKind::ShortDevice]);
+        }
     }
 
-    image_file.flush()?;
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn a_device_without_direct_io_is_read_through_the_page_cache() {
+        use crate::fault::{self, Fault};
+
+        let contents: Vec<u8> = (0..MIB).map(|i| (i % 251) as u8).collect();
+        let mut device = tempfile::tempfile().unwrap();
+        device.write_all(&contents).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.img");
+        let read = |direct_io, warnings: &mut Vec<WarningKind>| {
+            fault::arm(&device, Fault::DirectIo, 1);
+            ReadOptions::from_device_file(device.try_clone().unwrap(), &image)
+                .reported_len(MIB)
+                .direct_io(direct_io)
+                .on_warning(|warning| warnings.push(warning.kind))
+                .run()
+        };
+
+        let mut warnings = Vec::new();
+        let report = read(DirectIo::Preferred, &mut warnings).unwrap();
+        assert!(!report.direct_io);
+        assert_eq!(warnings, [WarningKind::DirectIoFallback]);
+        assert!(std::fs::read(&image).unwrap() == contents);
+
+        let mut warnings = Vec::new();
+        let e = read(DirectIo::Required, &mut warnings).unwrap_err();
+        let e = e.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(warnings, []);
+    }
+}
@@ -0,0 +1,160 @@
+//! Where an image to write comes from: a file, or an HTTP(S) URL.
+//!
+//! A URL is downloaded as it is written, so the wait for the download and the
+//! wait for the write overlap instead of adding up, and the image never has to
+//! fit on the local disk. The response body goes through the same decoders as
+//! an image file, except that a zip archive has to be downloaded first, as its
+//! directory is at the end. Downloading needs the `http` feature.
+//!
+//! A connection that drops in the middle of a download is picked up again with
+//! a `Range` request for the rest, if the server supports them. The bytes are
+//! hashed as they arrive, so a download can be checked against its published
+//! SHA-256 once it is complete.
+//!
+//! An image file can also be split into numbered parts, which are found next
+//! to the one given by an [`ImageFile`] and read as one file by a
+//! [`MultiFileReader`].
+use std::convert::Infallible;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[cfg(feature = "http")]
+mod http;
+mod split;
+
+#[cfg(feature = "http")]
+pub(crate) use http::Download;
+pub(crate) use split::whole_name;
+pub use split::{ImageFile, MultiFileReader, split_parts};
+
+/// An image file or an HTTP(S) URL to write an image from.
+///
+/// Strings and paths convert into a `Source`, which is a URL if it starts with
+/// `http://` or `https://`, and a file otherwise.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// An image file, which can be compressed or split into parts.
+    Path(PathBuf),
+    /// An image to download, which can be compressed.
+    Url(String),
+}
+
+impl Source {
+    /// Parses `source` as a URL if it starts with `http://` or `https://`
+    /// (in any case), and as a path otherwise.
+    pub fn parse(source: impl AsRef<OsStr>) -> Self {
+        let source = source.as_ref();
+        match source.to_str() {
+            Some(s) if is_url(s) => Source::Url(s.to_string()),
+            _ => Source::Path(PathBuf::from(source)),
+        }
+    }
+
+    /// The path of the image file, or `None` for a URL.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Source::Path(path) => Some(path),
+            Source::Url(_) => None,
+        }
+    }
+
+    /// Whether the image is downloaded.
+    pub fn is_url(&self) -> bool {
+        matches!(self, Source::Url(_))
+    }
+
+    /// The name of the image: the file name of a path, or the last segment of
+    /// the path of a URL, without its query. Its extension tells what format
+    /// the image is supposed to be in, and checksum files list images by it.
+    pub fn name(&self) -> Option<&Path> {
+        match self {
+            Source::Path(path) => path.file_name().map(Path::new),
+            Source::Url(url) => {
+                let path = url.split(['?', '#']).next().unwrap_or_default();
+                let (_, after_scheme) = path.split_once("://")?;
+                let (_, path) = after_scheme.split_once('/')?;
+                path.rsplit('/')
+                    .next()
+                    .filter(|name| !name.is_empty())
+                    .map(Path::new)
+            }
+        }
+    }
+}
+
+fn is_url(s: &str) -> bool {
+    let starts_with = |prefix: &str| {
+        s.get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+    };
+    starts_with("http://") || starts_with("https://")
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Path(path) => write!(f, "{}", path.display()),
+            Source::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Source::parse(s))
+    }
+}
+
+impl<T: AsRef<OsStr> + ?Sized> From<&T> for Source {
+    fn from(source: &T) -> Self {
+        Source::parse(source)
+    }
+}
+
+impl From<PathBuf> for Source {
+    fn from(path: PathBuf) -> Self {
+        Source::parse(path)
+    }
+}
+
+impl From<String> for Source {
+    fn from(s: String) -> Self {
+        Source::parse(s)
+    }
+}
+
+/// Stands in for a download in a build without the `http` feature, where
+/// opening one always fails.
+#[cfg(not(feature = "http"))]
+pub(crate) enum Download {}
+
+#[cfg(not(feature = "http"))]
+impl Download {
+    pub(crate) fn open(
+        url: &str,
+        _running: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    ) -> anyhow::Result<Self> {
+        Err(crate::error::Error::Download {
+            url: url.to_string(),
+            reason: "this build of etchr cannot download images (it lacks the `http` feature)"
+                .to_string(),
+        }
+        .into())
+    }
+
+    pub(crate) fn len(&self) -> Option<u64> {
+        match *self {}
+    }
+
+    pub(crate) fn reader(&self) -> std::io::Empty {
+        match *self {}
+    }
+
+    pub(crate) fn finish(&self) -> std::io::Result<[u8; 32]> {
+        match *self {}
+    }
+}
@@ -0,0 +1,275 @@
+//! Streams an image from an HTTP(S) server, picking the download up again
+//! where it left off if the connection drops.
+use crate::error::Error;
+use crate::throttle::cancellable_sleep;
+use sha2::{Digest, Sha256};
+use std::io::{self, ErrorKind, Read};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use ureq::{Agent, AgentBuilder, Response};
+
+/// How many redirects are followed before giving up.
+const MAX_REDIRECTS: u32 = 10;
+/// How long to wait for a connection to the server.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the server may stay silent before the connection is taken for
+/// dead and the download is resumed on a new one.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many times in a row the download is resumed without getting any
+/// further before it fails. The waits in between double from one second.
+const MAX_RECONNECTS: u32 = 5;
+
+/// An image being downloaded.
+///
+/// The body is shared between the [`reader`](Download::reader) the image is
+/// decoded from and the download itself, which reads whatever the decoder
+/// left over once it is done, so that all of it is hashed.
+pub(crate) struct Download {
+    body: Arc<Mutex<Body>>,
+    len: Option<u64>,
+}
+
+impl Download {
+    /// Requests `url`, following redirects, and returns once the server has
+    /// answered. Clearing `running` stops waiting to reconnect.
+    pub(crate) fn open(url: &str, running: Arc<AtomicBool>) -> anyhow::Result<Self> {
+        let agent = AgentBuilder::new()
+            .redirects(MAX_REDIRECTS)
+            .timeout_connect(CONNECT_TIMEOUT)
+            .timeout_read(READ_TIMEOUT)
+            .user_agent(concat!("etchr/", env!("CARGO_PKG_VERSION")))
+            .build();
+        let failed = |reason| Error::Download {
+            url: url.to_string(),
+            reason,
+        };
+        let response = agent.get(url).call().map_err(|e| failed(describe(e)))?;
+        if response.status() != 200 {
+            return Err(failed(format!(
+                "the server answered {} {}",
+                response.status(),
+                response.status_text()
+            ))
+            .into());
+        }
+
+        let len = response
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok());
+        let ranges = response
+            .header("Accept-Ranges")
+            .is_some_and(|unit| unit.eq_ignore_ascii_case("bytes"));
+        // A weak ETag cannot be used to resume a download.
+        let validator = response
+            .header("ETag")
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(response.header("Last-Modified"))
+            .map(str::to_string);
+        let body = Body {
+            url: url.to_string(),
+            location: response.get_url().to_string(),
+            agent,
+            reader: response.into_reader(),
+            offset: 0,
+            len,
+            ranges,
+            validator,
+            hasher: Sha256::new(),
+            running,
+        };
+        Ok(Self {
+            body: Arc::new(Mutex::new(body)),
+            len,
+        })
+    }
+
+    /// The size of the download, if the server gave it.
+    pub(crate) fn len(&self) -> Option<u64> {
+        self.len
+    }
+
+    /// A reader of the downloaded bytes.
+    pub(crate) fn reader(&self) -> DownloadReader {
+        DownloadReader(self.body.clone())
+    }
+
+    /// Downloads whatever has not been read yet and returns the SHA-256 of
+    /// the whole download.
+    pub(crate) fn finish(&self) -> io::Result<[u8; 32]> {
+        let mut body = lock(&self.body);
+        io::copy(&mut *body, &mut io::sink())?;
+        Ok(body.hasher.clone().finalize().into())
+    }
+}
+
+/// Reads the body of a [`Download`].
+pub(crate) struct DownloadReader(Arc<Mutex<Body>>);
+
+impl Read for DownloadReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        lock(&self.0).read(buf)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The body of a response, and what it takes to ask for the rest of it.
+struct Body {
+    /// The URL that was asked for, for error messages.
+    url: String,
+    /// Where the redirects led, which is where the rest is asked for.
+    location: String,
+    agent: Agent,
+    reader: Box<dyn Read + Send + Sync>,
+    /// How many bytes of the body have been read.
+    offset: u64,
+    len: Option<u64>,
+    /// Whether the server takes `Range` requests.
+    ranges: bool,
+    /// The strong ETag or the modification time of the body, which the server
+    /// checks to only send the rest of it if it has not changed since.
+    validator: Option<String>,
+    hasher: Sha256,
+    running: Arc<AtomicBool>,
+}
+
+impl Body {
+    /// Asks for the rest of the body on a new connection. Errors that another
+    /// attempt cannot fix are returned as [`Error::Download`].
+    fn resume(&mut self) -> io::Result<()> {
+        let mut request = self.agent.get(&self.location);
+        if self.ranges {
+            request = request.set("Range", &format!("bytes={}-", self.offset));
+            if let Some(validator) = &self.validator {
+                request = request.set("If-Range", validator);
+            }
+        }
+        let response = match request.call() {
+            Ok(response) => response,
+            // Only an error on the side of the server may go away.
+            Err(ureq::Error::Status(status, response)) if status < 500 && status != 429 => {
+                return Err(self.fatal(describe(ureq::Error::Status(status, response))));
+            }
+            Err(e) => return Err(io::Error::other(describe(e))),
+        };
+
+        match response.status() {
+            206 => {
+                let start = response
+                    .header("Content-Range")
+                    .and_then(|range| range.strip_prefix("bytes "))
+                    .and_then(|range| range.split('-').next())
+                    .and_then(|start| start.parse::<u64>().ok());
+                if start != Some(self.offset) {
+                    return Err(self.fatal(format!(
+                        "the server did not resume the download at byte {}",
+                        self.offset
+                    )));
+                }
+                self.reader = response.into_reader();
+            }
+            // The server ignores `If-Range` only when it ignores `Range`.
+            200 if self.ranges && self.validator.is_some() => {
+                return Err(self
+                    .fatal("the file on the server changed while it was downloaded".to_string()));
+            }
+            200 => self.restart(response)?,
+            status => {
+                return Err(io::Error::other(format!(
+                    "the server answered {} {}",
+                    status,
+                    response.status_text()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts reading `response`, the whole body once more, after skipping the
+    /// part that was already read.
+    fn restart(&mut self, response: Response) -> io::Result<()> {
+        let mut reader = response.into_reader();
+        let skipped = io::copy(&mut (&mut reader).take(self.offset), &mut io::sink())?;
+        if skipped != self.offset {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "the connection closed before the download got back to where it was",
+            ));
+        }
+        self.reader = reader;
+        Ok(())
+    }
+
+    fn fatal(&self, reason: String) -> io::Error {
+        io::Error::other(Error::Download {
+            url: self.url.clone(),
+            reason,
+        })
+    }
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut attempts = 0;
+        loop {
+            let mut error = match self.reader.read(buf) {
+                Ok(0) if self.len.is_some_and(|len| self.offset < len) => io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "the connection closed before the whole file arrived",
+                ),
+                Ok(n) => {
+                    self.hasher.update(&buf[..n]);
+                    self.offset += n as u64;
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+
+            loop {
+                if attempts == MAX_RECONNECTS {
+                    return Err(self.fatal(format!(
+                        "the connection failed after {} bytes and could not be resumed: {}",
+                        self.offset, error
+                    )));
+                }
+                // Cancelling returns the error as it is.
+                let wait = Duration::from_secs(1 << attempts);
+                if !cancellable_sleep(wait, &self.running) {
+                    return Err(error);
+                }
+                attempts += 1;
+                match self.resume() {
+                    Ok(()) => break,
+                    Err(e) if e.get_ref().is_some_and(|e| e.is::<Error>()) => return Err(e),
+                    Err(e) => error = e,
+                }
+            }
+        }
+    }
+}
+
+/// Describes why a request failed, without repeating the URL.
+fn describe(e: ureq::Error) -> String {
+    match e {
+        ureq::Error::Status(status, response) => {
+            format!("the server answered {} {}", status, response.status_text())
+        }
+        ureq::Error::Transport(transport) => {
+            let mut reason = transport.kind().to_string();
+            if let Some(message) = transport.message() {
+                reason = format!("{}: {}", reason, message);
+            }
+            if let Some(source) = std::error::Error::source(&transport) {
+                reason = format!("{}: {}", reason, source);
+            }
+            reason
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! Images split into numbered parts, such as `image.img.00`, `image.img.01`,
+//! ... or `image.img.001`, `image.img.002`, ..., to get around the 4 GiB
+//! limit of FAT32 or the upload limits of a file host.
+use crate::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Finds the parts of the split image that the file at `path` is one of, in
+/// order. Returns `None` if the file is not part of a split image: its name
+/// does not end in a two or three digit number, or no other part is next to
+/// it.
+///
+/// Parts with two digits are numbered from 0, as `split -d` does. Those with
+/// three may also start from 1, as 7-Zip and HJSplit do. If a number is
+/// missing from the sequence, this fails with [`Error::SplitImageIncomplete`],
+/// carried inside the I/O error. A missing last part cannot be told from the
+/// end of the image.
+pub fn split_parts(path: &Path) -> io::Result<Option<Vec<PathBuf>>> {
+    let Some((whole, digits)) = part_name(path) else {
+        return Ok(None);
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some((entry_whole, entry_digits)) = part_name(Path::new(&name))
+            && entry_whole == whole
+            && entry_digits.len() == digits.len()
+        {
+            let number: u32 = entry_digits.parse().expect("the digits are a number");
+            found.push((number, path.with_file_name(&name)));
+        }
+    }
+    if found.len() < 2 {
+        return Ok(None);
+    }
+    found.sort();
+
+    let first = match digits.len() {
+        2 => 0,
+        _ => found[0].0.min(1),
+    };
+    let last = found[found.len() - 1].0;
+    let parts: Vec<PathBuf> = found.iter().map(|(_, path)| path.clone()).collect();
+    if (last - first + 1) as usize != found.len() {
+        let missing = (first..last)
+            .filter(|n| {
+                found
+                    .binary_search_by_key(n, |(number, _)| *number)
+                    .is_err()
+            })
+            .map(|n| format!("{}.{:0width$}", whole, n, width = digits.len()))
+            .collect();
+        return Err(io::Error::other(Error::SplitImageIncomplete {
+            parts,
+            missing,
+        }));
+    }
+    Ok(Some(parts))
+}
+
+/// Splits the file name of a part into the name of the whole image and the
+/// digits of its number.
+fn part_name(path: &Path) -> Option<(&str, &str)> {
+    let name = path.file_name()?.to_str()?;
+    let (whole, digits) = name.rsplit_once('.')?;
+    let numbered = (2..=3).contains(&digits.len()) && digits.bytes().all(|b| b.is_ascii_digit());
+    (numbered && !whole.is_empty()).then_some((whole, digits))
+}
+
+/// The name of the image that the file at `path` is a part of, or `path`
+/// itself if it does not look like a part. Only the name is looked at.
+pub(crate) fn whole_name(path: &Path) -> &Path {
+    match part_name(path) {
+        Some((whole, _)) => Path::new(whole),
+        None => path,
+    }
+}
+
+/// An image file, together with the parts of the split image it is one of.
+///
+/// Finding the parts lists the directory they are in, so they are found once
+/// with [`ImageFile::find`], and every read of the image opens the same parts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageFile {
+    path: PathBuf,
+    /// The parts in order, or only `path` if the image is not split.
+    parts: Vec<PathBuf>,
+}
+
+impl ImageFile {
+    /// Finds the parts of the split image that the file at `path` is one of,
+    /// if it is one. See [`split_parts`].
+    pub fn find(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let parts = split_parts(&path)?.unwrap_or_else(|| vec![path.clone()]);
+        Ok(Self { path, parts })
+    }
+
+    /// A file that is read on its own, without looking for other parts, such
+    /// as a copy of an image that etchr decompressed itself.
+    pub(crate) fn single(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Self {
+            parts: vec![path.clone()],
+            path,
+        }
+    }
+
+    /// The path the image file was found from. Its name tells the format the
+    /// image is supposed to be in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The files the image is read from, in order.
+    pub fn parts(&self) -> &[PathBuf] {
+        &self.parts
+    }
+
+    /// Whether the image is split into more than one part.
+    pub fn is_split(&self) -> bool {
+        self.parts.len() > 1
+    }
+
+    /// Opens the parts to read the image from.
+    pub fn open(&self) -> io::Result<MultiFileReader> {
+        let mut parts = Vec::with_capacity(self.parts.len());
+        let mut len = 0;
+        for path in &self.parts {
+            let file = File::open(path)?;
+            let part_len = file.metadata()?.len();
+            parts.push((file, len));
+            len += part_len;
+        }
+        Ok(MultiFileReader {
+            parts,
+            len,
+            pos: 0,
+            current: Some(0),
+        })
+    }
+}
+
+/// Reads the parts of a split image as one file.
+///
+/// A file that is not split is read as the only part, so an image file can
+/// always be opened with [`MultiFileReader::open`].
+pub struct MultiFileReader {
+    /// Each part, with the offset in the image where it starts.
+    parts: Vec<(File, u64)>,
+    len: u64,
+    pos: u64,
+    /// The part whose file position is at `pos`, if any.
+    current: Option<usize>,
+}
+
+impl MultiFileReader {
+    /// Opens the image file at `path`, together with the other parts of the
+    /// split image it is a part of. To read the image more than once, find
+    /// its parts once with [`ImageFile::find`] instead.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        ImageFile::find(path)?.open()
+    }
+
+    /// The size of the whole image, the sum of the sizes of its parts.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the image is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The offset in the image where part `index` ends.
+    fn part_end(&self, index: usize) -> u64 {
+        self.parts
+            .get(index + 1)
+            .map_or(self.len, |(_, start)| *start)
+    }
+}
+
+impl Read for MultiFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+        // Empty parts are skipped by looking for the last part starting here.
+        let index = self.parts.partition_point(|(_, start)| *start <= self.pos) - 1;
+        let end = self.part_end(index);
+        let (file, start) = &mut self.parts[index];
+        if self.current != Some(index) {
+            file.seek(SeekFrom::Start(self.pos - *start))?;
+            self.current = Some(index);
+        }
+        let max = buf.len().min((end - self.pos) as usize);
+        let n = file.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "a part of the split image got shorter while it was read",
+            ));
+        }
+        self.pos += n as u64;
+        if self.pos == end {
+            self.current = None;
+        }
+        Ok(n)
+    }
+}
+
+impl Seek for MultiFileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        let Some(pos) = pos else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        };
+        self.pos = pos;
+        self.current = None;
+        Ok(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory holding a file with each of `names`, whose contents are
+    /// its name.
+    fn dir_with(names: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for name in names {
+            fs::write(dir.path().join(name), name).unwrap();
+        }
+        dir
+    }
+
+    /// The names of the parts `split_parts` finds for `name` in `dir`.
+    fn parts_of(dir: &tempfile::TempDir, name: &str) -> io::Result<Option<Vec<String>>> {
+        let parts = split_parts(&dir.path().join(name))?;
+        Ok(parts.map(|parts| {
+            parts
+                .iter()
+                .map(|part| part.file_name().unwrap().to_str().unwrap().to_string())
+                .collect()
+        }))
+    }
+
+    #[test]
+    fn two_digit_parts_start_from_zero() {
+        let dir = dir_with(&[
+            "image.img.02",
+            "image.img.00",
+            "image.img.01",
+            // Neither of these is a part of the same image.
+            "image.img.003",
+            "other.img.01",
+        ]);
+        let expected = ["image.img.00", "image.img.01", "image.img.02"];
+        // Any part leads to all of them.
+        for name in expected {
+            assert_eq!(parts_of(&dir, name).unwrap().unwrap(), expected);
+        }
+
+        let mut image = String::new();
+        ImageFile::find(dir.path().join("image.img.01"))
+            .unwrap()
+            .open()
+            .unwrap()
+            .read_to_string(&mut image)
+            .unwrap();
+        assert_eq!(image, expected.concat());
+    }
+
+    #[test]
+    fn three_digit_parts_start_from_zero_or_one() {
+        let from_one = dir_with(&["image.img.001", "image.img.002", "image.img.003"]);
+        assert_eq!(
+            parts_of(&from_one, "image.img.002").unwrap().unwrap(),
+            ["image.img.001", "image.img.002", "image.img.003"]
+        );
+
+        let from_zero = dir_with(&["image.img.000", "image.img.001"]);
+        assert_eq!(
+            parts_of(&from_zero, "image.img.000").unwrap().unwrap(),
+            ["image.img.000", "image.img.001"]
+        );
+    }
+
+    #[test]
+    fn a_gap_is_reported() {
+        for (names, missing) in [
+            (
+                &["image.img.00", "image.img.01", "image.img.03"][..],
+                "image.img.02",
+            ),
+            // Two digit parts start from 0.
+            (&["image.img.01", "image.img.02"][..], "image.img.00"),
+            // Three digit parts start from 1 at the latest.
+            (&["image.img.002", "image.img.003"][..], "image.img.001"),
+        ] {
+            let dir = dir_with(names);
+            let e = parts_of(&dir, names[1]).unwrap_err();
+            match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+                Some(Error::SplitImageIncomplete {
+                    parts,
+                    missing: found,
+                }) => {
+                    assert_eq!(parts.len(), names.len());
+                    assert_eq!(found, &[missing]);
+                }
+                other => panic!("{:?}: {:?}", names, other),
+            }
+        }
+    }
+
+    #[test]
+    fn a_numbered_file_alone_is_not_split() {
+        for name in ["image.img.00", "image.img.001", "image.img.07"] {
+            let dir = dir_with(&[name, "image.img"]);
+            assert_eq!(parts_of(&dir, name).unwrap(), None);
+
+            let file = ImageFile::find(dir.path().join(name)).unwrap();
+            assert!(!file.is_split());
+            assert_eq!(file.parts(), [dir.path().join(name)]);
+        }
+        // Nor is a file whose name does not end in a number.
+        let dir = dir_with(&["image.img", "image.img.00", "image.img.01"]);
+        assert_eq!(parts_of(&dir, "image.img").unwrap(), None);
+    }
+}
@@ -0,0 +1,57 @@
+//! Rate limiting for the read and write loops.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Sleeps for `duration`, waking early if the operation is cancelled.
+///
+/// Returns `false` if the operation was cancelled while sleeping.
+pub(crate) fn cancellable_sleep(duration: Duration, running: &AtomicBool) -> bool {
+    let deadline = Instant::now() + duration;
+    while running.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        std::thread::sleep((deadline - now).min(Duration::from_millis(50)));
+    }
+    false
+}
+
+/// A token bucket that keeps the average transfer rate at or below a limit.
+///
+/// The bucket holds at most a tenth of a second worth of bytes, so a device
+/// that stalls for a while cannot burst far past the limit afterwards.
+pub(crate) struct RateLimiter {
+    bytes_per_sec: f64,
+    /// Bytes that may be transferred without waiting. Negative while the
+    /// caller is ahead of the limit.
+    tokens: f64,
+    capacity: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self {
+            bytes_per_sec,
+            tokens: 0.0,
+            capacity: bytes_per_sec / 10.0,
+            last: Instant::now(),
+        }
+    }
+
+    /// Accounts for `bytes` that were just transferred, sleeping for as long
+    /// as it takes to get back under the limit.
+    pub(crate) fn throttle(&mut self, bytes: u64, running: &AtomicBool) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.bytes_per_sec;
+        self.tokens = (self.tokens + refill).min(self.capacity) - bytes as f64;
+        self.last = now;
+
+        if self.tokens < 0.0 {
+            let wait = Duration::from_secs_f64(-self.tokens / self.bytes_per_sec);
+            cancellable_sleep(wait, running);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Working out which parts of a device its filesystems actually use.
+//!
+//! Even when a read stops at the last partition, most of what it copies is
+//! usually free space inside the filesystems. For each partition, the
+//! providers below look for a filesystem they recognise and read its
+//! allocation metadata (the block bitmaps of ext2/3/4, the allocation table
+//! of FAT) to list the blocks that hold data. Everything else is treated as
+//! used: the partition table, the gaps between partitions (where boot loaders
+//! often live), and partitions whose filesystem is not recognised.
+mod ext4;
+mod fat;
+
+use crate::partition_table;
+use crate::warning::{Warning, WarningKind};
+use anyhow::{Result, anyhow};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+/// A provider reads the allocation metadata of one kind of filesystem. It
+/// returns the byte ranges of the volume that are in use, or `None` if the
+/// volume does not hold its kind of filesystem.
+type Provider = fn(&mut Volume) -> Result<Option<Vec<Range<u64>>>>;
+
+/// The providers to try on each partition, with the names of their
+/// filesystems for messages.
+const PROVIDERS: [(&str, Provider); 2] = [("ext2/3/4", ext4::used), ("FAT", fat::used)];
+
+/// A partition, or a whole device without a partition table, that may hold a
+/// filesystem.
+struct Volume<'f> {
+    file: &'f mut File,
+    start: u64,
+    len: u64,
+}
+
+impl Volume<'_> {
+    /// The size of the volume in bytes.
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Reads `buf.len()` bytes at `offset` within the volume.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        if offset.saturating_add(buf.len() as u64) > self.len {
+            return Err(anyhow!(
+                "the filesystem extends past the end of its partition"
+            ));
+        }
+        self.file.seek(SeekFrom::Start(self.start + offset))?;
+        self.file.read_exact(buf)?;
+        Ok(())
+    }
+}
+
+/// Returns the parts of a device of `len` bytes that have to be read: all of
+/// it except the blocks that a recognised filesystem reports as free. The
+/// ranges are in order, do not overlap and are rounded out to whole sectors
+/// of `sector` bytes.
+///
+/// Whatever cannot be worked out is read in full, with a warning sent to
+/// `on_warning`. The device must not be open with `O_DIRECT`.
+pub(crate) fn used_ranges(
+    file: &mut File,
+    sector: u64,
+    len: u64,
+    on_warning: &mut dyn FnMut(Warning),
+) -> Vec<Range<u64>> {
+    let mut free = Vec::new();
+    match partition_table::partitions(file, 0, sector, len) {
+        Ok(Some(partitions)) => {
+            for partition in partitions {
+                let name = format!("Partition {}", partition.number);
+                if partition.extended {
+                    on_warning(Warning::new(
+                        WarningKind::UnknownFilesystem,
+                        format!(
+                            "{} is an extended partition, so its logical partitions are read in full.",
+                            name
+                        ),
+                    ));
+                    continue;
+                }
+                free.extend(free_ranges(file, partition.range, &name, on_warning));
+            }
+        }
+        // A filesystem may also take up the whole device.
+        Ok(None) => free.extend(free_ranges(file, 0..len, "The device", on_warning)),
+        Err(e) => on_warning(Warning::new(
+            WarningKind::UnknownFilesystem,
+            format!(
+                "The partition table of the device cannot be used ({}), so all of it is read.",
+                e
+            ),
+        )),
+    }
+
+    // Only whole sectors can be skipped.
+    free.sort_by_key(|r| r.start);
+    let mut used = Vec::new();
+    let mut pos = 0;
+    for range in free {
+        let (start, end) = (
+            range.start.next_multiple_of(sector),
+            range.end / sector * sector,
+        );
+        if start >= end {
+            continue;
+        }
+        if start > pos {
+            used.push(pos..start);
+        }
+        pos = pos.max(end);
+    }
+    if pos < len {
+        used.push(pos..len);
+    }
+    used
+}
+
+/// Returns the free parts of the volume at `range` on the device, in device
+/// offsets, or nothing if its filesystem is not recognised. `name` names the
+/// volume in warnings.
+fn free_ranges(
+    file: &mut File,
+    range: Range<u64>,
+    name: &str,
+    on_warning: &mut dyn FnMut(Warning),
+) -> Vec<Range<u64>> {
+    let mut volume = Volume {
+        file,
+        start: range.start,
+        len: range.end - range.start,
+    };
+    for (filesystem, provider) in PROVIDERS {
+        match provider(&mut volume) {
+            Ok(Some(mut used)) => {
+                used.sort_by_key(|r| r.start);
+                let mut free = Vec::new();
+                let mut pos = 0;
+                for r in used {
+                    if r.start > pos {
+                        free.push(range.start + pos..range.start + r.start);
+                    }
+                    pos = pos.max(r.end);
+                }
+                if pos < volume.len() {
+                    free.push(range.start + pos..range.end);
+                }
+                return free;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                on_warning(Warning::new(
+                    WarningKind::UnknownFilesystem,
+                    format!(
+                        "{} holds a {} filesystem that cannot be used ({}), so all of it is read.",
+                        name, filesystem, e
+                    ),
+                ));
+                return Vec::new();
+            }
+        }
+    }
+    on_warning(Warning::new(
+        WarningKind::UnknownFilesystem,
+        format!(
+            "{} holds no filesystem that etchr recognises, so all of it is read.",
+            name
+        ),
+    ));
+    Vec::new()
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+/// Appends `start..end` to `ranges`, merging it with the last range if they
+/// touch or overlap.
+fn push_range(ranges: &mut Vec<Range<u64>>, start: u64, end: u64) {
+    match ranges.last_mut() {
+        Some(last) if last.end >= start => last.end = last.end.max(end),
+        _ => ranges.push(start..end),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::Path;
+
+    /// Decompresses the fixture `name` from `tests/data` into a temporary
+    /// file.
+    pub(crate) fn fixture(name: &str) -> File {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/data")
+            .join(name);
+        let mut file = tempfile::tempfile().unwrap();
+        zstd::stream::copy_decode(File::open(path).unwrap(), &mut file).unwrap();
+        file
+    }
+
+    /// A device of `len` bytes with an MBR holding `partitions`, each a
+    /// partition type, first sector and sector count.
+    pub(crate) fn mbr_device(len: u64, partitions: &[(u8, u32, u32)]) -> File {
+        let mut mbr = [0u8; 512];
+        for (i, &(kind, first, count)) in partitions.iter().enumerate() {
+            let entry = 446 + 16 * i;
+            mbr[entry + 4] = kind;
+            mbr[entry + 8..entry + 12].copy_from_slice(&first.to_le_bytes());
+            mbr[entry + 12..entry + 16].copy_from_slice(&count.to_le_bytes());
+        }
+        mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(len).unwrap();
+        file.write_all(&mbr).unwrap();
+        file
+    }
+
+    fn used_ranges_of(file: &mut File) -> (Vec<Range<u64>>, Vec<Warning>) {
+        let len = file.metadata().unwrap().len();
+        let mut warnings = Vec::new();
+        let used = used_ranges(file, 512, len, &mut |w| warnings.push(w));
+        (used, warnings)
+    }
+
+    #[test]
+    fn whole_device_filesystem() {
+        let mut file = fixture("ext2.img.zst");
+        let (used, warnings) = used_ranges_of(&mut file);
+        assert_eq!(used, [0..651 * 1024, 8193 * 1024..8772 * 1024]);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn partitions_are_read_at_their_offsets() {
+        const MIB: u64 = 1 << 20;
+        // A 16 MiB ext2 filesystem at 1 MiB, then an empty partition.
+        let mut device = mbr_device(24 * MIB, &[(0x83, 2048, 32768), (0x83, 36864, 4096)]);
+        let mut ext2 = fixture("ext2.img.zst");
+        ext2.rewind().unwrap();
+        device.seek(SeekFrom::Start(MIB)).unwrap();
+        std::io::copy(&mut ext2, &mut device).unwrap();
+
+        let (used, warnings) = used_ranges_of(&mut device);
+        // The table and the gap before the first partition are read, as are
+        // the second partition and the space after it.
+        assert_eq!(
+            used,
+            [
+                0..MIB + 651 * 1024,
+                MIB + 8193 * 1024..MIB + 8772 * 1024,
+                17 * MIB..24 * MIB,
+            ]
+        );
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::UnknownFilesystem);
+        assert!(warnings[0].message.starts_with("Partition 2 "));
+    }
+
+    #[test]
+    fn extended_partitions_are_read_in_full() {
+        let mut device = mbr_device(4 << 20, &[(0x05, 2048, 4096)]);
+        let (used, warnings) = used_ranges_of(&mut device);
+        assert_eq!(used.first(), Some(&(0..4 << 20)));
+        assert_eq!(used.len(), 1);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].message.contains("extended partition"));
+    }
+}
@@ -0,0 +1,258 @@
+//! The block bitmaps of ext2, ext3 and ext4.
+//!
+//! The blocks of the filesystem are split into groups, each with a bitmap
+//! marking the blocks in use. A group whose bitmap was never written (flagged
+//! `BLOCK_UNINIT`) only holds the metadata that the descriptors point at, and
+//! the backup superblock and descriptors if it has them.
+use super::{Volume, push_range, u16_at, u32_at};
+use anyhow::{Result, anyhow};
+use std::ops::Range;
+
+/// The magic number of the superblock.
+const MAGIC: u16 = 0xEF53;
+
+const COMPAT_RESIZE_INODE: u32 = 0x10;
+const COMPAT_SPARSE_SUPER2: u32 = 0x200;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_64BIT: u32 = 0x80;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_BIGALLOC: u32 = 0x200;
+
+/// The flag of a group descriptor whose block bitmap is not initialised.
+const BG_BLOCK_UNINIT: u16 = 0x2;
+
+/// The locations a group descriptor points at, in blocks.
+struct Group {
+    block_bitmap: u64,
+    inode_bitmap: u64,
+    inode_table: u64,
+    flags: u16,
+}
+
+pub(super) fn used(volume: &mut Volume) -> Result<Option<Vec<Range<u64>>>> {
+    // The superblock is always 1024 bytes into the volume.
+    if volume.len() < 2048 {
+        return Ok(None);
+    }
+    let mut sb = [0u8; 1024];
+    volume.read_at(1024, &mut sb)?;
+    if u16_at(&sb, 56) != MAGIC {
+        return Ok(None);
+    }
+
+    let log_block_size = u32_at(&sb, 24);
+    if log_block_size > 6 {
+        return Err(anyhow!("the block size is out of range"));
+    }
+    let block_size = 1024u64 << log_block_size;
+    let compat = u32_at(&sb, 0x5C);
+    let incompat = u32_at(&sb, 0x60);
+    let ro_compat = u32_at(&sb, 0x64);
+    if incompat & INCOMPAT_META_BG != 0 {
+        return Err(anyhow!("meta block groups are not supported"));
+    }
+    if ro_compat & RO_COMPAT_BIGALLOC != 0 {
+        return Err(anyhow!("bigalloc is not supported"));
+    }
+    let is_64bit = incompat & INCOMPAT_64BIT != 0;
+    let mut blocks_count = u32_at(&sb, 4) as u64;
+    if is_64bit {
+        blocks_count |= (u32_at(&sb, 0x150) as u64) << 32;
+    }
+    let first_data_block = u32_at(&sb, 20) as u64;
+    let blocks_per_group = u32_at(&sb, 32) as u64;
+    let inodes_per_group = u32_at(&sb, 40) as u64;
+    // Revision 0 has fixed-size inodes.
+    let inode_size = match u32_at(&sb, 76) {
+        0 => 128,
+        _ => u16_at(&sb, 88) as u64,
+    };
+    let desc_size = if is_64bit {
+        u16_at(&sb, 0xFE) as usize
+    } else {
+        32
+    };
+    if blocks_per_group == 0 || blocks_per_group > block_size * 8 || desc_size < 32 {
+        return Err(anyhow!("the superblock is corrupt"));
+    }
+    if blocks_count <= first_data_block || blocks_count.saturating_mul(block_size) > volume.len() {
+        return Err(anyhow!("the filesystem does not fit its partition"));
+    }
+
+    let groups = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+    let gdt_blocks = (groups * desc_size as u64).div_ceil(block_size);
+    let reserved_gdt_blocks = if compat & COMPAT_RESIZE_INODE != 0 {
+        u16_at(&sb, 0xCE) as u64
+    } else {
+        0
+    };
+    let inode_table_blocks = (inodes_per_group * inode_size).div_ceil(block_size);
+    let backup_groups = [u32_at(&sb, 0x24C) as u64, u32_at(&sb, 0x250) as u64];
+    let has_backup = |group: u64| {
+        if group == 0 {
+            true
+        } else if compat & COMPAT_SPARSE_SUPER2 != 0 {
+            backup_groups.contains(&group)
+        } else if ro_compat & RO_COMPAT_SPARSE_SUPER != 0 {
+            group == 1 || [3, 5, 7].iter().any(|&base| is_power_of(group, base))
+        } else {
+            true
+        }
+    };
+
+    let mut gdt = vec![0u8; (gdt_blocks * block_size) as usize];
+    volume.read_at((first_data_block + 1) * block_size, &mut gdt)?;
+    let descriptors: Vec<Group> = gdt
+        .chunks_exact(desc_size)
+        .take(groups as usize)
+        .map(|d| {
+            let hi = |at| if is_64bit { u32_at(d, at) as u64 } else { 0 };
+            Group {
+                block_bitmap: u32_at(d, 0) as u64 | hi(0x20) << 32,
+                inode_bitmap: u32_at(d, 4) as u64 | hi(0x24) << 32,
+                inode_table: u32_at(d, 8) as u64 | hi(0x28) << 32,
+                flags: u16_at(d, 0x12),
+            }
+        })
+        .collect();
+
+    // The metadata of every group, which may lie in any group.
+    let mut metadata: Vec<Range<u64>> = Vec::new();
+    for (group, descriptor) in (0..groups).zip(&descriptors) {
+        if has_backup(group) {
+            let start = first_data_block + group * blocks_per_group;
+            metadata.push(start..start + 1 + gdt_blocks + reserved_gdt_blocks);
+        }
+        metadata.push(descriptor.block_bitmap..descriptor.block_bitmap + 1);
+        metadata.push(descriptor.inode_bitmap..descriptor.inode_bitmap + 1);
+        metadata.push(descriptor.inode_table..descriptor.inode_table + inode_table_blocks);
+    }
+    metadata.sort_by_key(|r| r.start);
+
+    // The boot sector and superblock come before the first group with 1 KiB
+    // blocks, and share its first block otherwise.
+    let mut used = Vec::new();
+    push_range(&mut used, 0, (first_data_block + 1) * block_size);
+    let mut bitmap = vec![0u8; block_size as usize];
+    for (group, descriptor) in (0..groups).zip(&descriptors) {
+        let start = first_data_block + group * blocks_per_group;
+        let end = (start + blocks_per_group).min(blocks_count);
+        if descriptor.flags & BG_BLOCK_UNINIT != 0 {
+            for r in &metadata {
+                let (from, to) = (r.start.max(start), r.end.min(end));
+                if from < to {
+                    push_range(&mut used, from * block_size, to * block_size);
+                }
+            }
+            continue;
+        }
+        if descriptor.block_bitmap >= blocks_count {
+            return Err(anyhow!("the bitmap of group {} is out of range", group));
+        }
+        volume.read_at(descriptor.block_bitmap * block_size, &mut bitmap)?;
+        for block in start..end {
+            let bit = (block - start) as usize;
+            if bitmap[bit / 8] & (1 << (bit % 8)) != 0 {
+                push_range(&mut used, block * block_size, (block + 1) * block_size);
+            }
+        }
+    }
+    Ok(Some(used))
+}
+
+/// Whether `n` is a power of `base`, counting `base` itself.
+fn is_power_of(n: u64, base: u64) -> bool {
+    let mut power = base;
+    while power < n {
+        power *= base;
+    }
+    power == n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usedblocks::tests::fixture;
+    use std::io::{Seek, SeekFrom, Write};
+
+    /// The used parts of the filesystem in the fixture `name`, in blocks of
+    /// `block_size`.
+    fn used_blocks(name: &str, block_size: u64) -> Vec<Range<u64>> {
+        let mut file = fixture(name);
+        let len = file.metadata().unwrap().len();
+        let mut volume = Volume {
+            file: &mut file,
+            start: 0,
+            len,
+        };
+        used(&mut volume)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.start / block_size..r.end / block_size)
+            .collect()
+    }
+
+    // The expected blocks are the complement of what `dumpe2fs` lists as
+    // free in each fixture.
+
+    #[test]
+    fn ext2() {
+        assert_eq!(used_blocks("ext2.img.zst", 1024), [0..651, 8193..8772]);
+    }
+
+    #[test]
+    fn ext4_with_flex_bg_and_uninit_groups() {
+        assert_eq!(
+            used_blocks("ext4.img.zst", 1024),
+            [0..2384, 8193..8450, 16385..20481, 24577..24834]
+        );
+    }
+
+    #[test]
+    fn ext4_without_flex_bg() {
+        assert_eq!(
+            used_blocks("ext4-noflex.img.zst", 1024),
+            [0..842, 8193..8964, 16385..20995, 24577..25348]
+        );
+    }
+
+    #[test]
+    fn ext4_with_4k_blocks() {
+        assert_eq!(
+            used_blocks("ext4-4k.img.zst", 4096),
+            [0..350, 1024..1089, 2048..3137]
+        );
+    }
+
+    #[test]
+    fn unsupported_features_are_refused() {
+        for (at, flag) in [(0x60, INCOMPAT_META_BG), (0x64, RO_COMPAT_BIGALLOC)] {
+            let mut file = fixture("ext2.img.zst");
+            let len = file.metadata().unwrap().len();
+            let mut volume = Volume {
+                file: &mut file,
+                start: 0,
+                len,
+            };
+            let mut features = [0u8; 4];
+            volume.read_at(1024 + at, &mut features).unwrap();
+            let features = u32::from_le_bytes(features) | flag;
+            volume.file.seek(SeekFrom::Start(1024 + at)).unwrap();
+            volume.file.write_all(&features.to_le_bytes()).unwrap();
+            assert!(used(&mut volume).is_err());
+        }
+    }
+
+    #[test]
+    fn other_filesystems_are_not_recognised() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(1 << 20).unwrap();
+        let mut volume = Volume {
+            file: &mut file,
+            start: 0,
+            len: 1 << 20,
+        };
+        assert!(used(&mut volume).unwrap().is_none());
+    }
+}
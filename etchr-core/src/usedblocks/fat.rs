@@ -0,0 +1,220 @@
+//! The allocation table of FAT12, FAT16 and FAT32.
+//!
+//! Everything before the data area (the reserved sectors, the tables and, up
+//! to FAT16, the root directory) is in use. A cluster of the data area is in
+//! use when its entry in the first table is neither free nor marked bad.
+use super::{Volume, push_range, u16_at, u32_at};
+use anyhow::{Result, anyhow};
+use std::ops::Range;
+
+pub(super) fn used(volume: &mut Volume) -> Result<Option<Vec<Range<u64>>>> {
+    if volume.len() < 512 {
+        return Ok(None);
+    }
+    let mut boot = [0u8; 512];
+    volume.read_at(0, &mut boot)?;
+    let bytes_per_sector = u16_at(&boot, 11) as u64;
+    let sectors_per_cluster = boot[13] as u64;
+    let reserved_sectors = u16_at(&boot, 14) as u64;
+    let fat_count = boot[16] as u64;
+    let root_entries = u16_at(&boot, 17) as u64;
+    let total_sectors = match u16_at(&boot, 19) {
+        0 => u32_at(&boot, 32) as u64,
+        n => n as u64,
+    };
+    let fat_sectors = match u16_at(&boot, 22) {
+        0 => u32_at(&boot, 36) as u64,
+        n => n as u64,
+    };
+    // NTFS and exFAT boot sectors start the same way, but fail these checks.
+    let looks_like_fat = [0xEB, 0xE9].contains(&boot[0])
+        && boot[510..512] == [0x55, 0xAA]
+        && [512, 1024, 2048, 4096].contains(&bytes_per_sector)
+        && sectors_per_cluster.is_power_of_two()
+        && reserved_sectors > 0
+        && fat_count > 0
+        && fat_sectors > 0
+        && total_sectors > 0;
+    if !looks_like_fat {
+        return Ok(None);
+    }
+
+    let root_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+    let data_start = reserved_sectors + fat_count * fat_sectors + root_sectors;
+    if total_sectors * bytes_per_sector > volume.len() || data_start >= total_sectors {
+        return Err(anyhow!("the filesystem does not fit its partition"));
+    }
+    // The type of FAT follows from the number of clusters alone.
+    let clusters = (total_sectors - data_start) / sectors_per_cluster;
+    let (entry_bits, bad) = if clusters < 4085 {
+        (12, 0xFF7)
+    } else if clusters < 65525 {
+        (16, 0xFFF7)
+    } else {
+        (32, 0x0FFF_FFF7)
+    };
+    let fat_len = fat_sectors * bytes_per_sector;
+    if (clusters + 2) * entry_bits / 8 > fat_len {
+        return Err(anyhow!(
+            "the allocation table is too small for the filesystem"
+        ));
+    }
+
+    // A spare byte lets the last FAT12 entry be read as a pair of bytes.
+    let mut fat = vec![0u8; fat_len as usize + 1];
+    volume.read_at(
+        reserved_sectors * bytes_per_sector,
+        &mut fat[..fat_len as usize],
+    )?;
+    let entry = |n: u64| -> u32 {
+        match entry_bits {
+            12 => {
+                let at = (n + n / 2) as usize;
+                let pair = u16_at(&fat, at);
+                if n.is_multiple_of(2) {
+                    (pair & 0x0FFF) as u32
+                } else {
+                    (pair >> 4) as u32
+                }
+            }
+            16 => u16_at(&fat, n as usize * 2) as u32,
+            _ => u32_at(&fat, n as usize * 4) & 0x0FFF_FFFF,
+        }
+    };
+
+    let cluster_len = sectors_per_cluster * bytes_per_sector;
+    let data_offset = data_start * bytes_per_sector;
+    let mut used = Vec::new();
+    push_range(&mut used, 0, data_offset);
+    for cluster in 2..clusters + 2 {
+        let value = entry(cluster);
+        if value != 0 && value != bad {
+            let start = data_offset + (cluster - 2) * cluster_len;
+            push_range(&mut used, start, start + cluster_len);
+        }
+    }
+    Ok(Some(used))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom, Write};
+
+    /// A FAT volume of `sectors` 512-byte sectors and 1-sector clusters with
+    /// `bits`-bit entries, the given `entries` set in its first table.
+    /// Returns it with the offset of its data area.
+    fn volume(bits: u64, sectors: u64, entries: &[(u64, u32)]) -> (File, u64) {
+        let (reserved, root_entries) = if bits == 32 { (32, 0) } else { (1, 512) };
+        let fat_sectors = ((sectors + 2) * bits / 8).div_ceil(512);
+        let mut boot = [0u8; 512];
+        boot[0] = 0xEB;
+        boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+        boot[13] = 1;
+        boot[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+        boot[16] = 2;
+        boot[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+        boot[32..36].copy_from_slice(&(sectors as u32).to_le_bytes());
+        if bits == 32 {
+            boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+        } else {
+            boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+        }
+        boot[510..].copy_from_slice(&[0x55, 0xAA]);
+
+        let mut fat = vec![0u8; (fat_sectors * 512) as usize + 1];
+        for &(n, value) in entries {
+            match bits {
+                12 => {
+                    let at = (n + n / 2) as usize;
+                    let pair = u16_at(&fat, at);
+                    let pair = if n.is_multiple_of(2) {
+                        pair & 0xF000 | value as u16
+                    } else {
+                        pair & 0x000F | (value as u16) << 4
+                    };
+                    fat[at..at + 2].copy_from_slice(&pair.to_le_bytes());
+                }
+                16 => fat[n as usize * 2..][..2].copy_from_slice(&(value as u16).to_le_bytes()),
+                _ => fat[n as usize * 4..][..4].copy_from_slice(&value.to_le_bytes()),
+            }
+        }
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(sectors * 512).unwrap();
+        file.write_all(&boot).unwrap();
+        file.seek(SeekFrom::Start(reserved * 512)).unwrap();
+        file.write_all(&fat[..fat.len() - 1]).unwrap();
+        let root_sectors = root_entries * 32 / 512;
+        (file, (reserved + 2 * fat_sectors + root_sectors) * 512)
+    }
+
+    fn used_of(file: &mut File) -> Result<Option<Vec<Range<u64>>>> {
+        let len = file.metadata().unwrap().len();
+        used(&mut Volume {
+            file,
+            start: 0,
+            len,
+        })
+    }
+
+    /// Checks a volume whose clusters 2 to 4, 10 and the last are in use,
+    /// with cluster 6 marked bad and cluster 8 free.
+    fn check(bits: u64, sectors: u64, end: u32, bad: u32, free: u32) {
+        let probe = volume(bits, sectors, &[]).1 / 512;
+        let last = sectors - probe + 1;
+        let entries = [
+            (2, 3),
+            (3, 4),
+            (4, end),
+            (6, bad),
+            (8, free),
+            (10, end),
+            (last, end),
+        ];
+        let (mut file, data) = volume(bits, sectors, &entries);
+        let used = used_of(&mut file).unwrap().unwrap();
+        let cluster = |n: u64| data + (n - 2) * 512;
+        assert_eq!(
+            used,
+            [
+                0..cluster(5),
+                cluster(10)..cluster(11),
+                cluster(last)..cluster(last + 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn fat12() {
+        check(12, 4000, 0xFFF, 0xFF7, 0);
+    }
+
+    #[test]
+    fn fat16() {
+        check(16, 20_000, 0xFFFF, 0xFFF7, 0);
+    }
+
+    #[test]
+    fn fat32() {
+        // The top four bits of a FAT32 entry are reserved.
+        check(32, 70_000, 0x0FFF_FFFF, 0x0FFF_FFF7, 0xF000_0000);
+    }
+
+    #[test]
+    fn other_filesystems_are_not_recognised() {
+        let (mut file, _) = volume(16, 20_000, &[]);
+        file.rewind().unwrap();
+        file.write_all(&[0; 512]).unwrap();
+        assert!(used_of(&mut file).unwrap().is_none());
+    }
+
+    #[test]
+    fn a_table_too_small_is_refused() {
+        let (mut file, _) = volume(16, 20_000, &[]);
+        file.seek(SeekFrom::Start(22)).unwrap();
+        file.write_all(&1u16.to_le_bytes()).unwrap();
+        assert!(used_of(&mut file).is_err());
+    }
+}
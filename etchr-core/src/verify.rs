@@ -0,0 +1,715 @@
+//! Checking a device against an image without writing it.
+//!
+//! A [`VerifyOptions`] reads the image (decompressing it on the fly if
+//! needed) and the device side by side, and checks that the device holds the
+//! image, as the verify stage of a write does. This confirms that a card
+//! still holds an image written to it earlier, without writing it again.
+use crate::buffer::AlignedBuffer;
+use crate::device::{DirectIo, SectorSizes};
+use crate::error::{self, Error};
+use crate::hash::{Digest, HashAlgorithm, Hasher};
+use crate::os_options::DeviceOpenOptions;
+use crate::platform;
+use crate::progress::{Stage, StageTiming};
+use crate::source::ImageFile;
+use crate::warning::{Warning, WarningKind};
+use crate::write::{
+    DEFAULT_ZSTD_WINDOW_LOG_MAX, check_zstd_window_log_max, io_error, open_image, read_full,
+};
+use anyhow::{Result, anyhow};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Use a 1 MiB buffer for I/O operations.
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// How many chunks of the image can be read ahead of the device.
+const READ_AHEAD: usize = 4;
+
+/// The size of the chunks that a sampled verification picks from.
+const SAMPLE_CHUNK: u64 = 1024 * 1024;
+
+/// How much of the start and of the end of the image a sampled verification
+/// always checks, as that is where partition tables and boot loaders live.
+const SAMPLE_EDGE: u64 = 4 * 1024 * 1024;
+
+/// How the device is checked against the image, set with
+/// [`VerifyOptions::method`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VerifyMethod {
+    /// The whole device is read and its hash compared with the image's. A
+    /// mismatch ([`Error::HashMismatch`]) only says that the two differ.
+    #[default]
+    Hash,
+    /// Each chunk of the device is compared with the image as it is read, and
+    /// the first byte that differs fails the check with
+    /// [`Error::VerifyMismatch`], which gives its offset. A failing device is
+    /// caught without reading the rest of it.
+    Compare,
+}
+
+/// A summary of a successful verification, returned by
+/// [`VerifyOptions::run`].
+#[derive(Clone, Debug)]
+pub struct VerifyReport {
+    /// The number of bytes of the device that were checked, which is the size
+    /// of the (decompressed) image.
+    pub bytes_verified: u64,
+    /// The digest of the (decompressed) image data, in the algorithm set with
+    /// [`VerifyOptions::hash`]. The device matched it.
+    pub digest: Digest,
+    /// The digest of what the device holds in place of the image, in the same
+    /// algorithm, which is the same as `digest`. With
+    /// [`VerifyMethod::Compare`], the device is not hashed but matched the
+    /// image byte for byte, so this is `digest` as well.
+    pub device_digest: Digest,
+    /// Time spent reading the image and the device.
+    pub duration: Duration,
+    /// The sector sizes of the device.
+    pub sector_sizes: SectorSizes,
+    /// Whether the device was read with `O_DIRECT`. It is read through the
+    /// page cache if [`DirectIo::Off`] was requested, or if it rejected
+    /// `O_DIRECT`.
+    pub direct_io: bool,
+}
+
+impl VerifyReport {
+    /// The bytes checked and the time it took.
+    pub fn timing(&self) -> StageTiming {
+        StageTiming {
+            bytes: self.bytes_verified,
+            duration: self.duration,
+        }
+    }
+}
+
+/// The options for checking that a device holds an image.
+///
+/// Nothing is written to the device. Anything on it past the end of the image
+/// is ignored.
+///
+/// ```rust,no_run
+/// use etchr_core::verify::VerifyOptions;
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let report = VerifyOptions::new("raspios.img.xz", "/dev/sdb")
+///     .on_verify_progress(|bytes| println!("{} bytes verified", bytes))
+///     .run()?;
+/// println!("The device holds the image ({})", report.digest);
+/// # Ok(())
+/// # }
+/// ```
+pub struct VerifyOptions<'a> {
+    image_path: PathBuf,
+    device_path: PathBuf,
+    offset: u64,
+    buffer_size: usize,
+    direct_io: DirectIo,
+    method: VerifyMethod,
+    hash: HashAlgorithm,
+    zstd_window_log_max: u32,
+    running: Arc<AtomicBool>,
+    on_verify_start: Box<dyn FnMut(u64) + 'a>,
+    on_verify_progress: Box<dyn FnMut(u64) + 'a>,
+    on_warning: Box<dyn FnMut(Warning) + 'a>,
+}
+
+impl<'a> VerifyOptions<'a> {
+    /// Creates the options for checking the device at `device_path` against
+    /// the image at `image_path`. A gzip, xz, zstd, bzip2 or lz4 image is
+    /// decompressed as it is read, and the image inside a zip archive is read
+    /// from it (see [`crate::image::detect_format`]). The device may also be a
+    /// regular file, such as a copy of the image.
+    pub fn new(image_path: impl Into<PathBuf>, device_path: impl Into<PathBuf>) -> Self {
+        Self {
+            image_path: image_path.into(),
+            device_path: device_path.into(),
+            offset: 0,
+            buffer_size: BUFFER_SIZE,
+            direct_io: DirectIo::default(),
+            method: VerifyMethod::default(),
+            hash: HashAlgorithm::default(),
+            zstd_window_log_max: DEFAULT_ZSTD_WINDOW_LOG_MAX,
+            running: Arc::new(AtomicBool::new(true)),
+            on_verify_start: Box::new(|_| {}),
+            on_verify_progress: Box::new(|_| {}),
+            on_warning: Box::new(|_| {}),
+        }
+    }
+
+    /// Where the image starts on the device, in bytes, as set with
+    /// [`WriteOptions::offset`](crate::write::WriteOptions::offset) when it
+    /// was written. Defaults to 0.
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// The size of each chunk read from the device. Must be a non-zero
+    /// multiple of its logical sector size. Defaults to 1 MiB.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Whether to read the device with `O_DIRECT`, so that the data comes
+    /// from the device rather than the page cache. Defaults to
+    /// [`DirectIo::Preferred`].
+    pub fn direct_io(mut self, direct_io: DirectIo) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// How the device is checked against the image. Defaults to
+    /// [`VerifyMethod::Hash`].
+    pub fn method(mut self, method: VerifyMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// The algorithm the image and the device are hashed with. A faster one
+    /// such as [`HashAlgorithm::Blake3`] keeps hashing from slowing down the
+    /// check of a fast device. Defaults to [`HashAlgorithm::Sha256`].
+    pub fn hash(mut self, hash: HashAlgorithm) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// The largest window, as a power of two, that a zstd image may need to
+    /// be decompressed, as for [`WriteOptions::zstd_window_log_max`]. Defaults
+    /// to 31 (30 on 32-bit platforms).
+    ///
+    /// [`WriteOptions::zstd_window_log_max`]: crate::write::WriteOptions::zstd_window_log_max
+    pub fn zstd_window_log_max(mut self, zstd_window_log_max: u32) -> Self {
+        self.zstd_window_log_max = zstd_window_log_max;
+        self
+    }
+
+    /// A flag that cancels the operation when set to `false`.
+    pub fn running(mut self, running: Arc<AtomicBool>) -> Self {
+        self.running = running;
+        self
+    }
+
+    /// Called when verification begins with the size of the image, or 0 for
+    /// a compressed image whose size is only known once it has been read.
+    pub fn on_verify_start(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_verify_start = Box::new(f);
+        self
+    }
+
+    /// Called with the number of bytes verified so far.
+    pub fn on_verify_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_verify_progress = Box::new(f);
+        self
+    }
+
+    /// Called with each [`Warning`] about a problem that does not stop the
+    /// verification, such as a device that rejected `O_DIRECT` and is read
+    /// through the page cache.
+    pub fn on_warning(mut self, f: impl FnMut(Warning) + 'a) -> Self {
+        self.on_warning = Box::new(f);
+        self
+    }
+
+    /// Checks the device against the image.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The image or the device cannot be opened.
+    /// - The image does not fit on the device at the offset
+    ///   ([`Error::ImageTooLarge`]), or, for a compressed image, the device
+    ///   ends before the image does.
+    /// - The buffer size is not a non-zero multiple of the device's logical
+    ///   sector size.
+    /// - An I/O error occurs. Errors reading the image are reported as an
+    ///   [`Error::Io`] in the [`Stage::Decompress`] stage, and errors reading
+    ///   the device in the [`Stage::Verify`] stage, or as
+    ///   [`Error::DeviceRemoved`] if it was unplugged.
+    /// - The device does not hold the image ([`Error::HashMismatch`], giving
+    ///   both digests). With [`VerifyMethod::Compare`], this is an
+    ///   [`Error::VerifyMismatch`] giving the first byte that differs.
+    /// - The operation is cancelled by the user ([`Error::Cancelled`]).
+    pub fn run(&mut self) -> Result<VerifyReport> {
+        self.verify().map_err(|e| error::detect_removal(e, 0))
+    }
+
+    /// Does the work of [`VerifyOptions::run`].
+    fn verify(&mut self) -> Result<VerifyReport> {
+        check_zstd_window_log_max(self.zstd_window_log_max)?;
+        let image = ImageFile::find(self.image_path.as_path())
+            .map_err(io_error(Stage::Decompress, None))?;
+        let mut source = open_image(&image, self.zstd_window_log_max)
+            .map_err(io_error(Stage::Decompress, None))?;
+        let (mut device, fallback) = DeviceOpenOptions::read_only()
+            .direct_io(self.direct_io)
+            .open(&self.device_path)?;
+        let mut direct_io = self.direct_io != DirectIo::Off;
+        if let Some(e) = fallback {
+            (self.on_warning)(Warning::new(
+                WarningKind::DirectIoFallback,
+                format!(
+                    "The device does not support direct I/O ({}), reading through the page cache instead.",
+                    e
+                ),
+            ));
+            direct_io = false;
+        }
+
+        let device_len = if platform::is_block_device(&self.device_path) {
+            platform::get_device_size(&device)?
+        } else {
+            device.metadata()?.len()
+        };
+        let offset = self.offset;
+        if offset > device_len {
+            return Err(anyhow!("The offset is beyond the end of the device"));
+        }
+        if let Some(len) = source.len
+            && len > device_len - offset
+        {
+            return Err(Error::ImageTooLarge {
+                image: len,
+                device: device_len - offset,
+            }
+            .into());
+        }
+
+        // O_DIRECT requires buffers to be aligned to the logical sector size.
+        let sector_sizes = platform::get_sector_sizes(&device).unwrap_or_default();
+        let block_size = sector_sizes.logical as usize;
+        if self.buffer_size == 0 || !self.buffer_size.is_multiple_of(block_size) {
+            return Err(anyhow!(
+                "Buffer size must be a non-zero multiple of the {} byte sector size",
+                block_size
+            ));
+        }
+        if direct_io && !offset.is_multiple_of(block_size as u64) {
+            platform::set_direct_io(&device, false)?;
+            direct_io = false;
+        }
+
+        (self.on_verify_start)(source.len.unwrap_or(0));
+        let started = Instant::now();
+        let mut buffer = AlignedBuffer::new(self.buffer_size, block_size);
+        let (bytes_verified, digest, device_digest) = check_device(
+            &mut device,
+            offset,
+            Expected::Image {
+                image: &mut source.reader,
+                hash: self.hash,
+                compare: self.method == VerifyMethod::Compare,
+            },
+            &mut buffer,
+            block_size,
+            &self.running,
+            0,
+            &mut *self.on_verify_progress,
+        )?;
+
+        Ok(VerifyReport {
+            bytes_verified,
+            digest,
+            device_digest,
+            duration: started.elapsed(),
+            sector_sizes,
+            direct_io,
+        })
+    }
+}
+
+/// What [`check_device`] checks the device against.
+pub(crate) enum Expected<'r> {
+    /// The digest and length of the image, as hashed while it was written.
+    Digest { digest: Digest, len: u64 },
+    /// The image data itself, read alongside the device and hashed with
+    /// `hash`. With `compare`, each chunk of the device is compared with it
+    /// rather than hashed.
+    Image {
+        image: &'r mut (dyn Read + Send),
+        hash: HashAlgorithm,
+        compare: bool,
+    },
+}
+
+/// Reads the device from `offset` and checks that it holds the image described
+/// by `expected`, one chunk of up to `buffer.len()` bytes at a time. Returns
+/// the size of the image, its digest and that of the device. A device that
+/// does not match fails with [`Error::HashMismatch`], or, for a chunk
+/// compared with the image, with [`Error::VerifyMismatch`] at the first byte
+/// that differs. A device compared in full has the digest of the image.
+///
+/// The image is read and hashed on a thread of its own, up to
+/// [`READ_AHEAD`] chunks ahead of the device, so that the check takes as long
+/// as the slower of the two rather than both. `on_progress` is called with
+/// how much of the device has been checked. An error reading the image ends
+/// the check once the device catches up with it, and one reading the device
+/// stops the image thread.
+///
+/// Device reads are rounded up to a multiple of `align`, as `O_DIRECT`
+/// requires, so `buffer` must be aligned and a multiple of it in size. A
+/// cancelled check fails with [`Error::Cancelled`] reporting `bytes_synced`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn check_device(
+    device: &mut File,
+    offset: u64,
+    expected: Expected,
+    buffer: &mut [u8],
+    align: usize,
+    running: &AtomicBool,
+    bytes_synced: u64,
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<(u64, Digest, Digest)> {
+    device
+        .seek(SeekFrom::Start(offset))
+        .map_err(io_error(Stage::Verify, Some(offset)))?;
+    let chunk_len = buffer.len();
+    let mut device = DeviceChunks {
+        device,
+        offset,
+        buffer,
+        align,
+        verified: 0,
+    };
+
+    match expected {
+        Expected::Digest { digest, len } => {
+            let mut device_hasher = digest.algorithm().hasher();
+            while device.verified < len {
+                if !running.load(Ordering::SeqCst) {
+                    return Err(Error::Cancelled { bytes_synced }.into());
+                }
+                let n = (len - device.verified).min(chunk_len as u64) as usize;
+                device_hasher.update(device.next(n)?);
+                on_progress(device.verified);
+            }
+            let device_digest = Digest::new(digest.algorithm(), device_hasher.finalize());
+            if device_digest != digest {
+                return Err(Error::HashMismatch {
+                    image: digest,
+                    device: device_digest,
+                }
+                .into());
+            }
+            Ok((len, digest, device_digest))
+        }
+        Expected::Image {
+            image,
+            hash,
+            compare,
+        } => thread::scope(|scope| {
+            // The channels are dropped on the way out of the scope, which
+            // stops the image thread if the device fails first.
+            let (filled_tx, filled) = mpsc::sync_channel(READ_AHEAD);
+            let (free, free_rx) = mpsc::channel();
+            for _ in 0..=READ_AHEAD {
+                let _ = free.send(vec![0u8; chunk_len]);
+            }
+            let image_thread =
+                scope.spawn(move || hash_image(image, hash.hasher(), filled_tx, free_rx, running));
+
+            let mut device_hasher = hash.hasher();
+            loop {
+                if !running.load(Ordering::SeqCst) {
+                    return Err(Error::Cancelled { bytes_synced }.into());
+                }
+                // The image thread only hangs up early when it is cancelled.
+                let Ok(chunk) = filled.recv() else {
+                    return Err(Error::Cancelled { bytes_synced }.into());
+                };
+                let (image_buf, n) = chunk.map_err(io_error(Stage::Decompress, None))?;
+                if n == 0 {
+                    break;
+                }
+                let chunk_offset = device.offset + device.verified;
+                let data = device.next(n)?;
+                if compare {
+                    if let Some(i) = (0..n).find(|&i| data[i] != image_buf[i]) {
+                        return Err(Error::VerifyMismatch {
+                            offset: chunk_offset + i as u64,
+                        }
+                        .into());
+                    }
+                } else {
+                    device_hasher.update(data);
+                }
+                on_progress(device.verified);
+                let last = n < image_buf.len();
+                let _ = free.send(image_buf);
+                if last {
+                    break;
+                }
+            }
+
+            let image_hash = image_thread
+                .join()
+                .unwrap_or_else(|e| panic::resume_unwind(e));
+            let digest = Digest::new(hash, image_hash);
+            // Every byte of a compared device matched the image.
+            let device_digest = match compare {
+                true => digest.clone(),
+                false => Digest::new(hash, device_hasher.finalize()),
+            };
+            if device_digest != digest {
+                return Err(Error::HashMismatch {
+                    image: digest,
+                    device: device_digest,
+                }
+                .into());
+            }
+            Ok((device.verified, digest, device_digest))
+        }),
+    }
+}
+
+/// A chunk of the image read by [`hash_image`], and how much of it is filled.
+type ImageChunk = io::Result<(Vec<u8>, usize)>;
+
+/// Reads `image` into the buffers from `free` and hashes it with `hasher`,
+/// sending each chunk on `filled`. A chunk that is not full is the last one.
+/// Returns the digest once the image is read, or early once the other end
+/// hangs up or the operation is cancelled.
+fn hash_image(
+    image: &mut (dyn Read + Send),
+    mut hasher: Box<dyn Hasher>,
+    filled: SyncSender<ImageChunk>,
+    free: Receiver<Vec<u8>>,
+    running: &AtomicBool,
+) -> Vec<u8> {
+    while let Ok(mut buf) = free.recv() {
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+        let result = read_full(image, &mut buf);
+        if let Ok(n) = result {
+            hasher.update(&buf[..n]);
+        }
+        let done = !matches!(result, Ok(n) if n == buf.len());
+        if filled.send(result.map(|n| (buf, n))).is_err() || done {
+            break;
+        }
+    }
+    hasher.finalize()
+}
+
+/// The device as [`check_device`] reads it, one chunk after another.
+struct DeviceChunks<'a> {
+    device: &'a mut File,
+    offset: u64,
+    buffer: &'a mut [u8],
+    align: usize,
+    /// How much of the device has been read, from `offset`.
+    verified: u64,
+}
+
+impl DeviceChunks<'_> {
+    /// Reads the next `n` bytes of the device.
+    fn next(&mut self, n: usize) -> Result<&[u8]> {
+        let chunk_offset = self.offset + self.verified;
+        let len = n.next_multiple_of(self.align);
+        let filled = read_full(self.device, &mut self.buffer[..len])
+            .map_err(io_error(Stage::Verify, Some(chunk_offset)))?;
+        if filled < n {
+            return Err(anyhow!(
+                "The device ends {} bytes into the image, before the image does",
+                self.verified + filled as u64
+            ));
+        }
+        self.verified += n as u64;
+        Ok(&self.buffer[..n])
+    }
+}
+
+/// The CRC-32C of each chunk of an image, recorded as it is written so that a
+/// sample of the chunks can be checked against the device afterwards without
+/// reading the image again. A CRC is plenty to catch a device that lost or
+/// mangled data, and is cheap enough to take of every chunk. Which chunks
+/// hold nothing but zeros is recorded too.
+pub(crate) struct ChunkCrcs {
+    crcs: Vec<u32>,
+    zero: Vec<bool>,
+    current: u32,
+    current_zero: bool,
+    len: u64,
+}
+
+impl ChunkCrcs {
+    pub(crate) fn new() -> Self {
+        Self {
+            crcs: Vec::new(),
+            zero: Vec::new(),
+            current: 0,
+            current_zero: true,
+            len: 0,
+        }
+    }
+
+    /// Adds the next `data` of the image.
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let room = (SAMPLE_CHUNK - self.len % SAMPLE_CHUNK) as usize;
+            let (head, rest) = data.split_at(room.min(data.len()));
+            self.current = crc32c::crc32c_append(self.current, head);
+            self.current_zero &= head.iter().all(|&b| b == 0);
+            self.len += head.len() as u64;
+            if self.len.is_multiple_of(SAMPLE_CHUNK) {
+                self.crcs.push(self.current);
+                self.zero.push(self.current_zero);
+                self.current = 0;
+                self.current_zero = true;
+            }
+            data = rest;
+        }
+    }
+
+    /// The CRC of chunk `index`, counting a partial last chunk.
+    fn crc(&self, index: u64) -> u32 {
+        self.crcs
+            .get(index as usize)
+            .copied()
+            .unwrap_or(self.current)
+    }
+
+    /// Whether chunk `index` holds nothing but zeros, counting a partial last
+    /// chunk.
+    fn is_zero(&self, index: u64) -> bool {
+        self.zero
+            .get(index as usize)
+            .copied()
+            .unwrap_or(self.current_zero)
+    }
+}
+
+/// SplitMix64, a small random number generator that is plenty for picking
+/// chunks, so that the same seed always picks the same ones.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `n`.
+    fn below(&mut self, n: u64) -> u64 {
+        ((self.next() as u128 * n as u128) >> 64) as u64
+    }
+}
+
+/// Picks the parts of an image of `len` bytes that a sampled verification of
+/// `percent` of it checks: the first and last few MiB, and chunks in between
+/// chosen with a random number generator seeded with `seed`, up to `percent`
+/// of the image in all. Returns them as byte ranges of the image, in order.
+pub(crate) fn sample_ranges(len: u64, percent: f32, seed: u64) -> Vec<Range<u64>> {
+    let chunks = len.div_ceil(SAMPLE_CHUNK);
+    let head = (SAMPLE_EDGE / SAMPLE_CHUNK).min(chunks);
+    let tail = chunks.saturating_sub(SAMPLE_EDGE / SAMPLE_CHUNK).max(head);
+    let middle = head..tail;
+    let mut picked: BTreeSet<u64> = (0..middle.start).chain(middle.end..chunks).collect();
+
+    let target = ((chunks as f64 * percent as f64 / 100.0).ceil() as u64).min(chunks);
+    let m = middle.end - middle.start;
+    let k = target.saturating_sub(picked.len() as u64).min(m);
+    // The rest are picked from the chunks in between.
+    picked.extend(pick(m, k, seed).into_iter().map(|c| middle.start + c));
+    chunk_ranges(picked, len)
+}
+
+/// Picks the parts of the image recorded in `crcs` that a verification of
+/// only its non-zero chunks checks: every chunk that holds anything but
+/// zeros, and `zero_percent` of the others, chosen with a random number
+/// generator seeded with `seed`. Returns them as byte ranges of the image, in
+/// order.
+pub(crate) fn nonzero_ranges(crcs: &ChunkCrcs, zero_percent: f32, seed: u64) -> Vec<Range<u64>> {
+    let chunks = crcs.len.div_ceil(SAMPLE_CHUNK);
+    let (zero, nonzero): (Vec<u64>, Vec<u64>) = (0..chunks).partition(|&chunk| crcs.is_zero(chunk));
+    let mut picked: BTreeSet<u64> = nonzero.into_iter().collect();
+    let m = zero.len() as u64;
+    let k = ((m as f64 * zero_percent as f64 / 100.0).ceil() as u64).min(m);
+    picked.extend(pick(m, k, seed).into_iter().map(|c| zero[c as usize]));
+    chunk_ranges(picked, crcs.len)
+}
+
+/// Picks `k` of the numbers below `m` with a random number generator seeded
+/// with `seed`. Floyd's algorithm makes every set of them as likely as any
+/// other.
+fn pick(m: u64, k: u64, seed: u64) -> BTreeSet<u64> {
+    let mut rng = SplitMix64(seed);
+    let mut chosen = BTreeSet::new();
+    for j in m - k..m {
+        let t = rng.below(j + 1);
+        if !chosen.insert(t) {
+            chosen.insert(j);
+        }
+    }
+    chosen
+}
+
+/// Turns a set of chunks of an image of `len` bytes into byte ranges, merging
+/// neighbouring chunks.
+fn chunk_ranges(chunks: BTreeSet<u64>, len: u64) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for chunk in chunks {
+        let (start, end) = (chunk * SAMPLE_CHUNK, ((chunk + 1) * SAMPLE_CHUNK).min(len));
+        match ranges.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => ranges.push(start..end),
+        }
+    }
+    ranges
+}
+
+/// Reads the `ranges` of the image from the device, where the image starts at
+/// `offset`, and checks each chunk against its CRC in `crcs`. Device reads are
+/// rounded up to a multiple of `align`, as `O_DIRECT` requires. A chunk that
+/// does not match fails with [`Error::ChunkMismatch`], and a cancelled check
+/// with [`Error::Cancelled`] reporting `bytes_synced`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn check_sample(
+    device: &mut File,
+    offset: u64,
+    crcs: &ChunkCrcs,
+    ranges: &[Range<u64>],
+    align: usize,
+    running: &AtomicBool,
+    bytes_synced: u64,
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<()> {
+    let mut buffer = AlignedBuffer::new(SAMPLE_CHUNK as usize, align);
+    let mut verified: u64 = 0;
+    for range in ranges {
+        device
+            .seek(SeekFrom::Start(offset + range.start))
+            .map_err(io_error(Stage::Verify, Some(offset + range.start)))?;
+        for start in range.clone().step_by(SAMPLE_CHUNK as usize) {
+            if !running.load(Ordering::SeqCst) {
+                return Err(Error::Cancelled { bytes_synced }.into());
+            }
+            let n = (range.end - start).min(SAMPLE_CHUNK) as usize;
+            let filled = read_full(device, &mut buffer[..n.next_multiple_of(align)])
+                .map_err(io_error(Stage::Verify, Some(offset + start)))?;
+            if filled < n || crc32c::crc32c(&buffer[..n]) != crcs.crc(start / SAMPLE_CHUNK) {
+                return Err(Error::ChunkMismatch {
+                    range: offset + start..offset + start + n as u64,
+                }
+                .into());
+            }
+            verified += n as u64;
+            on_progress(verified);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//! Virtual Hard Disk (VHD) images, as exported by Hyper-V and Azure.
+//!
+//! Every VHD ends in a 512-byte footer that describes the disk. In a fixed
+//! VHD, the footer comes straight after the raw disk, so the image is written
+//! without it. Dynamic and differencing VHDs only hold the blocks of the disk
+//! that were used (or changed from a parent VHD), and are refused with
+//! [`Error::UnsupportedVhd`] rather than written as they are.
+use crate::error::Error;
+use crate::source::ImageFile;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// The size of the footer at the end of a VHD.
+pub const FOOTER_LEN: u64 = 512;
+
+/// How a VHD stores its disk, as recorded in its footer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DiskType {
+    /// The whole disk, followed by the footer.
+    Fixed,
+    /// The blocks of the disk that were written, found through a table.
+    Dynamic,
+    /// The blocks that changed from a parent VHD.
+    Differencing,
+    /// A disk type the format does not define.
+    Other(u32),
+}
+
+impl DiskType {
+    fn from_u32(disk_type: u32) -> Self {
+        match disk_type {
+            2 => DiskType::Fixed,
+            3 => DiskType::Dynamic,
+            4 => DiskType::Differencing,
+            other => DiskType::Other(other),
+        }
+    }
+}
+
+/// What the footer of a VHD says about its disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Footer {
+    pub disk_type: DiskType,
+    /// The size of the disk in bytes.
+    pub disk_size: u64,
+}
+
+impl Footer {
+    /// Reads a VHD footer. Returns `None` if `footer` is not one: it does not
+    /// start with the `conectix` cookie, or does not match its checksum.
+    ///
+    /// ```rust
+    /// use etchr_core::vhd::{DiskType, Footer};
+    ///
+    /// let mut footer = [0u8; 512];
+    /// footer[..8].copy_from_slice(b"conectix");
+    /// footer[48..56].copy_from_slice(&(1u64 << 20).to_be_bytes());
+    /// footer[60..64].copy_from_slice(&2u32.to_be_bytes());
+    /// let sum = footer.iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32));
+    /// footer[64..68].copy_from_slice(&(!sum).to_be_bytes());
+    ///
+    /// let parsed = Footer::parse(&footer).unwrap();
+    /// assert_eq!(parsed.disk_type, DiskType::Fixed);
+    /// assert_eq!(parsed.disk_size, 1 << 20);
+    ///
+    /// footer[100] ^= 1;
+    /// assert_eq!(Footer::parse(&footer), None);
+    /// ```
+    pub fn parse(footer: &[u8; FOOTER_LEN as usize]) -> Option<Footer> {
+        let field = |start: usize, len: usize| &footer[start..start + len];
+        if field(0, 8) != b"conectix" {
+            return None;
+        }
+        // The checksum is the one's complement of the sum of the other bytes.
+        let checksum = u32::from_be_bytes(field(64, 4).try_into().unwrap());
+        let sum = footer
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !(64..68).contains(i))
+            .fold(0u32, |sum, (_, &b)| sum.wrapping_add(b as u32));
+        if !sum != checksum {
+            return None;
+        }
+        Some(Footer {
+            disk_type: DiskType::from_u32(u32::from_be_bytes(field(60, 4).try_into().unwrap())),
+            disk_size: u64::from_be_bytes(field(48, 8).try_into().unwrap()),
+        })
+    }
+}
+
+/// Returns the size of the disk in the image `file` if it is a fixed VHD,
+/// which is where its footer starts, or `None` if it is not a VHD.
+///
+/// Other kinds of VHD are refused with [`Error::UnsupportedVhd`], carried
+/// inside the I/O error.
+pub(crate) fn disk_size(file: &ImageFile) -> io::Result<Option<u64>> {
+    let mut file = file.open()?;
+    if file.len() < FOOTER_LEN {
+        return Ok(None);
+    }
+    let mut footer = [0u8; FOOTER_LEN as usize];
+    file.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+    file.read_exact(&mut footer)?;
+    let Some(footer) = Footer::parse(&footer) else {
+        return Ok(None);
+    };
+    if footer.disk_type != DiskType::Fixed {
+        return Err(io::Error::other(Error::UnsupportedVhd {
+            disk_type: footer.disk_type,
+        }));
+    }
+    let payload = file.len() - FOOTER_LEN;
+    if footer.disk_size > payload {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the VHD footer says the disk is {} bytes, but the file only holds {}",
+                footer.disk_size, payload
+            ),
+        ));
+    }
+    Ok(Some(footer.disk_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// A valid footer for a disk of `disk_type` and `disk_size` bytes.
+    fn footer(disk_type: u32, disk_size: u64) -> [u8; FOOTER_LEN as usize] {
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        footer[..8].copy_from_slice(b"conectix");
+        footer[48..56].copy_from_slice(&disk_size.to_be_bytes());
+        footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+        let sum = footer
+            .iter()
+            .fold(0u32, |sum, &b| sum.wrapping_add(b as u32));
+        footer[64..68].copy_from_slice(&(!sum).to_be_bytes());
+        footer
+    }
+
+    /// The size `disk_size` finds for an image of `payload` bytes followed
+    /// by `footer`.
+    fn disk_size_of(payload: usize, footer: &[u8]) -> io::Result<Option<u64>> {
+        let mut image = tempfile::NamedTempFile::new().unwrap();
+        image.write_all(&vec![0x5A; payload]).unwrap();
+        image.write_all(footer).unwrap();
+        disk_size(&ImageFile::single(image.path()))
+    }
+
+    #[test]
+    fn fixed() {
+        assert_eq!(disk_size_of(4096, &footer(2, 4096)).unwrap(), Some(4096));
+        // Anything between the disk and the footer is left out too.
+        assert_eq!(disk_size_of(4096, &footer(2, 3072)).unwrap(), Some(3072));
+    }
+
+    #[test]
+    fn dynamic_and_differencing_are_refused() {
+        for (disk_type, expected) in [(3, DiskType::Dynamic), (4, DiskType::Differencing)] {
+            let e = disk_size_of(4096, &footer(disk_type, 1 << 20)).unwrap_err();
+            let inner = e.get_ref().and_then(|e| e.downcast_ref::<Error>());
+            assert!(
+                matches!(inner, Some(Error::UnsupportedVhd { disk_type }) if *disk_type == expected),
+                "{}",
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn a_bad_checksum_is_not_a_footer() {
+        let mut bad = footer(2, 4096);
+        bad[100] ^= 1;
+        assert_eq!(disk_size_of(4096, &bad).unwrap(), None);
+        // Nor is a file too short to hold one.
+        assert_eq!(disk_size_of(100, &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn a_disk_larger_than_the_file_is_refused() {
+        let e = disk_size_of(4096, &footer(2, 8192)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
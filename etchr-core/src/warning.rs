@@ -0,0 +1,86 @@
+//! Non-fatal problems reported while an operation carries on.
+//!
+//! Some things are worth telling the user about without failing the write or
+//! read: a device that had to be written through the page cache, a chunk that
+//! only went through on a retry, a device that could not be ejected. These are
+//! passed to an `on_warning` callback as a [`Warning`], which has a kind that
+//! front-ends can match on and a message they can show as is.
+use std::fmt;
+
+/// What a [`Warning`] is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WarningKind {
+    /// The device rejected `O_DIRECT` and is accessed through the page cache,
+    /// so verification may read back cached data rather than the medium.
+    DirectIoFallback,
+    /// A chunk failed to write and was retried.
+    WriteRetried,
+    /// The device does not support a secure discard, so a secure erase used a
+    /// weaker method.
+    EraseFallback,
+    /// Zeroing the rest of the device was cancelled before it reached the end.
+    WipeIncomplete,
+    /// The kernel could not re-read the partition table after it changed.
+    PartitionRescanFailed,
+    /// The device could not be ejected after the write.
+    EjectFailed,
+    /// The image does not start with a partition table or an ISO 9660
+    /// header, so it may be an archive or some other file rather than a disk
+    /// image. See [`crate::image::looks_like_disk_image`].
+    NotADiskImage,
+    /// A read that was to stop at the end of the last partition found no
+    /// partition table it could use, and read the whole device instead.
+    NoPartitionTable,
+    /// Some sectors could not be read and were filled with zeros in the
+    /// image. See [`crate::read::ReadOptions::tolerate_errors`].
+    UnreadableSectors,
+    /// The device ended before the size it reported, and the image was cut
+    /// short or padded with zeros. See
+    /// [`crate::read::ReadOptions::short_device`].
+    ShortDevice,
+    /// The checksum file could not be saved next to an image that was read.
+    /// See [`crate::read::ReadOptions::checksum_file`].
+    ChecksumFileFailed,
+    /// The block map could not be saved next to an image that was read. See
+    /// [`crate::read::ReadOptions::bmap`].
+    BmapFileFailed,
+    /// The SHA-256 and source of an image that was read could not be stored
+    /// in its extended attributes. See [`crate::read::ReadOptions::xattrs`].
+    XattrsFailed,
+    /// A partition holds no filesystem whose free space can be skipped, so
+    /// all of it is read. See [`crate::read::ReadOptions::used_blocks_only`].
+    UnknownFilesystem,
+    /// The image was written through the page cache and could not be dropped
+    /// from it, so verification may read back cached data rather than the
+    /// medium. See [`crate::write::WriteReport::cache_dropped`].
+    CacheDropFailed,
+    /// The image file starts with the magic number of a different format than
+    /// its extension names, and is read in the format of its contents. See
+    /// [`crate::image::detect_format`].
+    FormatMismatch,
+}
+
+/// A non-fatal problem, with a message that can be shown to the user.
+#[derive(Clone, Debug)]
+pub struct Warning {
+    /// What the warning is about.
+    pub kind: WarningKind,
+    /// A one-line description of what happened and what it means.
+    pub message: String,
+}
+
+impl Warning {
+    pub(crate) fn new(kind: WarningKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
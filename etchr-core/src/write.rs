@@ -1,232 +1,4552 @@
 //! Contains the logic for writing an image file to a device.
 //!
 //! This module handles the multi-stage process of writing, which includes:
-//! 1.  Decompressing the image file on-the-fly if it is compressed (`.gz`, `.xz`, `.zst`).
+//! 1.  Decompressing the image file on-the-fly if it is compressed (`.gz`, `.xz`, `.zst`, `.bz2`, `.lz4`),
+//!     or the image inside it if it is a `.zip` archive. The decoder output is streamed straight into the write loop, so no
+//!     temporary copy of the image is needed.
 //! 2.  Writing the (decompressed) image data to the target device.
 //! 3.  Optionally verifying the written data against the source image.
-use crate::os_options::OpenOptionsExt;
-use anyhow::{anyhow, Result};
-use flate2::read::GzDecoder;
+use crate::bmap::{BlockMap, RangeChecker};
+use crate::buffer::AlignedBuffer;
+use crate::cache::Cache;
+use crate::checkpoint::Checkpoint;
+use crate::customize::{self, PartitionFile};
+use crate::device::{Device, DirectIo, SectorSizes};
+use crate::error::{self, Corruption, Error, PartialTransfer};
+use crate::fault::{self, Fault};
+use crate::hash::{self, HashAlgorithm};
+use crate::image::{self, Format, ImageKind};
+use crate::os_options::DeviceOpenOptions;
+use crate::partition_table;
+use crate::platform;
+use crate::progress::{self, DecompressProgress, Progress, Stage, StageTiming};
+use crate::source::{Download, ImageFile, MultiFileReader, Source};
+use crate::throttle::{RateLimiter, cancellable_sleep};
+use crate::verify::{self, ChunkCrcs, Expected, VerifyMethod};
+use crate::vhd;
+use crate::warning::{Warning, WarningKind};
+use anyhow::{Result, anyhow};
+use bzip2::read::MultiBzDecoder;
+use flate2::read::{DeflateDecoder, GzDecoder};
+use lz4_flex::frame::FrameDecoder;
 use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::fmt;
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::{NamedTempFile, TempPath};
 use xz2::read::XzDecoder;
+use zip::result::ZipError;
+use zip::{CompressionMethod, ZipArchive};
 use zstd::stream::read::Decoder as ZstdDecoder;
 
+mod multi;
+mod session;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod worker;
+
+pub use multi::MultiWriteOptions;
+pub use session::Session;
+
 const BUFFER_SIZE: usize = 1024 * 1024; // 1 MiB
 
-/// Manages the lifetime of a decompressed image file.
-/// If the image was decompressed to a temp file, this struct holds the handle
-/// and will delete the file on drop.
-struct DecompressedImage {
-    path: PathBuf,
-    _temp_handle: Option<TempPath>,
-}
+/// How many writes are kept in flight through io_uring by default.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+const DEFAULT_QUEUE_DEPTH: u32 = 8;
+
+/// The default window over which throughput is averaged.
+const PROGRESS_WINDOW: Duration = Duration::from_secs(3);
+
+/// The compression ratio assumed when the decompressed size is not recorded.
+const DEFAULT_COMPRESSION_RATIO: f64 = 4.0;
+
+/// The largest zstd window accepted by default, as a power of two: 2 GiB, the
+/// most `zstd --long` uses, or 1 GiB where a 32-bit decoder cannot do more.
+pub(crate) const DEFAULT_ZSTD_WINDOW_LOG_MAX: u32 = if cfg!(target_pointer_width = "64") {
+    31
+} else {
+    30
+};
+
+/// How much of the device is discarded per `BLKDISCARD` call.
+const DISCARD_STEP: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+/// How much of the device is zeroed per `BLKZEROOUT` call.
+const WIPE_STEP: u64 = 64 * 1024 * 1024; // 64 MiB
+
+/// How much data is written between two checkpoints.
+const CHECKPOINT_INTERVAL: u64 = 256 * BUFFER_SIZE as u64;
+
+/// Controls how failed chunk writes are retried.
+///
+/// Cheap card readers occasionally fail a single write with `EIO` or
+/// `ETIMEDOUT` and then recover, so a chunk that fails with one of those
+/// errors is rewritten at the same offset before giving up.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// How many times a failed chunk is retried. Zero disables retrying.
+    pub attempts: u32,
+    /// How long to wait before each retry.
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            delay: Duration::from_millis(500),
+        }
+    }
+}
+
+/// How the device was erased by [`WriteOptions::secure_erase`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EraseMethod {
+    /// The device erased itself with `BLKSECDISCARD`, including any copies
+    /// of the data it kept internally.
+    SecureDiscard,
+    /// The device was discarded with `BLKDISCARD`. Whether discarded blocks
+    /// still hold their old data depends on the device.
+    Discard,
+    /// The device was overwritten with zeros. Blocks the device remapped
+    /// internally may still hold old data.
+    Zero,
+}
+
+impl fmt::Display for EraseMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EraseMethod::SecureDiscard => "secure discard",
+            EraseMethod::Discard => "discard",
+            EraseMethod::Zero => "zero-fill",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How the data written to the device is checked, set with
+/// [`WriteOptions::verify_mode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum VerifyMode {
+    /// The device is not read back.
+    None,
+    /// Once the whole image has been written and synced, the device is read
+    /// back and its hash compared with the image's.
+    #[default]
+    Full,
+    /// Each chunk is read back with `O_DIRECT` and compared with the image
+    /// right after it is written, so a failing device is caught within
+    /// seconds rather than after the whole write.
+    ReadBack,
+    /// Each chunk is read back as it is written, and the whole device once
+    /// more at the end.
+    ReadBackAndFull,
+    /// Once the whole image has been written and synced, only a sample of it
+    /// is read back: the first and last few MiB, and 1 MiB chunks in between
+    /// picked at random, up to `percent` of the image in all. The chunks are
+    /// picked with a random number generator seeded with `seed`, so the same
+    /// seed checks the same parts of the same image. Each chunk is checked
+    /// against a CRC-32C of the image taken as it was written, whatever
+    /// [`WriteOptions::verify_method`] and [`WriteOptions::verify_hash`] are.
+    ///
+    /// This only spot-checks the device: the report lists the parts that were
+    /// checked in [`WriteReport::sampled_ranges`], and does not count the
+    /// device as verified. A sample cannot be combined with a block map or
+    /// with resuming a write.
+    Sampled {
+        /// How much of the image to check, more than 0 and at most 100.
+        percent: f32,
+        /// The seed for picking the chunks.
+        seed: u64,
+    },
+    /// Once the whole image has been written and synced, only the 1 MiB
+    /// chunks of it that hold anything but zeros are read back, along with
+    /// `zero_percent` of the chunks that are all zeros, picked at random with
+    /// a random number generator seeded with `seed`. Each chunk is checked
+    /// against a CRC-32C of the image taken as it was written, as with
+    /// [`VerifyMode::Sampled`]. Most of the time spent verifying an image
+    /// that is mostly empty space is saved.
+    ///
+    /// The report lists the parts that were checked in
+    /// [`WriteReport::sampled_ranges`], and only counts the device as
+    /// verified with a block map, whose mapped ranges are then read back as
+    /// with [`VerifyMode::Full`]. It cannot be combined with resuming a
+    /// write.
+    NonZero {
+        /// How much of the zero chunks to check, from 0 to 100.
+        zero_percent: f32,
+        /// The seed for picking the zero chunks.
+        seed: u64,
+    },
+}
+
+impl VerifyMode {
+    /// Whether the whole device is read back after the write.
+    pub fn full(self) -> bool {
+        matches!(self, VerifyMode::Full | VerifyMode::ReadBackAndFull)
+    }
+
+    /// Whether each chunk is read back right after it is written.
+    pub fn read_back(self) -> bool {
+        matches!(self, VerifyMode::ReadBack | VerifyMode::ReadBackAndFull)
+    }
+
+    /// Whether a sample of the device is read back after the write.
+    pub fn sampled(self) -> bool {
+        matches!(self, VerifyMode::Sampled { .. })
+    }
+
+    /// Whether only part of the device is read back after the write, as with
+    /// [`VerifyMode::Sampled`] and [`VerifyMode::NonZero`].
+    pub fn partial(self) -> bool {
+        matches!(
+            self,
+            VerifyMode::Sampled { .. } | VerifyMode::NonZero { .. }
+        )
+    }
+}
+
+/// A partition grown to fill the device by
+/// [`WriteOptions::grow_last_partition`].
+///
+/// Only the partition table changes, so the filesystem inside still has its
+/// old size until it is grown too, e.g. with `resize2fs`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GrownPartition {
+    /// The 1-based number of the partition, as in `/dev/sdb2`.
+    pub number: u32,
+    /// The offset of the partition from the start of the image, in bytes.
+    pub start: u64,
+    /// The size of the partition in the image, in bytes.
+    pub old_len: u64,
+    /// The size of the partition now, in bytes.
+    pub new_len: u64,
+}
+
+/// A summary of a completed write, returned by [`WriteOptions::run`].
+#[derive(Clone, Debug)]
+pub struct WriteReport {
+    /// The number of image bytes written to the device.
+    pub bytes_written: u64,
+    /// The SHA-256 of the (decompressed) image data.
+    pub image_sha256: [u8; 32],
+    /// Whether the whole device was read back and matched the image.
+    pub verified: bool,
+    /// The parts of the image that were read back from the device and
+    /// matched, in bytes from the start of the image, if only part of it was
+    /// checked ([`VerifyMode::Sampled`] or [`VerifyMode::NonZero`]). The rest
+    /// of the device was not checked, so `verified` is `false`.
+    pub sampled_ranges: Option<Vec<Range<u64>>>,
+    /// The digest of the (decompressed) image data in the algorithm set with
+    /// [`WriteOptions::verify_hash`], which the device matched, if it was
+    /// verified in full. `None` if it was checked against a block map
+    /// instead.
+    pub verify_digest: Option<hash::Digest>,
+    /// The digest of what the device held in place of the image when it was
+    /// verified in full, in the same algorithm as `verify_digest`. It matched
+    /// `verify_digest`; a device compared byte for byte with the image
+    /// ([`VerifyMethod::Compare`]) is not hashed, so this is the digest of the
+    /// image it matched.
+    pub device_digest: Option<hash::Digest>,
+    /// Whether every chunk was read back and matched the image as it was
+    /// written (see [`VerifyMode::ReadBack`]).
+    pub read_back: bool,
+    /// Time spent decompressing the image. When the image is streamed, this
+    /// overlaps with `write_duration`; it is zero for uncompressed images.
+    pub decompress_duration: Duration,
+    /// Time spent writing and syncing the device.
+    pub write_duration: Duration,
+    /// Time spent verifying the device, or zero if it was not verified.
+    pub verify_duration: Duration,
+    /// The sector sizes of the device. Writes were aligned to the logical size.
+    pub sector_sizes: SectorSizes,
+    /// Why the device could not be ejected, if `eject_on_success` was set and
+    /// ejecting failed. The write itself still succeeded.
+    pub eject_error: Option<String>,
+    /// Whether this was a dry run (see [`WriteOptions::dry_run`]), in which
+    /// case nothing was written to the device.
+    pub simulated: bool,
+    /// How the device was erased before writing, if `secure_erase` was set.
+    pub erase_method: Option<EraseMethod>,
+    /// The number of bytes zeroed after the image, if `wipe_remainder` was set.
+    pub bytes_wiped: u64,
+    /// The number of bytes in the ranges of the block map, which were all
+    /// that was written, if [`WriteOptions::bmap`] was set.
+    pub bytes_mapped: Option<u64>,
+    /// The number of image bytes that were not written because the device
+    /// already held them, if [`WriteOptions::only_changed`] was set.
+    pub bytes_unchanged: Option<u64>,
+    /// Whether the backup GPT was moved to the end of the device, if
+    /// `relocate_gpt_backup` was set.
+    pub gpt_relocated: bool,
+    /// The partition that was grown to fill the device, if
+    /// `grow_last_partition` was set and the image has any partitions.
+    pub grown_partition: Option<GrownPartition>,
+    /// Whether zeroing the rest of the device was cancelled before it reached
+    /// the end. The image itself was still written and verified.
+    pub wipe_incomplete: bool,
+    /// Whether the device was written with `O_DIRECT`. It is written through
+    /// the page cache if it is a regular file, if [`DirectIo::Off`] was
+    /// requested, or if it rejected `O_DIRECT`.
+    pub direct_io: bool,
+    /// Whether the image was dropped from the page cache before the device
+    /// was verified, so that the verification read it from the device. This
+    /// is done whenever a block device was written through the page cache,
+    /// and with [`WriteOptions::drop_caches`] when only the verification goes
+    /// through it.
+    pub cache_dropped: bool,
+}
+
+impl WriteReport {
+    /// The image SHA-256 as a lowercase hex string.
+    pub fn image_sha256_hex(&self) -> String {
+        hex::encode(self.image_sha256)
+    }
+
+    /// The bytes written to the device and the time spent writing and syncing.
+    pub fn write_timing(&self) -> StageTiming {
+        StageTiming {
+            bytes: self.bytes_written,
+            duration: self.write_duration,
+        }
+    }
+
+    /// The number of image bytes read back from the device after the write
+    /// and found to match, or zero if it was not verified.
+    pub fn bytes_verified(&self) -> u64 {
+        match &self.sampled_ranges {
+            Some(ranges) => ranges.iter().map(|r| r.end - r.start).sum(),
+            None if self.verified => self.bytes_mapped.unwrap_or(self.bytes_written),
+            None => 0,
+        }
+    }
+
+    /// The bytes read back from the device and the time it took, if it was
+    /// verified in full or in part.
+    pub fn verify_timing(&self) -> Option<StageTiming> {
+        (self.verified || self.sampled_ranges.is_some()).then_some(StageTiming {
+            bytes: self.bytes_verified(),
+            duration: self.verify_duration,
+        })
+    }
+
+    /// The average write speed in bytes per second, over the write and sync.
+    pub fn average_bps(&self) -> f64 {
+        self.write_timing().bytes_per_sec()
+    }
+}
+
+/// Returns `true` for errors that a flaky device may recover from.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EIO) | Some(libc::ETIMEDOUT) | Some(libc::EAGAIN)
+    )
+}
+
+/// Reads the bytes just written at `device_offset` back from `file` into
+/// `buf` and fails with [`Error::VerifyMismatch`] if they differ from `expected`.
+///
+/// The read is rounded up to a multiple of `align`, as `O_DIRECT` requires.
+fn read_back(
+    file: &mut File,
+    buf: &mut [u8],
+    device_offset: u64,
+    expected: &[u8],
+    align: usize,
+) -> Result<()> {
+    let buf = &mut buf[..expected.len().next_multiple_of(align)];
+    file.seek(SeekFrom::Start(device_offset))
+        .and_then(|_| file.read_exact(buf))
+        .map_err(io_error(Stage::Verify, Some(device_offset)))?;
+    if let Some(i) = buf.iter().zip(expected).position(|(a, b)| a != b) {
+        return Err(Error::VerifyMismatch {
+            offset: device_offset + i as u64,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Reads the `data.len()` bytes at `device_offset` from `file` into `buf` and
+/// compares them with `data` one block at a time. Returns the span of `data`
+/// from the first to the last block that differs, or `None` if the device
+/// already holds all of it. Anything past the end of `file` differs.
+fn changed_span(
+    file: &mut File,
+    buf: &mut [u8],
+    device_offset: u64,
+    data: &[u8],
+    block_size: usize,
+) -> io::Result<Option<(usize, usize)>> {
+    let buf = &mut buf[..data.len().next_multiple_of(block_size)];
+    file.seek(SeekFrom::Start(device_offset))?;
+    let filled = read_full(file, buf)?.min(data.len());
+    let differs = |i: usize| {
+        let block = i * block_size..((i + 1) * block_size).min(data.len());
+        block.end > filled || buf[block.clone()] != data[block]
+    };
+    let blocks = data.len().div_ceil(block_size);
+    let Some(first) = (0..blocks).find(|&i| differs(i)) else {
+        return Ok(None);
+    };
+    let last = (first..blocks).rev().find(|&i| differs(i)).unwrap_or(first);
+    Ok(Some((
+        first * block_size,
+        ((last + 1) * block_size).min(data.len()),
+    )))
+}
+
+/// Reads the mapped ranges of `map` back from the device, which holds the
+/// image at `offset`, and compares each one with its SHA-256 in `hashes`.
+/// Reads are rounded up to a multiple of `align`, as `O_DIRECT` requires.
+///
+/// `written` is the size of the image, reported if the read back is cancelled.
+#[allow(clippy::too_many_arguments)]
+fn verify_mapped(
+    device_file: &mut File,
+    offset: u64,
+    map: &BlockMap,
+    hashes: &[Option<[u8; 32]>],
+    align: usize,
+    written: u64,
+    running: &AtomicBool,
+    on_verify_progress: &mut dyn FnMut(u64),
+) -> Result<()> {
+    let mut buf = AlignedBuffer::new(BUFFER_SIZE, align);
+    let mut verified = 0;
+    for (range, expected) in map.ranges.iter().zip(hashes) {
+        device_file
+            .seek(SeekFrom::Start(offset + range.start))
+            .map_err(io_error(Stage::Verify, Some(offset + range.start)))?;
+        let mut hasher = Sha256::new();
+        let mut pos = range.start;
+        while pos < range.end {
+            if !running.load(Ordering::SeqCst) {
+                return Err(Error::Cancelled {
+                    bytes_synced: written,
+                }
+                .into());
+            }
+            let len = (range.end - pos).min(BUFFER_SIZE as u64) as usize;
+            device_file
+                .read_exact(&mut buf[..len.next_multiple_of(align)])
+                .map_err(io_error(Stage::Verify, Some(offset + pos)))?;
+            hasher.update(&buf[..len]);
+            pos += len as u64;
+            verified += len as u64;
+            on_verify_progress(verified);
+        }
+        let actual: [u8; 32] = hasher.finalize().into();
+        if let Some(expected) = expected
+            && *expected != actual
+        {
+            return Err(Error::MappedRangeMismatch {
+                range: offset + range.start..offset + range.end,
+                image: hash::Digest::sha256(*expected),
+                device: hash::Digest::sha256(actual),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Writes `data` to the device at `offset`, retrying transient failures
+/// according to `retry`. `on_retry` is called with the offset and the attempt
+/// number before each retry.
+fn write_chunk(
+    device_file: &mut File,
+    offset: u64,
+    data: &[u8],
+    retry: &RetryPolicy,
+    running: &AtomicBool,
+    on_retry: &mut dyn FnMut(u64, u32),
+) -> io::Result<()> {
+    let mut attempt = 0;
+    loop {
+        let written =
+            fault::check(device_file, Fault::Write).and_then(|()| device_file.write_all(data));
+        match written {
+            Ok(()) => return Ok(()),
+            Err(e) if is_transient(&e) && attempt < retry.attempts => {
+                attempt += 1;
+                on_retry(offset, attempt);
+                if !cancellable_sleep(retry.delay, running) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Interrupted,
+                        "Operation cancelled by user",
+                    ));
+                }
+                // A failed write may have partially advanced the file position.
+                device_file.seek(SeekFrom::Start(offset))?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A reader that counts the bytes pulled through it.
+///
+/// Wrapped around the compressed input file so that decompression progress can
+/// be reported in compressed bytes consumed while the decoder output is streamed.
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Skipping ahead counts the bytes skipped as consumed.
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = self.inner.seek(pos)?;
+        self.count.store(offset, Ordering::Relaxed);
+        Ok(offset)
+    }
+}
+
+/// A reader that decodes every LZ4 frame in its input, one after another.
+///
+/// [`FrameDecoder`] reports the end of the input at the end of each frame, but
+/// `lz4` writes one frame per file it is given, so concatenated files are a
+/// common sight.
+pub(crate) struct Lz4Frames<R: BufRead>(FrameDecoder<R>);
+
+impl<R: BufRead> Lz4Frames<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self(FrameDecoder::new(inner))
+    }
+}
+
+impl<R: BufRead> Read for Lz4Frames<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let n = self.0.read(buf)?;
+            if n > 0 || self.0.get_mut().fill_buf()?.is_empty() {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+/// Fails unless zstd can decode with windows of up to 2^`window_log_max` bytes.
+pub(crate) fn check_zstd_window_log_max(window_log_max: u32) -> Result<()> {
+    if !(10..=DEFAULT_ZSTD_WINDOW_LOG_MAX).contains(&window_log_max) {
+        return Err(anyhow!(
+            "The zstd window log must be from 10 to {}, not {}",
+            DEFAULT_ZSTD_WINDOW_LOG_MAX,
+            window_log_max
+        ));
+    }
+    Ok(())
+}
+
+/// A zstd decoder that explains the error for a frame needing a larger window
+/// than it accepts.
+///
+/// Such frames are written by `zstd --long`, and libzstd only says that they
+/// need "too much memory".
+struct ZstdReader<R: BufRead> {
+    decoder: ZstdDecoder<'static, R>,
+    window_log_max: u32,
+}
+
+impl<R: BufRead> Read for ZstdReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf).map_err(|e| {
+            if e.to_string() == "Frame requires too much memory for decoding" {
+                io::Error::other(Error::ZstdWindowTooLarge {
+                    window_log_max: self.window_log_max,
+                })
+            } else {
+                e
+            }
+        })
+    }
+}
+
+/// A decoder that reports the damage it finds in its input as
+/// [`Error::CorruptImage`], telling a truncated file from one that fails its
+/// checksum and from one that is not in `format` at all.
+///
+/// Errors reading the input itself, and those already raised as an [`Error`],
+/// are passed on as they are.
+struct CheckedDecoder {
+    inner: Box<dyn Read + Send>,
+    format: Format,
+}
+
+impl Read for CheckedDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|e| match corruption(&e) {
+            Some(detail) => io::Error::other(Error::CorruptImage {
+                format: self.format,
+                detail,
+            }),
+            None => e,
+        })
+    }
+}
+
+/// Works out what a decoder error says is wrong with the compressed data, or
+/// returns `None` if it is not about the data.
+///
+/// The decoders of xz, bzip2 and lz4 fail with error types of their own. Those
+/// of gzip and zstd only give a message.
+fn corruption(e: &io::Error) -> Option<Corruption> {
+    // xz and bzip2 cannot tell a failed check from other damage.
+    const DAMAGED_BLOCK: &str = "a block is damaged or fails its integrity check";
+
+    let inner = e.get_ref();
+    if e.raw_os_error().is_some() || inner.is_some_and(|e| e.is::<Error>()) {
+        return None;
+    }
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => return Some(Corruption::Truncated),
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput | io::ErrorKind::Other => {}
+        _ => return None,
+    }
+
+    if let Some(e) = inner.and_then(|e| e.downcast_ref::<xz2::stream::Error>()) {
+        return Some(match e {
+            xz2::stream::Error::Format => Corruption::WrongFormat,
+            xz2::stream::Error::Data => Corruption::Invalid {
+                reason: DAMAGED_BLOCK.to_string(),
+            },
+            _ => return None,
+        });
+    }
+    if let Some(e) = inner.and_then(|e| e.downcast_ref::<bzip2::Error>()) {
+        return Some(match e {
+            bzip2::Error::DataMagic => Corruption::WrongFormat,
+            bzip2::Error::Data => Corruption::Invalid {
+                reason: DAMAGED_BLOCK.to_string(),
+            },
+            _ => return None,
+        });
+    }
+    if let Some(e) = inner.and_then(|e| e.downcast_ref::<lz4_flex::frame::Error>()) {
+        use lz4_flex::frame::Error as Lz4Error;
+        return Some(match e {
+            Lz4Error::WrongMagicNumber
+            | Lz4Error::UnsupportedVersion(_)
+            | Lz4Error::ReservedBitsSet => Corruption::WrongFormat,
+            Lz4Error::HeaderChecksumError
+            | Lz4Error::BlockChecksumError
+            | Lz4Error::ContentChecksumError
+            | Lz4Error::ContentLengthError { .. } => Corruption::ChecksumMismatch,
+            _ => Corruption::Invalid {
+                reason: e.to_string(),
+            },
+        });
+    }
+
+    let message = e.to_string();
+    Some(match message.as_str() {
+        "invalid gzip header" | "Unknown frame descriptor" | "Unsupported frame parameter" => {
+            Corruption::WrongFormat
+        }
+        "corrupt gzip stream does not have a matching checksum"
+        | "Restored data doesn't match checksum" => Corruption::ChecksumMismatch,
+        _ => Corruption::Invalid { reason: message },
+    })
+}
+
+/// The disk image inside a zip archive, as found by [`zip_image`].
+struct ZipImage {
+    /// Where the (possibly compressed) data of the image starts in the archive.
+    data_start: u64,
+    compressed_size: u64,
+    /// The size of the image itself.
+    size: u64,
+    crc32: u32,
+    deflated: bool,
+}
+
+/// Finds the disk image, an `.img` or `.iso` file, in the zip archive in
+/// `file`.
+///
+/// An archive holding none or several is refused with
+/// [`Error::ArchiveContents`], and one whose directory cannot be read with
+/// [`Error::CorruptImage`], carried inside the I/O error. Only stored and
+/// deflated images can be read.
+fn zip_image(file: &ImageFile) -> io::Result<ZipImage> {
+    let file = BufReader::new(file.open()?);
+    let mut archive = ZipArchive::new(file).map_err(damaged_zip)?;
+    let mut entries = Vec::new();
+    let mut images = Vec::new();
+    for index in 0..archive.len() {
+        let entry = archive.by_index_raw(index).map_err(damaged_zip)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let ext = Path::new(entry.name())
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        if matches!(ext.as_str(), "img" | "iso") {
+            images.push(index);
+        }
+        entries.push(entry.name().to_string());
+    }
+
+    let [index] = images[..] else {
+        return Err(io::Error::other(Error::ArchiveContents {
+            images: images.len(),
+            entries,
+        }));
+    };
+    let entry = archive.by_index_raw(index).map_err(damaged_zip)?;
+    let unsupported = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} in the zip archive is {}", entry.name(), what),
+        )
+    };
+    if entry.encrypted() {
+        return Err(unsupported("encrypted"));
+    }
+    let deflated = match entry.compression() {
+        CompressionMethod::STORE => false,
+        CompressionMethod::DEFLATE => true,
+        _ => return Err(unsupported("compressed with a method other than deflate")),
+    };
+    Ok(ZipImage {
+        data_start: entry.data_start(),
+        compressed_size: entry.compressed_size(),
+        size: entry.size(),
+        crc32: entry.crc32(),
+        deflated,
+    })
+}
+
+/// Reports a zip archive whose directory is cut short or invalid as
+/// [`Error::CorruptImage`]. Other errors are passed on as they are.
+fn damaged_zip(e: ZipError) -> io::Error {
+    let detail = match e {
+        ZipError::InvalidArchive(reason) => Corruption::Invalid {
+            reason: reason.to_string(),
+        },
+        ZipError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => Corruption::Truncated,
+        e => return e.into(),
+    };
+    io::Error::other(Error::CorruptImage {
+        format: Format::Zip,
+        detail,
+    })
+}
+
+/// A reader for the image inside a zip archive, which checks it against the
+/// size and CRC-32 recorded for it once it ends.
+struct ZipImageReader<R> {
+    inner: R,
+    crc: flate2::Crc,
+    len: u64,
+    expected_len: u64,
+    expected_crc: u32,
+}
+
+impl<R: Read> Read for ZipImageReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            if self.len != self.expected_len || self.crc.sum() != self.expected_crc {
+                return Err(io::Error::other(Error::CorruptImage {
+                    format: Format::Zip,
+                    detail: Corruption::ChecksumMismatch,
+                }));
+            }
+            return Ok(0);
+        }
+        self.crc.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// An opened image, ready to be read from start to finish.
+pub(crate) struct ImageSource {
+    /// Yields the decompressed image data.
+    pub(crate) reader: Box<dyn Read + Send>,
+    /// The size of the image data, if known up front (i.e. the image is not compressed).
+    pub(crate) len: Option<u64>,
+    /// The number of bytes consumed from the file on disk so far.
+    consumed: Arc<AtomicU64>,
+    /// Whether the image is being decompressed on the fly.
+    compressed: bool,
+}
+
+/// Opens an image file, wrapping it in a decoder for the format it is in.
+///
+/// A zstd frame may need a window of at most 2^`zstd_window_log_max` bytes.
+pub(crate) fn open_image(input: &ImageFile, zstd_window_log_max: u32) -> io::Result<ImageSource> {
+    let input_file = input.open()?;
+    let file_len = input_file.len();
+    let consumed = Arc::new(AtomicU64::new(0));
+    let mut counted = BufReader::new(CountingReader {
+        inner: input_file,
+        count: consumed.clone(),
+    });
+
+    let format = match image::detect_format(input)? {
+        // Only the image is read from an archive, and its size is known.
+        Some(Format::Zip) => {
+            let image = zip_image(input)?;
+            counted.seek(SeekFrom::Start(image.data_start))?;
+            let data = counted.take(image.compressed_size);
+            let inner: Box<dyn Read + Send> = if image.deflated {
+                Box::new(DeflateDecoder::new(data))
+            } else {
+                Box::new(data)
+            };
+            let reader = ZipImageReader {
+                inner,
+                crc: flate2::Crc::new(),
+                len: 0,
+                expected_len: image.size,
+                expected_crc: image.crc32,
+            };
+            return Ok(ImageSource {
+                reader: Box::new(CheckedDecoder {
+                    inner: Box::new(reader),
+                    format: Format::Zip,
+                }),
+                len: Some(image.size),
+                consumed,
+                compressed: true,
+            });
+        }
+        Some(format) => format,
+        // Not a compressed file, read it as-is, except for the footer of a
+        // fixed VHD.
+        None => {
+            let (reader, len): (Box<dyn Read + Send>, _) = match vhd::disk_size(input)? {
+                Some(disk_size) => (Box::new(counted.take(disk_size)), disk_size),
+                None => (Box::new(counted), file_len),
+            };
+            return Ok(ImageSource {
+                reader,
+                len: Some(len),
+                consumed,
+                compressed: false,
+            });
+        }
+    };
+
+    Ok(ImageSource {
+        reader: decoder(counted, format, zstd_window_log_max)?,
+        len: None,
+        consumed,
+        compressed: true,
+    })
+}
+
+/// Starts reading `download`, wrapped in a decoder for the format its first
+/// bytes are in. Also returns that format.
+fn open_download(
+    download: &Download,
+    zstd_window_log_max: u32,
+) -> io::Result<(ImageSource, Option<Format>)> {
+    let consumed = Arc::new(AtomicU64::new(0));
+    let mut counted = BufReader::new(CountingReader {
+        inner: download.reader(),
+        count: consumed.clone(),
+    });
+    let format = Format::from_magic(counted.fill_buf()?);
+    let source = match format {
+        Some(format) => ImageSource {
+            reader: decoder(counted, format, zstd_window_log_max)?,
+            len: None,
+            consumed,
+            compressed: true,
+        },
+        None => ImageSource {
+            reader: Box::new(counted),
+            len: download.len(),
+            consumed,
+            compressed: false,
+        },
+    };
+    Ok((source, format))
+}
+
+/// Wraps `input` in a decoder for `format`. A zip archive cannot be read from
+/// a stream, as its directory is at the end.
+///
+/// The decoder is read to the end of the compressed data, even when the size
+/// of the image is known, so that the checksum stored after it (the CRC-32 of
+/// gzip, the checks of xz, zstd and lz4) is always verified.
+fn decoder<R: BufRead + Send + 'static>(
+    input: R,
+    format: Format,
+    zstd_window_log_max: u32,
+) -> io::Result<Box<dyn Read + Send>> {
+    let inner: Box<dyn Read + Send> = match format {
+        Format::Gzip => Box::new(GzDecoder::new(input)),
+        Format::Xz => Box::new(XzDecoder::new(input)),
+        Format::Zstd => {
+            let mut decoder = ZstdDecoder::with_buffer(input)?;
+            decoder.window_log_max(zstd_window_log_max)?;
+            Box::new(ZstdReader {
+                decoder,
+                window_log_max: zstd_window_log_max,
+            })
+        }
+        // pbzip2 and lbzip2 write one stream per block of the input.
+        Format::Bzip2 => Box::new(MultiBzDecoder::new(input)),
+        Format::Lz4 => Box::new(Lz4Frames::new(input)),
+        Format::Zip => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the image in a zip archive can only be written once the whole archive is downloaded",
+            ));
+        }
+    };
+    Ok(Box::new(CheckedDecoder { inner, format }))
+}
+
+/// Estimates the decompressed size of a compressed image from its metadata.
+///
+/// The result is a lower bound on the real size: xz, zstd and lz4 record the
+/// exact size of a single stream or frame (lz4 only if the compressor chose
+/// to), while gzip only stores the size modulo 2^32. bzip2 does not record it
+/// at all. A zip archive records the exact size of the image in it. Returns
+/// `None` if the metadata is missing, unreadable or cannot be right, as the
+/// gzip trailer of a truncated file often is.
+pub(crate) fn decompressed_size_hint(input: &ImageFile, format: Format) -> Option<u64> {
+    // Deflate cannot expand its input more than this many times.
+    const MAX_DEFLATE_RATIO: u64 = 1032;
+
+    let mut file = input.open().ok()?;
+    match format {
+        Format::Gzip => {
+            // The last four bytes of a gzip member hold ISIZE, the input size mod 2^32.
+            let mut isize = [0u8; 4];
+            let len = file.seek(SeekFrom::End(-4)).ok()? + 4;
+            file.read_exact(&mut isize).ok()?;
+            // A truncated file ends in compressed data rather than ISIZE,
+            // which is then often more than the file could hold.
+            let isize = u32::from_le_bytes(isize) as u64;
+            (isize <= len.saturating_mul(MAX_DEFLATE_RATIO)).then_some(isize)
+        }
+        Format::Xz => xz_uncompressed_size(&mut file).ok().flatten(),
+        Format::Zstd => {
+            // The frame header is at most 18 bytes long.
+            let mut header = [0u8; 18];
+            let n = read_full(&mut file, &mut header).ok()?;
+            zstd::zstd_safe::get_frame_content_size(&header[..n])
+                .ok()
+                .flatten()
+        }
+        Format::Bzip2 => None,
+        Format::Lz4 => {
+            // The magic number, the flags and the block descriptor come before
+            // the optional content size and dictionary ID, then a checksum of
+            // the header.
+            let mut header = [0u8; 19];
+            file.read_exact(&mut header[..7]).ok()?;
+            let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+            let has_size = header[4] & 0x08 != 0;
+            if magic != 0x184D_2204 || !has_size {
+                return None;
+            }
+            let end = if header[4] & 0x01 != 0 { 18 } else { 14 };
+            file.read_exact(&mut header[7..=end]).ok()?;
+            let checksum = (xxhash_rust::xxh32::xxh32(&header[4..end], 0) >> 8) as u8;
+            (header[end] == checksum).then(|| u64::from_le_bytes(header[6..14].try_into().unwrap()))
+        }
+        Format::Zip => zip_image(input).ok().map(|image| image.size),
+    }
+}
+
+/// Computes the SHA-256 of an image file as it is on disk, before any
+/// decompression, reporting the number of bytes hashed.
+pub(crate) fn hash_file(
+    input: &ImageFile,
+    running: &AtomicBool,
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<[u8; 32]> {
+    let tag = || io_error(Stage::Checksum, None);
+    let mut file = input.open().map_err(tag())?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut total: u64 = 0;
+    loop {
+        if !running.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled { bytes_synced: 0 }.into());
+        }
+
+        let n = read_full(&mut file, &mut buffer).map_err(tag())?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        total += n as u64;
+        on_progress(total);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Reads the uncompressed size of the last stream in an xz file from its index.
+fn xz_uncompressed_size(file: &mut MultiFileReader) -> io::Result<Option<u64>> {
+    // Skip any stream padding, which is a multiple of four null bytes.
+    let mut end = file.len();
+    let mut word = [0u8; 4];
+    while end >= 4 {
+        file.seek(SeekFrom::Start(end - 4))?;
+        file.read_exact(&mut word)?;
+        if word != [0; 4] {
+            break;
+        }
+        end -= 4;
+    }
+
+    let mut footer = [0u8; 12];
+    if end < footer.len() as u64 {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(end - 12))?;
+    file.read_exact(&mut footer)?;
+    if &footer[10..12] != b"YZ" {
+        return Ok(None);
+    }
+
+    let backward_size = (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as u64 + 1) * 4;
+    let Some(index_start) = (end - 12).checked_sub(backward_size) else {
+        return Ok(None);
+    };
+    let mut index = vec![0u8; backward_size as usize];
+    file.seek(SeekFrom::Start(index_start))?;
+    file.read_exact(&mut index)?;
+
+    // The index starts with a null indicator byte, the record count, then an
+    // (unpadded size, uncompressed size) pair per block, all as varints.
+    let mut fields = index[1..].iter().copied();
+    let mut next_varint = || -> Option<u64> {
+        let mut value = 0u64;
+        for i in 0..9 {
+            let byte = fields.next()?;
+            value |= ((byte & 0x7F) as u64) << (i * 7);
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    };
+
+    if index[0] != 0 {
+        return Ok(None);
+    }
+    let Some(records) = next_varint() else {
+        return Ok(None);
+    };
+    let mut total = 0u64;
+    for _ in 0..records {
+        let (Some(_unpadded), Some(uncompressed)) = (next_varint(), next_varint()) else {
+            return Ok(None);
+        };
+        total += uncompressed;
+    }
+    Ok(Some(total))
+}
+
+/// Reads from `reader` until `buf` is full or the end of the stream is reached.
+///
+/// Decoders frequently return short reads, but the O_DIRECT write loop needs
+/// full, block-aligned chunks for everything except the final one.
+pub(crate) fn read_full(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// How many buffers circulate between the reader thread and the writer.
+const PIPELINE_DEPTH: usize = 4;
+
+/// A block-aligned buffer holding one chunk of image data.
+struct Chunk {
+    buf: AlignedBuffer,
+    /// The number of image bytes in the chunk.
+    len: usize,
+    /// How long it took to fill the chunk from the image.
+    read_time: Duration,
+}
+
+impl Chunk {
+    fn new(capacity: usize, block_size: usize) -> Self {
+        Self {
+            buf: AlignedBuffer::new(capacity, block_size),
+            len: 0,
+            read_time: Duration::ZERO,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buf
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    /// Fills the chunk from `reader`, returning `true` if it is the last one.
+    fn fill(&mut self, reader: &mut dyn Read) -> io::Result<bool> {
+        let started = Instant::now();
+        self.len = read_full(reader, self.as_mut_slice())?;
+        self.read_time = started.elapsed();
+        Ok(self.len < self.capacity())
+    }
+}
+
+/// Hands the image to the write loop one chunk at a time.
+///
+/// In pipelined mode a background thread reads (and decompresses) the next
+/// chunks into a small ring of buffers while the current one is being written.
+/// Otherwise the image is read inline, between writes.
+enum ChunkReader {
+    Inline {
+        reader: Box<dyn Read + Send>,
+        spare: Vec<Chunk>,
+        buffer_size: usize,
+        block_size: usize,
+    },
+    Pipelined {
+        filled: Receiver<io::Result<Chunk>>,
+        free: Sender<Chunk>,
+    },
+}
+
+impl ChunkReader {
+    /// `in_flight` is the number of chunks the writer may hold on to before
+    /// recycling them, on top of the ones being read ahead.
+    fn new(
+        mut reader: Box<dyn Read + Send>,
+        pipelined: bool,
+        buffer_size: usize,
+        block_size: usize,
+        in_flight: usize,
+        running: Arc<AtomicBool>,
+    ) -> Self {
+        if !pipelined {
+            return ChunkReader::Inline {
+                reader,
+                spare: Vec::new(),
+                buffer_size,
+                block_size,
+            };
+        }
+
+        let (filled_tx, filled) = mpsc::sync_channel(PIPELINE_DEPTH);
+        let (free, free_rx) = mpsc::channel();
+        for _ in 0..PIPELINE_DEPTH + in_flight {
+            let _ = free.send(Chunk::new(buffer_size, block_size));
+        }
+
+        thread::spawn(move || {
+            // The thread stops once the writer hangs up its end of the channels.
+            while let Ok(mut chunk) = free_rx.recv() {
+                if !running.load(Ordering::SeqCst) {
+                    return;
+                }
+                let (result, done) = match chunk.fill(&mut reader) {
+                    Ok(last) => (Ok(chunk), last),
+                    Err(e) => (Err(e), true),
+                };
+                if filled_tx.send(result).is_err() || done {
+                    return;
+                }
+            }
+        });
+
+        ChunkReader::Pipelined { filled, free }
+    }
+
+    /// Returns the next chunk of the image, or `None` if the reader thread
+    /// stopped because the operation was cancelled.
+    fn next(&mut self) -> io::Result<Option<Chunk>> {
+        match self {
+            ChunkReader::Inline {
+                reader,
+                spare,
+                buffer_size,
+                block_size,
+            } => {
+                let mut chunk = spare
+                    .pop()
+                    .unwrap_or_else(|| Chunk::new(*buffer_size, *block_size));
+                chunk.fill(reader)?;
+                Ok(Some(chunk))
+            }
+            ChunkReader::Pipelined { filled, .. } => filled.recv().ok().transpose(),
+        }
+    }
+
+    /// Returns a chunk to the reader once it has been written.
+    fn recycle(&mut self, chunk: Chunk) {
+        match self {
+            ChunkReader::Inline { spare, .. } => spare.push(chunk),
+            ChunkReader::Pipelined { free, .. } => {
+                let _ = free.send(chunk);
+            }
+        }
+    }
+}
+
+/// A chunk on its way to the device.
+struct PendingWrite {
+    chunk: Chunk,
+    /// Where on the device the written part of the chunk goes.
+    device_offset: u64,
+    /// Where the written part starts in the chunk, a multiple of the block size.
+    start: usize,
+    /// The number of bytes to write, a multiple of the block size for O_DIRECT.
+    len: usize,
+}
+
+/// Why the write loop stopped before the end of the image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Interruption {
+    /// The operation was cancelled.
+    Cancelled,
+    /// The image ran past the end of the device.
+    OutOfSpace,
+}
+
+impl Interruption {
+    /// Recognizes the chunk write errors that stop the loop without failing
+    /// the device.
+    fn of(e: &io::Error) -> Option<Self> {
+        if e.kind() == io::ErrorKind::Interrupted {
+            Some(Interruption::Cancelled)
+        } else if e.raw_os_error() == Some(libc::ENOSPC) {
+            Some(Interruption::OutOfSpace)
+        } else {
+            None
+        }
+    }
+}
+
+/// Issues chunk writes to the device and hands the chunks back, in order,
+/// once they have been written.
+enum ChunkWriter {
+    /// Writes each chunk before the next one is submitted.
+    Sync(Option<PendingWrite>),
+    /// Writes each chunk on a worker thread, so a write that hangs can be
+    /// given up on.
+    Worker(worker::WorkerWriter),
+    /// Keeps several writes in flight through io_uring.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring(Box<uring::UringWriter>),
+}
+
+impl ChunkWriter {
+    fn submit(
+        &mut self,
+        write: PendingWrite,
+        device_file: &mut File,
+        retry: &RetryPolicy,
+        running: &AtomicBool,
+        on_retry: &mut dyn FnMut(u64, u32),
+    ) -> io::Result<()> {
+        match self {
+            ChunkWriter::Sync(finished) => {
+                // Chunks are not contiguous on the device with a block map.
+                device_file.seek(SeekFrom::Start(write.device_offset))?;
+                write_chunk(
+                    device_file,
+                    write.device_offset,
+                    &write.chunk.as_slice()[write.start..write.start + write.len],
+                    retry,
+                    running,
+                    on_retry,
+                )?;
+                *finished = Some(write);
+                Ok(())
+            }
+            ChunkWriter::Worker(worker) => worker.submit(write),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ChunkWriter::Uring(uring) => uring.submit(write),
+        }
+    }
+
+    /// Returns the oldest write that has finished. With `wait` set, this
+    /// blocks until the oldest write in flight finishes, and only returns
+    /// `None` once nothing is left in flight.
+    #[cfg_attr(
+        not(all(target_os = "linux", feature = "io-uring")),
+        allow(unused_variables)
+    )]
+    fn next_finished(
+        &mut self,
+        wait: bool,
+        device_file: &mut File,
+        retry: &RetryPolicy,
+        running: &AtomicBool,
+        on_retry: &mut dyn FnMut(u64, u32),
+    ) -> io::Result<Option<PendingWrite>> {
+        match self {
+            ChunkWriter::Sync(finished) => Ok(finished.take()),
+            ChunkWriter::Worker(worker) => worker.next_finished(on_retry),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ChunkWriter::Uring(uring) => {
+                uring.next_finished(wait, device_file, retry, running, on_retry)
+            }
+        }
+    }
+}
+
+/// Returns a function that tags an I/O error with the stage it happened in and,
+/// for the write and verify loops, the device offset of the failing chunk.
+pub(crate) fn io_error(
+    stage: Stage,
+    offset: Option<u64>,
+) -> impl FnOnce(io::Error) -> anyhow::Error {
+    move |source| {
+        // Problems with what the image holds are raised while opening it.
+        if source.get_ref().is_some_and(|e| e.is::<Error>()) {
+            let inner = source.into_inner().unwrap().downcast::<Error>().unwrap();
+            return (*inner).into();
+        }
+        Error::Io {
+            stage,
+            offset,
+            source,
+        }
+        .into()
+    }
+}
+
+/// Discards `len` bytes of the device starting at `start`.
+///
+/// The range is discarded in steps, so that progress can be reported and the
+/// operation cancelled. `on_start` is only called once the device has accepted
+/// the first step; a device without discard support is silently skipped.
+fn discard_range(
+    device_file: &File,
+    start: u64,
+    len: u64,
+    running: &AtomicBool,
+    on_start: &mut dyn FnMut(u64),
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<()> {
+    let mut done: u64 = 0;
+    while done < len {
+        if !running.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled { bytes_synced: 0 }.into());
+        }
+
+        let step = std::cmp::min(DISCARD_STEP, len - done);
+        match platform::discard(device_file, start + done, step) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return Ok(()),
+            Err(e) => return Err(io_error(Stage::Discard, Some(start + done))(e)),
+        }
+        if done == 0 {
+            on_start(len);
+        }
+        done += step;
+        on_progress(done);
+    }
+    Ok(())
+}
+
+/// Erases the first `len` bytes of the device, returning how it was erased.
+///
+/// Each method is tried in turn, from `BLKSECDISCARD` to `BLKDISCARD` to
+/// writing zeros, until the device accepts the first step of one. `on_start`
+/// is called with the method that was settled on.
+fn erase_device(
+    device_file: &mut File,
+    len: u64,
+    block_size: usize,
+    running: &AtomicBool,
+    on_start: &mut dyn FnMut(EraseMethod, u64),
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<EraseMethod> {
+    'methods: for method in [EraseMethod::SecureDiscard, EraseMethod::Discard] {
+        let mut done: u64 = 0;
+        while done < len {
+            if !running.load(Ordering::SeqCst) {
+                return Err(Error::Cancelled { bytes_synced: 0 }.into());
+            }
+
+            let step = std::cmp::min(DISCARD_STEP, len - done);
+            let discarded = match method {
+                EraseMethod::SecureDiscard => platform::secure_discard(device_file, done, step),
+                _ => platform::discard(device_file, done, step),
+            };
+            match discarded {
+                Ok(()) => {}
+                Err(e)
+                    if done == 0
+                        && matches!(
+                            e.raw_os_error(),
+                            Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL)
+                        ) =>
+                {
+                    continue 'methods;
+                }
+                Err(e) => return Err(io_error(Stage::Erase, Some(done))(e)),
+            }
+            if done == 0 {
+                on_start(method, len);
+            }
+            done += step;
+            on_progress(done);
+        }
+        return Ok(method);
+    }
+
+    on_start(EraseMethod::Zero, len);
+    let done = zero_range(
+        device_file,
+        0,
+        len,
+        block_size,
+        Stage::Erase,
+        running,
+        on_progress,
+    )?;
+    if done < len {
+        return Err(Error::Cancelled { bytes_synced: 0 }.into());
+    }
+    device_file
+        .sync_data()
+        .map_err(io_error(Stage::Sync, None))?;
+    Ok(EraseMethod::Zero)
+}
+
+/// Zeroes `len` bytes of the device starting at `start`, returning how many
+/// were zeroed before the operation was cancelled.
+///
+/// The device zeroes the range itself where it supports `BLKZEROOUT`. Otherwise
+/// zeros are written from a buffer aligned to `block_size`, like image data.
+fn zero_range(
+    device_file: &mut File,
+    start: u64,
+    len: u64,
+    block_size: usize,
+    stage: Stage,
+    running: &AtomicBool,
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<u64> {
+    let mut zeros: Option<AlignedBuffer> = None;
+    let mut done: u64 = 0;
+    while done < len && running.load(Ordering::SeqCst) {
+        let offset = start + done;
+        let step = match &zeros {
+            None => {
+                let step = std::cmp::min(WIPE_STEP, len - done);
+                match platform::zero_out(device_file, offset, step) {
+                    Ok(()) => step,
+                    Err(e)
+                        if done == 0
+                            && matches!(
+                                e.raw_os_error(),
+                                Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL)
+                            ) =>
+                    {
+                        zeros = Some(AlignedBuffer::new(BUFFER_SIZE, block_size));
+                        device_file
+                            .seek(SeekFrom::Start(offset))
+                            .map_err(io_error(stage, Some(offset)))?;
+                        continue;
+                    }
+                    Err(e) => return Err(io_error(stage, Some(offset))(e)),
+                }
+            }
+            Some(zeros) => {
+                let step = std::cmp::min(BUFFER_SIZE as u64, len - done) as usize;
+                device_file
+                    .write_all(&zeros[..step])
+                    .map_err(io_error(stage, Some(offset)))?;
+                step as u64
+            }
+        };
+        done += step;
+        on_progress(done);
+    }
+    Ok(done)
+}
+
+/// Saves a checkpoint for a write that has synced `offset` bytes to the device.
+fn record_checkpoint(path: &Path, offset: u64, device_size: u64, hasher: &Sha256) -> Result<()> {
+    Checkpoint {
+        offset,
+        device_size,
+        image_sha256: hasher.clone().finalize().into(),
+    }
+    .save(path)
+}
+
+/// Validates the checkpoint at `path` and advances `source` past the part of
+/// the image that has already been written, feeding it into `hasher`.
+///
+/// Returns the offset to resume writing from.
+fn skip_to_checkpoint(
+    path: &Path,
+    source: &mut ImageSource,
+    device_len: u64,
+    block_size: usize,
+    hasher: &mut Sha256,
+    running: &AtomicBool,
+    on_decompress_progress: &mut dyn FnMut(DecompressProgress),
+) -> Result<u64> {
+    let checkpoint = Checkpoint::load(path)?;
+    let invalid = |reason: &str| Error::InvalidCheckpoint {
+        reason: reason.to_string(),
+    };
+
+    if checkpoint.device_size != device_len {
+        return Err(invalid("the device size has changed").into());
+    }
+    if !checkpoint.offset.is_multiple_of(block_size as u64) {
+        return Err(invalid("the offset is not aligned to the device block size").into());
+    }
+
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut skipped: u64 = 0;
+    while skipped < checkpoint.offset {
+        if !running.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled { bytes_synced: 0 }.into());
+        }
+
+        let chunk = std::cmp::min(BUFFER_SIZE as u64, checkpoint.offset - skipped) as usize;
+        let n = read_full(&mut source.reader, &mut buffer[..chunk])
+            .map_err(io_error(Stage::Decompress, None))?;
+        if source.compressed {
+            on_decompress_progress(DecompressProgress {
+                compressed_read: source.consumed.load(Ordering::Relaxed),
+                decompressed_written: skipped + n as u64,
+            });
+        }
+        if n < chunk {
+            return Err(invalid("the image is shorter than the checkpoint offset").into());
+        }
+        hasher.update(&buffer[..n]);
+        skipped += n as u64;
+    }
+
+    let digest: [u8; 32] = hasher.clone().finalize().into();
+    if digest != checkpoint.image_sha256 {
+        return Err(invalid("it was recorded for a different image").into());
+    }
+    Ok(checkpoint.offset)
+}
+
+/// Reads the whole image from `source` for a dry run, feeding it into `hasher`
+/// and reporting it as written without touching the device.
+///
+/// `capacity` is the space available on the device, if it is limited. Returns
+/// the size of the image and the time spent reading it.
+fn simulate_write(
+    source: &mut ImageSource,
+    capacity: Option<u64>,
+    limiter: &mut Option<RateLimiter>,
+    hasher: &mut Sha256,
+    running: &AtomicBool,
+    on_decompress_progress: &mut dyn FnMut(DecompressProgress),
+    on_write_progress: &mut dyn FnMut(u64),
+) -> Result<(u64, Duration)> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut written: u64 = 0;
+    let mut read_time = Duration::ZERO;
+    loop {
+        if !running.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled { bytes_synced: 0 }.into());
+        }
+
+        let started = Instant::now();
+        let n = read_full(&mut source.reader, &mut buffer)
+            .map_err(io_error(Stage::Decompress, None))?;
+        read_time += started.elapsed();
+        if source.compressed {
+            on_decompress_progress(DecompressProgress {
+                compressed_read: source.consumed.load(Ordering::Relaxed),
+                decompressed_written: written + n as u64,
+            });
+        }
+        if capacity.is_some_and(|capacity| written + n as u64 > capacity) {
+            return Err(Error::DeviceFull {
+                capacity,
+                bytes_written: written,
+            }
+            .into());
+        }
+        hasher.update(&buffer[..n]);
+        written += n as u64;
+        on_write_progress(written);
+        if let Some(limiter) = limiter {
+            limiter.throttle(n as u64, running);
+        }
+
+        if n < BUFFER_SIZE {
+            return Ok((written, read_time));
+        }
+    }
+}
+
+/// Where the image data comes from.
+///
+/// `P` is the path of an image file, until [`WriteOptions::run`] finds the
+/// parts it is read from.
+enum ImageInput<P = PathBuf> {
+    /// An image file, decompressed on the fly according to its format.
+    Path(P),
+    /// An image downloaded as it is written, decompressed on the fly like a
+    /// file.
+    Url(String),
+    /// An arbitrary stream of raw image data, taken by the first `run`.
+    ///
+    /// `size_hint` is a lower bound on the size when `len` is not known, used
+    /// only to reject images that cannot fit.
+    Reader {
+        reader: Option<Box<dyn Read + Send>>,
+        len: Option<u64>,
+        size_hint: Option<u64>,
+    },
+}
+
+/// Manages the lifetime of a decompressed image file.
+/// If the image was decompressed to a temp file, this struct holds the handle
+/// and will delete the file on drop.
+struct DecompressedImage {
+    file: ImageFile,
+    _temp_handle: Option<TempPath>,
+}
+
+/// Fails with [`Error::InsufficientTempSpace`] if `dir` cannot hold the
+/// image in `input` once it is decompressed.
+///
+/// The size is read from the compression metadata where possible, and is
+/// otherwise estimated as `ratio` times the size of the compressed file.
+fn check_temp_space(input: &ImageFile, dir: &Path, ratio: f64) -> Result<()> {
+    let compressed_len = input
+        .open()
+        .map_err(io_error(Stage::Decompress, None))?
+        .len();
+    let estimate = (compressed_len as f64 * ratio) as u64;
+    let format = image::detect_format(input).map_err(io_error(Stage::Decompress, None))?;
+    let hint = format.and_then(|f| Some((f, decompressed_size_hint(input, f)?)));
+    let needed = match hint {
+        // gzip only records the size modulo 4 GiB. Even incompressible data
+        // barely grows when gzipped, so a size well below that of the
+        // compressed file must have wrapped around.
+        Some((Format::Gzip, size)) if size < compressed_len - compressed_len / 100 => estimate,
+        Some((_, size)) => size,
+        None => estimate,
+    };
+
+    let available = platform::free_space(dir).map_err(io_error(Stage::Decompress, None))?;
+    if needed > available {
+        return Err(Error::InsufficientTempSpace {
+            dir: dir.to_path_buf(),
+            needed,
+            available,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Decompresses an image to a temporary file if necessary.
+///
+/// By default the write path streams the decoder output directly to the
+/// device; this is used when [`WriteOptions::decompress_to_temp`] is set.
+/// Unless `assumed_ratio` is `None`, the free space in the temporary directory
+/// is checked first (see [`check_temp_space`]).
+///
+/// With a `cache`, an intact cached copy is used if there is one, reporting
+/// the bytes of it checked. Otherwise the image is decompressed into the cache
+/// directory and stored in the cache if it fits.
+fn decompress_image<F>(
+    input: &ImageFile,
+    zstd_window_log_max: u32,
+    cache: Option<&Cache>,
+    assumed_ratio: Option<f64>,
+    running: Arc<AtomicBool>,
+    mut on_progress: F,
+) -> Result<DecompressedImage>
+where
+    F: FnMut(DecompressProgress),
+{
+    let tag = || io_error(Stage::Decompress, None);
+    let mut source = open_image(input, zstd_window_log_max).map_err(tag())?;
+    if !source.compressed {
+        // Not a compressed file, return the original.
+        return Ok(DecompressedImage {
+            file: input.clone(),
+            _temp_handle: None,
+        });
+    }
+
+    let key = match cache {
+        Some(cache) => {
+            // Hashing the compressed file for the key reads all of it, and a
+            // hit then reads the cached image back to check it.
+            let key = hash_file(input, &running, &mut |compressed_read| {
+                on_progress(DecompressProgress {
+                    compressed_read,
+                    decompressed_written: 0,
+                })
+            })?;
+            let compressed_read = input.open().map_or(0, |file| file.len());
+            let mut on_checked = |decompressed_written| {
+                on_progress(DecompressProgress {
+                    compressed_read,
+                    decompressed_written,
+                })
+            };
+            if let Some(path) = cache.lookup(&key, &running, &mut on_checked)? {
+                return Ok(DecompressedImage {
+                    file: ImageFile::single(path),
+                    _temp_handle: None,
+                });
+            }
+            Some(key)
+        }
+        None => None,
+    };
+
+    let temp_dir = cache.map_or_else(std::env::temp_dir, |c| c.dir().to_path_buf());
+    let mut temp_file = match cache {
+        Some(cache) => cache.partial_file(),
+        None => NamedTempFile::new_in(&temp_dir),
+    }
+    .map_err(tag())?;
+    if let Some(ratio) = assumed_ratio {
+        check_temp_space(input, &temp_dir, ratio)?;
+    }
+
+    let mut hasher = cache.map(|_| Sha256::new());
+    let mut total: u64 = 0;
+    {
+        let mut writer = BufWriter::new(&mut temp_file);
+        let mut buffer = [0u8; 8192];
+
+        loop {
+            if !running.load(Ordering::SeqCst) {
+                return Err(Error::Cancelled { bytes_synced: 0 }.into());
+            }
+
+            let n = source.reader.read(&mut buffer).map_err(tag())?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..n]).map_err(tag())?;
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&buffer[..n]);
+            }
+            total += n as u64;
+            on_progress(DecompressProgress {
+                compressed_read: source.consumed.load(Ordering::Relaxed),
+                decompressed_written: total,
+            });
+        }
+        writer.flush().map_err(tag())?;
+    }
+
+    if let (Some(cache), Some(key), Some(hasher)) = (cache, key, hasher)
+        && cache.admits(total)
+    {
+        let path = cache
+            .insert(&key, temp_file, total, hasher.finalize().into())
+            .map_err(tag())?;
+        return Ok(DecompressedImage {
+            file: ImageFile::single(path),
+            _temp_handle: None,
+        });
+    }
+
+    // Hand over ownership of the temp file to the DecompressedImage struct.
+    let temp_path = temp_file.into_temp_path();
+    Ok(DecompressedImage {
+        file: ImageFile::single(temp_path.to_path_buf()),
+        _temp_handle: Some(temp_path),
+    })
+}
+
+/// Checks a download against its published SHA-256, once the rest of it has
+/// arrived.
+fn check_download(download: Option<&Download>, expected: Option<[u8; 32]>) -> Result<()> {
+    if let (Some(download), Some(expected)) = (download, expected) {
+        let actual = download
+            .finish()
+            .map_err(io_error(Stage::Decompress, None))?;
+        if actual != expected {
+            return Err(Error::DownloadChecksumMismatch { expected, actual }.into());
+        }
+    }
+    Ok(())
+}
+
+/// Configures and runs the writing of an image file to a block device.
+///
+/// This is the main entry point for the writing process. Compressed images are
+/// decompressed on the fly and streamed directly to the device. The image data
+/// is hashed as it is written, so verification only needs to read the device
+/// back.
+///
+/// Every setting has a sensible default, so only the options that differ need
+/// to be set before calling [`WriteOptions::run`]:
+///
+/// ```rust,no_run
+/// use etchr_core::write::WriteOptions;
+///
+/// # fn main() -> anyhow::Result<()> {
+/// WriteOptions::new("image.img.xz", "/dev/sdb")
+///     .auto_unmount(true)
+///     .on_write_progress(|bytes| println!("{} bytes written", bytes))
+///     .run()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct WriteOptions<'a> {
+    image: ImageInput,
+    device_path: PathBuf,
+    verify_mode: VerifyMode,
+    verify_method: VerifyMethod,
+    verify_hash: HashAlgorithm,
+    exclusive: bool,
+    auto_unmount: bool,
+    allow_file_target: bool,
+    allow_system_disk: bool,
+    allow_partition: bool,
+    sync_writes: bool,
+    device: Option<Device>,
+    device_file: Option<File>,
+    expected_device_size: Option<u64>,
+    buffer_size: usize,
+    retry: RetryPolicy,
+    checkpoint: Option<PathBuf>,
+    resume: Option<PathBuf>,
+    bmap: Option<PathBuf>,
+    decompress_to_temp: bool,
+    cache: Option<Cache>,
+    check_temp_space: bool,
+    assumed_compression_ratio: f64,
+    zstd_window_log_max: u32,
+    pipelined: bool,
+    discard: bool,
+    only_changed: bool,
+    eject_on_success: bool,
+    dry_run: bool,
+    wipe_remainder: bool,
+    secure_erase: bool,
+    relocate_gpt_backup: bool,
+    grow_last_partition: bool,
+    direct_io: DirectIo,
+    drop_caches: bool,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    queue_depth: u32,
+    stall_timeout: Option<Duration>,
+    offset: u64,
+    max_bytes_per_sec: Option<u64>,
+    files: Vec<PartitionFile>,
+    expected_source_sha256: Option<[u8; 32]>,
+    running: Arc<AtomicBool>,
+    on_checksum_start: Box<dyn FnMut(u64) + 'a>,
+    on_checksum_progress: Box<dyn FnMut(u64) + 'a>,
+    on_discard_start: Box<dyn FnMut(u64) + 'a>,
+    on_discard_progress: Box<dyn FnMut(u64) + 'a>,
+    on_download_start: Box<dyn FnMut(Option<u64>) + 'a>,
+    on_decompress_start: Box<dyn FnMut(Option<u64>) + 'a>,
+    on_decompress_progress: Box<dyn FnMut(u64) + 'a>,
+    on_decompress_counts: Box<dyn FnMut(DecompressProgress) + 'a>,
+    on_write_start: Box<dyn FnMut(u64) + 'a>,
+    on_write_progress: Box<dyn FnMut(u64) + 'a>,
+    on_sync_start: Box<dyn FnMut() + 'a>,
+    on_sync_done: Box<dyn FnMut() + 'a>,
+    on_verify_start: Box<dyn FnMut(u64) + 'a>,
+    on_verify_progress: Box<dyn FnMut(u64) + 'a>,
+    on_wipe_start: Box<dyn FnMut(u64) + 'a>,
+    on_erase_start: Box<dyn FnMut(EraseMethod, u64) + 'a>,
+    on_erase_progress: Box<dyn FnMut(u64) + 'a>,
+    on_wipe_progress: Box<dyn FnMut(u64) + 'a>,
+    on_progress: Box<dyn FnMut(&Progress) + 'a>,
+    progress_window: Duration,
+    on_retry: Box<dyn FnMut(u64, u32) + 'a>,
+    on_warning: Box<dyn FnMut(Warning) + 'a>,
+}
+
+impl<'a> WriteOptions<'a> {
+    /// Creates the options for writing `image` (which can be compressed) to
+    /// the block device at `device_path`.
+    ///
+    /// `image` is an image file, or an HTTP(S) URL to download the image from
+    /// as it is written (see [`Source`]). `device_path` may also name a
+    /// regular file if [`WriteOptions::allow_file_target`] is enabled.
+    pub fn new(image: impl Into<Source>, device_path: impl Into<PathBuf>) -> Self {
+        let input = match image.into() {
+            Source::Path(path) => ImageInput::Path(path),
+            Source::Url(url) => ImageInput::Url(url),
+        };
+        Self::with_input(input, device_path.into())
+    }
+
+    /// Creates the options for writing `image` to `device`, as found by
+    /// [`platform::get_removable_devices`].
+    ///
+    /// What discovery learned about the device enables checks that a bare path
+    /// does not allow:
+    /// - The device must still have the size it had when it was discovered
+    ///   ([`Error::DeviceChanged`]), as with
+    ///   [`WriteOptions::expected_device_size`].
+    /// - A device that was mounted is refused with [`Error::DeviceInUse`]
+    ///   unless [`WriteOptions::auto_unmount`] is set (or
+    ///   [`WriteOptions::exclusive`] is not), before anything else happens.
+    /// - An image that is known to be larger than the device is refused with
+    ///   [`Error::ImageTooLarge`] before the device is unmounted.
+    pub fn for_device(image: impl Into<Source>, device: &Device) -> Self {
+        let mut options = Self::new(image, device.path.clone());
+        options.expected_device_size = Some(device.size_bytes);
+        options.device = Some(device.clone());
+        options
+    }
+
+    /// Creates the options for writing the raw image data produced by `reader`
+    /// (for example stdin) to the block device at `device_path`.
+    ///
+    /// `len` is the size of the image, if known. It is used to check that the
+    /// image fits before writing and is passed to `on_write_start`, which
+    /// receives `0` otherwise. The data is not decompressed, and the reader is
+    /// consumed by the first call to [`WriteOptions::run`].
+    pub fn from_reader(
+        reader: impl Read + Send + 'static,
+        len: Option<u64>,
+        device_path: impl Into<PathBuf>,
+    ) -> Self {
+        let input = ImageInput::Reader {
+            reader: Some(Box::new(reader)),
+            len,
+            size_hint: None,
+        };
+        Self::with_input(input, device_path.into())
+    }
+
+    fn with_input(image: ImageInput, device_path: PathBuf) -> Self {
+        Self {
+            image,
+            device_path,
+            verify_mode: VerifyMode::Full,
+            verify_method: VerifyMethod::Hash,
+            verify_hash: HashAlgorithm::Sha256,
+            exclusive: true,
+            auto_unmount: false,
+            allow_file_target: false,
+            allow_system_disk: false,
+            allow_partition: false,
+            sync_writes: false,
+            device: None,
+            device_file: None,
+            expected_device_size: None,
+            buffer_size: BUFFER_SIZE,
+            retry: RetryPolicy::default(),
+            checkpoint: None,
+            resume: None,
+            bmap: None,
+            decompress_to_temp: false,
+            cache: None,
+            check_temp_space: true,
+            assumed_compression_ratio: DEFAULT_COMPRESSION_RATIO,
+            zstd_window_log_max: DEFAULT_ZSTD_WINDOW_LOG_MAX,
+            pipelined: true,
+            discard: false,
+            only_changed: false,
+            eject_on_success: false,
+            dry_run: false,
+            wipe_remainder: false,
+            secure_erase: false,
+            relocate_gpt_backup: false,
+            grow_last_partition: false,
+            direct_io: DirectIo::default(),
+            drop_caches: false,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            stall_timeout: None,
+            offset: 0,
+            max_bytes_per_sec: None,
+            files: Vec::new(),
+            expected_source_sha256: None,
+            running: Arc::new(AtomicBool::new(true)),
+            on_checksum_start: Box::new(|_| {}),
+            on_checksum_progress: Box::new(|_| {}),
+            on_discard_start: Box::new(|_| {}),
+            on_discard_progress: Box::new(|_| {}),
+            on_download_start: Box::new(|_| {}),
+            on_decompress_start: Box::new(|_| {}),
+            on_decompress_progress: Box::new(|_| {}),
+            on_decompress_counts: Box::new(|_| {}),
+            on_write_start: Box::new(|_| {}),
+            on_write_progress: Box::new(|_| {}),
+            on_sync_start: Box::new(|| {}),
+            on_sync_done: Box::new(|| {}),
+            on_verify_start: Box::new(|_| {}),
+            on_verify_progress: Box::new(|_| {}),
+            on_wipe_start: Box::new(|_| {}),
+            on_erase_start: Box::new(|_, _| {}),
+            on_erase_progress: Box::new(|_| {}),
+            on_wipe_progress: Box::new(|_| {}),
+            on_progress: Box::new(|_| {}),
+            progress_window: PROGRESS_WINDOW,
+            on_retry: Box::new(|_, _| {}),
+            on_warning: Box::new(|_| {}),
+        }
+    }
+
+    /// Whether to read the device back and compare it with the image after
+    /// writing. Defaults to `true`. This is a shorthand for
+    /// [`WriteOptions::verify_mode`] with [`VerifyMode::Full`] or
+    /// [`VerifyMode::None`].
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify_mode = if verify {
+            VerifyMode::Full
+        } else {
+            VerifyMode::None
+        };
+        self
+    }
+
+    /// How to check the data written to the device. Defaults to
+    /// [`VerifyMode::Full`].
+    ///
+    /// Reading each chunk back fails the write with [`Error::VerifyMismatch`]
+    /// as soon as the device returns something else, at the cost of reading
+    /// every chunk while the write is still going.
+    pub fn verify_mode(mut self, verify_mode: VerifyMode) -> Self {
+        self.verify_mode = verify_mode;
+        self
+    }
+
+    /// How the device is checked once the whole image has been written, with
+    /// [`VerifyMode::Full`] or [`VerifyMode::ReadBackAndFull`]. With
+    /// [`VerifyMethod::Compare`], the image is read a second time and
+    /// compared with the device chunk by chunk, so that a mismatch fails with
+    /// [`Error::VerifyMismatch`] at the first byte that differs. A compressed
+    /// image is decompressed again as it streams past, unless it was
+    /// decompressed to a temporary file, which is read instead. Only an image
+    /// file can be compared, not a reader. A write with a block map checks
+    /// each of its ranges against their hashes either way. Defaults to
+    /// [`VerifyMethod::Hash`].
+    pub fn verify_method(mut self, verify_method: VerifyMethod) -> Self {
+        self.verify_method = verify_method;
+        self
+    }
+
+    /// The algorithm the image and the device are hashed with when the device
+    /// is checked once the whole image has been written. The image is hashed
+    /// with it as it is written, alongside the SHA-256 that the report and
+    /// checkpoints record, so a faster one such as [`HashAlgorithm::Blake3`]
+    /// mostly speeds up reading the device back. A write can only be resumed
+    /// with SHA-256. Defaults to [`HashAlgorithm::Sha256`].
+    pub fn verify_hash(mut self, verify_hash: HashAlgorithm) -> Self {
+        self.verify_hash = verify_hash;
+        self
+    }
+
+    /// Whether to open the device with `O_EXCL`, so the kernel refuses the open
+    /// while the device or any of its partitions is mounted. Callers that handle
+    /// unmounting themselves can disable this. Has no effect when the target is
+    /// a regular file. Defaults to `true`.
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// Whether to unmount every mounted partition of the device (see
+    /// [`platform::unmount_device`]) before opening it. Defaults to `false`.
+    pub fn auto_unmount(mut self, auto_unmount: bool) -> Self {
+        self.auto_unmount = auto_unmount;
+        self
+    }
+
+    /// Whether the target may be a regular file rather than a block device. The
+    /// file is created if it does not exist, written through the page cache
+    /// and grows as needed, up to [`WriteOptions::expected_device_size`] if
+    /// that is set. This is useful for testing, or for building disk images.
+    /// Defaults to `false`.
+    pub fn allow_file_target(mut self, allow_file_target: bool) -> Self {
+        self.allow_file_target = allow_file_target;
+        self
+    }
+
+    /// Whether the target may be the disk the running system was booted from
+    /// (see [`platform::system_disk`]), or one of its partitions. Overwriting
+    /// it destroys the running system, so it is refused unless this is set.
+    /// Defaults to `false`.
+    pub fn allow_system_disk(mut self, allow_system_disk: bool) -> Self {
+        self.allow_system_disk = allow_system_disk;
+        self
+    }
+
+    /// Whether the target may be a partition rather than a whole disk, to
+    /// write a filesystem image into it. A disk image written to a partition
+    /// (`/dev/sdb1` instead of `/dev/sdb`) does not boot, so this is refused
+    /// unless set. Defaults to `false`.
+    pub fn allow_partition(mut self, allow_partition: bool) -> Self {
+        self.allow_partition = allow_partition;
+        self
+    }
+
+    /// Whether to open the device with `O_SYNC` (`FILE_FLAG_WRITE_THROUGH` on
+    /// Windows), so that each chunk write only returns once it has reached the
+    /// device. This is slower, but keeps the progress honest on devices with a
+    /// large write cache. Has no effect on a handle passed to
+    /// [`WriteOptions::device_file`]. Defaults to `false`.
+    pub fn sync_writes(mut self, sync_writes: bool) -> Self {
+        self.sync_writes = sync_writes;
+        self
+    }
+
+    /// Writes through `file`, a handle to the device at `device_path` that the
+    /// caller already opened, instead of opening the device itself. This lets
+    /// a small privileged helper open the device and hand it to an
+    /// unprivileged process that does the write.
+    ///
+    /// The caller is responsible for the open flags: the handle must be open
+    /// for reading and writing, and [`WriteOptions::exclusive`] has no effect.
+    /// `O_DIRECT` is still switched on or off with `fcntl` as
+    /// [`WriteOptions::direct_io`] asks. The path is still used to identify
+    /// the device, e.g. for the system disk check, unmounting and ejecting.
+    pub fn device_file(mut self, file: File) -> Self {
+        self.device_file = Some(file);
+        self
+    }
+
+    /// The size in bytes the device had when it was selected, such as
+    /// [`Device::size_bytes`](crate::device::Device::size_bytes). The write
+    /// fails with [`Error::DeviceChanged`] before anything is written if the
+    /// opened device is a different size, e.g. because the card in a
+    /// multi-slot reader was swapped in the meantime. Not checked by default.
+    ///
+    /// A regular file target is taken to be this size, so an image that does
+    /// not fit in it is refused as it would be for a device.
+    pub fn expected_device_size(mut self, size: u64) -> Self {
+        self.expected_device_size = Some(size);
+        self
+    }
+
+    /// The size of each chunk written to the device. Must be a non-zero
+    /// multiple of the device's logical sector size. Defaults to 1 MiB.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// How chunks that fail with a transient error are retried.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Periodically records the progress of the write in this file (see
+    /// [`Checkpoint`]). The file is removed once the write completes.
+    pub fn checkpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
+    /// Continues the write from the checkpoint in this file. The already-written
+    /// part of the image is read back in and hashed to make sure it is the same
+    /// image, but it is not written again.
+    pub fn resume(mut self, path: impl Into<PathBuf>) -> Self {
+        self.resume = Some(path.into());
+        self
+    }
+
+    /// Writes only the blocks of the image that the block map (`.bmap` file)
+    /// at `path` lists as holding data, as `bmaptool` does. The whole image is
+    /// still read, and each mapped range is checked against its checksum in
+    /// the block map as it streams past; verification then only reads back
+    /// the mapped ranges. The rest of the device keeps its old contents. A
+    /// dry run ignores the block map.
+    pub fn bmap(mut self, path: impl Into<PathBuf>) -> Self {
+        self.bmap = Some(path.into());
+        self
+    }
+
+    /// Whether to decompress a compressed image to a temporary file before
+    /// writing, instead of streaming it to the device. With this set,
+    /// `on_decompress_progress` reports decompressed bytes. A download is
+    /// always streamed. Defaults to `false`.
+    pub fn decompress_to_temp(mut self, decompress_to_temp: bool) -> Self {
+        self.decompress_to_temp = decompress_to_temp;
+        self
+    }
+
+    /// Keeps the decompressed image in `cache` for the next write of the same
+    /// image, or writes the copy kept there by an earlier one. This implies
+    /// `decompress_to_temp` for compressed images, with the cache directory
+    /// taking the place of the temporary directory. A download is not cached.
+    pub fn cache(mut self, cache: Cache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Whether to check that the temporary directory has room for the
+    /// decompressed image before decompressing to it, failing early with
+    /// [`Error::InsufficientTempSpace`] instead of when the disk fills up.
+    /// Only applies with `decompress_to_temp`. Defaults to `true`.
+    pub fn check_temp_space(mut self, check_temp_space: bool) -> Self {
+        self.check_temp_space = check_temp_space;
+        self
+    }
+
+    /// How many times larger than the compressed file the decompressed image
+    /// is assumed to be for the temporary space check, when its size is not
+    /// recorded in the compression metadata. Defaults to 4.
+    pub fn assumed_compression_ratio(mut self, ratio: f64) -> Self {
+        self.assumed_compression_ratio = ratio;
+        self
+    }
+
+    /// The largest window, as a power of two, that a zstd image may need to
+    /// be decompressed. `zstd --long` compresses with windows of up to 2^31
+    /// bytes, which the decoder then has to hold in memory; lowering the limit
+    /// refuses such images with [`Error::ZstdWindowTooLarge`] instead. zstd
+    /// frames are always decoded on one thread, as libzstd has no
+    /// multithreaded decoder. Defaults to 31 (30 on 32-bit platforms).
+    pub fn zstd_window_log_max(mut self, zstd_window_log_max: u32) -> Self {
+        self.zstd_window_log_max = zstd_window_log_max;
+        self
+    }
+
+    /// Whether to read (and decompress) the image on a background thread while
+    /// the previous chunks are being written. Turning this off reads and writes
+    /// in turn on the calling thread, which can help when debugging. Defaults
+    /// to `true`.
+    pub fn pipelined(mut self, pipelined: bool) -> Self {
+        self.pipelined = pipelined;
+        self
+    }
+
+    /// Whether to discard (TRIM) the device from the write offset to its end
+    /// before writing. This speeds up writes and reduces wear on flash-based
+    /// media. Devices without discard support are written as usual, and the
+    /// discard is skipped when resuming so the data already written is kept.
+    /// Defaults to `false`.
+    pub fn discard(mut self, discard: bool) -> Self {
+        self.discard = discard;
+        self
+    }
+
+    /// Whether to read each chunk back from the device before writing it, and
+    /// only write the blocks that differ from the image. Re-flashing a card
+    /// with an image that barely changed then costs mostly reads, which are
+    /// faster than writes and do not wear out the flash. The bytes skipped
+    /// are reported in [`WriteReport::bytes_unchanged`]; verification works
+    /// as usual.
+    ///
+    /// This cannot be combined with [`WriteOptions::discard`] or
+    /// [`WriteOptions::secure_erase`], which leave nothing to compare with.
+    /// Defaults to `false`.
+    pub fn only_changed(mut self, only_changed: bool) -> Self {
+        self.only_changed = only_changed;
+        self
+    }
+
+    /// Whether to flush and eject the device (see [`platform::eject`]) once the
+    /// write and verification have succeeded, so it can be unplugged right
+    /// away. A failure to eject is reported in [`WriteReport::eject_error`]
+    /// and as a [`WarningKind::EjectFailed`] warning rather than failing the
+    /// write. Defaults to `false`.
+    pub fn eject_on_success(mut self, eject_on_success: bool) -> Self {
+        self.eject_on_success = eject_on_success;
+        self
+    }
+
+    /// Whether to only simulate the write. The image is read, decompressed and
+    /// checked against the device as usual and every callback fires, but the
+    /// device is only opened read-only to query its size, and nothing is
+    /// written to it. Erasing, discarding, unmounting, checkpoints, resuming,
+    /// wiping, editing the partition table, adding files and ejecting are
+    /// skipped, and verification is only simulated.
+    ///
+    /// The simulated write runs as fast as the image can be read, or at
+    /// [`WriteOptions::max_bytes_per_sec`] if set. Defaults to `false`.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Whether to zero the rest of the device once the image has been written
+    /// and verified, so no old data is left behind it. Cancelling this stage
+    /// does not fail the write; it is reported in
+    /// [`WriteReport::wipe_incomplete`] instead. Has no effect when the target
+    /// is a regular file. Defaults to `false`.
+    pub fn wipe_remainder(mut self, wipe_remainder: bool) -> Self {
+        self.wipe_remainder = wipe_remainder;
+        self
+    }
+
+    /// Whether to move the image's backup GPT to the end of the device once
+    /// the image has been written and verified. An image carries its backup
+    /// GPT at its own end, so on a larger device it is out of place until it
+    /// is moved. Images without a GPT are left alone. Has no effect when the
+    /// target is a regular file. Defaults to `false`.
+    pub fn relocate_gpt_backup(mut self, relocate_gpt_backup: bool) -> Self {
+        self.relocate_gpt_backup = relocate_gpt_backup;
+        self
+    }
+
+    /// Whether to grow the partition that ends last in the image up to the
+    /// end of the device once the image has been written and verified. Both
+    /// MBR and GPT partition tables are supported; with a GPT the backup GPT
+    /// is moved to the end of the device as well. Only the partition entry
+    /// changes, not the filesystem inside it; the new size is reported in
+    /// [`WriteReport::grown_partition`]. Has no effect when the target is a
+    /// regular file. Defaults to `false`.
+    pub fn grow_last_partition(mut self, grow_last_partition: bool) -> Self {
+        self.grow_last_partition = grow_last_partition;
+        self
+    }
+
+    /// Whether to erase the whole device, not just the part the image covers,
+    /// before writing. The device is securely discarded with `BLKSECDISCARD`
+    /// where it supports it, and otherwise discarded with `BLKDISCARD` or, as
+    /// a last resort, overwritten with zeros. The method used is reported in
+    /// [`WriteReport::erase_method`]; only a secure discard also reaches the
+    /// copies of the data the device keeps internally.
+    ///
+    /// This replaces [`WriteOptions::discard`], cannot be combined with
+    /// resuming, and has no effect when the target is a regular file.
+    /// Defaults to `false`.
+    pub fn secure_erase(mut self, secure_erase: bool) -> Self {
+        self.secure_erase = secure_erase;
+        self
+    }
+
+    /// Whether to write to a block device with `O_DIRECT`. With the default,
+    /// [`DirectIo::Preferred`], a device that rejects `O_DIRECT` is written
+    /// through the page cache instead and a [`WarningKind::DirectIoFallback`]
+    /// warning is sent to `on_warning`.
+    pub fn direct_io(mut self, direct_io: DirectIo) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// Whether to drop the device from the page cache before it is verified,
+    /// if it has to be read through the page cache because
+    /// [`DirectIo::Off`] was requested or the device rejected `O_DIRECT`.
+    /// Otherwise, pages left in the cache by the write can be verified in
+    /// place of what the device holds. An image written through the page
+    /// cache is always dropped from it; this also covers a device written
+    /// with `O_DIRECT` that rejects it for reading. Has no effect when the
+    /// target is a regular file. Defaults to `false`.
+    pub fn drop_caches(mut self, drop_caches: bool) -> Self {
+        self.drop_caches = drop_caches;
+        self
+    }
+
+    /// How many writes to keep in flight through io_uring. Values of `0` or
+    /// `1` use the synchronous write loop instead, which is also used when the
+    /// kernel does not support io_uring. Defaults to 8.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn queue_depth(mut self, queue_depth: u32) -> Self {
+        self.queue_depth = queue_depth;
+        self
+    }
+
+    /// Fails the write with [`Error::Stalled`] if no chunk write completes
+    /// within `timeout`, instead of waiting forever on a device that stopped
+    /// responding. This also bounds how long a cancellation can take to be
+    /// noticed. Without io_uring, the chunks are then written from a worker
+    /// thread, which is left behind if its write never returns. Not set by
+    /// default.
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.stall_timeout = Some(timeout);
+        self
+    }
+
+    /// Writes the image starting this many bytes into the device, leaving
+    /// everything before it untouched (e.g. a bootloader area, or to write
+    /// straight into a partition). Must be a multiple of the device's logical
+    /// sector size. Verification reads back from the same offset. Defaults to `0`.
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Limits the average write rate, so that flashing does not saturate a
+    /// USB bus shared with other devices. Progress and speed reports reflect
+    /// the limited rate. Defaults to no limit.
+    pub fn max_bytes_per_sec(mut self, max_bytes_per_sec: u64) -> Self {
+        self.max_bytes_per_sec = Some(max_bytes_per_sec);
+        self
+    }
+
+    /// Adds a file to a FAT partition of the image once it has been written and
+    /// verified, e.g. `ssh` or `userconf.txt` on a Raspberry Pi boot partition.
+    ///
+    /// `partition` is the 1-based number of a partition in the image's MBR or
+    /// GPT, as in `/dev/sdb1`, and `path` is `/`-separated and relative to the
+    /// root of its filesystem. Missing parent directories are created and an
+    /// existing file is replaced. Can be called several times; the files are
+    /// written in order, before the device is ejected.
+    pub fn add_file(
+        mut self,
+        partition: u32,
+        path: impl Into<String>,
+        contents: impl Into<Vec<u8>>,
+    ) -> Self {
+        self.files.push(PartitionFile {
+            partition,
+            path: path.into(),
+            contents: contents.into(),
+        });
+        self
+    }
+
+    /// The published SHA-256 of the image file, as downloaded (i.e. before
+    /// decompression). The file is hashed and checked against it before
+    /// anything is written, so a corrupt download fails with
+    /// [`Error::ChecksumMismatch`] and leaves the device untouched.
+    ///
+    /// A download is hashed as it arrives instead, and checked once it is
+    /// complete, so a corrupt one fails with [`Error::DownloadChecksumMismatch`]
+    /// after it has been written. Readers cannot be checked.
+    pub fn expected_source_sha256(mut self, sha256: [u8; 32]) -> Self {
+        self.expected_source_sha256 = Some(sha256);
+        self
+    }
+
+    /// A flag that cancels the operation when set to `false`.
+    pub fn running(mut self, running: Arc<AtomicBool>) -> Self {
+        self.running = running;
+        self
+    }
+
+    /// Called when the image file starts being hashed for
+    /// [`WriteOptions::expected_source_sha256`] with the size of the file.
+    pub fn on_checksum_start(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_checksum_start = Box::new(f);
+        self
+    }
+
+    /// Called with the number of bytes of the image file hashed.
+    pub fn on_checksum_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_checksum_progress = Box::new(f);
+        self
+    }
+
+    /// Called when the device starts being discarded with the number of bytes
+    /// to discard. Not called if the device does not support discard.
+    pub fn on_discard_start(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_discard_start = Box::new(f);
+        self
+    }
+
+    /// Called when the device starts being erased with the method settled on
+    /// and the number of bytes to erase.
+    pub fn on_erase_start(mut self, f: impl FnMut(EraseMethod, u64) + 'a) -> Self {
+        self.on_erase_start = Box::new(f);
+        self
+    }
+
+    /// Called with the number of bytes erased.
+    pub fn on_erase_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_erase_progress = Box::new(f);
+        self
+    }
+
+    /// Called with the number of bytes discarded.
+    pub fn on_discard_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_discard_progress = Box::new(f);
+        self
+    }
+
+    /// Called when the server answers the request for a downloaded image,
+    /// with the size of the download if the server gave it. A compressed
+    /// download is reported through the decompression callbacks, in the bytes
+    /// downloaded, and one that is not through the write callbacks.
+    pub fn on_download_start(mut self, f: impl FnMut(Option<u64>) + 'a) -> Self {
+        self.on_download_start = Box::new(f);
+        self
+    }
+
+    /// Called when decompression begins with the size of the decompressed
+    /// image, if its compression metadata records it (see
+    /// [`image::decompressed_size`]). The size may turn out to be too small,
+    /// so a progress bar should stop at full rather than fail.
+    pub fn on_decompress_start(mut self, f: impl FnMut(Option<u64>) + 'a) -> Self {
+        self.on_decompress_start = Box::new(f);
+        self
+    }
+
+    /// Called with the number of compressed bytes consumed, or with the
+    /// number of decompressed bytes written when decompressing to a temp file
+    /// or to the cache. [`on_decompress_counts`](Self::on_decompress_counts)
+    /// reports both.
+    pub fn on_decompress_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_decompress_progress = Box::new(f);
+        self
+    }
+
+    /// Called with the number of compressed bytes consumed and decompressed
+    /// bytes produced so far. The compressed count can be shown against the
+    /// size of the image file even when the decompressed size is unknown.
+    pub fn on_decompress_counts(mut self, f: impl FnMut(DecompressProgress) + 'a) -> Self {
+        self.on_decompress_counts = Box::new(f);
+        self
+    }
+
+    /// Called when writing begins with the total image size, or `0` if the
+    /// size is not known up front (a compressed image, or a reader of unknown
+    /// length).
+    pub fn on_write_start(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_write_start = Box::new(f);
+        self
+    }
+
+    /// Called with the number of bytes written. The final total is only
+    /// reported once the data has been synced to the device.
+    pub fn on_write_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_write_progress = Box::new(f);
+        self
+    }
+
+    /// Called once every byte has been written, when the device starts being
+    /// synced. Flushing the cache of a slow USB stick can take a minute, during
+    /// which no progress is reported.
+    pub fn on_sync_start(mut self, f: impl FnMut() + 'a) -> Self {
+        self.on_sync_start = Box::new(f);
+        self
+    }
+
+    /// Called once the device has been synced.
+    pub fn on_sync_done(mut self, f: impl FnMut() + 'a) -> Self {
+        self.on_sync_done = Box::new(f);
+        self
+    }
+
+    /// Called when verification begins with the number of bytes to verify.
+    pub fn on_verify_start(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_verify_start = Box::new(f);
+        self
+    }
+
+    /// Called with the number of bytes verified.
+    pub fn on_verify_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_verify_progress = Box::new(f);
+        self
+    }
+
+    /// Called when the rest of the device starts being zeroed with the number
+    /// of bytes to zero.
+    pub fn on_wipe_start(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_wipe_start = Box::new(f);
+        self
+    }
+
+    /// Called with the number of bytes zeroed after the image.
+    pub fn on_wipe_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_wipe_progress = Box::new(f);
+        self
+    }
+
+    /// Called alongside each of the stage-specific progress callbacks with a
+    /// [`Progress`] report that also carries the stage, total, speed, ETA and
+    /// the overall fraction of the write (see [`progress`] for the weighting).
+    pub fn on_progress(mut self, f: impl FnMut(&Progress) + 'a) -> Self {
+        self.on_progress = Box::new(f);
+        self
+    }
+
+    /// The window over which [`Progress::bytes_per_sec`] is averaged. A longer
+    /// window smooths out bursty devices. Defaults to 3 seconds.
+    pub fn progress_window(mut self, window: Duration) -> Self {
+        self.progress_window = window;
+        self
+    }
+
+    /// Called with the device offset and attempt number each time a failed
+    /// chunk is retried. A [`WarningKind::WriteRetried`] warning is sent to
+    /// `on_warning` as well.
+    pub fn on_retry(mut self, f: impl FnMut(u64, u32) + 'a) -> Self {
+        self.on_retry = Box::new(f);
+        self
+    }
+
+    /// Called with each [`Warning`] about a problem that does not stop the
+    /// write, such as a device that had to be written through the page cache
+    /// or could not be ejected.
+    pub fn on_warning(mut self, f: impl FnMut(Warning) + 'a) -> Self {
+        self.on_warning = Box::new(f);
+        self
+    }
+
+    /// Writes the image to the device.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The target is not a block device, or a regular file when those are
+    ///   allowed ([`Error::NotABlockDevice`]).
+    /// - The target is the disk the running system was booted from, or one of
+    ///   its partitions, and `allow_system_disk` was not set
+    ///   ([`Error::SystemDisk`]).
+    /// - The target is a partition and `allow_partition` was not set
+    ///   ([`Error::PartitionTarget`]).
+    /// - The buffer size is not a non-zero multiple of the device's logical
+    ///   sector size.
+    /// - The image file or device cannot be accessed.
+    /// - There is not enough free space to decompress the image to a
+    ///   temporary file ([`Error::InsufficientTempSpace`]).
+    /// - A partition could not be unmounted ([`Error::UnmountFailed`]).
+    /// - The device is in use and `exclusive` was requested ([`Error::DeviceInUse`]).
+    /// - The resume checkpoint does not match the image or device
+    ///   ([`Error::InvalidCheckpoint`]).
+    /// - The image file does not match the expected checksum
+    ///   ([`Error::ChecksumMismatch`]).
+    /// - The offset is not sector-aligned or lies beyond the end of the device.
+    /// - The image is larger than the device, less the offset
+    ///   ([`Error::ImageTooLarge`]). For
+    ///   compressed images that are streamed, this is checked up front only when
+    ///   the decompressed size can be read from the compression metadata.
+    ///   Otherwise the write stops once the device is full ([`Error::DeviceFull`]).
+    /// - An I/O error occurs during any stage ([`Error::Io`]).
+    /// - The verification hash does not match ([`Error::HashMismatch`], or
+    ///   [`Error::MappedRangeMismatch`] for a block map), or a chunk checked
+    ///   by a partial verification does not ([`Error::ChunkMismatch`]).
+    /// - A file could not be added to the image ([`Error::CustomizeFailed`]).
+    /// - The operation is cancelled ([`Error::Cancelled`]). Writes in flight are
+    ///   allowed to finish and the device is synced before returning.
+    /// - The device is unplugged ([`Error::DeviceRemoved`]).
+    ///
+    /// Errors that occur once writing has started carry a [`PartialTransfer`]
+    /// context with the number of bytes written and synced so far.
+    pub fn run(&mut self) -> Result<WriteReport> {
+        let mut transfer = None;
+        self.write(&mut transfer).map_err(|e| {
+            let bytes_done = transfer.map_or(0, |t| t.bytes_done);
+            let e = error::detect_removal(e, bytes_done);
+            match transfer {
+                Some(transfer) => e.context(transfer),
+                None => e,
+            }
+        })
+    }
+
+    /// Does the work of [`WriteOptions::run`], keeping `transfer` up to date
+    /// once the device is being written.
+    fn write(&mut self, transfer: &mut Option<PartialTransfer>) -> Result<WriteReport> {
+        // Take the reader up front, so a second run fails before the device is touched.
+        // The parts of a split image are found once, and every read of it
+        // opens the same ones.
+        let input: ImageInput<ImageFile> = match &mut self.image {
+            ImageInput::Path(path) => ImageInput::Path(
+                ImageFile::find(path.as_path()).map_err(io_error(Stage::Decompress, None))?,
+            ),
+            ImageInput::Url(url) => ImageInput::Url(url.clone()),
+            ImageInput::Reader {
+                reader,
+                len,
+                size_hint,
+            } => ImageInput::Reader {
+                reader: Some(
+                    reader
+                        .take()
+                        .ok_or_else(|| anyhow!("The image reader has already been written"))?,
+                ),
+                len: *len,
+                size_hint: *size_hint,
+            },
+        };
+        if self.verify_mode.full()
+            && self.verify_method == VerifyMethod::Compare
+            && !matches!(input, ImageInput::Path(_))
+        {
+            return Err(anyhow!(
+                "Only an image file can be compared with the device, not a reader or a download"
+            ));
+        }
+        if let VerifyMode::Sampled { percent, .. } = self.verify_mode {
+            if !(percent > 0.0 && percent <= 100.0) {
+                return Err(anyhow!(
+                    "The sample to verify must be more than 0% and at most 100% of the image"
+                ));
+            }
+            if self.bmap.is_some() || self.resume.is_some() {
+                return Err(anyhow!(
+                    "A sampled verification cannot be combined with a block map or with resuming"
+                ));
+            }
+        }
+        if let VerifyMode::NonZero { zero_percent, .. } = self.verify_mode {
+            if !(0.0..=100.0).contains(&zero_percent) {
+                return Err(anyhow!(
+                    "The share of zero chunks to verify must be from 0% to 100%"
+                ));
+            }
+            if self.resume.is_some() {
+                return Err(anyhow!(
+                    "Verifying only the non-zero chunks cannot be combined with resuming"
+                ));
+            }
+        }
+        check_zstd_window_log_max(self.zstd_window_log_max)?;
+        // A block map already says which parts of the image hold data, and
+        // only those are read back in full.
+        let verify_mode = match self.verify_mode {
+            VerifyMode::NonZero { .. } if self.bmap.is_some() => VerifyMode::Full,
+            mode => mode,
+        };
+        // Resolve symlinks such as /dev/disk/by-id/..., so that the checks below
+        // and any error refer to the real device node.
+        let device_path =
+            std::fs::canonicalize(&self.device_path).unwrap_or_else(|_| self.device_path.clone());
+        let running = self.running.clone();
+        let (exclusive, retry) = (self.exclusive, self.retry);
+        let checkpoint = self.checkpoint.as_deref();
+        let window = self.progress_window;
+        let structured = progress::Reporter::new(&mut *self.on_progress);
+        let on_warning = RefCell::new(&mut *self.on_warning);
+        let warn = |warning| (on_warning.borrow_mut())(warning);
+        let on_retry = &mut self.on_retry;
+        let on_retry = &mut |offset, attempt| {
+            on_retry(offset, attempt);
+            warn(Warning::new(
+                WarningKind::WriteRetried,
+                format!(
+                    "Writing at offset {} failed, retrying (attempt {})",
+                    offset, attempt
+                ),
+            ));
+        };
+
+        // The target can also be a regular file (a loop-file for testing, or a
+        // disk image being built), if allowed. Files are written through the
+        // page cache, are created if missing and grow as needed.
+        let is_block_device = platform::is_block_device(&device_path);
+        if !is_block_device {
+            let is_file = match std::fs::metadata(&device_path) {
+                Ok(metadata) => metadata.is_file(),
+                Err(e) => e.kind() == io::ErrorKind::NotFound,
+            };
+            if !(is_file && self.allow_file_target) {
+                return Err(Error::NotABlockDevice { path: device_path }.into());
+            }
+        }
 
-impl AsRef<Path> for DecompressedImage {
-    fn as_ref(&self) -> &Path {
-        &self.path
-    }
-}
+        // Device discovery hides the system disk, but a path can also come
+        // straight from the caller.
+        if is_block_device
+            && !self.allow_system_disk
+            && let Some(system_disk) = platform::system_disk()
+            && platform::is_on_disk(&device_path, &system_disk)
+        {
+            return Err(Error::SystemDisk {
+                path: device_path,
+                system_disk,
+            }
+            .into());
+        }
 
-/// Decompresses an image to a temporary file if necessary.
-fn decompress_image<F>(
-    input_path: &Path,
-    running: Arc<AtomicBool>,
-    mut on_progress: F,
-) -> io::Result<DecompressedImage>
-where
-    F: FnMut(u64),
-{
-    let ext = input_path
-        .extension()
-        .and_then(|e| e.to_str())
-        .unwrap_or("")
-        .to_lowercase();
-
-    let input_file = File::open(input_path)?;
-
-    // Create a reader based on the file extension.
-    let mut reader: Box<dyn Read> = match ext.as_str() {
-        "gz" | "gzip" => Box::new(GzDecoder::new(BufReader::new(input_file))),
-        "xz" => Box::new(XzDecoder::new(BufReader::new(input_file))),
-        "zst" | "zstd" => Box::new(ZstdDecoder::new(BufReader::new(input_file))?),
-        // Not a compressed file, return a path to the original.
-        _ => {
-            return Ok(DecompressedImage {
-                path: input_path.to_path_buf(),
-                _temp_handle: None,
+        if is_block_device
+            && !self.allow_partition
+            && let Some(disk) = platform::parent_disk(&device_path)
+        {
+            return Err(Error::PartitionTarget {
+                path: device_path,
+                disk,
+            }
+            .into());
+        }
+
+        // The download starts before anything on the device changes, so that
+        // a broken link fails early. Its first bytes tell its format.
+        let (download, mut downloaded) = match &input {
+            ImageInput::Url(url) => {
+                let download = Download::open(url, running.clone())?;
+                (self.on_download_start)(download.len());
+                let downloaded = open_download(&download, self.zstd_window_log_max)
+                    .map_err(io_error(Stage::Decompress, None))?;
+                (Some(download), Some(downloaded))
+            }
+            _ => (None, None),
+        };
+        let (compression, image_len) = match &input {
+            ImageInput::Url(url) => {
+                let (source, compression) =
+                    downloaded.as_ref().expect("the download was opened above");
+                let named = Source::Url(url.clone())
+                    .name()
+                    .and_then(Format::from_extension);
+                if let Some(format) = *compression
+                    && named != Some(format)
+                {
+                    let name = match named {
+                        Some(named) => format!("its name says {}", named),
+                        None => "its name does not say so".to_string(),
+                    };
+                    warn(Warning::new(
+                        WarningKind::FormatMismatch,
+                        format!(
+                            "The download holds {} data, although {}, so it is unpacked as {}.",
+                            format, name, format
+                        ),
+                    ));
+                }
+                (*compression, source.len)
+            }
+            ImageInput::Path(file) => {
+                let compression =
+                    image::detect_format(file).map_err(io_error(Stage::Decompress, None))?;
+                let named = Format::from_extension(file.path());
+                if let Some(format) = compression
+                    && named != Some(format)
+                {
+                    let name = match named {
+                        Some(named) => format!("its name says {}", named),
+                        None => "its name does not say so".to_string(),
+                    };
+                    warn(Warning::new(
+                        WarningKind::FormatMismatch,
+                        format!(
+                            "The image file holds {} data, although {}, so it is unpacked as {}.",
+                            format, name, format
+                        ),
+                    ));
+                }
+                let image_len = match compression {
+                    Some(c) => decompressed_size_hint(file, c),
+                    None => {
+                        match vhd::disk_size(file).map_err(io_error(Stage::Decompress, None))? {
+                            Some(disk_size) => Some(disk_size),
+                            None => Some(file.open()?.len()),
+                        }
+                    }
+                };
+                (compression, image_len)
+            }
+            ImageInput::Reader { len, size_hint, .. } => (None, len.or(*size_hint)),
+        };
+        let decompressed_len = image_len.filter(|_| compression.is_some());
+        // Decompression is tracked in compressed bytes consumed, whose total
+        // is always known for an image file, and usually for a download. The plain callback keeps
+        // reporting decompressed bytes when decompressing to a file.
+        let decompress_total = match &input {
+            ImageInput::Path(file) => file.open().ok().map(|reader| reader.len()),
+            ImageInput::Url(_) => download.as_ref().and_then(Download::len),
+            ImageInput::Reader { .. } => None,
+        };
+        let to_file = (self.decompress_to_temp || self.cache.is_some())
+            && matches!(input, ImageInput::Path(_));
+        let on_decompress_plain = &mut self.on_decompress_progress;
+        let on_decompress_counts = &mut self.on_decompress_counts;
+        let mut ignore = |_| {};
+        let mut on_decompress_tracked = progress::tracked(
+            Stage::Decompress,
+            decompress_total,
+            window,
+            &mut ignore,
+            &structured,
+        );
+        let mut on_decompress_progress = |counts: DecompressProgress| {
+            on_decompress_counts(counts);
+            on_decompress_plain(if to_file {
+                counts.decompressed_written
+            } else {
+                counts.compressed_read
             });
+            on_decompress_tracked(counts.compressed_read);
+        };
+        let block_map = self.bmap.as_deref().map(BlockMap::read).transpose()?;
+        let image_len = match &block_map {
+            Some(map) => {
+                let exact = match &input {
+                    ImageInput::Path(_) | ImageInput::Url(_) => {
+                        image_len.filter(|_| compression.is_none())
+                    }
+                    ImageInput::Reader { len, .. } => *len,
+                };
+                if let Some(len) = exact
+                    && len != map.image_size
+                {
+                    return Err(anyhow!(
+                        "The block map is for an image of {} bytes, but the image is {} bytes",
+                        map.image_size,
+                        len
+                    ));
+                }
+                Some(map.image_size)
+            }
+            None => image_len,
+        };
+
+        // Discovery already knows the size of the device and whether it is
+        // mounted, so these are refused before anything changes, unmounting
+        // included. The size is checked for real once the device is open.
+        if let Some(device) = &self.device {
+            if !device.mount_point.is_empty() && exclusive && !self.auto_unmount && !self.dry_run {
+                return Err(Error::DeviceInUse { path: device_path }.into());
+            }
+            let available = device.size_bytes.saturating_sub(self.offset);
+            if let Some(image_len) = image_len
+                && image_len > available
+            {
+                return Err(Error::ImageTooLarge {
+                    image: image_len,
+                    device: available,
+                }
+                .into());
+            }
         }
-    };
 
-    let mut temp_file = NamedTempFile::new()?;
-    {
-        let mut writer = BufWriter::new(&mut temp_file);
-        let mut buffer = [0u8; 8192];
-        let mut total: u64 = 0;
+        if self.auto_unmount && is_block_device && !self.dry_run {
+            platform::unmount_device(&device_path)?;
+        }
 
-        loop {
-            if !running.load(Ordering::SeqCst) {
-                return Err(io::Error::new(
-                    io::ErrorKind::Interrupted,
-                    "Operation cancelled by user",
+        // The device is also read from if files are added to it afterwards.
+        let mut direct_io = DirectIo::Off;
+        let open_options = if self.dry_run {
+            // Only to query the size of the device.
+            DeviceOpenOptions::read_only()
+        } else if is_block_device {
+            // Use O_DIRECT for unbuffered I/O, unless asked not to. The kernel
+            // refuses an exclusive open of a mounted block device.
+            direct_io = self.direct_io;
+            DeviceOpenOptions::read_write()
+                .direct_io(direct_io)
+                .exclusive(exclusive)
+                .sync_writes(self.sync_writes)
+        } else {
+            DeviceOpenOptions::read_write()
+                .create(true)
+                .sync_writes(self.sync_writes)
+        };
+        // A dry run leaves file targets alone, even if they do not exist yet.
+        let mut device_file = if self.dry_run && !is_block_device {
+            None
+        } else {
+            let (file, fallback) = if let Some(file) = &self.device_file {
+                // The caller opened the device, so only O_DIRECT is up to us.
+                let file = file.try_clone()?;
+                let mut fallback = None;
+                if is_block_device && !self.dry_run {
+                    match platform::set_direct_io(&file, direct_io != DirectIo::Off) {
+                        Ok(()) => {}
+                        Err(e) if direct_io == DirectIo::Preferred => fallback = Some(e),
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                (file, fallback)
+            } else {
+                let opened = open_options.open(&device_path);
+                opened.map_err(|e| match e.raw_os_error() {
+                    Some(libc::EBUSY) if exclusive => Error::DeviceInUse {
+                        path: device_path.clone(),
+                    }
+                    .into(),
+                    _ => anyhow::Error::from(e),
+                })?
+            };
+            if let Some(e) = fallback {
+                warn(Warning::new(
+                    WarningKind::DirectIoFallback,
+                    format!(
+                        "The device does not support direct I/O ({}), writing through the page cache instead. Verification may be less reliable.",
+                        e
+                    ),
                 ));
+                direct_io = DirectIo::Off;
             }
+            Some(file)
+        };
 
-            let n = reader.read(&mut buffer)?;
-            if n == 0 {
+        // O_DIRECT transfers must be aligned to the logical sector size. Fall
+        // back to 512 bytes if the device cannot report it.
+        let sector_sizes = match &device_file {
+            Some(file) if is_block_device => platform::get_sector_sizes(file).unwrap_or_default(),
+            _ => SectorSizes::default(),
+        };
+        let block_size = sector_sizes.logical as usize;
+        if self.buffer_size == 0 || !self.buffer_size.is_multiple_of(block_size) {
+            return Err(anyhow!(
+                "Buffer size must be a non-zero multiple of the {} byte sector size",
+                block_size
+            ));
+        }
+
+        // Make sure the image fits before a single byte is written.
+        let device_len = match &device_file {
+            Some(file) if is_block_device => platform::get_device_size(file)?,
+            // A file grows as it is written, up to the size it is expected to have.
+            _ => self.expected_device_size.unwrap_or(u64::MAX),
+        };
+        // Sizes from discovery may be rounded to 512 byte units.
+        if is_block_device
+            && let Some(expected) = self.expected_device_size
+            && expected.abs_diff(device_len) >= block_size as u64
+        {
+            return Err(Error::DeviceChanged {
+                path: device_path,
+                expected,
+                actual: device_len,
+            }
+            .into());
+        }
+        let offset = self.offset;
+        if !offset.is_multiple_of(block_size as u64) {
+            return Err(anyhow!(
+                "The device offset must be a multiple of the {} byte sector size",
+                block_size
+            ));
+        }
+        if offset > device_len {
+            return Err(anyhow!("The device offset is beyond the end of the device"));
+        }
+        if self.secure_erase && self.resume.is_some() {
+            return Err(anyhow!("A secure erase cannot be combined with resuming"));
+        }
+        if self.verify_hash != HashAlgorithm::Sha256 && self.resume.is_some() {
+            return Err(anyhow!(
+                "A write can only be resumed with SHA-256 verification, as that is what the checkpoint records"
+            ));
+        }
+        if self.only_changed && (self.discard || self.secure_erase) {
+            return Err(anyhow!(
+                "Only writing changed blocks cannot be combined with discarding or erasing the device"
+            ));
+        }
+        let available = device_len - offset;
+        let capacity =
+            (is_block_device || self.expected_device_size.is_some()).then_some(available);
+        if let Some(image_len) = image_len
+            && image_len > available
+        {
+            return Err(Error::ImageTooLarge {
+                image: image_len,
+                device: available,
+            }
+            .into());
+        }
+
+        // A corrupt download is caught before anything on the device changes,
+        // unless it is only downloaded as it is written.
+        if let Some(expected) = self.expected_source_sha256
+            && download.is_none()
+        {
+            let ImageInput::Path(file) = &input else {
+                return Err(anyhow!(
+                    "Only an image file can be checked against a checksum"
+                ));
+            };
+            let file_len = file.open().map_err(io_error(Stage::Checksum, None))?.len();
+            (self.on_checksum_start)(file_len);
+            let actual = hash_file(
+                file,
+                &running,
+                &mut progress::tracked(
+                    Stage::Checksum,
+                    Some(file_len),
+                    window,
+                    &mut *self.on_checksum_progress,
+                    &structured,
+                ),
+            )?;
+            if actual != expected {
+                return Err(Error::ChecksumMismatch { expected, actual }.into());
+            }
+        }
+
+        let mut erase_method = None;
+        if let Some(device_file) = &mut device_file
+            && self.secure_erase
+            && is_block_device
+            && !self.dry_run
+        {
+            erase_method = Some(erase_device(
+                device_file,
+                device_len,
+                block_size,
+                &running,
+                &mut |method, len| {
+                    if method != EraseMethod::SecureDiscard {
+                        warn(Warning::new(
+                            WarningKind::EraseFallback,
+                            format!(
+                                "The device does not support secure discard, erasing it with {} instead. Old data may survive in blocks the device remapped.",
+                                method
+                            ),
+                        ));
+                    }
+                    (self.on_erase_start)(method, len);
+                },
+                &mut progress::tracked(
+                    Stage::Erase,
+                    Some(device_len),
+                    window,
+                    &mut *self.on_erase_progress,
+                    &structured,
+                ),
+            )?);
+        } else if let Some(device_file) = &device_file
+            && self.discard
+            && is_block_device
+            && self.resume.is_none()
+            && !self.dry_run
+        {
+            discard_range(
+                device_file,
+                offset,
+                available,
+                &running,
+                &mut *self.on_discard_start,
+                &mut progress::tracked(
+                    Stage::Discard,
+                    Some(available),
+                    window,
+                    &mut *self.on_discard_progress,
+                    &structured,
+                ),
+            )?;
+        }
+
+        // Either stream the decoder output, or inflate the whole image to a
+        // temporary file first and write that as a plain image.
+        let mut decompress_duration = Duration::ZERO;
+        // The image may be read again to compare it with the device.
+        let image_file = match &input {
+            ImageInput::Path(file) => Some(file.clone()),
+            ImageInput::Url(_) | ImageInput::Reader { .. } => None,
+        };
+        let (mut source, decompressed) = match input {
+            ImageInput::Url(_) => {
+                let (source, _) = downloaded.take().expect("the download was opened above");
+                if source.compressed {
+                    (self.on_decompress_start)(decompressed_len);
+                }
+                (source, None)
+            }
+            ImageInput::Reader { reader, len, .. } => {
+                let source = ImageSource {
+                    reader: reader.expect("reader was taken above"),
+                    len,
+                    consumed: Arc::new(AtomicU64::new(0)),
+                    compressed: false,
+                };
+                (source, None)
+            }
+            ImageInput::Path(image_file)
+                if (self.decompress_to_temp || self.cache.is_some()) && compression.is_some() =>
+            {
+                (self.on_decompress_start)(decompressed_len);
+                let decompress_started = Instant::now();
+                let image = decompress_image(
+                    &image_file,
+                    self.zstd_window_log_max,
+                    self.cache.as_ref(),
+                    self.check_temp_space
+                        .then_some(self.assumed_compression_ratio),
+                    running.clone(),
+                    &mut on_decompress_progress,
+                )?;
+                decompress_duration = decompress_started.elapsed();
+                let source = open_image(&image.file, self.zstd_window_log_max)
+                    .map_err(io_error(Stage::Decompress, None))?;
+                if let Some(len) = source.len
+                    && len > available
+                {
+                    return Err(Error::ImageTooLarge {
+                        image: len,
+                        device: available,
+                    }
+                    .into());
+                }
+                (source, Some(image))
+            }
+            ImageInput::Path(image_file) => {
+                let source = open_image(&image_file, self.zstd_window_log_max)
+                    .map_err(io_error(Stage::Decompress, None))?;
+                if source.compressed {
+                    (self.on_decompress_start)(decompressed_len);
+                }
+                (source, None)
+            }
+        };
+
+        // Users regularly write the archive an image came in rather than the
+        // image itself. Only the start of the device should hold a partition
+        // table, so data written further in or resumed is not looked at.
+        if offset == 0 && self.resume.is_none() {
+            let mut head = vec![0; image::PROBE_LEN];
+            let n = read_full(&mut source.reader, &mut head)
+                .map_err(io_error(Stage::Decompress, None))?;
+            head.truncate(n);
+            if image::looks_like_disk_image(&head) == ImageKind::Unknown {
+                warn(Warning::new(
+                    WarningKind::NotADiskImage,
+                    "The image does not start with a partition table or an ISO 9660 header, so it may not be a disk image.",
+                ));
+            }
+            source.reader = Box::new(io::Cursor::new(head).chain(source.reader));
+        }
+
+        if compression.is_some() {
+            structured.plan(Stage::Decompress, decompress_total);
+        }
+        // A recorded decompressed size is good enough for weighting the stages,
+        // even where it is not trusted as the exact length of the image.
+        let expected_len = source.len.or(image_len);
+        structured.plan(Stage::Write, expected_len);
+        // Only the mapped blocks are read back with a block map.
+        let mapped_len = block_map.as_ref().map(BlockMap::mapped_len);
+        if verify_mode.full() {
+            structured.plan(Stage::Verify, mapped_len.or(expected_len));
+        } else if let VerifyMode::Sampled { percent, .. } = verify_mode {
+            structured.plan(
+                Stage::Verify,
+                expected_len.map(|len| (len as f64 * percent as f64 / 100.0) as u64),
+            );
+        } else if verify_mode.partial() {
+            // How much of the image is zeros is only known once it is written,
+            // so all of it is counted until then.
+            structured.plan(Stage::Verify, expected_len);
+        }
+
+        (self.on_write_start)(source.len.unwrap_or(0));
+        let mut on_write_progress = progress::tracked(
+            Stage::Write,
+            source.len,
+            window,
+            &mut *self.on_write_progress,
+            &structured,
+        );
+        let write_started = Instant::now();
+
+        // The image is hashed as it streams past, so verification never has to
+        // read (or decompress) the image a second time.
+        let mut image_hasher = Sha256::new();
+        let mut verify_hasher = (verify_mode.full()
+            && self.verify_method == VerifyMethod::Hash
+            && self.bmap.is_none()
+            && self.verify_hash != HashAlgorithm::Sha256)
+            .then(|| self.verify_hash.hasher());
+        // Part of the image is checked against the CRCs of its chunks instead.
+        let mut chunk_crcs = verify_mode.partial().then(ChunkCrcs::new);
+
+        let mut limiter = self.max_bytes_per_sec.map(RateLimiter::new);
+        if self.dry_run {
+            let (written, read_time) = simulate_write(
+                &mut source,
+                capacity,
+                &mut limiter,
+                &mut image_hasher,
+                &running,
+                &mut on_decompress_progress,
+                &mut on_write_progress,
+            )?;
+            check_download(download.as_ref(), self.expected_source_sha256)?;
+            if source.compressed {
+                decompress_duration += read_time;
+            }
+            (self.on_sync_start)();
+            (self.on_sync_done)();
+            structured.plan(Stage::Write, Some(written));
+            if verify_mode.full() {
+                structured.plan(Stage::Verify, Some(written));
+            }
+            on_write_progress(written);
+            let write_duration = write_started.elapsed();
+
+            let verify_started = Instant::now();
+            if verify_mode.full() {
+                (self.on_verify_start)(written);
+                let mut on_verify_progress = progress::tracked(
+                    Stage::Verify,
+                    Some(written),
+                    window,
+                    &mut *self.on_verify_progress,
+                    &structured,
+                );
+                let mut verified = 0;
+                while verified < written {
+                    if !running.load(Ordering::SeqCst) {
+                        return Err(Error::Cancelled { bytes_synced: 0 }.into());
+                    }
+                    let chunk = (written - verified).min(BUFFER_SIZE as u64);
+                    if let Some(limiter) = &mut limiter {
+                        limiter.throttle(chunk, &running);
+                    }
+                    verified += chunk;
+                    on_verify_progress(verified);
+                }
+            }
+
+            return Ok(WriteReport {
+                bytes_written: written,
+                image_sha256: image_hasher.finalize().into(),
+                verified: false,
+                sampled_ranges: None,
+                verify_digest: None,
+                device_digest: None,
+                read_back: false,
+                decompress_duration,
+                write_duration,
+                verify_duration: verify_started.elapsed(),
+                sector_sizes,
+                eject_error: None,
+                erase_method: None,
+                bytes_wiped: 0,
+                bytes_mapped: None,
+                bytes_unchanged: None,
+                gpt_relocated: false,
+                grown_partition: None,
+                wipe_incomplete: false,
+                simulated: true,
+                direct_io: false,
+                cache_dropped: false,
+            });
+        }
+        let mut device_file = device_file.expect("the device is open unless this is a dry run");
+
+        let mut written: u64 = 0;
+        if let Some(resume) = &self.resume {
+            written = skip_to_checkpoint(
+                resume,
+                &mut source,
+                device_len,
+                block_size,
+                &mut image_hasher,
+                &running,
+                &mut on_decompress_progress,
+            )?;
+        }
+        device_file
+            .seek(SeekFrom::Start(offset + written))
+            .map_err(io_error(Stage::Write, Some(offset + written)))?;
+        let mut next_checkpoint = written + CHECKPOINT_INTERVAL;
+
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let mut writer = match self.queue_depth {
+            // Fall back to the synchronous loop if the kernel lacks io_uring.
+            depth if depth > 1 => uring::UringWriter::new(&device_file, depth, self.stall_timeout)
+                .map(|uring| ChunkWriter::Uring(Box::new(uring)))
+                .unwrap_or(ChunkWriter::Sync(None)),
+            _ => ChunkWriter::Sync(None),
+        };
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        let mut writer = ChunkWriter::Sync(None);
+        // A write on the calling thread could hang it for good.
+        let stall_timeout = self.stall_timeout;
+        if let (ChunkWriter::Sync(_), Some(timeout)) = (&writer, stall_timeout) {
+            writer = ChunkWriter::Worker(
+                worker::WorkerWriter::new(&device_file, retry, running.clone(), timeout)
+                    .map_err(io_error(Stage::Write, None))?,
+            );
+        }
+        let in_flight = match &writer {
+            ChunkWriter::Sync(_) | ChunkWriter::Worker(_) => 1,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ChunkWriter::Uring(_) => self.queue_depth as usize,
+        };
+
+        // A handle of its own, so that chunks are read back from the device
+        // rather than from the page cache whenever the writes bypass it.
+        let align = if is_block_device { block_size } else { 1 };
+        // A handle passed in by the caller is duplicated instead, which shares
+        // its O_DIRECT flag with the writes.
+        let mut read_back_file = if verify_mode.read_back() {
+            let file = match &self.device_file {
+                Some(_) => device_file.try_clone(),
+                None => DeviceOpenOptions::read_only()
+                    .direct_io(direct_io)
+                    .open(&device_path)
+                    .map(|(file, _)| file),
+            }
+            .map_err(io_error(Stage::Verify, None))?;
+            Some((file, AlignedBuffer::new(self.buffer_size, block_size)))
+        } else {
+            None
+        };
+        // The device is read through a handle of its own for the same reason
+        // before each chunk is written, if only changed blocks are written.
+        let mut compare_file = if self.only_changed {
+            let file = match &self.device_file {
+                Some(_) => device_file.try_clone(),
+                None => DeviceOpenOptions::read_only()
+                    .direct_io(direct_io)
+                    .open(&device_path)
+                    .map(|(file, _)| file),
+            }
+            .map_err(io_error(Stage::Write, None))?;
+            Some((file, AlignedBuffer::new(self.buffer_size, block_size)))
+        } else {
+            None
+        };
+        let mut unchanged = 0;
+
+        let mut range_checker = block_map
+            .as_ref()
+            .map(|map| RangeChecker::new(map, written));
+
+        let consumed = source.consumed.clone();
+        let mut chunks = ChunkReader::new(
+            source.reader,
+            self.pipelined,
+            self.buffer_size,
+            block_size,
+            in_flight,
+            running.clone(),
+        );
+
+        let mut completed = written;
+        // A resumed write picks up from a checkpoint that was synced.
+        let transfer = transfer.insert(PartialTransfer {
+            bytes_done: written,
+            bytes_synced: written,
+        });
+        let mut tail = None;
+        let mut interrupted = None;
+        let mut last_completion = Instant::now();
+        'write: loop {
+            let next = if running.load(Ordering::SeqCst) {
+                match chunks.next() {
+                    // A reader that fails because of the cancellation is not
+                    // a failure of the write.
+                    Err(_) if !running.load(Ordering::SeqCst) => None,
+                    next => next.map_err(io_error(Stage::Decompress, None))?,
+                }
+            } else {
+                None
+            };
+            let Some(chunk) = next else {
+                interrupted = Some(Interruption::Cancelled);
                 break;
+            };
+
+            let n = chunk.len;
+            if source.compressed {
+                decompress_duration += chunk.read_time;
+                on_decompress_progress(DecompressProgress {
+                    compressed_read: consumed.load(Ordering::Relaxed),
+                    decompressed_written: written + n as u64,
+                });
+            }
+            let last = n < chunk.capacity();
+            // A stream of unknown size can run past the end of the device.
+            if written + n as u64 > available {
+                chunks.recycle(chunk);
+                interrupted = Some(Interruption::OutOfSpace);
+                break;
+            }
+            image_hasher.update(&chunk.as_slice()[..n]);
+            if let Some(hasher) = &mut verify_hasher {
+                hasher.update(&chunk.as_slice()[..n]);
+            }
+            if let Some(crcs) = &mut chunk_crcs {
+                crcs.update(&chunk.as_slice()[..n]);
+            }
+            if let Some(checker) = &mut range_checker {
+                checker.update(written, &chunk.as_slice()[..n])?;
+            }
+
+            // The last chunk of data may not be a multiple of the block size,
+            // which O_DIRECT cannot write. The partial block is held back and
+            // written through the page cache once everything else has landed,
+            // so nothing past the end of the image is overwritten.
+            let direct_len = if is_block_device {
+                n / block_size * block_size
+            } else {
+                n
+            };
+            if direct_len < n {
+                tail = Some((
+                    offset + written + direct_len as u64,
+                    chunk.as_slice()[direct_len..n].to_vec(),
+                ));
+            }
+
+            // With a block map, only the part of the chunk from its first to
+            // its last mapped block is written. A chunk without any still goes
+            // through the writer as an empty write, so progress stays in order.
+            let (start, end) = match &block_map {
+                Some(map) => map
+                    .mapped_span(written, written + direct_len as u64)
+                    .map_or((0, 0), |(start, end)| {
+                        (
+                            (start - written) as usize / block_size * block_size,
+                            ((end - written) as usize)
+                                .next_multiple_of(block_size)
+                                .min(direct_len),
+                        )
+                    }),
+                None => (0, direct_len),
+            };
+            // Blocks the device already holds are left out as well, which may
+            // leave nothing to write at all.
+            let (start, end) = match &mut compare_file {
+                Some((file, buf)) if end > start => {
+                    let device_offset = offset + written + start as u64;
+                    let (first, last) = changed_span(
+                        file,
+                        buf,
+                        device_offset,
+                        &chunk.as_slice()[start..end],
+                        block_size,
+                    )
+                    .map_err(io_error(Stage::Write, Some(device_offset)))?
+                    .map_or((0, 0), |(first, last)| (start + first, start + last));
+                    unchanged += (end - start - (last - first)) as u64;
+                    (first, last)
+                }
+                _ => (start, end),
+            };
+            if direct_len > 0 {
+                let write = PendingWrite {
+                    chunk,
+                    device_offset: offset + written + start as u64,
+                    start,
+                    len: end - start,
+                };
+                match writer.submit(write, &mut device_file, &retry, &running, &mut *on_retry) {
+                    Ok(()) => {}
+                    Err(e) if Interruption::of(&e).is_some() => {
+                        interrupted = Interruption::of(&e);
+                        break;
+                    }
+                    Err(e) => return Err(io_error(Stage::Write, Some(offset + written))(e)),
+                }
+            } else {
+                chunks.recycle(chunk);
+            }
+            written += n as u64;
+            if let Some(limiter) = &mut limiter {
+                limiter.throttle((n - direct_len + end - start) as u64, &running);
+            }
+
+            // Everything in flight must land before the device is synced,
+            // either at the end or for a checkpoint.
+            let checkpoint_due = checkpoint.is_some() && written >= next_checkpoint;
+            loop {
+                let done = match writer.next_finished(
+                    last || checkpoint_due,
+                    &mut device_file,
+                    &retry,
+                    &running,
+                    &mut *on_retry,
+                ) {
+                    Ok(Some(done)) => done,
+                    Ok(None) => break,
+                    Err(e) if Interruption::of(&e).is_some() => {
+                        interrupted = Interruption::of(&e);
+                        break 'write;
+                    }
+                    // The hung write cannot be waited for, so unlike a
+                    // cancellation nothing is flushed before returning.
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut && stall_timeout.is_some() => {
+                        return Err(Error::Stalled {
+                            offset: offset + completed,
+                            elapsed: last_completion.elapsed(),
+                        }
+                        .into());
+                    }
+                    Err(e) => return Err(io_error(Stage::Write, Some(offset + completed))(e)),
+                };
+                if let Some((file, buf)) = &mut read_back_file {
+                    read_back(
+                        file,
+                        buf,
+                        done.device_offset,
+                        &done.chunk.as_slice()[done.start..done.start + done.len],
+                        align,
+                    )?;
+                }
+                last_completion = Instant::now();
+                // Progress trails by one chunk so that the bar only reaches the
+                // end once the device has been synced below.
+                on_write_progress(completed);
+                completed += done.chunk.len as u64;
+                transfer.bytes_done = completed;
+                chunks.recycle(done.chunk);
+            }
+
+            if last {
+                break;
+            }
+
+            if let Some(path) = checkpoint
+                && checkpoint_due
+            {
+                device_file
+                    .sync_data()
+                    .map_err(io_error(Stage::Sync, None))?;
+                transfer.bytes_synced = written;
+                record_checkpoint(path, written, device_len, &image_hasher)?;
+                next_checkpoint = written + CHECKPOINT_INTERVAL;
             }
-            writer.write_all(&buffer[..n])?;
-            total += n as u64;
-            on_progress(total);
         }
-        writer.flush()?;
-    }
 
-    // Hand over ownership of the temp file to the DecompressedImage struct.
-    let temp_path = temp_file.into_temp_path();
-    Ok(DecompressedImage {
-        path: temp_path.to_path_buf(),
-        _temp_handle: Some(temp_path),
-    })
+        if interrupted.is_none()
+            && let Some((tail_offset, bytes)) = &tail
+        {
+            platform::set_direct_io(&device_file, false)?;
+            device_file
+                .seek(SeekFrom::Start(*tail_offset))
+                .map_err(io_error(Stage::Write, Some(*tail_offset)))?;
+            match write_chunk(
+                &mut device_file,
+                *tail_offset,
+                bytes,
+                &retry,
+                &running,
+                &mut *on_retry,
+            ) {
+                Ok(()) => {
+                    if let Some((file, buf)) = &mut read_back_file {
+                        read_back(file, buf, *tail_offset, bytes, align)?;
+                    }
+                    tail = None;
+                }
+                Err(e) if Interruption::of(&e).is_some() => interrupted = Interruption::of(&e),
+                Err(e) => return Err(io_error(Stage::Write, Some(*tail_offset))(e)),
+            }
+        }
+
+        if let Some(interruption) = interrupted {
+            // Stop issuing writes, but let the ones in flight land and flush
+            // the drive's cache, so the device is left in a known state.
+            while let Ok(Some(done)) =
+                writer.next_finished(true, &mut device_file, &retry, &running, &mut *on_retry)
+            {
+                completed += done.chunk.len as u64;
+            }
+            transfer.bytes_done = completed;
+            // A full target may fail to sync as well, which says nothing new.
+            let synced = match device_file.sync_data() {
+                Ok(()) => true,
+                Err(_) if interruption == Interruption::OutOfSpace => false,
+                Err(e) => return Err(io_error(Stage::Sync, None)(e)),
+            };
+
+            // Only a prefix that landed in full matches the running hash, and
+            // a held-back partial block has not been written yet.
+            if let Some((_, bytes)) = &tail
+                && completed == written
+            {
+                completed -= bytes.len() as u64;
+                transfer.bytes_done = completed;
+            } else if let Some(path) = checkpoint
+                && completed == written
+                && interruption == Interruption::Cancelled
+            {
+                // Leave a checkpoint behind so the write can be picked up again.
+                record_checkpoint(path, written, device_len, &image_hasher)?;
+            }
+            if synced {
+                transfer.bytes_synced = completed;
+            }
+            return Err(match interruption {
+                Interruption::Cancelled => Error::Cancelled {
+                    bytes_synced: completed,
+                },
+                Interruption::OutOfSpace => Error::DeviceFull {
+                    capacity,
+                    bytes_written: completed,
+                },
+            }
+            .into());
+        }
+
+        check_download(download.as_ref(), self.expected_source_sha256)?;
+        let range_hashes = range_checker
+            .map(|checker| checker.finish(written))
+            .transpose()?;
+
+        // Make sure the data has left the drive's cache before reporting success.
+        // On slow USB sticks this can take a while, which is why the final
+        // progress tick is held back until it completes.
+        (self.on_sync_start)();
+        device_file
+            .sync_data()
+            .map_err(io_error(Stage::Sync, None))?;
+        (self.on_sync_done)();
+        *transfer = PartialTransfer {
+            bytes_done: written,
+            bytes_synced: written,
+        };
+        // An image written through the page cache is still in it, where a
+        // buffered verification would read it back instead of the device.
+        let verifies = verify_mode.full() || verify_mode.partial();
+        let mut cache_dropped = false;
+        if verifies && is_block_device && direct_io == DirectIo::Off {
+            match platform::drop_page_cache(&device_file, offset, written) {
+                Ok(()) => cache_dropped = true,
+                Err(e) => warn(Warning::new(
+                    WarningKind::CacheDropFailed,
+                    format!(
+                        "The written image could not be dropped from the page cache ({}), so verification may read it back from memory rather than the device.",
+                        e
+                    ),
+                )),
+            }
+        }
+        // Now that the image size is known for certain, so is the overall one.
+        structured.plan(Stage::Write, Some(written));
+        let sampled_ranges = match (verify_mode, &chunk_crcs) {
+            (VerifyMode::Sampled { percent, seed }, _) => {
+                Some(verify::sample_ranges(written, percent, seed))
+            }
+            (VerifyMode::NonZero { zero_percent, seed }, Some(crcs)) => {
+                Some(verify::nonzero_ranges(crcs, zero_percent, seed))
+            }
+            _ => None,
+        };
+        if verify_mode.full() {
+            structured.plan(Stage::Verify, mapped_len.or(Some(written)));
+        } else if let Some(ranges) = &sampled_ranges {
+            structured.plan(
+                Stage::Verify,
+                Some(ranges.iter().map(|r| r.end - r.start).sum()),
+            );
+        }
+        on_write_progress(written);
+        let write_duration = write_started.elapsed();
+        let image_sha256: [u8; 32] = image_hasher.finalize().into();
+
+        // The write is complete, so there is nothing left to resume.
+        if let Some(path) = checkpoint
+            && path.exists()
+        {
+            std::fs::remove_file(path)?;
+        }
+
+        let verify_started = Instant::now();
+        let mut verify_digest = None;
+        let mut device_digest = None;
+        if verify_mode.full() || sampled_ranges.is_some() {
+            // The device is read back with O_DIRECT where it can be, so that
+            // every byte checked comes from the device rather than from pages
+            // the write left in the page cache.
+            let mut verify_direct_io = if is_block_device {
+                self.direct_io
+            } else {
+                DirectIo::Off
+            };
+            // With a block map, whole sectors can only be read if its blocks
+            // are made of them.
+            if let Some(map) = &block_map
+                && !map.block_size.is_multiple_of(block_size as u64)
+            {
+                verify_direct_io = DirectIo::Off;
+            }
+            let (mut device_file, fallback) = match &self.device_file {
+                // A duplicate shares the O_DIRECT flag of the caller's handle,
+                // which is restored below.
+                Some(_) => {
+                    let file = device_file
+                        .try_clone()
+                        .map_err(io_error(Stage::Verify, None))?;
+                    match platform::set_direct_io(&file, verify_direct_io != DirectIo::Off) {
+                        Ok(()) => (file, None),
+                        Err(e) if verify_direct_io == DirectIo::Preferred => (file, Some(e)),
+                        Err(e) => return Err(io_error(Stage::Verify, None)(e)),
+                    }
+                }
+                None => DeviceOpenOptions::read_only()
+                    .direct_io(verify_direct_io)
+                    .open(&device_path)
+                    .map_err(io_error(Stage::Verify, None))?,
+            };
+            if let Some(e) = fallback {
+                warn(Warning::new(
+                    WarningKind::DirectIoFallback,
+                    format!(
+                        "The device does not support direct I/O ({}), verifying through the page cache instead.",
+                        e
+                    ),
+                ));
+                verify_direct_io = DirectIo::Off;
+            }
+            if verify_direct_io == DirectIo::Off
+                && is_block_device
+                && self.drop_caches
+                && !cache_dropped
+            {
+                platform::drop_page_cache(&device_file, offset, written)
+                    .map_err(io_error(Stage::Verify, None))?;
+                cache_dropped = true;
+            }
+            let align = if verify_direct_io == DirectIo::Off {
+                1
+            } else {
+                block_size
+            };
+
+            let verify_len = match &sampled_ranges {
+                Some(ranges) => ranges.iter().map(|r| r.end - r.start).sum(),
+                None => mapped_len.unwrap_or(written),
+            };
+            (self.on_verify_start)(verify_len);
+            let mut on_verify_progress = progress::tracked(
+                Stage::Verify,
+                Some(verify_len),
+                window,
+                &mut *self.on_verify_progress,
+                &structured,
+            );
+
+            if let (Some(ranges), Some(crcs)) = (&sampled_ranges, &chunk_crcs) {
+                verify::check_sample(
+                    &mut device_file,
+                    offset,
+                    crcs,
+                    ranges,
+                    align,
+                    &running,
+                    written,
+                    &mut on_verify_progress,
+                )?;
+            } else if let (Some(map), Some(hashes)) = (&block_map, &range_hashes) {
+                verify_mapped(
+                    &mut device_file,
+                    offset,
+                    map,
+                    hashes,
+                    align,
+                    written,
+                    &running,
+                    &mut on_verify_progress,
+                )?;
+            } else if self.verify_method == VerifyMethod::Compare {
+                // Read from the decompressed copy where there is one.
+                let file = match &decompressed {
+                    Some(image) => &image.file,
+                    None => image_file
+                        .as_ref()
+                        .expect("a reader is rejected before the device is written"),
+                };
+                let mut image = open_image(file, self.zstd_window_log_max)
+                    .map_err(io_error(Stage::Decompress, None))?;
+                let (_, digest, device) = verify::check_device(
+                    &mut device_file,
+                    offset,
+                    Expected::Image {
+                        image: &mut image.reader,
+                        hash: self.verify_hash,
+                        compare: true,
+                    },
+                    &mut AlignedBuffer::new(BUFFER_SIZE, align),
+                    align,
+                    &running,
+                    written,
+                    &mut on_verify_progress,
+                )?;
+                verify_digest = Some(digest);
+                device_digest = Some(device);
+            } else {
+                let digest = match verify_hasher {
+                    Some(hasher) => hash::Digest::new(self.verify_hash, hasher.finalize()),
+                    None => hash::Digest::sha256(image_sha256),
+                };
+                let (_, digest, device) = verify::check_device(
+                    &mut device_file,
+                    offset,
+                    Expected::Digest {
+                        digest,
+                        len: written,
+                    },
+                    &mut AlignedBuffer::new(BUFFER_SIZE, align),
+                    align,
+                    &running,
+                    written,
+                    &mut on_verify_progress,
+                )?;
+                verify_digest = Some(digest);
+                device_digest = Some(device);
+            }
+            if self.device_file.is_some() && is_block_device {
+                platform::set_direct_io(&device_file, direct_io != DirectIo::Off)?;
+            }
+        }
+        let verify_duration = if verify_mode.full() || sampled_ranges.is_some() {
+            verify_started.elapsed()
+        } else {
+            Duration::ZERO
+        };
+
+        // The image is safely on the device by now, so a cancelled wipe leaves
+        // part of the old data behind rather than failing the write.
+        let (mut bytes_wiped, mut wipe_incomplete) = (0, false);
+        if self.wipe_remainder && is_block_device {
+            let start = (offset + written).next_multiple_of(block_size as u64);
+            let len = device_len.saturating_sub(start);
+            (self.on_wipe_start)(len);
+            bytes_wiped = zero_range(
+                &mut device_file,
+                start,
+                len,
+                block_size,
+                Stage::Wipe,
+                &running,
+                &mut progress::tracked(
+                    Stage::Wipe,
+                    Some(len),
+                    window,
+                    &mut *self.on_wipe_progress,
+                    &structured,
+                ),
+            )?;
+            device_file
+                .sync_data()
+                .map_err(io_error(Stage::Sync, None))?;
+            wipe_incomplete = bytes_wiped < len;
+            if wipe_incomplete {
+                warn(Warning::new(
+                    WarningKind::WipeIncomplete,
+                    format!(
+                        "Wiping was cancelled after {} bytes; the rest of the device still holds old data.",
+                        bytes_wiped
+                    ),
+                ));
+            }
+        }
+
+        // The backup GPT goes at the very end of the device, so the partition
+        // table is only edited once the wipe is done.
+        let mut gpt_relocated = false;
+        if self.relocate_gpt_backup && is_block_device {
+            platform::set_direct_io(&device_file, false)?;
+            gpt_relocated = partition_table::relocate_backup(
+                &mut device_file,
+                offset,
+                block_size as u64,
+                device_len,
+            )
+            .map_err(|e| anyhow!("Failed to move the backup GPT: {:#}", e))?;
+        }
+        let mut grown_partition = None;
+        if self.grow_last_partition && is_block_device {
+            platform::set_direct_io(&device_file, false)?;
+            grown_partition = partition_table::grow_last_partition(
+                &mut device_file,
+                offset,
+                block_size as u64,
+                device_len,
+            )
+            .map_err(|e| anyhow!("Failed to grow the last partition: {:#}", e))?;
+        }
+
+        // The kernel has to see any new partition table before the partitions
+        // are used, by this process or after the device is replugged.
+        let table_changed = gpt_relocated || grown_partition.is_some_and(|p| p.new_len > p.old_len);
+        if is_block_device && (table_changed || !self.files.is_empty()) {
+            platform::set_direct_io(&device_file, false)?;
+            match platform::reread_partitions(&device_file) {
+                // Devices that cannot be partitioned (e.g. a loop device without
+                // partition scanning) can still be edited in place.
+                Ok(()) => {}
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+                Err(e) => warn(Warning::new(
+                    WarningKind::PartitionRescanFailed,
+                    format!(
+                        "The kernel could not re-read the partition table ({}); replug the device to see the new partitions.",
+                        e
+                    ),
+                )),
+            }
+        }
+
+        // Files are added after verification, which covers the image as written.
+        if !self.files.is_empty() {
+            customize::add_files(
+                &mut device_file,
+                offset,
+                block_size as u64,
+                available,
+                &self.files,
+            )
+            .map_err(|e| Error::CustomizeFailed {
+                reason: format!("{:#}", e),
+            })?;
+        }
+
+        // The image is safely on the device at this point, so failing to eject
+        // it is reported rather than turned into an error.
+        let mut eject_error = None;
+        if self.eject_on_success && is_block_device {
+            drop(device_file);
+            if let Err(e) = platform::eject(&device_path) {
+                warn(Warning::new(
+                    WarningKind::EjectFailed,
+                    format!(
+                        "The device could not be ejected ({}). Eject it before unplugging.",
+                        e
+                    ),
+                ));
+                eject_error = Some(e.to_string());
+            }
+        }
+
+        Ok(WriteReport {
+            bytes_written: written,
+            image_sha256,
+            verified: verify_mode.full(),
+            sampled_ranges,
+            verify_digest,
+            device_digest,
+            read_back: verify_mode.read_back(),
+            decompress_duration,
+            write_duration,
+            verify_duration,
+            sector_sizes,
+            eject_error,
+            erase_method,
+            bytes_wiped,
+            bytes_mapped: mapped_len,
+            bytes_unchanged: self.only_changed.then_some(unchanged),
+            gpt_relocated,
+            grown_partition,
+            wipe_incomplete,
+            simulated: false,
+            direct_io: direct_io != DirectIo::Off,
+            cache_dropped,
+        })
+    }
 }
 
 /// Writes an image file to a block device, with optional verification.
 ///
-/// This is the main entry point for the writing process. It orchestrates the
-/// decompression, writing, and verification stages, reporting progress for each
-/// stage via callbacks.
-///
-/// # Arguments
-///
-/// * `image_path` - Path to the source image file. Can be compressed.
-/// * `device_path` - Path to the target block device.
-/// * `verify` - If `true`, a verification pass will be performed after writing.
-/// * `running` - An `Arc<AtomicBool>` to allow for graceful cancellation.
-/// * `on_decompress_start` - Closure called when decompression begins.
-/// * `on_decompress_progress` - Closure called with the number of bytes decompressed.
-/// * `on_write_start` - Closure called when writing begins, providing the total image size.
-/// * `on_write_progress` - Closure called with the number of bytes written.
-/// * `on_verify_start` - Closure called when verification begins, providing the total image size.
-/// * `on_verify_progress` - Closure called with the number of bytes verified.
-///
-/// # Errors
-///
-/// This function will return an error if:
-/// - The image file or device cannot be accessed.
-/// - An I/O error occurs during any stage.
-/// - The verification hash does not match.
-/// - The operation is cancelled.
+/// This is the positional form of [`WriteOptions`], kept for compatibility with
+/// etchr-core 1.0. It uses the default for every option that it does not take.
+#[deprecated(note = "use `WriteOptions` instead")]
+#[allow(clippy::too_many_arguments)]
 pub fn run<F1, F2, F3>(
     image_path: &Path,
     device_path: &Path,
     verify: bool,
     running: Arc<AtomicBool>,
     on_decompress_start: impl FnOnce(),
-    mut on_decompress_progress: F1,
+    on_decompress_progress: F1,
     on_write_start: impl FnOnce(u64),
-    mut on_write_progress: F2,
+    on_write_progress: F2,
     on_verify_start: impl FnOnce(u64),
-    mut on_verify_progress: F3,
+    on_verify_progress: F3,
 ) -> Result<()>
 where
     F1: FnMut(u64),
     F2: FnMut(u64),
     F3: FnMut(u64),
 {
-    on_decompress_start();
-    let image = match decompress_image(image_path, running.clone(), &mut on_decompress_progress) {
-        Ok(img) => img,
-        Err(e) if e.kind() == io::ErrorKind::Interrupted => {
-            return Err(anyhow!("Operation cancelled by user"));
+    let mut on_decompress_start = Some(on_decompress_start);
+    let mut on_write_start = Some(on_write_start);
+    let mut on_verify_start = Some(on_verify_start);
+
+    WriteOptions::new(image_path, device_path)
+        .verify(verify)
+        .running(running)
+        .on_decompress_start(move |_| {
+            if let Some(f) = on_decompress_start.take() {
+                f();
+            }
+        })
+        .on_decompress_progress(on_decompress_progress)
+        .on_write_start(move |len| {
+            if let Some(f) = on_write_start.take() {
+                f(len);
+            }
+        })
+        .on_write_progress(on_write_progress)
+        .on_verify_start(move |len| {
+            if let Some(f) = on_verify_start.take() {
+                f(len);
+            }
+        })
+        .on_verify_progress(on_verify_progress)
+        .run()
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 256 KiB of text-like data, the same on every run.
+    fn sample() -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut n: u64 = 1;
+        while data.len() < 256 << 10 {
+            n = n
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            data.extend_from_slice(format!("line {:x} of the sample image\n", n >> 40).as_bytes());
         }
-        Err(e) => return Err(e.into()),
-    };
+        data.truncate(256 << 10);
+        data
+    }
+
+    /// `data` compressed as `format`, with the checksums each format can
+    /// carry. A zip archive holds it stored, as `image.img`.
+    fn compress(format: Format, data: &[u8]) -> Vec<u8> {
+        match format {
+            Format::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Format::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Format::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 3).unwrap();
+                encoder.include_checksum(true).unwrap();
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Format::Bzip2 => {
+                let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), Default::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Format::Lz4 => {
+                let info = lz4_flex::frame::FrameInfo::new().content_checksum(true);
+                let mut encoder = lz4_flex::frame::FrameEncoder::with_frame_info(info, Vec::new());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Format::Zip => zip_archive("image.img", data, false),
+        }
+    }
 
-    let mut image_file = File::open(&image)?;
-    let image_len = image_file.metadata()?.len();
+    /// A zip archive holding `data` as `name`, deflated or stored.
+    fn zip_archive(name: &str, data: &[u8], deflated: bool) -> Vec<u8> {
+        let (method, stored) = if deflated {
+            let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), Default::default());
+            encoder.write_all(data).unwrap();
+            (8u16, encoder.finish().unwrap())
+        } else {
+            (0, data.to_vec())
+        };
+        let mut crc = flate2::Crc::new();
+        crc.update(data);
 
-    let mut device_file = std::fs::OpenOptions::new()
-        .write(true)
-        .custom_flags(libc::O_DIRECT) // Use O_DIRECT for unbuffered I/O
-        .open(device_path)?;
+        // The fields that the local header and the central directory share.
+        let mut common = Vec::new();
+        common.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        common.extend_from_slice(&0u16.to_le_bytes()); // flags
+        common.extend_from_slice(&method.to_le_bytes());
+        common.extend_from_slice(&[0; 4]); // time and date
+        common.extend_from_slice(&crc.sum().to_le_bytes());
+        common.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes()); // extra field
 
-    on_write_start(image_len);
+        let mut zip = b"PK\x03\x04".to_vec();
+        zip.extend_from_slice(&common);
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(&stored);
+        let directory = zip.len();
+        zip.extend_from_slice(b"PK\x01\x02");
+        zip.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        zip.extend_from_slice(&common);
+        zip.extend_from_slice(&[0; 10]); // comment, disk, attributes
+        zip.extend_from_slice(&0u32.to_le_bytes()); // local header offset
+        zip.extend_from_slice(name.as_bytes());
+        let directory_len = zip.len() - directory;
+        zip.extend_from_slice(b"PK\x05\x06");
+        zip.extend_from_slice(&[0, 0, 0, 0, 1, 0, 1, 0]);
+        zip.extend_from_slice(&(directory_len as u32).to_le_bytes());
+        zip.extend_from_slice(&(directory as u32).to_le_bytes());
+        zip.extend_from_slice(&0u16.to_le_bytes()); // comment
+        zip
+    }
 
-    // Align buffer to 512 bytes for O_DIRECT compatibility.
-    let block_size = 512;
-    let mut buf = vec![0u8; BUFFER_SIZE + block_size];
-    let offset = buf.as_ptr().align_offset(block_size);
-    let buffer = &mut buf[offset..offset + BUFFER_SIZE];
+    /// Reads the image file `file` to the end, returning what was found
+    /// wrong with its compressed data. The reason given for invalid data is
+    /// the decoder's own message, and is left out.
+    fn read_image(suffix: &str, file: &[u8]) -> Option<Corruption> {
+        let mut image = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+        image.write_all(file).unwrap();
+        let input = ImageFile::single(image.path());
+        let e = match open_image(&input, DEFAULT_ZSTD_WINDOW_LOG_MAX) {
+            Ok(mut source) => io::copy(&mut source.reader, &mut io::sink()).err()?,
+            Err(e) => e,
+        };
+        match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(Error::CorruptImage {
+                detail: Corruption::Invalid { .. },
+                ..
+            }) => Some(invalid()),
+            Some(Error::CorruptImage { detail, .. }) => Some(detail.clone()),
+            _ => panic!("not reported as corrupt: {}", e),
+        }
+    }
 
-    let mut written: u64 = 0;
-    while written < image_len {
-        if !running.load(Ordering::SeqCst) {
-            return Err(anyhow!("Operation cancelled by user"));
+    fn invalid() -> Corruption {
+        Corruption::Invalid {
+            reason: String::new(),
         }
+    }
 
-        let to_read = std::cmp::min(BUFFER_SIZE as u64, image_len - written) as usize;
-        image_file.read_exact(&mut buffer[..to_read])?;
+    /// Stands for the middle of the compressed data in [`check`].
+    const MIDDLE: isize = isize::MIN;
 
-        // The last chunk of data may not be a multiple of the block size.
-        // We need to pad it with zeros to satisfy O_DIRECT requirements.
-        let padded_size = if to_read % block_size != 0 {
-            let pad = (to_read + block_size - 1) / block_size * block_size;
-            buffer[to_read..pad].fill(0);
-            pad
-        } else {
-            to_read
-        };
+    /// Checks that `format` reads back, is reported as truncated when cut
+    /// short, and that flipping the bits of `mask` in the byte at `at`
+    /// (counted from the end if negative) is reported as `expected`.
+    fn check(format: Format, suffix: &str, flips: &[(isize, u8, Corruption)]) {
+        let good = compress(format, &sample());
+        assert_eq!(read_image(suffix, &good), None);
+        for end in [good.len() / 2, good.len() - 1] {
+            let truncated = read_image(suffix, &good[..end]);
+            assert_eq!(truncated, Some(Corruption::Truncated), "cut at {}", end);
+        }
+        for (at, mask, expected) in flips {
+            let mut bad = good.clone();
+            let at = match *at {
+                MIDDLE => good.len() / 2,
+                at => at.rem_euclid(good.len() as isize) as usize,
+            };
+            bad[at] ^= mask;
+            let found = read_image(suffix, &bad);
+            assert_eq!(found.as_ref(), Some(expected), "byte {} flipped", at);
+        }
+    }
 
-        device_file.write_all(&buffer[..padded_size])?;
-        written += to_read as u64;
-        on_write_progress(written);
+    #[test]
+    fn corrupt_gzip() {
+        check(
+            Format::Gzip,
+            ".img.gz",
+            &[
+                // A reserved flag.
+                (3, 0x20, Corruption::WrongFormat),
+                // The CRC-32 and the size in the trailer.
+                (-8, 0x01, Corruption::ChecksumMismatch),
+                (-1, 0x01, Corruption::ChecksumMismatch),
+            ],
+        );
+    }
+
+    #[test]
+    fn corrupt_xz() {
+        check(
+            Format::Xz,
+            ".img.xz",
+            &[
+                // The stream flags.
+                (5, 0x01, Corruption::WrongFormat),
+                // The compressed data, which fails the check of its block.
+                (MIDDLE, 0x10, invalid()),
+                (-20, 0x01, invalid()),
+            ],
+        );
     }
 
-    device_file.flush()?;
+    #[test]
+    fn corrupt_zstd() {
+        check(
+            Format::Zstd,
+            ".img.zst",
+            &[
+                // A reserved bit of the frame header.
+                (4, 0x08, Corruption::WrongFormat),
+                // The checksum at the end of the frame.
+                (-1, 0x01, Corruption::ChecksumMismatch),
+            ],
+        );
+    }
 
-    if verify {
-        let mut image_file = File::open(&image)?;
-        let mut device_file = File::open(device_path)?;
+    #[test]
+    fn corrupt_bzip2() {
+        check(
+            Format::Bzip2,
+            ".img.bz2",
+            &[
+                // The block size in the header.
+                (3, 0x10, Corruption::WrongFormat),
+                // The compressed data and the CRC of the stream.
+                (MIDDLE, 0x10, invalid()),
+                (-1, 0x01, invalid()),
+            ],
+        );
+    }
 
-        on_verify_start(image_len);
+    #[test]
+    fn corrupt_lz4() {
+        check(
+            Format::Lz4,
+            ".img.lz4",
+            &[
+                // The version in the frame descriptor.
+                (4, 0x40, Corruption::WrongFormat),
+                // The checksums of the descriptor and of the content.
+                (6, 0x01, Corruption::ChecksumMismatch),
+                (-1, 0x01, Corruption::ChecksumMismatch),
+            ],
+        );
+    }
 
-        let mut image_hasher = Sha256::new();
-        let mut device_hasher = Sha256::new();
+    #[test]
+    fn lz4_frames_are_read_one_after_another() {
+        let data = sample();
+        let (first, second) = data.split_at(100_000);
+        let mut file = compress(Format::Lz4, first);
+        let boundary = file.len();
+        file.extend_from_slice(&compress(Format::Lz4, second));
 
-        let mut image_buf = vec![0u8; BUFFER_SIZE];
-        let mut device_buf = vec![0u8; BUFFER_SIZE];
+        let mut read = Vec::new();
+        Lz4Frames::new(&file[..]).read_to_end(&mut read).unwrap();
+        assert!(read == data);
+        assert_eq!(read_image(".img.lz4", &file), None);
 
-        let mut remaining = image_len;
-        while remaining > 0 {
-            if !running.load(Ordering::SeqCst) {
-                return Err(anyhow!("Operation cancelled by user"));
-            }
+        // Cut short in the second frame, including in its magic number.
+        for end in [boundary + 2, boundary + 100, file.len() - 1] {
+            let truncated = read_image(".img.lz4", &file[..end]);
+            assert_eq!(truncated, Some(Corruption::Truncated), "cut at {}", end);
+        }
+    }
+
+    #[test]
+    fn corrupt_zip() {
+        let data = sample();
+        let good = compress(Format::Zip, &data);
+        assert_eq!(read_image(".zip", &good), None);
+        let deflated = zip_archive("image.img", &data, true);
+        assert_eq!(read_image(".zip", &deflated), None);
 
-            let chunk = std::cmp::min(BUFFER_SIZE as u64, remaining) as usize;
-            image_file.read_exact(&mut image_buf[..chunk])?;
-            device_file.read_exact(&mut device_buf[..chunk])?;
+        // The stored image, which fails its CRC-32.
+        let mut bad = good.clone();
+        bad[good.len() / 2] ^= 0x10;
+        assert_eq!(read_image(".zip", &bad), Some(Corruption::ChecksumMismatch));
+        // The directory at the end of the archive, cut off entirely or in
+        // part.
+        assert_eq!(read_image(".zip", &good[..good.len() / 2]), Some(invalid()));
+        assert_eq!(
+            read_image(".zip", &good[..good.len() - 1]),
+            Some(Corruption::Truncated)
+        );
+    }
 
-            image_hasher.update(&image_buf[..chunk]);
-            device_hasher.update(&device_buf[..chunk]);
+    #[test]
+    fn every_format_is_written_and_verified() {
+        // Runs of zeros as well as data, across more than one chunk.
+        let mut data = sample();
+        data.resize(3 << 19, 0);
+        data.extend_from_slice(&sample());
+        for (format, suffix) in [
+            (Format::Gzip, ".img.gz"),
+            (Format::Xz, ".img.xz"),
+            (Format::Zstd, ".img.zst"),
+            (Format::Bzip2, ".img.bz2"),
+            (Format::Lz4, ".img.lz4"),
+            (Format::Zip, ".zip"),
+        ] {
+            let mut image = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+            image.write_all(&compress(format, &data)).unwrap();
+            // A sparse file larger than the image stands in for the device.
+            let device = tempfile::NamedTempFile::new().unwrap();
+            device.as_file().set_len(4 << 20).unwrap();
+            let report = WriteOptions::new(image.path(), device.path())
+                .allow_file_target(true)
+                .verify(true)
+                .run()
+                .unwrap_or_else(|e| panic!("{}: {}", suffix, e));
+            assert_eq!(report.bytes_verified(), data.len() as u64, "{}", suffix);
 
-            remaining -= chunk as u64;
-            on_verify_progress(image_len - remaining);
+            let written = std::fs::read(device.path()).unwrap();
+            assert_eq!(written.len(), 4 << 20, "{}", suffix);
+            assert!(written[..data.len()] == data[..], "{}", suffix);
+            assert!(written[data.len()..].iter().all(|&b| b == 0), "{}", suffix);
         }
+    }
 
-        let hash1 = image_hasher.finalize();
-        let hash2 = device_hasher.finalize();
+    #[test]
+    fn compare_decompresses_the_image_again() {
+        use std::os::unix::fs::FileExt;
 
-        if hash1 != hash2 {
-            return Err(anyhow!("Verification failed: hash mismatch."));
+        let data = sample();
+        let formats = [
+            (Format::Gzip, ".img.gz"),
+            (Format::Xz, ".img.xz"),
+            (Format::Zstd, ".img.zst"),
+        ];
+        for (format, suffix) in formats {
+            let mut image = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+            image.write_all(&compress(format, &data)).unwrap();
+            let device = tempfile::NamedTempFile::new().unwrap();
+            let write = |corrupt: bool| {
+                WriteOptions::new(image.path(), device.path())
+                    .allow_file_target(true)
+                    .verify_method(VerifyMethod::Compare)
+                    .on_verify_start(|_| {
+                        // The device changes between the write and the check.
+                        if corrupt {
+                            device.as_file().write_all_at(b"?", 100_000).unwrap();
+                        }
+                    })
+                    .run()
+            };
+
+            write(false).unwrap_or_else(|e| panic!("{}: {}", suffix, e));
+            let e = write(true).unwrap_err();
+            assert!(
+                matches!(
+                    e.downcast_ref::<Error>(),
+                    Some(Error::VerifyMismatch { offset: 100_000 })
+                ),
+                "{}: {}",
+                suffix,
+                e
+            );
         }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn sampled_chunks_that_changed_are_reported() {
+        use std::os::unix::fs::FileExt;
+
+        let data = sample().repeat(12);
+        let mut image = tempfile::NamedTempFile::new().unwrap();
+        image.write_all(&data).unwrap();
+        let device = tempfile::NamedTempFile::new().unwrap();
+        let e = WriteOptions::new(image.path(), device.path())
+            .allow_file_target(true)
+            .offset(4096)
+            .verify_mode(VerifyMode::Sampled {
+                percent: 100.0,
+                seed: 1,
+            })
+            .on_verify_start(|_| {
+                device
+                    .as_file()
+                    .write_all_at(b"?", 4096 + (3 << 19))
+                    .unwrap();
+            })
+            .run()
+            .unwrap_err();
+        match e.downcast_ref::<Error>() {
+            Some(Error::ChunkMismatch { range }) => {
+                assert_eq!(*range, 4096 + (1 << 20)..4096 + (2 << 20))
+            }
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn mapped_ranges_are_read_back_from_the_device() {
+        use crate::bmap::tests::{BLOCK, fixture, image};
+
+        let map = BlockMap::read(&fixture("2.0")).unwrap();
+        let hashes: Vec<_> = map.ranges.iter().map(|r| r.sha256).collect();
+        let offset = 8192;
+        let mut device = tempfile::tempfile().unwrap();
+        device.write_all(&[0xEE; 8192]).unwrap();
+        device.write_all(&image()).unwrap();
+        let running = AtomicBool::new(true);
+        let verify = |device: &mut File| {
+            let written = map.image_size;
+            verify_mapped(
+                device,
+                offset,
+                &map,
+                &hashes,
+                512,
+                written,
+                &running,
+                &mut |_| {},
+            )
+        };
+        verify(&mut device).unwrap();
+
+        device
+            .seek(SeekFrom::Start(offset + 22 * BLOCK as u64))
+            .unwrap();
+        device.write_all(&[0]).unwrap();
+        let e = verify(&mut device).unwrap_err();
+        match e.downcast_ref::<Error>() {
+            Some(Error::MappedRangeMismatch { range, .. }) => {
+                assert_eq!(
+                    *range,
+                    offset + 20 * BLOCK as u64..offset + 24 * BLOCK as u64
+                )
+            }
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn failed_writes_are_retried_with_a_warning() {
+        // A boot sector, so that the image itself draws no warning.
+        let mut data = sample().repeat(8);
+        data[510..512].copy_from_slice(&[0x55, 0xAA]);
+        let mut image = tempfile::NamedTempFile::new().unwrap();
+        image.write_all(&data).unwrap();
+        let retry = RetryPolicy {
+            attempts: 2,
+            delay: Duration::from_millis(1),
+        };
+
+        for pipelined in [false, true] {
+            let device = tempfile::NamedTempFile::new().unwrap();
+            fault::arm(device.as_file(), Fault::Write, 2);
+            let mut retries = Vec::new();
+            let mut warnings = Vec::new();
+            WriteOptions::new(image.path(), device.path())
+                .allow_file_target(true)
+                .pipelined(pipelined)
+                .retry(retry)
+                .on_retry(|offset, attempt| retries.push((offset, attempt)))
+                .on_warning(|warning| warnings.push(warning.kind))
+                .run()
+                .unwrap();
+            assert_eq!(retries, [(0, 1), (0, 2)]);
+            assert_eq!(warnings, [WarningKind::WriteRetried; 2]);
+            assert!(std::fs::read(device.path()).unwrap() == data);
+
+            // A chunk that keeps failing fails the write once it is out of
+            // retries.
+            let device = tempfile::NamedTempFile::new().unwrap();
+            fault::arm(device.as_file(), Fault::Write, 3);
+            let e = WriteOptions::new(image.path(), device.path())
+                .allow_file_target(true)
+                .pipelined(pipelined)
+                .retry(retry)
+                .run()
+                .unwrap_err();
+            assert!(
+                matches!(
+                    e.downcast_ref::<Error>(),
+                    Some(Error::Io { stage: Stage::Write, offset: Some(0), source })
+                        if source.raw_os_error() == Some(libc::EIO)
+                ),
+                "{:#}",
+                e
+            );
+        }
+    }
+}
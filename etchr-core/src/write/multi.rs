@@ -0,0 +1,650 @@
+//! Writing one image to several devices at once.
+//!
+//! The image is read (and decompressed) once on the calling thread, and each
+//! chunk is handed to every device as a shared, read-only buffer. Each device
+//! is written by its own [`WriteOptions`] on its own thread, so a failure on
+//! one device leaves the others running. The slowest device sets the pace.
+use super::{
+    BUFFER_SIZE, DEFAULT_ZSTD_WINDOW_LOG_MAX, EraseMethod, ImageInput, PIPELINE_DEPTH,
+    PROGRESS_WINDOW, RetryPolicy, VerifyMode, WriteOptions, WriteReport, check_zstd_window_log_max,
+    decompressed_size_hint, open_image, read_full,
+};
+use crate::device::DirectIo;
+use crate::error::Error;
+use crate::image;
+use crate::progress::Progress;
+use crate::source::ImageFile;
+use crate::warning::Warning;
+use anyhow::{Result, anyhow};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+/// A chunk of image data shared by every device. An empty chunk marks the end
+/// of the image.
+type SharedChunk = io::Result<Arc<Vec<u8>>>;
+
+/// Reads the image from the chunks fanned out to one device.
+struct FanOutReader {
+    chunks: Receiver<SharedChunk>,
+    current: Arc<Vec<u8>>,
+    pos: usize,
+    done: bool,
+}
+
+impl Read for FanOutReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.current.len() && !self.done {
+            match self.chunks.recv() {
+                Ok(Ok(chunk)) => {
+                    self.done = chunk.is_empty();
+                    self.current = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => return Err(e),
+                // The image reader stopped early, which only happens on cancel.
+                Err(_) => return Err(io::Error::other("The image source stopped unexpectedly")),
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Callbacks that also receive the index of the device they report on.
+type DeviceCallback<'a> = Box<dyn FnMut(usize, u64) + Send + 'a>;
+type DeviceProgressCallback<'a> = Box<dyn FnMut(usize, &Progress) + Send + 'a>;
+type DeviceWarningCallback<'a> = Box<dyn FnMut(usize, Warning) + Send + 'a>;
+type DeviceEraseCallback<'a> = Box<dyn FnMut(usize, EraseMethod, u64) + Send + 'a>;
+
+/// The plain settings handed to the [`WriteOptions`] of each device.
+#[derive(Clone, Copy)]
+struct Settings {
+    verify_mode: VerifyMode,
+    exclusive: bool,
+    auto_unmount: bool,
+    allow_file_target: bool,
+    allow_system_disk: bool,
+    allow_partition: bool,
+    sync_writes: bool,
+    buffer_size: usize,
+    retry: RetryPolicy,
+    discard: bool,
+    only_changed: bool,
+    eject_on_success: bool,
+    dry_run: bool,
+    wipe_remainder: bool,
+    secure_erase: bool,
+    relocate_gpt_backup: bool,
+    grow_last_partition: bool,
+    direct_io: DirectIo,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    queue_depth: u32,
+    offset: u64,
+    max_bytes_per_sec: Option<u64>,
+    progress_window: Duration,
+}
+
+/// Locks a callback shared by the writer threads. A callback that panicked on
+/// one thread does not stop the others from reporting.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Copies an error reading the image for each device. A corrupt image, or a
+/// zstd window over the limit, is still reported as such, so that every device
+/// fails with the same explanation.
+fn copy_error(e: &io::Error) -> io::Error {
+    match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        Some(Error::CorruptImage { format, detail }) => io::Error::other(Error::CorruptImage {
+            format: *format,
+            detail: detail.clone(),
+        }),
+        Some(Error::ZstdWindowTooLarge { window_log_max }) => {
+            io::Error::other(Error::ZstdWindowTooLarge {
+                window_log_max: *window_log_max,
+            })
+        }
+        _ => io::Error::new(e.kind(), e.to_string()),
+    }
+}
+
+/// Configures and runs the writing of one image to several block devices in
+/// parallel.
+///
+/// The options mirror [`WriteOptions`], and apply to every device. The
+/// per-device callbacks receive the index of the device in the target list
+/// first, so front-ends can show one progress bar per device. They are called
+/// from the writer threads, which is why they must be `Send`.
+///
+/// ```rust,no_run
+/// use etchr_core::write::MultiWriteOptions;
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let results = MultiWriteOptions::new("image.img.xz", ["/dev/sdb", "/dev/sdc"])
+///     .on_write_progress(|device, bytes| println!("#{}: {} bytes written", device, bytes))
+///     .run()?;
+/// for result in results {
+///     if let Err(e) = result {
+///         eprintln!("{}", e);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct MultiWriteOptions<'a> {
+    image_path: PathBuf,
+    device_paths: Vec<PathBuf>,
+    settings: Settings,
+    zstd_window_log_max: u32,
+    running: Arc<AtomicBool>,
+    on_decompress_start: Box<dyn FnMut(Option<u64>) + 'a>,
+    on_decompress_progress: Box<dyn FnMut(u64) + 'a>,
+    on_erase_start: DeviceEraseCallback<'a>,
+    on_erase_progress: DeviceCallback<'a>,
+    on_discard_start: DeviceCallback<'a>,
+    on_discard_progress: DeviceCallback<'a>,
+    on_write_start: DeviceCallback<'a>,
+    on_write_progress: DeviceCallback<'a>,
+    on_sync_start: Box<dyn FnMut(usize) + Send + 'a>,
+    on_sync_done: Box<dyn FnMut(usize) + Send + 'a>,
+    on_verify_start: DeviceCallback<'a>,
+    on_verify_progress: DeviceCallback<'a>,
+    on_wipe_start: DeviceCallback<'a>,
+    on_wipe_progress: DeviceCallback<'a>,
+    on_progress: DeviceProgressCallback<'a>,
+    on_retry: Box<dyn FnMut(usize, u64, u32) + Send + 'a>,
+    on_warning: DeviceWarningCallback<'a>,
+}
+
+impl<'a> MultiWriteOptions<'a> {
+    /// Creates the options for writing `image_path` (which can be compressed)
+    /// to every device in `device_paths`.
+    pub fn new(
+        image_path: impl Into<PathBuf>,
+        device_paths: impl IntoIterator<Item = impl Into<PathBuf>>,
+    ) -> Self {
+        Self {
+            image_path: image_path.into(),
+            device_paths: device_paths.into_iter().map(Into::into).collect(),
+            settings: Settings {
+                verify_mode: VerifyMode::Full,
+                exclusive: true,
+                auto_unmount: false,
+                allow_file_target: false,
+                allow_system_disk: false,
+                allow_partition: false,
+                sync_writes: false,
+                buffer_size: BUFFER_SIZE,
+                retry: RetryPolicy::default(),
+                discard: false,
+                only_changed: false,
+                eject_on_success: false,
+                dry_run: false,
+                wipe_remainder: false,
+                secure_erase: false,
+                relocate_gpt_backup: false,
+                grow_last_partition: false,
+                direct_io: DirectIo::default(),
+                #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                queue_depth: super::DEFAULT_QUEUE_DEPTH,
+                offset: 0,
+                max_bytes_per_sec: None,
+                progress_window: PROGRESS_WINDOW,
+            },
+            zstd_window_log_max: DEFAULT_ZSTD_WINDOW_LOG_MAX,
+            running: Arc::new(AtomicBool::new(true)),
+            on_decompress_start: Box::new(|_| {}),
+            on_decompress_progress: Box::new(|_| {}),
+            on_erase_start: Box::new(|_, _, _| {}),
+            on_erase_progress: Box::new(|_, _| {}),
+            on_discard_start: Box::new(|_, _| {}),
+            on_discard_progress: Box::new(|_, _| {}),
+            on_write_start: Box::new(|_, _| {}),
+            on_write_progress: Box::new(|_, _| {}),
+            on_sync_start: Box::new(|_| {}),
+            on_sync_done: Box::new(|_| {}),
+            on_verify_start: Box::new(|_, _| {}),
+            on_verify_progress: Box::new(|_, _| {}),
+            on_wipe_start: Box::new(|_, _| {}),
+            on_wipe_progress: Box::new(|_, _| {}),
+            on_progress: Box::new(|_, _| {}),
+            on_retry: Box::new(|_, _, _| {}),
+            on_warning: Box::new(|_, _| {}),
+        }
+    }
+
+    /// See [`WriteOptions::verify`]. Each device is read back on its own.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.settings.verify_mode = if verify {
+            VerifyMode::Full
+        } else {
+            VerifyMode::None
+        };
+        self
+    }
+
+    /// See [`WriteOptions::verify_mode`].
+    pub fn verify_mode(mut self, verify_mode: VerifyMode) -> Self {
+        self.settings.verify_mode = verify_mode;
+        self
+    }
+
+    /// See [`WriteOptions::exclusive`].
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.settings.exclusive = exclusive;
+        self
+    }
+
+    /// See [`WriteOptions::auto_unmount`].
+    pub fn auto_unmount(mut self, auto_unmount: bool) -> Self {
+        self.settings.auto_unmount = auto_unmount;
+        self
+    }
+
+    /// See [`WriteOptions::buffer_size`]. Must suit the sector size of every device.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.settings.buffer_size = buffer_size;
+        self
+    }
+
+    /// See [`WriteOptions::allow_file_target`].
+    pub fn allow_file_target(mut self, allow_file_target: bool) -> Self {
+        self.settings.allow_file_target = allow_file_target;
+        self
+    }
+
+    /// See [`WriteOptions::allow_system_disk`].
+    pub fn allow_system_disk(mut self, allow_system_disk: bool) -> Self {
+        self.settings.allow_system_disk = allow_system_disk;
+        self
+    }
+
+    /// See [`WriteOptions::allow_partition`].
+    pub fn allow_partition(mut self, allow_partition: bool) -> Self {
+        self.settings.allow_partition = allow_partition;
+        self
+    }
+
+    /// See [`WriteOptions::sync_writes`].
+    pub fn sync_writes(mut self, sync_writes: bool) -> Self {
+        self.settings.sync_writes = sync_writes;
+        self
+    }
+
+    /// See [`WriteOptions::retry`].
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.settings.retry = retry;
+        self
+    }
+
+    /// See [`WriteOptions::discard`].
+    pub fn discard(mut self, discard: bool) -> Self {
+        self.settings.discard = discard;
+        self
+    }
+
+    /// See [`WriteOptions::only_changed`].
+    pub fn only_changed(mut self, only_changed: bool) -> Self {
+        self.settings.only_changed = only_changed;
+        self
+    }
+
+    /// See [`WriteOptions::eject_on_success`]. Each device is ejected as soon
+    /// as its own write has succeeded.
+    pub fn eject_on_success(mut self, eject_on_success: bool) -> Self {
+        self.settings.eject_on_success = eject_on_success;
+        self
+    }
+
+    /// See [`WriteOptions::dry_run`]. The image is still read only once.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.settings.dry_run = dry_run;
+        self
+    }
+
+    /// See [`WriteOptions::wipe_remainder`]. Each device is wiped to its own end.
+    pub fn wipe_remainder(mut self, wipe_remainder: bool) -> Self {
+        self.settings.wipe_remainder = wipe_remainder;
+        self
+    }
+
+    /// See [`WriteOptions::secure_erase`]. Each device falls back to a weaker
+    /// method on its own.
+    pub fn secure_erase(mut self, secure_erase: bool) -> Self {
+        self.settings.secure_erase = secure_erase;
+        self
+    }
+
+    /// See [`WriteOptions::relocate_gpt_backup`].
+    pub fn relocate_gpt_backup(mut self, relocate_gpt_backup: bool) -> Self {
+        self.settings.relocate_gpt_backup = relocate_gpt_backup;
+        self
+    }
+
+    /// See [`WriteOptions::grow_last_partition`].
+    pub fn grow_last_partition(mut self, grow_last_partition: bool) -> Self {
+        self.settings.grow_last_partition = grow_last_partition;
+        self
+    }
+
+    /// See [`WriteOptions::direct_io`].
+    pub fn direct_io(mut self, direct_io: DirectIo) -> Self {
+        self.settings.direct_io = direct_io;
+        self
+    }
+
+    /// See [`WriteOptions::queue_depth`].
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn queue_depth(mut self, queue_depth: u32) -> Self {
+        self.settings.queue_depth = queue_depth;
+        self
+    }
+
+    /// See [`WriteOptions::offset`].
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.settings.offset = offset;
+        self
+    }
+
+    /// See [`WriteOptions::max_bytes_per_sec`]. The limit applies to each
+    /// device separately.
+    pub fn max_bytes_per_sec(mut self, max_bytes_per_sec: u64) -> Self {
+        self.settings.max_bytes_per_sec = Some(max_bytes_per_sec);
+        self
+    }
+
+    /// See [`WriteOptions::zstd_window_log_max`].
+    pub fn zstd_window_log_max(mut self, zstd_window_log_max: u32) -> Self {
+        self.zstd_window_log_max = zstd_window_log_max;
+        self
+    }
+
+    /// A flag that cancels the writes to every device when set to `false`.
+    pub fn running(mut self, running: Arc<AtomicBool>) -> Self {
+        self.running = running;
+        self
+    }
+
+    /// Called when decompression begins, with the size of the decompressed
+    /// image if its compression metadata records it, as for
+    /// [`WriteOptions::on_decompress_start`]. The image is only decompressed
+    /// once, on the calling thread.
+    pub fn on_decompress_start(mut self, f: impl FnMut(Option<u64>) + 'a) -> Self {
+        self.on_decompress_start = Box::new(f);
+        self
+    }
+
+    /// Called with the number of compressed bytes consumed.
+    pub fn on_decompress_progress(mut self, f: impl FnMut(u64) + 'a) -> Self {
+        self.on_decompress_progress = Box::new(f);
+        self
+    }
+
+    /// Called with the device index, the method it is erased with and the
+    /// number of bytes to erase.
+    pub fn on_erase_start(mut self, f: impl FnMut(usize, EraseMethod, u64) + Send + 'a) -> Self {
+        self.on_erase_start = Box::new(f);
+        self
+    }
+
+    /// Called with the device index and the number of bytes erased.
+    pub fn on_erase_progress(mut self, f: impl FnMut(usize, u64) + Send + 'a) -> Self {
+        self.on_erase_progress = Box::new(f);
+        self
+    }
+
+    /// Called with the device index and the number of bytes to discard.
+    pub fn on_discard_start(mut self, f: impl FnMut(usize, u64) + Send + 'a) -> Self {
+        self.on_discard_start = Box::new(f);
+        self
+    }
+
+    /// Called with the device index and the number of bytes discarded.
+    pub fn on_discard_progress(mut self, f: impl FnMut(usize, u64) + Send + 'a) -> Self {
+        self.on_discard_progress = Box::new(f);
+        self
+    }
+
+    /// Called with the device index and the image size, or `0` if the image
+    /// is compressed, when writing to that device begins.
+    pub fn on_write_start(mut self, f: impl FnMut(usize, u64) + Send + 'a) -> Self {
+        self.on_write_start = Box::new(f);
+        self
+    }
+
+    /// Called with the device index and the number of bytes written to it.
+    pub fn on_write_progress(mut self, f: impl FnMut(usize, u64) + Send + 'a) -> Self {
+        self.on_write_progress = Box::new(f);
+        self
+    }
+
+    /// Called with the device index once every byte has been written to that
+    /// device, when it starts being synced.
+    pub fn on_sync_start(mut self, f: impl FnMut(usize) + Send + 'a) -> Self {
+        self.on_sync_start = Box::new(f);
+        self
+    }
+
+    /// Called with the device index once that device has been synced.
+    pub fn on_sync_done(mut self, f: impl FnMut(usize) + Send + 'a) -> Self {
+        self.on_sync_done = Box::new(f);
+        self
+    }
+
+    /// Called with the device index and the number of bytes to verify.
+    pub fn on_verify_start(mut self, f: impl FnMut(usize, u64) + Send + 'a) -> Self {
+        self.on_verify_start = Box::new(f);
+        self
+    }
+
+    /// Called with the device index and the number of bytes verified.
+    pub fn on_verify_progress(mut self, f: impl FnMut(usize, u64) + Send + 'a) -> Self {
+        self.on_verify_progress = Box::new(f);
+        self
+    }
+
+    /// Called with the device index and the number of bytes to zero after the
+    /// image on that device.
+    pub fn on_wipe_start(mut self, f: impl FnMut(usize, u64) + Send + 'a) -> Self {
+        self.on_wipe_start = Box::new(f);
+        self
+    }
+
+    /// Called with the device index and the number of bytes zeroed after the
+    /// image on that device.
+    pub fn on_wipe_progress(mut self, f: impl FnMut(usize, u64) + Send + 'a) -> Self {
+        self.on_wipe_progress = Box::new(f);
+        self
+    }
+
+    /// Called with the device index and a [`Progress`] report alongside each
+    /// of the per-device progress callbacks. Decompression is shared by all
+    /// devices and only reported through `on_decompress_progress`.
+    pub fn on_progress(mut self, f: impl FnMut(usize, &Progress) + Send + 'a) -> Self {
+        self.on_progress = Box::new(f);
+        self
+    }
+
+    /// See [`WriteOptions::progress_window`].
+    pub fn progress_window(mut self, window: Duration) -> Self {
+        self.settings.progress_window = window;
+        self
+    }
+
+    /// Called with the device index, offset and attempt number each time a
+    /// failed chunk is retried.
+    pub fn on_retry(mut self, f: impl FnMut(usize, u64, u32) + Send + 'a) -> Self {
+        self.on_retry = Box::new(f);
+        self
+    }
+
+    /// Called with the device index and each [`Warning`] about a problem
+    /// that does not stop the write to that device.
+    pub fn on_warning(mut self, f: impl FnMut(usize, Warning) + Send + 'a) -> Self {
+        self.on_warning = Box::new(f);
+        self
+    }
+
+    /// Writes the image to every device.
+    ///
+    /// Returns one result per device, in the order the devices were given. A
+    /// device that fails (see [`WriteOptions::run`]) does not stop the others.
+    /// The reported `decompress_duration` is always zero, as decompression is
+    /// shared by all devices.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the image cannot be opened. An
+    /// error while reading the image later on fails every device still being
+    /// written.
+    pub fn run(&mut self) -> Result<Vec<Result<WriteReport>>> {
+        check_zstd_window_log_max(self.zstd_window_log_max)?;
+        let image = ImageFile::find(self.image_path.as_path())?;
+        let mut source = open_image(&image, self.zstd_window_log_max)?;
+        let size_hint =
+            image::detect_format(&image)?.and_then(|f| decompressed_size_hint(&image, f));
+        let running = self.running.clone();
+
+        let on_erase_start = Mutex::new(&mut self.on_erase_start);
+        let on_erase_progress = Mutex::new(&mut self.on_erase_progress);
+        let on_discard_start = Mutex::new(&mut self.on_discard_start);
+        let on_discard_progress = Mutex::new(&mut self.on_discard_progress);
+        let on_write_start = Mutex::new(&mut self.on_write_start);
+        let on_write_progress = Mutex::new(&mut self.on_write_progress);
+        let on_sync_start = Mutex::new(&mut self.on_sync_start);
+        let on_sync_done = Mutex::new(&mut self.on_sync_done);
+        let on_verify_start = Mutex::new(&mut self.on_verify_start);
+        let on_verify_progress = Mutex::new(&mut self.on_verify_progress);
+        let on_wipe_start = Mutex::new(&mut self.on_wipe_start);
+        let on_wipe_progress = Mutex::new(&mut self.on_wipe_progress);
+        let on_progress = Mutex::new(&mut self.on_progress);
+        let on_retry = Mutex::new(&mut self.on_retry);
+        let on_warning = Mutex::new(&mut self.on_warning);
+
+        let settings = self.settings;
+        let results = thread::scope(|scope| {
+            let mut senders = Vec::new();
+            let mut writers = Vec::new();
+            for (index, device_path) in self.device_paths.iter().enumerate() {
+                let (tx, rx) = mpsc::sync_channel::<SharedChunk>(PIPELINE_DEPTH);
+                senders.push(tx);
+                let reader = FanOutReader {
+                    chunks: rx,
+                    current: Arc::default(),
+                    pos: 0,
+                    done: false,
+                };
+                let input = ImageInput::Reader {
+                    reader: Some(Box::new(reader)),
+                    len: source.len,
+                    size_hint,
+                };
+                let device_path = device_path.clone();
+                let running = running.clone();
+
+                let (on_erase_start, on_erase_progress) = (&on_erase_start, &on_erase_progress);
+                let (on_discard_start, on_discard_progress) =
+                    (&on_discard_start, &on_discard_progress);
+                let (on_write_start, on_write_progress) = (&on_write_start, &on_write_progress);
+                let (on_sync_start, on_sync_done) = (&on_sync_start, &on_sync_done);
+                let (on_verify_start, on_verify_progress) = (&on_verify_start, &on_verify_progress);
+                let (on_wipe_start, on_wipe_progress) = (&on_wipe_start, &on_wipe_progress);
+                let (on_progress, on_retry) = (&on_progress, &on_retry);
+                let on_warning = &on_warning;
+                writers.push(scope.spawn(move || {
+                    let options = WriteOptions::with_input(input, device_path)
+                        .verify_mode(settings.verify_mode)
+                        .exclusive(settings.exclusive)
+                        .auto_unmount(settings.auto_unmount)
+                        .allow_file_target(settings.allow_file_target)
+                        .allow_system_disk(settings.allow_system_disk)
+                        .allow_partition(settings.allow_partition)
+                        .sync_writes(settings.sync_writes)
+                        .buffer_size(settings.buffer_size)
+                        .retry(settings.retry)
+                        .discard(settings.discard)
+                        .only_changed(settings.only_changed)
+                        .eject_on_success(settings.eject_on_success)
+                        .dry_run(settings.dry_run)
+                        .wipe_remainder(settings.wipe_remainder)
+                        .secure_erase(settings.secure_erase)
+                        .relocate_gpt_backup(settings.relocate_gpt_backup)
+                        .grow_last_partition(settings.grow_last_partition)
+                        .direct_io(settings.direct_io)
+                        .offset(settings.offset)
+                        .running(running)
+                        .progress_window(settings.progress_window);
+                    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                    let options = options.queue_depth(settings.queue_depth);
+                    let options = match settings.max_bytes_per_sec {
+                        Some(limit) => options.max_bytes_per_sec(limit),
+                        None => options,
+                    };
+                    options
+                        .on_erase_start(|method, len| lock(on_erase_start)(index, method, len))
+                        .on_erase_progress(|done| lock(on_erase_progress)(index, done))
+                        .on_discard_start(|len| lock(on_discard_start)(index, len))
+                        .on_discard_progress(|done| lock(on_discard_progress)(index, done))
+                        .on_write_start(|len| lock(on_write_start)(index, len))
+                        .on_write_progress(|done| lock(on_write_progress)(index, done))
+                        .on_sync_start(|| lock(on_sync_start)(index))
+                        .on_sync_done(|| lock(on_sync_done)(index))
+                        .on_verify_start(|len| lock(on_verify_start)(index, len))
+                        .on_verify_progress(|done| lock(on_verify_progress)(index, done))
+                        .on_wipe_start(|len| lock(on_wipe_start)(index, len))
+                        .on_wipe_progress(|done| lock(on_wipe_progress)(index, done))
+                        .on_progress(|progress| lock(on_progress)(index, progress))
+                        .on_retry(|offset, attempt| lock(on_retry)(index, offset, attempt))
+                        .on_warning(|warning| lock(on_warning)(index, warning))
+                        .run()
+                }));
+            }
+
+            if source.compressed {
+                (self.on_decompress_start)(size_hint);
+            }
+            // Each chunk is read once and shared by every device still writing.
+            // A device that fails hangs up its channel and is dropped from the
+            // list; once none are left, there is no point reading further.
+            while running.load(Ordering::SeqCst) && !senders.is_empty() {
+                let mut buf = vec![0u8; BUFFER_SIZE];
+                let n = match read_full(&mut source.reader, &mut buf) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        for tx in &senders {
+                            let _ = tx.send(Err(copy_error(&e)));
+                        }
+                        break;
+                    }
+                };
+                if source.compressed {
+                    (self.on_decompress_progress)(source.consumed.load(Ordering::Relaxed));
+                }
+                buf.truncate(n);
+                let chunk = Arc::new(buf);
+                senders.retain(|tx| tx.send(Ok(chunk.clone())).is_ok());
+                if n == 0 {
+                    break;
+                }
+            }
+            drop(senders);
+
+            writers
+                .into_iter()
+                .map(|writer| {
+                    writer
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow!("The writer thread panicked")))
+                })
+                .collect()
+        });
+        Ok(results)
+    }
+}
@@ -0,0 +1,90 @@
+//! Writing one image to several devices in turn, decompressing it only once.
+//!
+//! A compressed image is normally decoded again for every write, so flashing a
+//! stack of cards one after another would decompress it once per card. A
+//! [`Session`] decompresses it to a temporary file up front and keeps that
+//! file for as long as it lives, handing out a [`WriteOptions`] for each card.
+use super::{
+    DEFAULT_COMPRESSION_RATIO, DEFAULT_ZSTD_WINDOW_LOG_MAX, DecompressedImage, WriteOptions,
+    check_zstd_window_log_max, decompress_image, io_error,
+};
+use crate::progress::Stage;
+use crate::source::ImageFile;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
+/// An image prepared once and then written to any number of devices.
+///
+/// ```rust,no_run
+/// use etchr_core::write::Session;
+/// use std::sync::{Arc, atomic::AtomicBool};
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let running = Arc::new(AtomicBool::new(true));
+/// let session = Session::prepare("image.img.xz", None, running.clone(), |_| {})?;
+/// for device in ["/dev/sdb", "/dev/sdc"] {
+///     session
+///         .flash(device)
+///         .running(running.clone())
+///         .on_write_progress(|bytes| println!("{}: {} bytes written", device, bytes))
+///         .run()?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The temporary file is deleted when the session is dropped.
+pub struct Session {
+    image: DecompressedImage,
+}
+
+impl Session {
+    /// Decompresses the image at `image_path` to a temporary file, after
+    /// checking that the temporary directory has room for it. An image that
+    /// is not compressed is used as it is.
+    ///
+    /// `zstd_window_log_max` limits the window of a zstd image, as
+    /// [`WriteOptions::zstd_window_log_max`] does; `None` keeps the default.
+    ///
+    /// `on_decompress_progress` is called with the number of decompressed
+    /// bytes written so far, and clearing `running` cancels the decompression.
+    pub fn prepare(
+        image_path: impl AsRef<Path>,
+        zstd_window_log_max: Option<u32>,
+        running: Arc<AtomicBool>,
+        mut on_decompress_progress: impl FnMut(u64),
+    ) -> Result<Self> {
+        let zstd_window_log_max = zstd_window_log_max.unwrap_or(DEFAULT_ZSTD_WINDOW_LOG_MAX);
+        check_zstd_window_log_max(zstd_window_log_max)?;
+        let image_file =
+            ImageFile::find(image_path.as_ref()).map_err(io_error(Stage::Decompress, None))?;
+        let image = decompress_image(
+            &image_file,
+            zstd_window_log_max,
+            None,
+            Some(DEFAULT_COMPRESSION_RATIO),
+            running,
+            |counts| on_decompress_progress(counts.decompressed_written),
+        )?;
+        Ok(Self { image })
+    }
+
+    /// The path of the image data that is written: the temporary file, or
+    /// the original image if it was not compressed.
+    pub fn image_path(&self) -> &Path {
+        self.image.file.path()
+    }
+
+    /// Creates the options for writing the prepared image to the device at
+    /// `device_path`. Anything set on them, callbacks included, only applies
+    /// to this write.
+    ///
+    /// Since the data is already decompressed, an expected checksum set with
+    /// [`WriteOptions::expected_source_sha256`] is compared with the
+    /// decompressed image rather than the compressed file.
+    pub fn flash(&self, device_path: impl Into<PathBuf>) -> WriteOptions<'_> {
+        WriteOptions::new(self.image_path(), device_path)
+    }
+}
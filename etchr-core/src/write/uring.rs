@@ -0,0 +1,211 @@
+//! An io_uring backend for the write loop.
+//!
+//! The synchronous loop waits for every chunk to reach the device before the
+//! next one is issued, which leaves fast devices idle between writes. This
+//! backend keeps up to `queue_depth` O_DIRECT writes in flight instead. Writes
+//! may complete in any order, but they are handed back in submission order so
+//! that progress never runs ahead of a gap on the device.
+use super::{PendingWrite, RetryPolicy, is_transient, write_chunk};
+use io_uring::{IoUring, opcode, types};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+
+/// A write that has been submitted to the ring.
+struct InFlight {
+    write: PendingWrite,
+    /// The value of the completion, once the kernel has posted it.
+    result: Option<i32>,
+}
+
+pub(super) struct UringWriter {
+    ring: IoUring,
+    fd: types::Fd,
+    queue_depth: usize,
+    in_flight: VecDeque<InFlight>,
+    /// The `user_data` of the write at the front of `in_flight`.
+    first_id: u64,
+    stall_timeout: Option<Duration>,
+    /// When a write last completed, or the queue last stopped being empty.
+    last_completion: Instant,
+}
+
+impl UringWriter {
+    /// Sets up a ring for writing to `device_file`. Fails if the kernel does
+    /// not support io_uring (or it has been disabled), or cannot wait for
+    /// completions with a timeout when a `stall_timeout` is given.
+    pub(super) fn new(
+        device_file: &File,
+        queue_depth: u32,
+        stall_timeout: Option<Duration>,
+    ) -> io::Result<Self> {
+        let queue_depth = queue_depth.max(1);
+        let ring = IoUring::new(queue_depth)?;
+        if stall_timeout.is_some() && !ring.params().is_feature_ext_arg() {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        Ok(Self {
+            ring,
+            fd: types::Fd(device_file.as_raw_fd()),
+            queue_depth: queue_depth as usize,
+            in_flight: VecDeque::new(),
+            first_id: 0,
+            stall_timeout,
+            last_completion: Instant::now(),
+        })
+    }
+
+    pub(super) fn is_full(&self) -> bool {
+        self.in_flight.len() >= self.queue_depth
+    }
+
+    /// Submits `write` to the ring. The caller must make room with
+    /// [`UringWriter::next_finished`] once the queue is full.
+    pub(super) fn submit(&mut self, write: PendingWrite) -> io::Result<()> {
+        let data = &write.chunk.as_slice()[write.start..write.start + write.len];
+        let entry = opcode::Write::new(self.fd, data.as_ptr(), data.len() as u32)
+            .offset(write.device_offset)
+            .build()
+            .user_data(self.first_id + self.in_flight.len() as u64);
+
+        // SAFETY: the chunk's buffer is heap-allocated and stays in
+        // `in_flight` (or is drained on drop) until its completion is reaped.
+        unsafe {
+            self.ring
+                .submission()
+                .push(&entry)
+                .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+        }
+        if self.in_flight.is_empty() {
+            self.last_completion = Instant::now();
+        }
+        self.in_flight.push_back(InFlight {
+            write,
+            result: None,
+        });
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    /// Returns the oldest write once it has completed.
+    ///
+    /// Blocks for it if `wait` is set or the queue is full, and otherwise only
+    /// returns it if it has already completed. A write that failed with a
+    /// transient error, or was cut short, is finished through [`write_chunk`]
+    /// so it is retried like any other chunk.
+    pub(super) fn next_finished(
+        &mut self,
+        wait: bool,
+        device_file: &mut File,
+        retry: &RetryPolicy,
+        running: &AtomicBool,
+        on_retry: &mut dyn FnMut(u64, u32),
+    ) -> io::Result<Option<PendingWrite>> {
+        if self.in_flight.is_empty() {
+            return Ok(None);
+        }
+        let must_wait = wait || self.is_full();
+
+        self.reap();
+        if front_pending(&self.in_flight) {
+            if !must_wait {
+                return Ok(None);
+            }
+            while front_pending(&self.in_flight) {
+                self.wait_one()?;
+            }
+        }
+
+        let done = self.in_flight.pop_front().expect("front write was checked");
+        self.first_id += 1;
+        let write = done.write;
+        let result = done.result.expect("front write has completed");
+
+        let written = if result < 0 {
+            let e = io::Error::from_raw_os_error(-result);
+            if !is_transient(&e) {
+                return Err(e);
+            }
+            0
+        } else {
+            result as usize
+        };
+        if written < write.len {
+            let offset = write.device_offset + written as u64;
+            device_file.seek(SeekFrom::Start(offset))?;
+            write_chunk(
+                device_file,
+                offset,
+                &write.chunk.as_slice()[write.start + written..write.start + write.len],
+                retry,
+                running,
+                on_retry,
+            )?;
+        }
+        Ok(Some(write))
+    }
+
+    /// Blocks until the kernel posts at least one more completion, failing
+    /// with [`io::ErrorKind::TimedOut`] once none has been posted for the
+    /// stall timeout.
+    fn wait_one(&mut self) -> io::Result<()> {
+        loop {
+            let waited = match self.stall_timeout {
+                Some(timeout) => {
+                    let left = timeout.saturating_sub(self.last_completion.elapsed());
+                    if left.is_zero() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "no write completed within the stall timeout",
+                        ));
+                    }
+                    let left = types::Timespec::from(left);
+                    let args = types::SubmitArgs::new().timespec(&left);
+                    self.ring.submitter().submit_with_args(1, &args)
+                }
+                None => self.ring.submit_and_wait(1),
+            };
+            match waited {
+                Ok(_) => break,
+                // A signal (e.g. Ctrl+C) interrupted the wait, not the writes.
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.raw_os_error() == Some(libc::ETIME) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.reap();
+        Ok(())
+    }
+
+    /// Records every completion the kernel has posted so far.
+    fn reap(&mut self) {
+        for entry in self.ring.completion() {
+            let index = (entry.user_data() - self.first_id) as usize;
+            if let Some(in_flight) = self.in_flight.get_mut(index) {
+                in_flight.result = Some(entry.result());
+                self.last_completion = Instant::now();
+            }
+        }
+    }
+}
+
+fn front_pending(in_flight: &VecDeque<InFlight>) -> bool {
+    in_flight.front().is_some_and(|w| w.result.is_none())
+}
+
+impl Drop for UringWriter {
+    /// Waits for the writes still in flight, so the kernel never reads from a
+    /// buffer that has been freed. If they cannot be waited for (the device
+    /// stalled), their buffers are leaked instead.
+    fn drop(&mut self) {
+        while self.in_flight.iter().any(|w| w.result.is_none()) {
+            if self.wait_one().is_err() {
+                std::mem::forget(std::mem::take(&mut self.in_flight));
+                break;
+            }
+        }
+    }
+}
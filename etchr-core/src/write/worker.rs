@@ -0,0 +1,122 @@
+//! A write backend that writes each chunk from a thread of its own.
+//!
+//! A failing card reader can stop responding in the middle of a write, and a
+//! blocking write to it may then never return. Issuing the writes from a worker
+//! thread keeps the write loop in charge: it stops waiting once no write has
+//! completed within the stall timeout, and leaves the worker behind.
+use super::{PendingWrite, RetryPolicy, write_chunk};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What the worker reports back about the write it is working on.
+enum Event {
+    /// The write is being retried, with the offset and attempt number.
+    Retry(u64, u32),
+    /// The write is done, successfully or not.
+    Done(PendingWrite, io::Result<()>),
+}
+
+pub(super) struct WorkerWriter {
+    writes: Sender<PendingWrite>,
+    events: Receiver<Event>,
+    in_flight: bool,
+    stall_timeout: Duration,
+}
+
+impl WorkerWriter {
+    /// Starts a worker that writes to its own handle of `device_file`.
+    pub(super) fn new(
+        device_file: &File,
+        retry: RetryPolicy,
+        running: Arc<AtomicBool>,
+        stall_timeout: Duration,
+    ) -> io::Result<Self> {
+        let mut file = device_file.try_clone()?;
+        let (writes, pending) = mpsc::channel::<PendingWrite>();
+        let (report, events) = mpsc::channel();
+        thread::Builder::new()
+            .name("etchr-writer".into())
+            .spawn(move || {
+                for write in pending {
+                    let data = &write.chunk.as_slice()[write.start..write.start + write.len];
+                    let result = file
+                        .seek(SeekFrom::Start(write.device_offset))
+                        .and_then(|_| {
+                            write_chunk(
+                                &mut file,
+                                write.device_offset,
+                                data,
+                                &retry,
+                                &running,
+                                &mut |offset, attempt| {
+                                    report.send(Event::Retry(offset, attempt)).ok();
+                                },
+                            )
+                        });
+                    if report.send(Event::Done(write, result)).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Self {
+            writes,
+            events,
+            in_flight: false,
+            stall_timeout,
+        })
+    }
+
+    /// Hands `write` to the worker. Only one write is in flight at a time, so
+    /// the previous one must have been returned by
+    /// [`WorkerWriter::next_finished`].
+    pub(super) fn submit(&mut self, write: PendingWrite) -> io::Result<()> {
+        self.writes
+            .send(write)
+            .map_err(|_| io::Error::other("the writer thread has exited"))?;
+        self.in_flight = true;
+        Ok(())
+    }
+
+    /// Waits for the write in flight to finish, failing with
+    /// [`io::ErrorKind::TimedOut`] if the worker has not heard back from the
+    /// device within the stall timeout.
+    pub(super) fn next_finished(
+        &mut self,
+        on_retry: &mut dyn FnMut(u64, u32),
+    ) -> io::Result<Option<PendingWrite>> {
+        if !self.in_flight {
+            return Ok(None);
+        }
+        // A retry means the device answered, even if only with an error.
+        let mut deadline = Instant::now() + self.stall_timeout;
+        loop {
+            match self
+                .events
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(Event::Retry(offset, attempt)) => {
+                    on_retry(offset, attempt);
+                    deadline = Instant::now() + self.stall_timeout;
+                }
+                Ok(Event::Done(write, result)) => {
+                    self.in_flight = false;
+                    return result.map(|()| Some(write));
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "no write completed within the stall timeout",
+                    ));
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::other("the writer thread has exited"));
+                }
+            }
+        }
+    }
+}
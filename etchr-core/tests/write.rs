@@ -0,0 +1,416 @@
+//! Writing images to a regular file standing in for the device.
+use etchr_core::error::Error;
+use etchr_core::vhd::DiskType;
+use etchr_core::warning::WarningKind;
+use etchr_core::write::{MultiWriteOptions, Session, WriteOptions};
+use flate2::write::GzEncoder;
+use std::fs::{self, File};
+use std::io::{Cursor, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tempfile::TempDir;
+
+/// Writes the image `name` holding `contents` to the file standing in for the
+/// device, creating it if needed, verifying it, and returns what the file
+/// holds.
+fn write_image(dir: &TempDir, name: &str, contents: &[u8]) -> Vec<u8> {
+    let image = dir.path().join(name);
+    fs::write(&image, contents).unwrap();
+    let device = device(dir);
+    WriteOptions::new(image, &device)
+        .allow_file_target(true)
+        .verify(true)
+        .run()
+        .unwrap();
+    fs::read(device).unwrap()
+}
+
+/// The path of a file in `dir` that does not exist yet, for the device.
+fn device(dir: &TempDir) -> PathBuf {
+    dir.path().join("device")
+}
+
+/// A VHD footer for a disk of `disk_type` (2 for fixed) and `disk_size`
+/// bytes.
+fn vhd_footer(disk_type: u32, disk_size: u64) -> Vec<u8> {
+    let mut footer = vec![0u8; 512];
+    footer[..8].copy_from_slice(b"conectix");
+    footer[48..56].copy_from_slice(&disk_size.to_be_bytes());
+    footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+    let sum = footer
+        .iter()
+        .fold(0u32, |sum, &b| sum.wrapping_add(b as u32));
+    footer[64..68].copy_from_slice(&(!sum).to_be_bytes());
+    footer
+}
+
+#[test]
+fn fixed_vhd_is_written_without_its_footer() {
+    let dir = TempDir::new().unwrap();
+    let disk: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    let mut vhd = disk.clone();
+    vhd.extend_from_slice(&vhd_footer(2, disk.len() as u64));
+    assert_eq!(write_image(&dir, "disk.vhd", &vhd), disk);
+}
+
+#[test]
+fn dynamic_vhd_is_refused() {
+    let dir = TempDir::new().unwrap();
+    let image = dir.path().join("disk.vhd");
+    let mut vhd = vhd_footer(3, 1 << 20);
+    vhd.extend_from_slice(&[0; 4096]);
+    vhd.extend_from_slice(&vhd_footer(3, 1 << 20));
+    fs::write(&image, vhd).unwrap();
+    let e = WriteOptions::new(image, device(&dir))
+        .allow_file_target(true)
+        .run()
+        .unwrap_err();
+    assert!(matches!(
+        e.downcast_ref::<Error>(),
+        Some(Error::UnsupportedVhd {
+            disk_type: DiskType::Dynamic
+        })
+    ));
+    assert!(!device(&dir).exists());
+}
+
+#[test]
+fn only_mapped_blocks_are_written() {
+    // The image that `tests/data/image-*.bmap` maps: blocks 0-1, 10, 20-23
+    // and 63 (the last, half a block) are filled with their number plus one.
+    const BLOCK: usize = 4096;
+    let mut image = vec![0u8; 63 * BLOCK + 2048];
+    for block in [0, 1, 10, 20, 21, 22, 23, 63] {
+        let end = ((block + 1) * BLOCK).min(image.len());
+        image[block * BLOCK..end].fill(block as u8 + 1);
+    }
+
+    for version in ["1.4", "2.0"] {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("image.img");
+        fs::write(&path, &image).unwrap();
+        fs::write(device(&dir), vec![0xEE; image.len()]).unwrap();
+        let bmap = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/data")
+            .join(format!("image-{}.bmap", version));
+        // Unmapped blocks between two mapped ones in the same chunk are
+        // written too, so each chunk here is a single block.
+        WriteOptions::new(path, device(&dir))
+            .allow_file_target(true)
+            .buffer_size(BLOCK)
+            .bmap(bmap)
+            .verify(true)
+            .run()
+            .unwrap();
+
+        let written = fs::read(device(&dir)).unwrap();
+        for (block, (written, image)) in written.chunks(BLOCK).zip(image.chunks(BLOCK)).enumerate()
+        {
+            if image.iter().all(|&b| b == 0) {
+                assert!(written.iter().all(|&b| b == 0xEE), "block {}", block);
+            } else {
+                assert_eq!(written, image, "block {}", block);
+            }
+        }
+    }
+}
+
+#[test]
+fn a_partial_last_sector_is_written_exactly() {
+    // Longer than one chunk, and ending 100 bytes into a sector.
+    let image: Vec<u8> = (0..(1 << 20) + 3 * 512 + 100)
+        .map(|i| (i % 251) as u8)
+        .collect();
+
+    // A new file ends where the image does.
+    let dir = TempDir::new().unwrap();
+    assert_eq!(write_image(&dir, "image.img", &image), image);
+
+    // Nothing past the image is overwritten on a larger target.
+    let dir = TempDir::new().unwrap();
+    fs::write(device(&dir), vec![0xEE; (1 << 20) + 8192]).unwrap();
+    let written = write_image(&dir, "image.img", &image);
+    assert_eq!(written.len(), (1 << 20) + 8192);
+    assert_eq!(&written[..image.len()], &image[..]);
+    assert!(written[image.len()..].iter().all(|&b| b == 0xEE));
+}
+
+/// Writes a 4 MiB image in 64 KiB chunks, gzipped if `gzip`, clearing the
+/// `running` flag once `chunks` of them have been written, and checks that
+/// the write stops with what it reports as synced on the device.
+fn cancel_after(chunks: u64, pipelined: bool, gzip: bool) {
+    const CHUNK: usize = 64 << 10;
+    let image: Vec<u8> = (0..4 << 20).map(|i| (i / CHUNK + 1) as u8).collect();
+    let dir = TempDir::new().unwrap();
+    let path = if gzip {
+        let path = dir.path().join("image.img.gz");
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Default::default());
+        encoder.write_all(&image).unwrap();
+        encoder.finish().unwrap();
+        path
+    } else {
+        let path = dir.path().join("image.img");
+        fs::write(&path, &image).unwrap();
+        path
+    };
+
+    let running = Arc::new(AtomicBool::new(true));
+    let flag = running.clone();
+    let e = WriteOptions::new(path, device(&dir))
+        .allow_file_target(true)
+        .buffer_size(CHUNK)
+        .pipelined(pipelined)
+        .running(running)
+        .on_write_progress(move |written| {
+            if written >= chunks * CHUNK as u64 {
+                flag.store(false, Ordering::SeqCst);
+            }
+        })
+        .run()
+        .unwrap_err();
+    let Some(Error::Cancelled { bytes_synced }) = e.downcast_ref::<Error>() else {
+        panic!("not cancelled: {}", e);
+    };
+    let synced = *bytes_synced as usize;
+    assert!(synced >= chunks as usize * CHUNK, "{} bytes synced", synced);
+    assert!(synced < image.len(), "{} bytes synced", synced);
+    assert_eq!(synced % CHUNK, 0);
+    let written = fs::read(device(&dir)).unwrap();
+    assert!(written.len() >= synced);
+    assert!(written[..synced] == image[..synced]);
+}
+
+#[test]
+fn cancelling_stops_the_write() {
+    cancel_after(4, false, false);
+}
+
+#[test]
+fn cancelling_stops_the_reader_thread_too() {
+    // The reader thread is still decompressing ahead of the writer when the
+    // flag is cleared, at different points of the image.
+    for chunks in [1, 4, 16, 48] {
+        cancel_after(chunks, true, true);
+    }
+    cancel_after(4, true, false);
+}
+
+/// The numbers in `numbers` as lines of 15 digits, as the fixtures in
+/// `tests/data` hold them.
+fn lines(numbers: Range<u32>) -> Vec<u8> {
+    numbers
+        .flat_map(|n| format!("{:015}\n", n).into_bytes())
+        .collect()
+}
+
+fn is_window_too_large(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<Error>(),
+        Some(Error::ZstdWindowTooLarge { window_log_max: 27 })
+    )
+}
+
+#[test]
+fn zstd_windows_are_limited() {
+    // Compressed with `zstd --long=28`, so it needs a 256 MiB window.
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/long.img.zst");
+
+    let dir = TempDir::new().unwrap();
+    WriteOptions::new(&path, device(&dir))
+        .allow_file_target(true)
+        .verify(true)
+        .run()
+        .unwrap();
+    assert!(fs::read(device(&dir)).unwrap() == lines(0..65536));
+
+    let dir = TempDir::new().unwrap();
+    let e = WriteOptions::new(&path, device(&dir))
+        .allow_file_target(true)
+        .zstd_window_log_max(27)
+        .run()
+        .unwrap_err();
+    assert!(is_window_too_large(&e), "{}", e);
+
+    let dir = TempDir::new().unwrap();
+    let results = MultiWriteOptions::new(&path, [device(&dir)])
+        .allow_file_target(true)
+        .zstd_window_log_max(27)
+        .run()
+        .unwrap();
+    let e = results[0].as_ref().unwrap_err();
+    assert!(is_window_too_large(e), "{}", e);
+
+    let running = Arc::new(AtomicBool::new(true));
+    let e = Session::prepare(&path, Some(27), running, |_| {})
+        .err()
+        .unwrap();
+    assert!(is_window_too_large(&e), "{}", e);
+}
+
+#[test]
+fn targets_that_are_not_block_devices_are_refused() {
+    let dir = TempDir::new().unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    let image = root.join("image.img");
+    fs::write(&image, [0x5A; 4096]).unwrap();
+    fs::create_dir(root.join("sub")).unwrap();
+    std::os::unix::fs::symlink("/dev/null", root.join("null")).unwrap();
+    fs::write(root.join("file"), [0u8; 4096]).unwrap();
+
+    // A directory and a character device are refused even when files are
+    // allowed, a missing path and a regular file only when they are not. The
+    // error names the target with symlinks resolved.
+    let refused = [
+        ("sub", true, root.join("sub")),
+        ("null", true, PathBuf::from("/dev/null")),
+        ("missing", false, root.join("missing")),
+        ("file", false, root.join("file")),
+    ];
+    for (target, allow_file_target, expected) in refused {
+        let e = WriteOptions::new(&image, root.join(target))
+            .allow_file_target(allow_file_target)
+            .run()
+            .unwrap_err();
+        match e.downcast_ref::<Error>() {
+            Some(Error::NotABlockDevice { path }) => assert_eq!(*path, expected),
+            _ => panic!("{}: {}", target, e),
+        }
+    }
+    assert!(!root.join("missing").exists());
+    assert_eq!(fs::read(root.join("file")).unwrap(), [0u8; 4096]);
+}
+
+/// Writes the image file `name` holding `contents` to a new file, returning
+/// the kinds of the warnings sent.
+fn warnings_for(name: &str, contents: &[u8]) -> Vec<WarningKind> {
+    let dir = TempDir::new().unwrap();
+    let image = dir.path().join(name);
+    fs::write(&image, contents).unwrap();
+    let mut warnings = Vec::new();
+    WriteOptions::new(image, device(&dir))
+        .allow_file_target(true)
+        .on_warning(|warning| warnings.push(warning.kind))
+        .run()
+        .unwrap();
+    warnings
+}
+
+#[test]
+fn warnings_are_sent_for_doubtful_images() {
+    let mut mbr = vec![0u8; 64 << 10];
+    mbr[510..512].copy_from_slice(&[0x55, 0xAA]);
+    let mut gzipped = GzEncoder::new(Vec::new(), Default::default());
+    gzipped.write_all(&mbr).unwrap();
+    let gzipped = gzipped.finish().unwrap();
+
+    assert_eq!(warnings_for("image.img", &mbr), []);
+    assert_eq!(warnings_for("image.img.gz", &gzipped), []);
+    // The data is unpacked as what it is, whatever the name says.
+    for name in ["image.img.xz", "image.img"] {
+        assert_eq!(
+            warnings_for(name, &gzipped),
+            [WarningKind::FormatMismatch],
+            "{}",
+            name
+        );
+    }
+    // No partition table at the start.
+    assert_eq!(
+        warnings_for("image.img", &[0x5A; 64 << 10]),
+        [WarningKind::NotADiskImage]
+    );
+}
+
+#[test]
+fn bzip2_streams_are_read_one_after_another() {
+    // Two streams made by `bzip2`, as `pbzip2` writes them.
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/streams.img.bz2");
+    let dir = TempDir::new().unwrap();
+    WriteOptions::new(&path, device(&dir))
+        .allow_file_target(true)
+        .verify(true)
+        .run()
+        .unwrap();
+    assert!(fs::read(device(&dir)).unwrap() == lines(0..12288));
+}
+
+#[test]
+fn a_dry_run_reports_progress_up_to_the_whole_image() {
+    let dir = TempDir::new().unwrap();
+    let image = dir.path().join("image.img");
+    fs::write(&image, lines(0..163840)).unwrap();
+    let mut progress = Vec::new();
+    WriteOptions::new(image, device(&dir))
+        .allow_file_target(true)
+        .dry_run(true)
+        .on_write_progress(|written| progress.push(written))
+        .run()
+        .unwrap();
+    // Each chunk is counted once it has gone through, the last one included,
+    // before the final report after the sync.
+    assert_eq!(progress[..3], [1 << 20, 2 << 20, 5 << 19]);
+    assert_eq!(progress[3..], [5 << 19]);
+    assert!(!device(&dir).exists());
+}
+
+#[test]
+fn a_stream_stops_once_the_file_is_full() {
+    const CAPACITY: u64 = (2 << 20) + (64 << 10);
+    let image = lines(0..200_000);
+    let dir = TempDir::new().unwrap();
+    let e = WriteOptions::from_reader(Cursor::new(image.clone()), None, device(&dir))
+        .allow_file_target(true)
+        .expected_device_size(CAPACITY)
+        .run()
+        .unwrap_err();
+    // The chunk that does not fit is not written at all.
+    assert!(
+        matches!(
+            e.downcast_ref::<Error>(),
+            Some(Error::DeviceFull {
+                capacity: Some(CAPACITY),
+                bytes_written: 0x20_0000,
+            })
+        ),
+        "{}",
+        e
+    );
+    assert!(fs::read(device(&dir)).unwrap() == image[..2 << 20]);
+
+    // A dry run stops at the same point, without creating the file.
+    let dir = TempDir::new().unwrap();
+    let e = WriteOptions::from_reader(Cursor::new(image.clone()), None, device(&dir))
+        .allow_file_target(true)
+        .expected_device_size(CAPACITY)
+        .dry_run(true)
+        .run()
+        .unwrap_err();
+    assert!(
+        matches!(
+            e.downcast_ref::<Error>(),
+            Some(Error::DeviceFull {
+                capacity: Some(CAPACITY),
+                bytes_written: 0x20_0000,
+            })
+        ),
+        "{}",
+        e
+    );
+    assert!(!device(&dir).exists());
+
+    // An image whose size is known is refused before anything is written.
+    let dir = TempDir::new().unwrap();
+    let len = image.len() as u64;
+    let e = WriteOptions::from_reader(Cursor::new(image), Some(len), device(&dir))
+        .allow_file_target(true)
+        .expected_device_size(CAPACITY)
+        .run()
+        .unwrap_err();
+    assert!(matches!(
+        e.downcast_ref::<Error>(),
+        Some(Error::ImageTooLarge { image, device: CAPACITY }) if *image == len
+    ));
+    assert_eq!(fs::read(device(&dir)).unwrap(), []);
+}
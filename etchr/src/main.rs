@@ -1,14 +1,24 @@
 use anyhow::{Result, anyhow};
 use clap::{Parser, Subcommand};
-use console::style;
+use console::{Term, style};
 use dialoguer::{Confirm, Select, theme::ColorfulTheme};
-use etchr_core::device::Device;
-use indicatif::{ProgressBar, ProgressStyle};
+use etchr_core::cache::Cache;
+use etchr_core::device::{Device, DirectIo};
+use etchr_core::error::{Corruption, Error as CoreError, PartialTransfer};
+use etchr_core::hash::HashAlgorithm;
+use etchr_core::progress::{DecompressProgress, Stage};
+use etchr_core::read::{Compression, ReadOptions, ShortDevice};
+use etchr_core::source::{ImageFile, Source};
+use etchr_core::verify::{VerifyMethod, VerifyOptions};
+use etchr_core::warning::Warning;
+use etchr_core::write::{VerifyMode, WriteOptions};
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+use std::cell::Cell;
 use std::io::{IsTerminal, stdout};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[cfg(unix)]
 use libc::ECHOCTL;
@@ -29,19 +39,241 @@ struct Cli {
 enum Commands {
     /// Write an image to a device interactively
     Write {
-        /// Image file to write
+        /// Image file to write, or an http(s):// URL to download it from as it is written
         #[arg(required = true)]
-        image: PathBuf,
+        image: Source,
 
         /// Skip write verification
         #[arg(short = 'n', long = "no-verify")]
         no_verify: bool,
+
+        /// Read each chunk back right after writing it, failing at the first mismatch
+        #[arg(long = "read-back")]
+        read_back: bool,
+
+        /// How much to verify: full, sample:PERCENT[:SEED] to spot-check part of the device, or nonzero[:PERCENT[:SEED]] to check only the parts of the image that are not zeros (and PERCENT of the rest)
+        #[arg(long = "verify", value_name = "MODE", value_parser = parse_verify, conflicts_with = "no_verify")]
+        verify: Option<VerifyMode>,
+
+        /// Compare the device with the image byte for byte when verifying, reporting the first difference
+        #[arg(long = "compare", conflicts_with = "no_verify")]
+        compare: bool,
+
+        /// Hash algorithm to verify with: sha256, sha512, blake3, crc32c or xxh64
+        #[arg(
+            long = "hash",
+            value_name = "ALGORITHM",
+            value_parser = parse_hash,
+            default_value = "sha256",
+            conflicts_with = "no_verify"
+        )]
+        hash: HashAlgorithm,
+
+        /// Drop the device from the page cache before verifying, if it cannot be read with O_DIRECT
+        #[arg(long = "drop-caches", conflicts_with = "no_verify")]
+        drop_caches: bool,
+
+        /// Discard (TRIM) the device before writing
+        #[arg(long = "discard")]
+        discard: bool,
+
+        /// Only write the blocks that differ from what the device already holds
+        #[arg(long = "only-changed", conflicts_with_all = ["discard", "secure_erase"])]
+        only_changed: bool,
+
+        /// Erase the whole device before writing, securely where supported
+        #[arg(long = "secure-erase", conflicts_with = "dry_run")]
+        secure_erase: bool,
+
+        /// Eject the device after a successful write (the default)
+        #[arg(long = "eject", overrides_with = "no_eject")]
+        eject: bool,
+
+        /// Leave the device attached after writing
+        #[arg(long = "no-eject", overrides_with = "eject")]
+        no_eject: bool,
+
+        /// Expected SHA-256 of the image file, checked before writing (a download is checked once it is complete)
+        #[arg(long = "checksum", value_name = "HEX", value_parser = parse_sha256)]
+        checksum: Option<[u8; 32]>,
+
+        /// Checksum file listing the image's SHA-256 (found next to the image by default)
+        #[arg(
+            long = "checksum-file",
+            value_name = "FILE",
+            conflicts_with = "checksum"
+        )]
+        checksum_file: Option<PathBuf>,
+
+        /// Limit the write rate, in bytes per second (e.g. 50M)
+        #[arg(long = "limit-rate", value_name = "RATE", value_parser = parse_rate)]
+        limit_rate: Option<u64>,
+
+        /// Give up if the device stops responding for this many seconds
+        #[arg(long = "timeout", value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: Option<u64>,
+
+        /// Zero the rest of the device after the image
+        #[arg(long = "wipe-remainder")]
+        wipe_remainder: bool,
+
+        /// Move the image's backup GPT to the end of the device
+        #[arg(long = "fix-gpt")]
+        fix_gpt: bool,
+
+        /// Grow the last partition to the end of the device
+        #[arg(long = "grow-last-partition")]
+        grow_last_partition: bool,
+
+        /// Go through the whole write without touching the device
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+
+        /// Block map (.bmap) listing the parts of the image that hold data; only those are written
+        #[arg(long = "bmap", value_name = "FILE")]
+        bmap: Option<PathBuf>,
+
+        /// Write the image even if it does not look like a disk image
+        #[arg(long = "force")]
+        force: bool,
+
+        /// Keep the decompressed image in ~/.cache/etchr, so writing it again skips decompression
+        #[arg(long = "cache")]
+        cache: bool,
+
+        /// Maximum size of the cache (e.g. 20G); the images used least recently are evicted
+        #[arg(long = "cache-size", value_name = "SIZE", value_parser = parse_size, requires = "cache")]
+        cache_size: Option<u64>,
+
+        /// Decompress to the cache even if its filesystem looks too full for the image
+        #[arg(long = "skip-space-check", requires = "cache")]
+        skip_space_check: bool,
+
+        /// Largest zstd window to accept, as a power of two (default 31, enough for any zstd --long)
+        #[arg(
+            long = "zstd-window-log",
+            value_name = "N",
+            value_parser = clap::value_parser!(u32).range(10..=31)
+        )]
+        zstd_window_log: Option<u32>,
     },
     /// Read a device to an image file interactively
     Read {
-        /// Output image file
+        /// Output image file, or - for stdout
         #[arg(required = true)]
         image: PathBuf,
+
+        /// Limit the read rate, in bytes per second (e.g. 50M)
+        #[arg(long = "limit-rate", value_name = "RATE", value_parser = parse_rate)]
+        limit_rate: Option<u64>,
+
+        /// Save the SHA-256 of the image next to it, in IMAGE.sha256
+        #[arg(long = "checksum-file")]
+        checksum_file: bool,
+
+        /// Save a block map of the image next to it, listing the blocks that are not all zeros
+        #[arg(long = "bmap")]
+        bmap: bool,
+
+        /// Compress the image as it is read
+        #[arg(long = "compress", value_name = "FORMAT", value_parser = ["gz", "xz", "zst", "lz4"])]
+        compress: Option<String>,
+
+        /// Compression level (gz and xz: 0-9, default 6; zst: 1-22, default 3; lz4 has none)
+        #[arg(long = "level", value_name = "LEVEL", requires = "compress")]
+        level: Option<u32>,
+
+        /// Number of threads to compress with (default: one per CPU)
+        #[arg(
+            long = "threads",
+            value_name = "N",
+            value_parser = clap::value_parser!(u32).range(1..),
+            requires = "compress"
+        )]
+        threads: Option<u32>,
+
+        /// Stop at the end of the last partition instead of reading the whole device
+        #[arg(long = "stop-at-last-partition")]
+        stop_at_last_partition: bool,
+
+        /// Only read the blocks that ext2/3/4 and FAT filesystems use, writing zeros for free space
+        #[arg(long = "used-blocks-only")]
+        used_blocks_only: bool,
+
+        /// Start reading at this offset into the device (e.g. 1M)
+        #[arg(long = "offset", value_name = "SIZE", value_parser = parse_size, default_value = "0")]
+        offset: u64,
+
+        /// Only read this many bytes (e.g. 8M)
+        #[arg(
+            long = "length",
+            value_name = "SIZE",
+            value_parser = parse_size,
+            conflicts_with = "stop_at_last_partition"
+        )]
+        length: Option<u64>,
+
+        /// Carry on past unreadable sectors, filling them with zeros
+        #[arg(long = "tolerate-errors")]
+        tolerate_errors: bool,
+
+        /// Read the device again afterwards and compare it with the image
+        #[arg(long = "verify", conflicts_with = "tolerate_errors")]
+        verify: bool,
+
+        /// Leave holes in the image where the device only holds zeros
+        #[arg(long = "sparse")]
+        sparse: bool,
+
+        /// Read even if the filesystem of the image looks too full for it
+        #[arg(long = "skip-space-check")]
+        skip_space_check: bool,
+
+        /// Keep what was read so far if the read is cancelled
+        #[arg(long = "keep-partial")]
+        keep_partial: bool,
+
+        /// Pad the image with zeros if the device ends before its reported size
+        #[arg(long = "pad-short-device")]
+        pad_short_device: bool,
+
+        /// Read through the page cache instead of with O_DIRECT (e.g. for dm-crypt mappings)
+        #[arg(long = "buffered")]
+        buffered: bool,
+
+        /// Save a ddrescue mapfile of the unreadable sectors to FILE
+        #[arg(long = "mapfile", value_name = "FILE", requires = "tolerate_errors")]
+        mapfile: Option<PathBuf>,
+    },
+    /// Check that a device still holds an image, without writing it
+    Verify {
+        /// Image file to check the device against
+        #[arg(required = true)]
+        image: PathBuf,
+
+        /// Where the image starts on the device (e.g. 1M)
+        #[arg(long = "offset", value_name = "SIZE", value_parser = parse_size, default_value = "0")]
+        offset: u64,
+
+        /// Read through the page cache instead of with O_DIRECT
+        #[arg(long = "buffered")]
+        buffered: bool,
+
+        /// Compare the device with the image byte for byte, stopping at the first difference
+        #[arg(long = "compare")]
+        compare: bool,
+
+        /// Hash algorithm to verify with: sha256, sha512, blake3, crc32c or xxh64
+        #[arg(long = "hash", value_name = "ALGORITHM", value_parser = parse_hash, default_value = "sha256")]
+        hash: HashAlgorithm,
+
+        /// Largest zstd window to accept, as a power of two (default 31, enough for any zstd --long)
+        #[arg(
+            long = "zstd-window-log",
+            value_name = "N",
+            value_parser = clap::value_parser!(u32).range(10..=31)
+        )]
+        zstd_window_log: Option<u32>,
     },
     /// List available removable devices
     List,
@@ -125,6 +357,460 @@ fn select_device(devices: &[Device], prompt: &str) -> Result<Device> {
     Ok(devices[selection].clone())
 }
 
+/// Builds the marquee-style spinner used for stages with no known total.
+fn spinner_style(template: &str) -> ProgressStyle {
+    ProgressStyle::default_spinner()
+        .template(template)
+        .unwrap()
+        .tick_strings(&[
+            &style("■■  ■  ■  ■  ■  ■                       ")
+                .blue()
+                .to_string(),
+            &style("■  ■  ■  ■  ■  ■  ■                     ")
+                .blue()
+                .to_string(),
+            &style(" ■  ■  ■  ■  ■  ■  ■                    ")
+                .blue()
+                .to_string(),
+            &style("  ■  ■  ■  ■  ■  ■  ■                   ")
+                .blue()
+                .to_string(),
+            &style("   ■  ■  ■  ■  ■  ■  ■                  ")
+                .blue()
+                .to_string(),
+            &style("    ■  ■  ■  ■  ■  ■  ■                 ")
+                .blue()
+                .to_string(),
+            &style("     ■  ■  ■  ■  ■  ■  ■                ")
+                .blue()
+                .to_string(),
+            &style("      ■  ■  ■  ■  ■  ■  ■               ")
+                .blue()
+                .to_string(),
+            &style("       ■  ■  ■  ■  ■  ■  ■              ")
+                .blue()
+                .to_string(),
+            &style("        ■  ■  ■  ■  ■  ■  ■             ")
+                .blue()
+                .to_string(),
+            &style("         ■  ■  ■  ■  ■  ■  ■            ")
+                .blue()
+                .to_string(),
+            &style("          ■  ■  ■  ■  ■  ■  ■           ")
+                .blue()
+                .to_string(),
+            &style("           ■  ■  ■  ■  ■  ■  ■          ")
+                .blue()
+                .to_string(),
+            &style("            ■  ■  ■  ■  ■  ■  ■         ")
+                .blue()
+                .to_string(),
+            &style("             ■  ■  ■  ■  ■  ■  ■        ")
+                .blue()
+                .to_string(),
+            &style("              ■  ■  ■  ■  ■  ■  ■       ")
+                .blue()
+                .to_string(),
+            &style("               ■  ■  ■  ■  ■  ■  ■      ")
+                .blue()
+                .to_string(),
+            &style("                ■  ■  ■  ■  ■  ■  ■     ")
+                .blue()
+                .to_string(),
+            &style("                 ■  ■  ■  ■  ■  ■  ■    ")
+                .blue()
+                .to_string(),
+            &style("                  ■  ■  ■  ■  ■  ■  ■   ")
+                .blue()
+                .to_string(),
+            &style("                   ■  ■  ■  ■  ■  ■  ■  ")
+                .blue()
+                .to_string(),
+            &style("                    ■  ■  ■  ■  ■  ■  ■ ")
+                .blue()
+                .to_string(),
+            &style("                     ■  ■  ■  ■  ■  ■  ■")
+                .blue()
+                .to_string(),
+            &style("                       ■  ■  ■  ■  ■  ■■")
+                .blue()
+                .to_string(),
+            &style("                     ■  ■  ■  ■  ■  ■  ■")
+                .blue()
+                .to_string(),
+            &style("                    ■  ■  ■  ■  ■  ■  ■ ")
+                .blue()
+                .to_string(),
+            &style("                   ■  ■  ■  ■  ■  ■  ■  ")
+                .blue()
+                .to_string(),
+            &style("                  ■  ■  ■  ■  ■  ■  ■   ")
+                .blue()
+                .to_string(),
+            &style("                 ■  ■  ■  ■  ■  ■  ■    ")
+                .blue()
+                .to_string(),
+            &style("                ■  ■  ■  ■  ■  ■  ■     ")
+                .blue()
+                .to_string(),
+            &style("               ■  ■  ■  ■  ■  ■  ■      ")
+                .blue()
+                .to_string(),
+            &style("              ■  ■  ■  ■  ■  ■  ■       ")
+                .blue()
+                .to_string(),
+            &style("             ■  ■  ■  ■  ■  ■  ■        ")
+                .blue()
+                .to_string(),
+            &style("            ■  ■  ■  ■  ■  ■  ■         ")
+                .blue()
+                .to_string(),
+            &style("           ■  ■  ■  ■  ■  ■  ■          ")
+                .blue()
+                .to_string(),
+            &style("          ■  ■  ■  ■  ■  ■  ■           ")
+                .blue()
+                .to_string(),
+            &style("         ■  ■  ■  ■  ■  ■  ■            ")
+                .blue()
+                .to_string(),
+            &style("        ■  ■  ■  ■  ■  ■  ■             ")
+                .blue()
+                .to_string(),
+            &style("       ■  ■  ■  ■  ■  ■  ■              ")
+                .blue()
+                .to_string(),
+            &style("      ■  ■  ■  ■  ■  ■  ■               ")
+                .blue()
+                .to_string(),
+            &style("     ■  ■  ■  ■  ■  ■  ■                ")
+                .blue()
+                .to_string(),
+            &style("    ■  ■  ■  ■  ■  ■  ■                 ")
+                .blue()
+                .to_string(),
+            &style("   ■  ■  ■  ■  ■  ■  ■                  ")
+                .blue()
+                .to_string(),
+            &style("  ■  ■  ■  ■  ■  ■  ■                   ")
+                .blue()
+                .to_string(),
+            &style(" ■  ■  ■  ■  ■  ■  ■                    ")
+                .blue()
+                .to_string(),
+            &style("■  ■  ■  ■  ■  ■  ■                     ")
+                .blue()
+                .to_string(),
+        ])
+}
+
+/// Parses a SHA-256 given as 64 hex digits, as published next to most images.
+fn parse_sha256(s: &str) -> Result<[u8; 32], String> {
+    let mut digest = [0u8; 32];
+    hex::decode_to_slice(s.trim(), &mut digest)
+        .map_err(|_| "expected a SHA-256 as 64 hex digits".to_string())?;
+    Ok(digest)
+}
+
+/// Parses the name of a hash algorithm, such as `blake3`.
+fn parse_hash(s: &str) -> Result<HashAlgorithm, String> {
+    HashAlgorithm::from_name(&s.trim().to_lowercase()).ok_or_else(|| {
+        let names: Vec<_> = HashAlgorithm::ALL.iter().map(|a| a.name()).collect();
+        format!("expected one of {}", names.join(", "))
+    })
+}
+
+/// Parses a verification mode: `full`, `sample:PERCENT` with an optional
+/// `:SEED`, or `nonzero` with an optional `:PERCENT` of the zero chunks to
+/// spot-check and `:SEED`. Without a seed, a new one is picked for each run.
+fn parse_verify(s: &str) -> Result<VerifyMode, String> {
+    let s = s.trim().to_lowercase();
+    if s == "full" {
+        return Ok(VerifyMode::Full);
+    }
+    if s == "nonzero" {
+        return Ok(VerifyMode::NonZero {
+            zero_percent: 0.0,
+            seed: 0,
+        });
+    }
+    let (sample, nonzero) = match (s.strip_prefix("sample:"), s.strip_prefix("nonzero:")) {
+        (Some(sample), _) => (sample, false),
+        (_, Some(zero)) => (zero, true),
+        _ => {
+            return Err(
+                "expected full, sample:PERCENT[:SEED] or nonzero[:PERCENT[:SEED]]".to_string(),
+            );
+        }
+    };
+    let (percent, seed) = match sample.split_once(':') {
+        Some((percent, seed)) => (
+            percent,
+            seed.parse()
+                .map_err(|_| format!("'{}' is not a seed such as 42", seed))?,
+        ),
+        None => (
+            sample,
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64),
+        ),
+    };
+    let percent: f32 = percent
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("'{}' is not a percentage such as 10", percent))?;
+    if nonzero {
+        if !(0.0..=100.0).contains(&percent) {
+            return Err("the share of zero chunks must be from 0% to 100%".to_string());
+        }
+        return Ok(VerifyMode::NonZero {
+            zero_percent: percent,
+            seed,
+        });
+    }
+    if !(percent > 0.0 && percent <= 100.0) {
+        return Err("the sample must be more than 0% and at most 100%".to_string());
+    }
+    Ok(VerifyMode::Sampled { percent, seed })
+}
+
+/// Parses a rate such as `500K`, `50M` or `1G` into bytes per second. The
+/// suffixes are binary multiples, and a trailing `B`, `iB` or `/s` is allowed.
+fn parse_rate(s: &str) -> Result<u64, String> {
+    let rate = parse_bytes(s.trim().to_lowercase().trim_end_matches("/s"))
+        .ok_or_else(|| format!("'{}' is not a rate such as 500K, 50M or 1G", s))?;
+    if rate == 0 {
+        return Err("the rate must be greater than zero".to_string());
+    }
+    Ok(rate)
+}
+
+/// Parses a size such as `500M` or `20G` into bytes, like [`parse_rate`].
+fn parse_size(s: &str) -> Result<u64, String> {
+    parse_bytes(&s.trim().to_lowercase())
+        .ok_or_else(|| format!("'{}' is not a size such as 500M or 20G", s))
+}
+
+/// Parses a lowercase byte count with an optional binary suffix.
+fn parse_bytes(trimmed: &str) -> Option<u64> {
+    let trimmed = trimmed
+        .strip_suffix("ib")
+        .or_else(|| trimmed.strip_suffix('b'))
+        .unwrap_or(trimmed);
+    let (number, multiplier) = match trimmed.chars().last() {
+        Some('k') => (&trimmed[..trimmed.len() - 1], 1u64 << 10),
+        Some('m') => (&trimmed[..trimmed.len() - 1], 1 << 20),
+        Some('g') => (&trimmed[..trimmed.len() - 1], 1 << 30),
+        _ => (trimmed, 1),
+    };
+    let value: f64 = number.parse().ok()?;
+    Some((value * multiplier as f64) as u64)
+}
+
+/// Converts a byte count to the gigabyte figure used throughout the UI.
+fn to_gb(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0 * 1024.0)
+}
+
+/// Replaces typed core errors with a human explanation of what went wrong.
+fn explain_error(e: anyhow::Error) -> anyhow::Error {
+    match e.downcast_ref::<CoreError>() {
+        Some(CoreError::ImageTooLarge { image, device }) => anyhow!(
+            "The image does not fit on this device: it needs at least {:.1} GB, but the device is only {:.1} GB.",
+            to_gb(*image),
+            to_gb(*device)
+        ),
+        Some(CoreError::DeviceFull {
+            capacity: Some(capacity),
+            bytes_written,
+        }) => anyhow!(
+            "The image does not fit on this device: it is larger than the device's {:.1} GB ({:.1} GB were written before it ran out of space).",
+            to_gb(*capacity),
+            to_gb(*bytes_written)
+        ),
+        Some(CoreError::DeviceFull {
+            capacity: None,
+            bytes_written,
+        }) => anyhow!(
+            "The target ran out of space after {:.1} GB of the image was written.",
+            to_gb(*bytes_written)
+        ),
+        Some(CoreError::InsufficientTempSpace {
+            dir,
+            needed,
+            available,
+        }) => anyhow!(
+            "There is not enough free space in {} to decompress the image: it needs about {:.1} GB, but only {:.1} GB are free. Free up some space, or pass --skip-space-check if the estimate is too high.",
+            dir.display(),
+            to_gb(*needed),
+            to_gb(*available)
+        ),
+        Some(CoreError::InsufficientSpace {
+            mount_point,
+            needed,
+            available,
+        }) => anyhow!(
+            "There is not enough free space on {} for the image: it may need up to {:.1} GB, but only {:.1} GB are free. Free up some space, compress the image, or pass --skip-space-check if it will fit.",
+            mount_point.display(),
+            to_gb(*needed),
+            to_gb(*available)
+        ),
+        Some(CoreError::UnmountFailed { mount_points }) => anyhow!(
+            "Could not unmount {}. Close any programs using it and try again.",
+            mount_points
+                .iter()
+                .map(|m| m.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Some(CoreError::PartitionTarget { path, disk }) => anyhow!(
+            "{} is a partition, and a disk image written into it will not boot. Did you mean {}?",
+            path.display(),
+            disk.display()
+        ),
+        Some(CoreError::DeviceChanged { path, .. }) => anyhow!(
+            "{} changed since it was selected (was the card swapped?), so nothing was written. Run the command again and select the device anew.",
+            path.display()
+        ),
+        Some(CoreError::DeviceInUse { path }) => anyhow!(
+            "{} is in use. Unmount all of its partitions and try again.",
+            path.display()
+        ),
+        Some(CoreError::Cancelled { bytes_synced }) => anyhow!(
+            "Write cancelled after {:.2} GB. The data written so far has been flushed to the device.",
+            to_gb(*bytes_synced)
+        ),
+        Some(CoreError::ChecksumMismatch { .. }) => anyhow!(
+            "The image does not match the expected checksum, so nothing was written. \
+             The download is probably corrupt or incomplete; download it again. ({})",
+            e
+        ),
+        Some(CoreError::UnsupportedVhd { .. }) => anyhow!(
+            "{}. Convert it to a raw image first, for example with `qemu-img convert -O raw image.vhd image.img`.",
+            e
+        ),
+        Some(CoreError::SplitImageIncomplete { parts, missing }) => anyhow!(
+            "The image is split into parts, but {} {} missing; only {} were found. Put all the parts in the same directory and try again.",
+            missing.join(", "),
+            if missing.len() == 1 { "is" } else { "are" },
+            parts
+                .iter()
+                .map(|part| part.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Some(CoreError::Download { url, reason }) => anyhow!(
+            "Could not download {}: {}. Check the URL and your connection, or download the image and write the file instead.",
+            url,
+            reason
+        ),
+        Some(CoreError::DownloadChecksumMismatch { .. }) => anyhow!(
+            "The downloaded image does not match the expected checksum, so the device now holds a corrupt copy of it. \
+             Write it again, or download it first and check it before writing. ({})",
+            e
+        ),
+        Some(CoreError::ArchiveContents { images: 0, entries }) => anyhow!(
+            "The archive does not hold a disk image: none of its files ({}) is an .img or .iso file. Extract the image from it and write that instead.",
+            entries.join(", ")
+        ),
+        Some(CoreError::ArchiveContents { images, entries }) => anyhow!(
+            "The archive holds {} .img or .iso files, so it is unclear which one to write. Its files are: {}. Extract the one you want and write it instead.",
+            images,
+            entries.join(", ")
+        ),
+        Some(CoreError::CorruptImage {
+            format,
+            detail: Corruption::WrongFormat,
+        }) => anyhow!(
+            "The image is not a valid {} file, even though it starts like one. \
+             It may be something else saved under its name, such as an error page; download it again.",
+            format
+        ),
+        Some(CoreError::CorruptImage { .. }) => anyhow!(
+            "{}. The image was probably not downloaded in full or got damaged; download it again.",
+            e
+        ),
+        Some(CoreError::ZstdWindowTooLarge { window_log_max }) => anyhow!(
+            "The image was compressed with zstd --long and needs a larger window than the {} MiB allowed. Pass a larger --zstd-window-log (up to 31) to decompress it.",
+            (1u64 << window_log_max) >> 20
+        ),
+        Some(CoreError::VerifyMismatch { offset }) => anyhow!(
+            "The device returned different data than the image at byte {} (512-byte sector {}, {:.2} GB). It is probably faulty or counterfeit.",
+            offset,
+            offset / 512,
+            to_gb(*offset)
+        ),
+        Some(CoreError::HashMismatch { image, device }) => anyhow!(
+            "The device does not hold the image: the image hashes to {}, but the device to {}. It is probably faulty or counterfeit.",
+            image,
+            device
+        ),
+        Some(CoreError::MappedRangeMismatch {
+            range,
+            image,
+            device,
+        }) => anyhow!(
+            "The device does not hold the image: bytes {} to {} ({:.2} GB in) hash to {} on the device, but to {} in the image. It is probably faulty or counterfeit.",
+            range.start,
+            range.end - 1,
+            to_gb(range.start),
+            device,
+            image
+        ),
+        Some(CoreError::ChunkMismatch { range }) => anyhow!(
+            "The device returned different data than the image in bytes {} to {} ({:.2} GB in). It is probably faulty or counterfeit.",
+            range.start,
+            range.end - 1,
+            to_gb(range.start)
+        ),
+        Some(CoreError::DeviceRemoved {
+            stage, bytes_done, ..
+        }) => anyhow!(
+            "The device was disconnected in the {} stage, after {:.2} GB of the image was written. Plug it back in and run the command again.",
+            stage,
+            to_gb(*bytes_done)
+        ),
+        Some(CoreError::Stalled { offset, elapsed }) => anyhow!(
+            "The device stopped responding at {:.2} GB and nothing was written for {}s. It is probably faulty; unplug it before trying again.",
+            to_gb(*offset),
+            elapsed.as_secs()
+        ),
+        Some(CoreError::Io {
+            stage: Stage::Decompress,
+            source,
+            ..
+        }) => anyhow!(
+            "Could not read the image: {}. The file may be corrupt or incomplete.",
+            source
+        ),
+        Some(CoreError::Io {
+            stage,
+            offset: Some(offset),
+            source,
+        }) => anyhow!(
+            "The device failed in the {} stage at {:.2} GB: {}. It may be faulty or have been unplugged.",
+            stage,
+            to_gb(*offset),
+            source
+        ),
+        _ => match e.downcast_ref::<PartialTransfer>() {
+            // Put the cause first, and how far the write got after it.
+            Some(transfer) => anyhow!(
+                "{} ({:.2} GB had been written, {:.2} GB of it flushed to the device.)",
+                e.chain()
+                    .skip(1)
+                    .map(|cause| cause.to_string())
+                    .collect::<Vec<_>>()
+                    .join(": "),
+                to_gb(transfer.bytes_done),
+                to_gb(transfer.bytes_synced)
+            ),
+            None => e,
+        },
+    }
+}
+
 /// Presents a final "Yes/No" confirmation to the user.
 fn confirm_operation(prompt: &str) -> Result<bool> {
     let confirmation = Confirm::with_theme(&ColorfulTheme::default())
@@ -151,218 +837,359 @@ fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Write { image, no_verify } => {
-            let devices = etchr_core::platform::get_removable_devices()?;
-            let device = select_device(&devices, "Select the target device to WRITE to")?;
+        Commands::Write {
+            image,
+            no_verify,
+            read_back,
+            verify,
+            compare,
+            hash,
+            drop_caches,
+            discard,
+            only_changed,
+            secure_erase,
+            no_eject,
+            checksum,
+            checksum_file,
+            limit_rate,
+            timeout,
+            dry_run,
+            wipe_remainder,
+            fix_gpt,
+            grow_last_partition,
+            force,
+            bmap,
+            cache,
+            cache_size,
+            skip_space_check,
+            zstd_window_log,
+            ..
+        } => {
+            // Missing parts of a split image are reported before it is looked at.
+            // The parts are found once for everything read from here on.
+            let image_file = image.path().map(ImageFile::find).transpose().map_err(|e| {
+                match e.downcast::<CoreError>() {
+                    Ok(e) => anyhow::Error::from(e),
+                    Err(e) => e.into(),
+                }
+            })?;
+            // A downloaded archive is easily mistaken for the image inside it.
+            // A download is only looked at as it is written.
+            if !force
+                && let Some(file) = &image_file
+                && !etchr_core::image::probe(file)?.is_disk_image()
+            {
+                return Err(anyhow!(
+                    "'{}' does not start with a partition table or an ISO 9660 header, so it does not look like a disk image. \
+                     If it is an archive, extract the image from it first. To write it anyway, pass --force.",
+                    image
+                ));
+            }
+            if cache && image.is_url() {
+                return Err(anyhow!(
+                    "--cache needs an image file, not a URL; a download is always streamed."
+                ));
+            }
+            // A checksum file published next to the image is used unless one
+            // is given.
+            let checksum = match (checksum, &checksum_file) {
+                (Some(checksum), _) => Some(checksum),
+                (None, Some(path)) => {
+                    let name = image.name().unwrap_or(Path::new(""));
+                    Some(etchr_core::checksum::read(path, name)?.ok_or_else(|| {
+                        anyhow!(
+                            "'{}' does not list a SHA-256 for '{}'.",
+                            path.display(),
+                            image
+                        )
+                    })?)
+                }
+                // Nothing is looked for next to a URL.
+                (None, None) => match image.path().map_or(Ok(None), etchr_core::checksum::find) {
+                    Ok(Some(sidecar)) => {
+                        println!(
+                            "Found the image's SHA-256 in {}; the image is checked against it before writing.",
+                            style(sidecar.path.display()).cyan()
+                        );
+                        Some(sidecar.sha256)
+                    }
+                    Ok(None) => None,
+                    Err(e) => {
+                        println!(
+                            "{} The checksum file next to the image could not be read ({}), so the image is not checked against it.",
+                            style("WARNING:").yellow().bold(),
+                            e
+                        );
+                        None
+                    }
+                },
+            };
+            // The card in a multi-slot reader can be swapped while the prompt
+            // is open, so the choice is checked against a fresh discovery.
+            let device = loop {
+                let devices = etchr_core::platform::get_removable_devices()?;
+                let device = select_device(&devices, "Select the target device to WRITE to")?;
 
-            println!(
-                "{} This will erase all data on '{}' ({:.1} GB).",
-                style("WARNING:").red().bold(),
-                device.name,
-                device.size_gb,
-            );
-            println!("  Device: {}", style(device.path.display()).cyan());
-            println!("  Image:  {}", style(image.display()).cyan());
-            println!();
+                if dry_run {
+                    println!(
+                        "Dry run: nothing will be written to '{}' ({:.1} GB).",
+                        device.name, device.size_gb,
+                    );
+                } else {
+                    println!(
+                        "{} This will erase all data on '{}' ({:.1} GB).",
+                        style("WARNING:").red().bold(),
+                        device.name,
+                        device.size_gb,
+                    );
+                }
+                println!("  Device: {}", style(device.path.display()).cyan());
+                match &image_file {
+                    Some(file) if file.is_split() => println!(
+                        "  Image:  {} (split into {} parts)",
+                        style(&image).cyan(),
+                        file.parts().len()
+                    ),
+                    _ => println!("  Image:  {}", style(&image).cyan()),
+                }
+                println!();
 
-            if !confirm_operation("Are you sure you want to proceed?")? {
-                println!("Write operation cancelled.");
-                return Ok(());
-            }
+                if !dry_run && !confirm_operation("Are you sure you want to proceed?")? {
+                    println!("Write operation cancelled.");
+                    return Ok(());
+                }
+                if secure_erase
+                    && !confirm_operation(
+                        "Secure erase wipes the WHOLE device first and can take several minutes. Continue?",
+                    )?
+                {
+                    println!("Write operation cancelled.");
+                    return Ok(());
+                }
 
+                let devices = etchr_core::platform::get_removable_devices()?;
+                if devices
+                    .iter()
+                    .any(|d| d.path == device.path && d.size_bytes == device.size_bytes)
+                {
+                    break device;
+                }
+                println!(
+                    "{} '{}' changed since it was selected. Select the device again.\n",
+                    style("WARNING:").red().bold(),
+                    device.name
+                );
+            };
             println!();
 
             // Set up progress bars for the multi-stage write process.
             // Conditionally create progress bars so they don't flash on screen if not needed.
-            let is_compressed = image.extension().and_then(|e| e.to_str()).map_or(false, |e| {
-                matches!(e.to_lowercase().as_str(), "gz" | "gzip" | "xz" | "zst" | "zstd")
-            });
+            // Whether a download is compressed is only known once it starts.
+            let is_compressed = match &image_file {
+                Some(file) => etchr_core::image::detect_format(file)?.is_some(),
+                None => true,
+            };
+            let decompressing = Cell::new(false);
+            let decompress_done = if image.is_url() {
+                "Download complete."
+            } else {
+                "Decompression complete."
+            };
+
+            // Decompression is streamed into the write, so both bars are live at once.
+            let multi = MultiProgress::new();
+
+            let checksum_pb = if checksum.is_some() {
+                multi.add(ProgressBar::new(0))
+            } else {
+                ProgressBar::hidden()
+            };
+
+            let erase_pb = if secure_erase {
+                multi.add(ProgressBar::new(0))
+            } else {
+                ProgressBar::hidden()
+            };
 
-            let decompress_pb = if is_compressed {
-                ProgressBar::new_spinner()
+            let discard_pb = if discard && !secure_erase {
+                multi.add(ProgressBar::new(0))
             } else {
                 ProgressBar::hidden()
             };
 
-            let write_pb = ProgressBar::new(0);
+            let decompress_pb = if let Some(file) = &image_file
+                && is_compressed
+            {
+                multi.add(ProgressBar::new(file.open()?.len()))
+            } else if is_compressed {
+                multi.add(ProgressBar::new(0))
+            } else {
+                ProgressBar::hidden()
+            };
+
+            let write_pb = multi.add(ProgressBar::new(0));
+
+            let sync_pb = multi.add(ProgressBar::new(0));
 
             let verify_pb = if !no_verify {
-                ProgressBar::new(0)
+                multi.add(ProgressBar::new(0))
             } else {
                 ProgressBar::hidden()
             };
 
+            let wipe_pb = if wipe_remainder && !dry_run {
+                multi.add(ProgressBar::new(0))
+            } else {
+                ProgressBar::hidden()
+            };
 
             // These closures connect the core library's progress reporting to our UI.
-            let on_decompress_start = || {
-                decompress_pb.set_prefix("Decompress");
-                decompress_pb.set_style(
-                    ProgressStyle::default_spinner()
-                        .template("{prefix:12} [{elapsed_precise}] [{spinner}] {bytes} ({bytes_per_sec}) {msg}")
+            let on_checksum_start = |len| {
+                checksum_pb.set_length(len);
+                checksum_pb.set_prefix("Checksum");
+                checksum_pb.set_style(
+                    ProgressStyle::default_bar()
+                        .template(
+                            "{prefix:12} [{elapsed_precise}] [{bar:40.cyan/black}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+                        )
                         .unwrap()
-                        .tick_strings(&[
-                            &style("■■  ■  ■  ■  ■  ■                       ")
-                                .blue()
-                                .to_string(),
-                            &style("■  ■  ■  ■  ■  ■  ■                     ")
-                                .blue()
-                                .to_string(),
-                            &style(" ■  ■  ■  ■  ■  ■  ■                    ")
-                                .blue()
-                                .to_string(),
-                            &style("  ■  ■  ■  ■  ■  ■  ■                   ")
-                                .blue()
-                                .to_string(),
-                            &style("   ■  ■  ■  ■  ■  ■  ■                  ")
-                                .blue()
-                                .to_string(),
-                            &style("    ■  ■  ■  ■  ■  ■  ■                 ")
-                                .blue()
-                                .to_string(),
-                            &style("     ■  ■  ■  ■  ■  ■  ■                ")
-                                .blue()
-                                .to_string(),
-                            &style("      ■  ■  ■  ■  ■  ■  ■               ")
-                                .blue()
-                                .to_string(),
-                            &style("       ■  ■  ■  ■  ■  ■  ■              ")
-                                .blue()
-                                .to_string(),
-                            &style("        ■  ■  ■  ■  ■  ■  ■             ")
-                                .blue()
-                                .to_string(),
-                            &style("         ■  ■  ■  ■  ■  ■  ■            ")
-                                .blue()
-                                .to_string(),
-                            &style("          ■  ■  ■  ■  ■  ■  ■           ")
-                                .blue()
-                                .to_string(),
-                            &style("           ■  ■  ■  ■  ■  ■  ■          ")
-                                .blue()
-                                .to_string(),
-                            &style("            ■  ■  ■  ■  ■  ■  ■         ")
-                                .blue()
-                                .to_string(),
-                            &style("             ■  ■  ■  ■  ■  ■  ■        ")
-                                .blue()
-                                .to_string(),
-                            &style("              ■  ■  ■  ■  ■  ■  ■       ")
-                                .blue()
-                                .to_string(),
-                            &style("               ■  ■  ■  ■  ■  ■  ■      ")
-                                .blue()
-                                .to_string(),
-                            &style("                ■  ■  ■  ■  ■  ■  ■     ")
-                                .blue()
-                                .to_string(),
-                            &style("                 ■  ■  ■  ■  ■  ■  ■    ")
-                                .blue()
-                                .to_string(),
-                            &style("                  ■  ■  ■  ■  ■  ■  ■   ")
-                                .blue()
-                                .to_string(),
-                            &style("                   ■  ■  ■  ■  ■  ■  ■  ")
-                                .blue()
-                                .to_string(),
-                            &style("                    ■  ■  ■  ■  ■  ■  ■ ")
-                                .blue()
-                                .to_string(),
-                            &style("                     ■  ■  ■  ■  ■  ■  ■")
-                                .blue()
-                                .to_string(),
-                            &style("                       ■  ■  ■  ■  ■  ■■")
-                                .blue()
-                                .to_string(),
-                            &style("                     ■  ■  ■  ■  ■  ■  ■")
-                                .blue()
-                                .to_string(),
-                            &style("                    ■  ■  ■  ■  ■  ■  ■ ")
-                                .blue()
-                                .to_string(),
-                            &style("                   ■  ■  ■  ■  ■  ■  ■  ")
-                                .blue()
-                                .to_string(),
-                            &style("                  ■  ■  ■  ■  ■  ■  ■   ")
-                                .blue()
-                                .to_string(),
-                            &style("                 ■  ■  ■  ■  ■  ■  ■    ")
-                                .blue()
-                                .to_string(),
-                            &style("                ■  ■  ■  ■  ■  ■  ■     ")
-                                .blue()
-                                .to_string(),
-                            &style("               ■  ■  ■  ■  ■  ■  ■      ")
-                                .blue()
-                                .to_string(),
-                            &style("              ■  ■  ■  ■  ■  ■  ■       ")
-                                .blue()
-                                .to_string(),
-                            &style("             ■  ■  ■  ■  ■  ■  ■        ")
-                                .blue()
-                                .to_string(),
-                            &style("            ■  ■  ■  ■  ■  ■  ■         ")
-                                .blue()
-                                .to_string(),
-                            &style("           ■  ■  ■  ■  ■  ■  ■          ")
-                                .blue()
-                                .to_string(),
-                            &style("          ■  ■  ■  ■  ■  ■  ■           ")
-                                .blue()
-                                .to_string(),
-                            &style("         ■  ■  ■  ■  ■  ■  ■            ")
-                                .blue()
-                                .to_string(),
-                            &style("        ■  ■  ■  ■  ■  ■  ■             ")
-                                .blue()
-                                .to_string(),
-                            &style("       ■  ■  ■  ■  ■  ■  ■              ")
-                                .blue()
-                                .to_string(),
-                            &style("      ■  ■  ■  ■  ■  ■  ■               ")
-                                .blue()
-                                .to_string(),
-                            &style("     ■  ■  ■  ■  ■  ■  ■                ")
-                                .blue()
-                                .to_string(),
-                            &style("    ■  ■  ■  ■  ■  ■  ■                 ")
-                                .blue()
-                                .to_string(),
-                            &style("   ■  ■  ■  ■  ■  ■  ■                  ")
-                                .blue()
-                                .to_string(),
-                            &style("  ■  ■  ■  ■  ■  ■  ■                   ")
-                                .blue()
-                                .to_string(),
-                            &style(" ■  ■  ■  ■  ■  ■  ■                    ")
-                                .blue()
-                                .to_string(),
-                            &style("■  ■  ■  ■  ■  ■  ■                     ")
-                                .blue()
-                                .to_string(),
-                        ]),
+                        .progress_chars("■ "),
                 );
-                decompress_pb.enable_steady_tick(Duration::from_millis(100));
             };
-            let on_decompress_progress = |bytes| decompress_pb.set_position(bytes);
+            let on_checksum_progress = |bytes| checksum_pb.set_position(bytes);
 
-            let on_write_start = |len| {
-                if is_compressed {
-                    decompress_pb.finish_with_message("Decompression complete.");
+            let on_erase_start = |_, len| {
+                checksum_pb.finish_with_message("Checksum matches.");
+                erase_pb.set_length(len);
+                erase_pb.set_prefix("Erasing");
+                erase_pb.set_style(
+                    ProgressStyle::default_bar()
+                        .template(
+                            "{prefix:12} [{elapsed_precise}] [{bar:40.red/black}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+                        )
+                        .unwrap()
+                        .progress_chars("■ "),
+                );
+            };
+            let on_erase_progress = |bytes| erase_pb.set_position(bytes);
+
+            let on_discard_start = |len| {
+                checksum_pb.finish_with_message("Checksum matches.");
+                discard_pb.set_length(len);
+                discard_pb.set_prefix("Discarding");
+                discard_pb.set_style(
+                    ProgressStyle::default_bar()
+                        .template(
+                            "{prefix:12} [{elapsed_precise}] [{bar:40.yellow/black}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+                        )
+                        .unwrap()
+                        .progress_chars("■ "),
+                );
+            };
+            let on_discard_progress = |bytes| discard_pb.set_position(bytes);
+
+            // Whether the bar counts decompressed bytes rather than compressed
+            // bytes consumed out of the size of the image file.
+            let decompressed_bar = Cell::new(false);
+            let on_download_start = |len: Option<u64>| {
+                if let Some(len) = len {
+                    decompress_pb.set_length(len);
                 }
-                write_pb.set_length(len);
-                write_pb.set_prefix("Writing");
-                write_pb.set_style(
+            };
+            let on_decompress_start = |len: Option<u64>| {
+                decompressing.set(true);
+                if image.is_url() {
+                    decompress_pb.set_prefix("Download");
+                    // Without a Content-Length, there is only a running total.
+                    if decompress_pb.length() == Some(0) {
+                        decompress_pb.set_style(spinner_style(
+                            "{prefix:12} [{elapsed_precise}] [{spinner}] {bytes} ({bytes_per_sec})",
+                        ));
+                        decompress_pb.enable_steady_tick(Duration::from_millis(100));
+                        return;
+                    }
+                } else {
+                    decompress_pb.set_prefix("Decompress");
+                }
+                // The cache holds the decompressed image, so show its size when
+                // the compression metadata records it.
+                if let (true, Some(len)) = (cache, len) {
+                    decompress_pb.set_length(len);
+                    decompressed_bar.set(true);
+                }
+                decompress_pb.set_style(
                     ProgressStyle::default_bar()
                         .template(
-                            "{prefix:12} [{elapsed_precise}] [{bar:40.green/black}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+                            "{prefix:12} [{elapsed_precise}] [{bar:40.blue/black}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
                         )
                         .unwrap()
                         .progress_chars("■ "),
                 );
             };
+            let on_decompress_counts = |counts: DecompressProgress| {
+                let bytes = if decompressed_bar.get() {
+                    counts.decompressed_written
+                } else {
+                    counts.compressed_read
+                };
+                // gzip only records the size modulo 4 GiB, so the bar may fill
+                // up early; it then stays full.
+                if decompress_pb.length().is_some_and(|len| bytes > len) {
+                    decompress_pb.set_length(bytes);
+                }
+                decompress_pb.set_position(bytes);
+            };
+
+            let on_write_start = |len| {
+                // A download that is not compressed only shows on the write bar.
+                if !decompressing.get() {
+                    decompress_pb.finish_and_clear();
+                }
+                checksum_pb.finish_with_message("Checksum matches.");
+                erase_pb.finish_with_message("Erase complete.");
+                // The bar never started if the device doesn't support discard.
+                if discard_pb.length() == Some(0) {
+                    discard_pb.finish_and_clear();
+                } else {
+                    discard_pb.finish_with_message("Discard complete.");
+                }
+                write_pb.set_prefix("Writing");
+                if len == 0 {
+                    // The decompressed size isn't known, so just show a running total.
+                    write_pb.set_style(spinner_style(
+                        "{prefix:12} [{elapsed_precise}] [{spinner}] {bytes} ({bytes_per_sec}) {msg}",
+                    ));
+                    write_pb.enable_steady_tick(Duration::from_millis(100));
+                } else {
+                    write_pb.set_length(len);
+                    write_pb.set_style(
+                        ProgressStyle::default_bar()
+                            .template(
+                                "{prefix:12} [{elapsed_precise}] [{bar:40.green/black}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+                            )
+                            .unwrap()
+                            .progress_chars("■ "),
+                    );
+                }
+            };
             let on_write_progress = |bytes| write_pb.set_position(bytes);
 
+            // Flushing a slow USB stick can take a minute, so show that the
+            // device is still busy rather than leaving the write bar at 100%.
+            let on_sync_start = || {
+                sync_pb.set_prefix("Syncing");
+                sync_pb.set_style(spinner_style(
+                    "{prefix:12} [{elapsed_precise}] [{spinner}] {msg}",
+                ));
+                sync_pb.set_message("Flushing the device cache...");
+                sync_pb.enable_steady_tick(Duration::from_millis(100));
+            };
+            let on_sync_done = || sync_pb.finish_with_message("Sync complete.");
+
             let on_verify_start = |len| {
+                if decompressing.get() {
+                    decompress_pb.finish_with_message(decompress_done);
+                }
                 write_pb.finish_with_message("Write complete.");
                 verify_pb.set_length(len);
                 verify_pb.set_prefix("Verifying");
@@ -377,98 +1204,600 @@ fn main() -> Result<()> {
             };
             let on_verify_progress = |bytes| verify_pb.set_position(bytes);
 
+            let on_wipe_start = |len| {
+                if !no_verify {
+                    verify_pb.finish_with_message("Verification successful.");
+                } else {
+                    write_pb.finish_with_message("Write complete (verification skipped).");
+                }
+                wipe_pb.set_length(len);
+                wipe_pb.set_prefix("Wiping");
+                wipe_pb.set_style(
+                    ProgressStyle::default_bar()
+                        .template(
+                            "{prefix:12} [{elapsed_precise}] [{bar:40.red/black}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+                        )
+                        .unwrap()
+                        .progress_chars("■ "),
+                );
+            };
+            let on_wipe_progress = |bytes| wipe_pb.set_position(bytes);
+
+            // Warnings are printed above the bars as they come in.
+            let on_warning = |warning: Warning| {
+                multi
+                    .println(format!("{} {}", style("WARNING:").yellow().bold(), warning))
+                    .ok();
+            };
+
             // Execute the write operation.
-            let result = etchr_core::write::run(
-                &image,
-                &device.path,
-                !no_verify,
-                running,
-                on_decompress_start,
-                on_decompress_progress,
-                on_write_start,
-                on_write_progress,
-                on_verify_start,
-                on_verify_progress,
-            );
+            let mut options = WriteOptions::for_device(image.clone(), &device);
+            if let Some(checksum) = checksum {
+                options = options.expected_source_sha256(checksum);
+            }
+            if let Some(limit_rate) = limit_rate {
+                options = options.max_bytes_per_sec(limit_rate);
+            }
+            if let Some(timeout) = timeout {
+                options = options.stall_timeout(Duration::from_secs(timeout));
+            }
+            if let Some(bmap) = bmap {
+                options = options.bmap(bmap);
+            }
+            if cache {
+                let dir = etchr_core::cache::default_dir().ok_or_else(|| {
+                    anyhow!("Could not find a cache directory; set HOME or XDG_CACHE_HOME.")
+                })?;
+                let mut cache = Cache::new(dir);
+                if let Some(cache_size) = cache_size {
+                    cache = cache.max_size(cache_size);
+                }
+                options = options.cache(cache).check_temp_space(!skip_space_check);
+            }
+            if let Some(zstd_window_log) = zstd_window_log {
+                options = options.zstd_window_log_max(zstd_window_log);
+            }
+            let verify_mode = match (read_back, no_verify, verify) {
+                (true, _, Some(mode)) if mode.partial() => {
+                    return Err(anyhow!(
+                        "--read-back cannot be combined with a partial verification"
+                    ));
+                }
+                (false, _, Some(mode)) if mode.partial() => mode,
+                (false, true, _) => VerifyMode::None,
+                (false, false, _) => VerifyMode::Full,
+                (true, true, _) => VerifyMode::ReadBack,
+                (true, false, _) => VerifyMode::ReadBackAndFull,
+            };
+            let result = options
+                .verify_mode(verify_mode)
+                .verify_method(if compare {
+                    VerifyMethod::Compare
+                } else {
+                    VerifyMethod::Hash
+                })
+                .verify_hash(hash)
+                .drop_caches(drop_caches)
+                .auto_unmount(true)
+                .discard(discard)
+                .only_changed(only_changed)
+                .secure_erase(secure_erase)
+                .eject_on_success(!no_eject)
+                .dry_run(dry_run)
+                .wipe_remainder(wipe_remainder)
+                .relocate_gpt_backup(fix_gpt)
+                .grow_last_partition(grow_last_partition)
+                .running(running)
+                .on_checksum_start(on_checksum_start)
+                .on_checksum_progress(on_checksum_progress)
+                .on_erase_start(on_erase_start)
+                .on_erase_progress(on_erase_progress)
+                .on_discard_start(on_discard_start)
+                .on_discard_progress(on_discard_progress)
+                .on_download_start(on_download_start)
+                .on_decompress_start(on_decompress_start)
+                .on_decompress_counts(on_decompress_counts)
+                .on_write_start(on_write_start)
+                .on_write_progress(on_write_progress)
+                .on_sync_start(on_sync_start)
+                .on_sync_done(on_sync_done)
+                .on_verify_start(on_verify_start)
+                .on_verify_progress(on_verify_progress)
+                .on_wipe_start(on_wipe_start)
+                .on_wipe_progress(on_wipe_progress)
+                .on_warning(on_warning)
+                .run();
 
             // Cleanly finish progress bars based on the result.
             match result {
-                Ok(_) => {
-                    if !no_verify {
+                Ok(report) => {
+                    if decompressing.get() {
+                        decompress_pb.finish_with_message(decompress_done);
+                    }
+                    if report.simulated {
+                        verify_pb.finish_with_message("Verification simulated.");
+                        write_pb.finish_with_message("Write simulated.");
+                        println!(
+                            "\n✨ Dry run complete: {} would be flashed with {}.",
+                            style(device.path.display()).cyan(),
+                            style(&image).cyan()
+                        );
+                        println!(
+                            "   Would write {}, sha256={}",
+                            HumanBytes(report.bytes_written),
+                            report.image_sha256_hex()
+                        );
+                        println!("   Device uses {}", report.sector_sizes);
+                        return Ok(());
+                    }
+                    if verify_mode.partial() && report.sampled_ranges.is_some() {
+                        verify_pb.finish_with_message("Spot check successful.");
+                    } else if !no_verify {
                         verify_pb.finish_with_message("Verification successful.");
                     } else {
                         // The write bar is already finished, but this sets a final message.
                         write_pb.finish_with_message("Write complete (verification skipped).");
                     }
+                    if report.wipe_incomplete {
+                        wipe_pb.abandon_with_message("Wipe cancelled.");
+                    } else {
+                        wipe_pb.finish_with_message("Wipe complete.");
+                    }
                     println!(
                         "\n✨ Successfully flashed {} with {}.",
                         style(device.path.display()).cyan(),
-                        style(image.display()).cyan()
+                        style(&image).cyan()
+                    );
+                    println!(
+                        "   Wrote {} in {} ({}/s), sha256={}{}",
+                        HumanBytes(report.bytes_written),
+                        HumanDuration(report.write_duration),
+                        HumanBytes(report.average_bps() as u64),
+                        report.image_sha256_hex(),
+                        if report.verified { ", verified" } else { "" }
                     );
+                    if let Some(digest) = &report.verify_digest
+                        && digest.algorithm() != HashAlgorithm::Sha256
+                    {
+                        println!("   Verified with {}", digest);
+                    }
+                    match (&report.sampled_ranges, verify_mode) {
+                        (Some(ranges), VerifyMode::Sampled { seed, .. }) => println!(
+                            "   Only spot-checked {} of the image in {} ranges (seed {}); the rest was not verified",
+                            HumanBytes(report.bytes_verified()),
+                            ranges.len(),
+                            seed
+                        ),
+                        (Some(ranges), VerifyMode::NonZero { seed, .. }) => println!(
+                            "   Only checked {} of the image in {} ranges, skipping zeros (seed {}); the rest was not verified",
+                            HumanBytes(report.bytes_verified()),
+                            ranges.len(),
+                            seed
+                        ),
+                        _ => {}
+                    }
+                    println!("   Device uses {}", report.sector_sizes);
+                    if report.cache_dropped {
+                        println!("   Dropped the image from the page cache before verifying");
+                    }
+                    if let Some(mapped) = report.bytes_mapped {
+                        println!(
+                            "   Only the {} mapped by the block map were written",
+                            HumanBytes(mapped)
+                        );
+                    }
+                    if let Some(unchanged) = report.bytes_unchanged {
+                        println!(
+                            "   Skipped {} the device already held",
+                            HumanBytes(unchanged)
+                        );
+                    }
+                    if report.gpt_relocated {
+                        println!("   Moved the backup GPT to the end of the device");
+                    }
+                    if let Some(partition) = report.grown_partition
+                        && partition.new_len > partition.old_len
+                    {
+                        println!(
+                            "   Grew partition {} from {} to {}; grow its filesystem to use the space (e.g. with resize2fs)",
+                            partition.number,
+                            HumanBytes(partition.old_len),
+                            HumanBytes(partition.new_len)
+                        );
+                    }
+                    if let Some(method) = report.erase_method {
+                        println!("   Erased the whole device first with {}", method);
+                    }
+                    // A failure to eject was already shown as a warning.
+                    if !no_eject && report.eject_error.is_none() {
+                        println!("   The device has been ejected and can be unplugged.");
+                    }
                 }
                 Err(e) => {
                     // On error, finish all bars with a failure message to unblock the terminal.
-                    if is_compressed {
+                    checksum_pb.finish_and_clear();
+                    erase_pb.finish_and_clear();
+                    discard_pb.finish_and_clear();
+                    if is_compressed && !decompress_pb.is_finished() {
                         decompress_pb.finish_with_message("❌ Operation failed.");
                     }
                     write_pb.finish_and_clear();
+                    sync_pb.finish_and_clear();
                     if !no_verify {
                         verify_pb.finish_and_clear();
                     }
-                    return Err(e);
+                    wipe_pb.finish_and_clear();
+                    return Err(explain_error(e));
                 }
             }
         }
-        Commands::Read { image } => {
+        Commands::Read {
+            image,
+            limit_rate,
+            checksum_file,
+            bmap,
+            compress,
+            level,
+            threads,
+            stop_at_last_partition,
+            used_blocks_only,
+            offset,
+            length,
+            tolerate_errors,
+            verify,
+            sparse,
+            skip_space_check,
+            keep_partial,
+            pad_short_device,
+            buffered,
+            mapfile,
+        } => {
+            // With `-`, the image goes to stdout, so everything else goes to stderr.
+            let to_stdout = image.as_os_str() == "-";
+            let term = if to_stdout {
+                Term::stderr()
+            } else {
+                Term::stdout()
+            };
+            if to_stdout {
+                for (set, flag) in [
+                    (checksum_file, "--checksum-file"),
+                    (bmap, "--bmap"),
+                    (keep_partial, "--keep-partial"),
+                    (verify, "--verify"),
+                ] {
+                    if set {
+                        return Err(anyhow!("{} needs an image file, not stdout.", flag));
+                    }
+                }
+            }
+            let output = if to_stdout {
+                "standard output".to_string()
+            } else {
+                image.display().to_string()
+            };
+
             let devices = etchr_core::platform::get_removable_devices()?;
             let device = select_device(&devices, "Select the source device to READ from")?;
 
-            println!(
-                "This will read {:.1} GB from '{}'.",
-                device.size_gb, device.name
-            );
-            println!("  Device: {}", style(device.path.display()).cyan());
-            println!("  Output: {}", style(image.display()).cyan());
-            println!();
+            if offset > 0 || length.is_some() {
+                let len = length.unwrap_or(device.size_bytes.saturating_sub(offset));
+                term.write_line(&format!(
+                    "This will read {} from '{}', starting at byte {}.",
+                    HumanBytes(len),
+                    device.name,
+                    offset
+                ))?;
+            } else {
+                term.write_line(&format!(
+                    "This will read {:.1} GB from '{}'.",
+                    device.size_gb, device.name
+                ))?;
+            }
+            term.write_line(&format!(
+                "  Device: {}",
+                style(device.path.display()).cyan()
+            ))?;
+            term.write_line(&format!("  Output: {}", style(&output).cyan()))?;
+            term.write_line("")?;
 
             if !confirm_operation("Are you sure you want to proceed?")? {
-                println!("Read operation cancelled.");
+                term.write_line("Read operation cancelled.")?;
                 return Ok(());
             }
 
-            println!();
+            term.write_line("")?;
+
+            // Progress bars would only get in the way of a pipeline.
+            let hide_progress = !stdout().is_terminal();
+            let new_bar = || {
+                if hide_progress {
+                    ProgressBar::hidden()
+                } else {
+                    ProgressBar::new(0)
+                }
+            };
+            let read_pb = new_bar();
 
-            let read_pb = ProgressBar::new(0);
+            let read_style = ProgressStyle::default_bar()
+                .template(
+                    "{prefix:12} [{elapsed_precise}] [{bar:40.green/black}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+                )
+                .unwrap()
+                .progress_chars("■ ");
 
             let on_read_start = |len| {
                 read_pb.set_length(len);
                 read_pb.set_prefix("Reading");
-                read_pb.set_style(
+                read_pb.set_style(read_style.clone());
+            };
+            let on_progress = |bytes| read_pb.set_position(bytes);
+
+            // Syncing a large image can take a while, so the bar turns into a
+            // spinner until the data is on disk.
+            let on_sync_start = || {
+                read_pb.set_prefix("Syncing");
+                read_pb.set_style(spinner_style(
+                    "{prefix:12} [{elapsed_precise}] [{spinner}] {msg}",
+                ));
+                read_pb.set_message("Flushing the image to disk...");
+                read_pb.enable_steady_tick(Duration::from_millis(100));
+            };
+            let on_sync_done = || {
+                read_pb.set_prefix("Reading");
+                read_pb.set_style(read_style.clone());
+            };
+
+            let verify_pb = new_bar();
+            let on_verify_start = |len| {
+                read_pb.finish_with_message("Read complete.");
+                verify_pb.set_length(len);
+                verify_pb.set_prefix("Verifying");
+                verify_pb.set_style(
                     ProgressStyle::default_bar()
                         .template(
-                            "{prefix:12} [{elapsed_precise}] [{bar:40.green/black}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+                            "{prefix:12} [{elapsed_precise}] [{bar:40.magenta/black}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
                         )
                         .unwrap()
                         .progress_chars("■ "),
                 );
             };
-            let on_progress = |bytes| read_pb.set_position(bytes);
+            let on_verify_progress = |bytes| verify_pb.set_position(bytes);
 
-            let result =
-                etchr_core::read::run(&device.path, &image, running, on_read_start, on_progress);
+            let on_warning = |warning: Warning| {
+                let line = format!("{} {}", style("WARNING:").yellow().bold(), warning);
+                if read_pb.is_hidden() {
+                    eprintln!("{}", line);
+                } else {
+                    read_pb.println(line);
+                }
+            };
+
+            let mut options = if to_stdout {
+                ReadOptions::to_writer(&device.path, stdout().lock())
+            } else {
+                ReadOptions::new(&device.path, &image)
+            };
+            if let Some(limit_rate) = limit_rate {
+                options = options.max_bytes_per_sec(limit_rate);
+            }
+            if let Some(format) = compress.as_deref() {
+                options = options.compression(match format {
+                    "gz" => Compression::Gzip(level.unwrap_or(6)),
+                    "xz" => Compression::Xz(level.unwrap_or(6)),
+                    "lz4" if level.is_some() => {
+                        return Err(anyhow!("lz4 does not take a compression level"));
+                    }
+                    "lz4" => Compression::Lz4,
+                    _ => Compression::Zstd(level.unwrap_or(3) as i32),
+                });
+            }
+            if let Some(threads) = threads {
+                options = options.compression_threads(threads);
+            }
+            if let Some(length) = length {
+                options = options.length(length);
+            }
+            let result = options
+                .offset(offset)
+                .stop_at_last_partition(stop_at_last_partition)
+                .used_blocks_only(used_blocks_only)
+                .tolerate_errors(tolerate_errors)
+                .keep_partial(keep_partial)
+                .verify(verify)
+                .sparse(sparse)
+                .check_space(!skip_space_check)
+                .checksum_file(checksum_file)
+                .bmap(bmap)
+                .direct_io(if buffered {
+                    DirectIo::Off
+                } else {
+                    DirectIo::Preferred
+                })
+                .short_device(if pad_short_device {
+                    ShortDevice::Pad
+                } else {
+                    ShortDevice::Truncate
+                })
+                .running(running)
+                .on_read_start(on_read_start)
+                .on_progress(on_progress)
+                .on_sync_start(on_sync_start)
+                .on_sync_done(on_sync_done)
+                .on_verify_start(on_verify_start)
+                .on_verify_progress(on_verify_progress)
+                .on_warning(on_warning)
+                .run();
+            // The bar that is still running is the one to finish.
+            let active_pb = if read_pb.is_finished() {
+                &verify_pb
+            } else {
+                &read_pb
+            };
 
             match result {
-                Ok(_) => {
-                    read_pb.finish_with_message("Read complete.");
-                    println!(
+                Ok(report) => {
+                    if report.verified {
+                        verify_pb.finish_with_message("Verification successful.");
+                    } else {
+                        read_pb.finish_with_message("Read complete.");
+                    }
+                    term.write_line(&format!(
                         "\n✨ Successfully read {} to {}.",
                         style(device.path.display()).cyan(),
+                        style(&output).cyan()
+                    ))?;
+                    term.write_line(&format!(
+                        "   Read {} in {} ({}/s), sha256={}{}",
+                        HumanBytes(report.bytes_read),
+                        HumanDuration(report.duration),
+                        HumanBytes(report.average_bps() as u64),
+                        report.sha256_hex(),
+                        if report.verified { ", verified" } else { "" }
+                    ))?;
+                    term.write_line(&format!("   Device uses {}", report.sector_sizes))?;
+                    if !report.direct_io {
+                        term.write_line("   Read through the page cache, without O_DIRECT")?;
+                    }
+                    if let Some(compressed) = report.compressed_bytes {
+                        term.write_line(&format!(
+                            "   Compressed to {} ({:.1}:1)",
+                            HumanBytes(compressed),
+                            report.bytes_read as f64 / compressed.max(1) as f64
+                        ))?;
+                    }
+                    if report.bytes_skipped > 0 {
+                        term.write_line(&format!(
+                            "   Skipped {} of free space in the filesystems",
+                            HumanBytes(report.bytes_skipped)
+                        ))?;
+                    }
+                    if !report.unreadable.is_empty() {
+                        term.write_line(&format!(
+                            "   {} could not be read and is zeroed",
+                            HumanBytes(report.bytes_unreadable())
+                        ))?;
+                    }
+                    if let Some(mapfile) = &mapfile {
+                        let mut out = std::fs::File::create(mapfile)?;
+                        report.write_mapfile(&mut out)?;
+                        term.write_line(&format!(
+                            "   Saved the mapfile to {}",
+                            style(mapfile.display()).cyan()
+                        ))?;
+                    }
+                    if let Some(sidecar) = &report.checksum_file {
+                        term.write_line(&format!(
+                            "   Saved the checksum to {}",
+                            style(sidecar.display()).cyan()
+                        ))?;
+                    }
+                    if let Some(bmap) = &report.bmap_file {
+                        term.write_line(&format!(
+                            "   Saved the block map to {}",
+                            style(bmap.display()).cyan()
+                        ))?;
+                    }
+                }
+                Err(e) => {
+                    active_pb.finish_with_message("❌ Operation failed.");
+                    if let Some(CoreError::Cancelled { bytes_synced }) = e.downcast_ref()
+                        && keep_partial
+                    {
+                        return Err(anyhow!(
+                            "Read cancelled. The first {} were kept in {}.",
+                            HumanBytes(*bytes_synced),
+                            image.display()
+                        ));
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Commands::Verify {
+            image,
+            offset,
+            buffered,
+            compare,
+            hash,
+            zstd_window_log,
+        } => {
+            let devices = etchr_core::platform::get_removable_devices()?;
+            let device = select_device(&devices, "Select the device to VERIFY")?;
+            println!("  Device: {}", style(device.path.display()).cyan());
+            println!("  Image:  {}", style(image.display()).cyan());
+            println!();
+
+            let verify_pb = ProgressBar::new(0);
+            // A compressed image has no known size until it has been read.
+            let on_verify_start = |len| {
+                verify_pb.set_prefix("Verifying");
+                if len > 0 {
+                    verify_pb.set_length(len);
+                    verify_pb.set_style(
+                        ProgressStyle::default_bar()
+                            .template(
+                                "{prefix:12} [{elapsed_precise}] [{bar:40.magenta/black}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+                            )
+                            .unwrap()
+                            .progress_chars("■ "),
+                    );
+                } else {
+                    verify_pb.set_style(spinner_style(
+                        "{prefix:12} [{elapsed_precise}] [{spinner}] {bytes} ({bytes_per_sec})",
+                    ));
+                    verify_pb.enable_steady_tick(Duration::from_millis(100));
+                }
+            };
+            let on_verify_progress = |bytes| verify_pb.set_position(bytes);
+            let on_warning = |warning: Warning| {
+                verify_pb.println(format!("{} {}", style("WARNING:").yellow().bold(), warning));
+            };
+
+            let mut options = VerifyOptions::new(&image, &device.path);
+            if let Some(zstd_window_log) = zstd_window_log {
+                options = options.zstd_window_log_max(zstd_window_log);
+            }
+            let result = options
+                .offset(offset)
+                .direct_io(if buffered {
+                    DirectIo::Off
+                } else {
+                    DirectIo::Preferred
+                })
+                .method(if compare {
+                    VerifyMethod::Compare
+                } else {
+                    VerifyMethod::Hash
+                })
+                .hash(hash)
+                .running(running)
+                .on_verify_start(on_verify_start)
+                .on_verify_progress(on_verify_progress)
+                .on_warning(on_warning)
+                .run();
+            match result {
+                Ok(report) => {
+                    verify_pb.finish_with_message("Verification successful.");
+                    println!(
+                        "\n✨ {} holds {}.",
+                        style(device.path.display()).cyan(),
                         style(image.display()).cyan()
                     );
+                    println!(
+                        "   Verified {} in {} ({}/s), {}",
+                        HumanBytes(report.bytes_verified),
+                        HumanDuration(report.duration),
+                        HumanBytes(report.timing().bytes_per_sec() as u64),
+                        report.digest
+                    );
+                    if !report.direct_io {
+                        println!("   Read through the page cache, without O_DIRECT");
+                    }
                 }
                 Err(e) => {
-                    read_pb.finish_with_message("❌ Operation failed.");
-                    return Err(e);
+                    verify_pb.finish_with_message("❌ Operation failed.");
+                    return Err(explain_error(e));
                 }
             }
         }
@@ -481,8 +1810,8 @@ fn main() -> Result<()> {
 
             println!("Found {} removable devices:", devices.len());
             println!(
-                "\n  {:<12} {:<25} {:<10} {}",
-                "DEVICE", "NAME", "SIZE", "LOCATION"
+                "\n  {:<12} {:<25} {:<10} LOCATION",
+                "DEVICE", "NAME", "SIZE"
             );
             println!("  {:-<12} {:-<25} {:-<10} {:-<20}", "", "", "", "");
             for device in devices {